@@ -0,0 +1,114 @@
+//! Time dumping a 16 MiB partition of REPLACE_BZ or REPLACE_XZ operations
+//! to memory, which is mostly the time decompressing it. Build with the
+//! `bzip2-native` and `xz-native` features to time libbzip2 and liblzma
+//! instead of the pure Rust decoders.
+//!
+//! ```bash
+//! cargo bench --bench decompress
+//! cargo bench --bench decompress --features bzip2-native,xz-native
+//! ```
+
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+use payload_dumper_rust::chromeos_update_engine::{
+    install_operation::Type, Extent, InstallOperation, PartitionUpdate,
+};
+use payload_dumper_rust::dump_streaming;
+
+const BLOCK_SIZE: u64 = 4096;
+/// 8 operations of 2 MiB.
+const OPERATIONS: u64 = 8;
+const OPERATION_BLOCKS: u64 = 512;
+const RUNS: u32 = 5;
+
+/// Data compressing about as well as a filesystem image: runs of text
+/// between pseudo random bytes.
+fn data(seed: u64, length: u64) -> Vec<u8> {
+    let mut state = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+    let mut data = Vec::with_capacity(length as usize);
+    while (data.len() as u64) < length {
+        state = state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        if state >> 62 == 0 {
+            data.extend_from_slice(&state.to_le_bytes());
+        } else {
+            data.extend_from_slice(b"payload-dumper-rust ");
+        }
+    }
+    data.truncate(length as usize);
+    data
+}
+
+fn compress(op_type: Type, data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    match op_type {
+        Type::ReplaceBz => libribzip2::stream::encode_stream(
+            data,
+            &mut out,
+            1,
+            libribzip2::EncodingStrategy::Single,
+        ),
+        Type::ReplaceXz => lzma_rs::xz_compress(&mut &data[..], &mut out).expect("compress"),
+        _ => unreachable!(),
+    }
+    out
+}
+
+fn partition(op_type: Type) -> (PartitionUpdate, Vec<u8>) {
+    let mut blobs = Vec::new();
+    let operations = (0..OPERATIONS)
+        .map(|i| {
+            let data = compress(op_type, &data(i, OPERATION_BLOCKS * BLOCK_SIZE));
+            let mut operation = InstallOperation {
+                data_offset: Some(blobs.len() as u64),
+                data_length: Some(data.len() as u64),
+                dst_extents: vec![Extent {
+                    start_block: Some(i * OPERATION_BLOCKS),
+                    num_blocks: Some(OPERATION_BLOCKS),
+                }],
+                ..Default::default()
+            };
+            operation.set_type(op_type);
+            blobs.extend(data);
+            operation
+        })
+        .collect();
+    let partition = PartitionUpdate {
+        partition_name: "bench".to_string(),
+        operations,
+        ..Default::default()
+    };
+    (partition, blobs)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let size = OPERATIONS * OPERATION_BLOCKS * BLOCK_SIZE;
+    for op_type in [Type::ReplaceBz, Type::ReplaceXz] {
+        let (partition, blobs) = partition(op_type);
+        let mut elapsed = Duration::ZERO;
+        for _ in 0..RUNS {
+            let mut dst = [Cursor::new(vec![0u8; size as usize])];
+            let start = Instant::now();
+            dump_streaming(
+                &mut Cursor::new(&blobs),
+                BLOCK_SIZE,
+                &[&partition],
+                &mut [None::<Cursor<Vec<u8>>>],
+                &mut dst,
+                false,
+                |_, _| true,
+            )?;
+            elapsed += start.elapsed();
+        }
+        let elapsed = elapsed / RUNS;
+        println!(
+            "{:<11} {:>8.2?} per run, {:>7.1} MiB/s",
+            op_type.as_str_name(),
+            elapsed,
+            size as f64 / (1 << 20) as f64 / elapsed.as_secs_f64()
+        );
+    }
+    Ok(())
+}
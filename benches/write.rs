@@ -0,0 +1,122 @@
+//! Time dumping a 64 MiB partition of REPLACE, ZERO or SOURCE_COPY
+//! operations to a file, and count the write syscalls, where
+//! `/proc/self/io` has them. SOURCE_COPY copies the blocks of an old image
+//! in reverse order of operations.
+//!
+//! ```bash
+//! cargo bench --bench write
+//! ```
+
+use std::fs::{File, OpenOptions};
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+use payload_dumper_rust::chromeos_update_engine::{
+    install_operation::Type, Extent, InstallOperation, PartitionUpdate,
+};
+use payload_dumper_rust::dump_streaming;
+
+const BLOCK_SIZE: u64 = 4096;
+/// 32 operations of 2 MiB.
+const OPERATIONS: u64 = 32;
+const OPERATION_BLOCKS: u64 = 512;
+const RUNS: u32 = 5;
+
+/// Number of write syscalls of this process so far.
+fn write_syscalls() -> Option<u64> {
+    let io = std::fs::read_to_string("/proc/self/io").ok()?;
+    io.lines()
+        .find_map(|line| line.strip_prefix("syscw: "))?
+        .parse()
+        .ok()
+}
+
+fn partition(op_type: Type) -> (PartitionUpdate, Vec<u8>) {
+    let mut blobs = Vec::new();
+    let operations = (0..OPERATIONS)
+        .map(|i| {
+            let mut operation = InstallOperation {
+                dst_extents: vec![Extent {
+                    start_block: Some(i * OPERATION_BLOCKS),
+                    num_blocks: Some(OPERATION_BLOCKS),
+                }],
+                ..Default::default()
+            };
+            operation.set_type(op_type);
+            if op_type == Type::SourceCopy {
+                operation.src_extents = vec![Extent {
+                    start_block: Some((OPERATIONS - 1 - i) * OPERATION_BLOCKS),
+                    num_blocks: Some(OPERATION_BLOCKS),
+                }];
+            }
+            if op_type == Type::Replace {
+                let length = OPERATION_BLOCKS * BLOCK_SIZE;
+                operation.data_offset = Some(blobs.len() as u64);
+                operation.data_length = Some(length);
+                blobs.extend((0..length).map(|j| (i + j) as u8));
+            }
+            operation
+        })
+        .collect();
+    let partition = PartitionUpdate {
+        partition_name: "bench".to_string(),
+        operations,
+        ..Default::default()
+    };
+    (partition, blobs)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let path =
+        std::env::temp_dir().join(format!("payload-dumper-bench-{}.img", std::process::id()));
+    let old_path = path.with_extension("old.img");
+    let size = OPERATIONS * OPERATION_BLOCKS * BLOCK_SIZE;
+    std::fs::write(
+        &old_path,
+        (0..size)
+            .map(|i| (i / BLOCK_SIZE) as u8)
+            .collect::<Vec<_>>(),
+    )?;
+    for op_type in [Type::Replace, Type::Zero, Type::SourceCopy] {
+        let (partition, blobs) = partition(op_type);
+        let mut elapsed = Duration::ZERO;
+        let mut syscalls = None;
+        for _ in 0..RUNS {
+            let img = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&path)?;
+            img.set_len(size)?;
+            let mut dst = [img];
+            let mut old = [Some(File::open(&old_path)?)];
+
+            let before = write_syscalls();
+            let start = Instant::now();
+            dump_streaming(
+                &mut Cursor::new(&blobs),
+                BLOCK_SIZE,
+                &[&partition],
+                &mut old,
+                &mut dst,
+                true,
+                |_, _| true,
+            )?;
+            elapsed += start.elapsed();
+            syscalls = write_syscalls()
+                .zip(before)
+                .map(|(after, before)| after - before);
+        }
+        let syscalls = syscalls.map_or_else(|| "n/a".to_string(), |n| n.to_string());
+        println!(
+            "{:<11} {:>8.2?} per run, {:>6} write syscalls",
+            op_type.as_str_name(),
+            elapsed / RUNS,
+            syscalls
+        );
+    }
+    std::fs::remove_file(&path)?;
+    std::fs::remove_file(&old_path)?;
+    Ok(())
+}
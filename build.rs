@@ -2,5 +2,27 @@ use std::io::Result;
 
 fn main() -> Result<()> {
     prost_build::compile_protos(&["src/update_metadata.proto"], &["src/"])?;
+    println!("cargo:rerun-if-changed=src/update_metadata.proto");
+    #[cfg(feature = "puffdiff")]
+    {
+        prost_build::compile_protos(&["src/puffin.proto"], &["src/"])?;
+        println!("cargo:rerun-if-changed=src/puffin.proto");
+    }
+    #[cfg(feature = "ffi")]
+    write_header();
     Ok(())
 }
+
+/// Generate include/payload_dumper.h from src/ffi.rs with cbindgen.
+#[cfg(feature = "ffi")]
+fn write_header() {
+    let config = cbindgen::Config::from_file("cbindgen.toml").expect("cbindgen.toml is valid");
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src("src/ffi.rs")
+        .generate()
+        .expect("src/ffi.rs is parsed by cbindgen")
+        .write_to_file("include/payload_dumper.h");
+    println!("cargo:rerun-if-changed=src/ffi.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+}
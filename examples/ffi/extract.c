@@ -0,0 +1,36 @@
+// Extract a partition with the C API of the `ffi` feature:
+//
+//   cargo rustc --release --lib --features ffi --crate-type cdylib
+//   cc examples/ffi/extract.c -Iinclude -Ltarget/release -lpayload_dumper_rust -o extract
+//   LD_LIBRARY_PATH=target/release ./extract payload.bin boot boot.img
+
+#include <stdio.h>
+
+#include "payload_dumper.h"
+
+static int progress(uint64_t done, uint64_t total, void *userdata) {
+    fprintf(stderr, "\r%s: %llu/%llu", (const char *)userdata, (unsigned long long)done, (unsigned long long)total);
+    return 1;
+}
+
+int main(int argc, char **argv) {
+    if (argc != 4) {
+        fprintf(stderr, "usage: %s PAYLOAD PARTITION IMAGE\n", argv[0]);
+        return 2;
+    }
+    Payload *payload = payload_open(argv[1]);
+    if (!payload) {
+        fprintf(stderr, "%s\n", payload_last_error_message());
+        return 1;
+    }
+    for (size_t i = 0; i < payload_partition_count(payload); i++) {
+        printf("%s\n", payload_partition_name(payload, i));
+    }
+    int result = payload_extract_partition(payload, argv[2], argv[3], progress, argv[2]);
+    fprintf(stderr, "\n");
+    if (result != PAYLOAD_OK) {
+        fprintf(stderr, "%s\n", payload_last_error_message());
+    }
+    payload_close(payload);
+    return result == PAYLOAD_OK ? 0 : 1;
+}
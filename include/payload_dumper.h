@@ -0,0 +1,92 @@
+#ifndef PAYLOAD_DUMPER_H
+#define PAYLOAD_DUMPER_H
+
+/* Generated with cbindgen from src/ffi.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Success.
+#define PAYLOAD_OK 0
+
+// The payload can't be read, or the partition can't be extracted.
+#define PAYLOAD_ERROR -1
+
+// An argument is null, not UTF-8, or not a partition of the payload.
+#define PAYLOAD_ERROR_INVALID_ARGUMENT -2
+
+// The progress callback stopped the extraction.
+#define PAYLOAD_ERROR_CANCELLED -3
+
+// A bug, the function panicked.
+#define PAYLOAD_ERROR_PANIC -4
+
+// A payload opened with [`payload_open`], freed with [`payload_close`].
+typedef struct Payload Payload;
+
+// Called with the number of operations of the partition applied and their
+// total, maybe from another thread. Returning zero stops the extraction.
+typedef int (*PayloadProgressCallback)(uint64_t done, uint64_t total, void *userdata);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Open the payload, or the OTA zip file with the payload, at `path`.
+// Returns null if it fails.
+//
+// # Safety
+//
+// `path` must be null or a NUL terminated string.
+Payload *payload_open(const char *path);
+
+// Number of partitions of `payload`, 0 if it's null.
+//
+// # Safety
+//
+// `payload` must be null or returned by [`payload_open`] and not closed.
+size_t payload_partition_count(Payload *payload);
+
+// Name of the partition `index` of `payload`, valid until it's closed.
+// Returns null if `index` is out of range.
+//
+// # Safety
+//
+// `payload` must be null or returned by [`payload_open`] and not closed.
+const char *payload_partition_name(Payload *payload, size_t index);
+
+// Extract the partition `name` of `payload` to a new image at `out_path`,
+// calling `progress`, if not null, with `userdata` as operations are
+// applied. Only full payloads can be extracted, as the old images of
+// delta payloads can't be given.
+//
+// # Safety
+//
+// `payload` must be null or returned by [`payload_open`] and not closed,
+// and `name` and `out_path` null or NUL terminated strings. `userdata` is
+// only passed to `progress`.
+int payload_extract_partition(Payload *payload,
+                              const char *name,
+                              const char *out_path,
+                              PayloadProgressCallback progress,
+                              void *userdata);
+
+// Message of the last error on this thread, or null if there was none.
+// It's valid until the next call failing on this thread.
+const char *payload_last_error_message(void);
+
+// Close `payload`, which may be null.
+//
+// # Safety
+//
+// `payload` must be null or returned by [`payload_open`] and not closed.
+void payload_close(Payload *payload);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* PAYLOAD_DUMPER_H */
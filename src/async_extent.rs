@@ -0,0 +1,389 @@
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+
+use crate::chromeos_update_engine;
+use crate::extent::{coalesce, Fragment, Overflow, SPARSE_HOLE};
+
+/// A section of `length` bytes starting at `offset` in `inner`, like
+/// [`SectionFile`](crate::SectionFile) for async readers and writers.
+pub struct AsyncSectionFile<T> {
+    inner: T,
+    offset: u64,
+    length: u64,
+
+    pos: u64,
+    /// Whether a seek of `inner` is started but not complete.
+    seeking: bool,
+}
+
+impl<T: AsyncSeek + Unpin> AsyncSectionFile<T> {
+    /// The section of `inner`, positioned at its start. The seek of `inner`
+    /// is started here and completed by the first read, write or seek.
+    pub fn new(mut inner: T, offset: u64, length: u64) -> std::io::Result<Self> {
+        if offset.checked_add(length).is_none() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("section of {} bytes at offset {} overflows", length, offset),
+            ));
+        }
+        Pin::new(&mut inner).start_seek(SeekFrom::Start(offset))?;
+
+        Ok(Self {
+            inner,
+            offset,
+            length,
+
+            pos: 0,
+            seeking: true,
+        })
+    }
+
+    /// Complete the pending seek of `inner`, if any.
+    fn poll_seeked(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        if self.seeking {
+            let pos = ready!(Pin::new(&mut self.inner).poll_complete(cx))?;
+            self.seeking = false;
+            self.pos = pos - self.offset;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T> AsyncSectionFile<T> {
+    /// Offset of the section in the inner reader.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Consume the section, returning the inner reader.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: AsyncSeek + Unpin> AsyncSeek for AsyncSectionFile<T> {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+        let this = self.get_mut();
+        let pos = match position {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::Current(pos) => this.pos.checked_add_signed(pos),
+            SeekFrom::End(pos) => this.length.checked_add_signed(pos),
+        }
+        .filter(|&pos| pos <= this.length)
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek outside of the section",
+            )
+        })?;
+
+        Pin::new(&mut this.inner).start_seek(SeekFrom::Start(this.offset + pos))?;
+        this.seeking = true;
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        let this = self.get_mut();
+        ready!(this.poll_seeked(cx))?;
+        Poll::Ready(Ok(this.pos))
+    }
+}
+
+impl<T: AsyncRead + AsyncSeek + Unpin> AsyncRead for AsyncSectionFile<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_seeked(cx))?;
+        let to_read =
+            std::cmp::min(buf.remaining() as u64, this.length.saturating_sub(this.pos)) as usize;
+        if to_read == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let mut section = ReadBuf::new(buf.initialize_unfilled_to(to_read));
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut section))?;
+        let read = section.filled().len();
+        buf.advance(read);
+        this.pos += read as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + AsyncSeek + Unpin> AsyncWrite for AsyncSectionFile<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_seeked(cx))?;
+        let to_write =
+            std::cmp::min(buf.len() as u64, this.length.saturating_sub(this.pos)) as usize;
+        if to_write == 0 {
+            return Poll::Ready(Ok(0));
+        }
+        let write = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..to_write]))?;
+        this.pos += write as u64;
+        Poll::Ready(Ok(write))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// The extents of an operation in `inner`, read or written as one
+/// contiguous file from their start. Extents that are holes, see
+/// [`SPARSE_HOLE`](crate::SPARSE_HOLE), read as zeros and writes to them
+/// are discarded.
+pub struct AsyncFragmentFile<T> {
+    inner: T,
+    fragments: Vec<Fragment>,
+    index: usize,
+    fragment_pos: u64,
+    size: u64,
+    /// Whether a seek of `inner` is started but not complete.
+    seeking: bool,
+}
+
+impl<T: AsyncSeek + Unpin> AsyncFragmentFile<T> {
+    fn new(inner: T, fragments: Vec<Fragment>) -> std::io::Result<Self> {
+        if fragments.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Empty fragments",
+            ));
+        }
+        let fragments = coalesce(&fragments)?;
+        let size = fragments.iter().try_fold(0u64, |size, fragment| {
+            size.checked_add(fragment.size).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "total size of the extents overflows",
+                )
+            })
+        })?;
+
+        let mut file = Self {
+            inner,
+            fragments,
+            index: 0,
+            fragment_pos: 0,
+            size,
+            seeking: false,
+        };
+        file.skip_empty()?;
+        Ok(file)
+    }
+
+    pub fn new_from_extents(
+        inner: T,
+        extents: &[chromeos_update_engine::Extent],
+        block_size: u64,
+    ) -> std::io::Result<Self> {
+        let fragments = extents
+            .iter()
+            .map(|extent| Fragment::from_extent(extent, block_size))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        Self::new(inner, fragments)
+    }
+
+    /// Move past empty fragments, and start seeking `inner` to the current
+    /// position unless it's in a hole.
+    fn skip_empty(&mut self) -> std::io::Result<()> {
+        while let Some(fragment) = self.fragments.get(self.index) {
+            if self.fragment_pos < fragment.size {
+                if fragment.offset != SPARSE_HOLE {
+                    Pin::new(&mut self.inner)
+                        .start_seek(SeekFrom::Start(fragment.offset + self.fragment_pos))?;
+                    self.seeking = true;
+                }
+                return Ok(());
+            }
+            self.index += 1;
+            self.fragment_pos = 0;
+        }
+        Ok(())
+    }
+
+    /// The current fragment and the bytes left in it, once `inner` is
+    /// seeked to it, or `None` at the end.
+    fn poll_fragment(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<Option<(bool, usize)>>> {
+        if self.seeking {
+            ready!(Pin::new(&mut self.inner).poll_complete(cx))?;
+            self.seeking = false;
+        }
+        Poll::Ready(Ok(self.fragments.get(self.index).map(|fragment| {
+            let remaining = (fragment.size - self.fragment_pos).min(usize::MAX as u64) as usize;
+            (fragment.offset == SPARSE_HOLE, remaining)
+        })))
+    }
+
+    /// Advance by `n` bytes in the current fragment, to the next one at its
+    /// end.
+    fn advance(&mut self, n: usize) -> std::io::Result<()> {
+        self.fragment_pos += n as u64;
+        if self.fragment_pos < self.fragments[self.index].size {
+            return Ok(());
+        }
+        self.index += 1;
+        self.fragment_pos = 0;
+        self.skip_empty()
+    }
+
+    /// Error of the underlying file ending before the current fragment.
+    fn inner_eof_error(&self, kind: std::io::ErrorKind, operation: &str) -> std::io::Error {
+        let fragment = &self.fragments[self.index];
+        std::io::Error::new(
+            kind,
+            format!(
+                "failed to {} at offset {}, the file is too short",
+                operation,
+                fragment.offset + self.fragment_pos
+            ),
+        )
+    }
+}
+
+impl<T> AsyncFragmentFile<T> {
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Get a mutable reference to the underlying file, which must not be
+    /// seeked while this `AsyncFragmentFile` is used.
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: AsyncRead + AsyncSeek + Unpin> AsyncRead for AsyncFragmentFile<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let Some((hole, remaining)) = ready!(this.poll_fragment(cx))? else {
+            return Poll::Ready(Ok(()));
+        };
+        let to_read = remaining.min(buf.remaining());
+        if to_read == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let read = if hole {
+            buf.initialize_unfilled_to(to_read).fill(0);
+            buf.advance(to_read);
+            to_read
+        } else {
+            let mut fragment = ReadBuf::new(buf.initialize_unfilled_to(to_read));
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut fragment))?;
+            let read = fragment.filled().len();
+            if read == 0 {
+                return Poll::Ready(Err(
+                    this.inner_eof_error(std::io::ErrorKind::UnexpectedEof, "read")
+                ));
+            }
+            buf.advance(read);
+            read
+        };
+        Poll::Ready(this.advance(read))
+    }
+}
+
+impl<T: AsyncWrite + AsyncSeek + Unpin> AsyncWrite for AsyncFragmentFile<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let Some((hole, remaining)) = ready!(this.poll_fragment(cx))? else {
+            return match buf.is_empty() {
+                true => Poll::Ready(Ok(0)),
+                false => Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::WriteZero,
+                    Overflow(buf.len() as u64),
+                ))),
+            };
+        };
+        let to_write = remaining.min(buf.len());
+        let written = match hole {
+            true => to_write,
+            false => ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..to_write]))?,
+        };
+        if written == 0 && to_write > 0 {
+            return Poll::Ready(Err(
+                this.inner_eof_error(std::io::ErrorKind::WriteZero, "write")
+            ));
+        }
+        this.advance(written)?;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::tests::extent;
+
+    #[test]
+    fn adapters() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let mut inner = Cursor::new((0..16u8).collect::<Vec<_>>());
+            let mut section = AsyncSectionFile::new(&mut inner, 4, 8).unwrap();
+            let mut data = Vec::new();
+            section.read_to_end(&mut data).await.unwrap();
+            assert_eq!(data, (4..12).collect::<Vec<_>>());
+            assert_eq!(section.seek(SeekFrom::End(-2)).await.unwrap(), 6);
+            assert_eq!(section.read(&mut [0; 4]).await.unwrap(), 2);
+            assert!(section.seek(SeekFrom::Start(9)).await.is_err());
+
+            let extents = [extent(3, 1), extent(SPARSE_HOLE, 1), extent(0, 2)];
+            let mut fragments =
+                AsyncFragmentFile::new_from_extents(&mut inner, &extents, 2).unwrap();
+            assert_eq!(fragments.size(), 8);
+            let mut data = vec![0xff; 8];
+            fragments.read_exact(&mut data).await.unwrap();
+            assert_eq!(data, [6, 7, 0, 0, 0, 1, 2, 3]);
+
+            let mut fragments =
+                AsyncFragmentFile::new_from_extents(&mut inner, &extents, 2).unwrap();
+            fragments
+                .write_all(&[10, 11, 12, 13, 14, 15, 16, 17])
+                .await
+                .unwrap();
+            assert!(fragments.write_all(&[0]).await.is_err());
+            assert_eq!(&inner.get_ref()[..8], [14, 15, 16, 17, 4, 5, 10, 11]);
+        });
+    }
+}
@@ -0,0 +1,495 @@
+//! Async API, with the `async` feature.
+//!
+//! ```no_run
+//! use payload_dumper_rust::{AsyncPayload, PayloadError};
+//! use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite};
+//!
+//! async fn dump_boot<R, W>(reader: R, img: &mut W) -> Result<(), PayloadError>
+//! where
+//!     R: AsyncRead + AsyncSeek + Unpin,
+//!     W: AsyncRead + AsyncWrite + AsyncSeek + Unpin,
+//! {
+//!     let mut payload = AsyncPayload::parse(reader).await?;
+//!     let mut dump = payload.dump_partition("boot", None::<&mut W>, img)?;
+//!     while let Some(progress) = dump.next().await {
+//!         let progress = progress?;
+//!         println!("{}/{} bytes", progress.bytes_written, progress.total_bytes);
+//!     }
+//!     Ok(())
+//! }
+//! ```
+
+use std::io::SeekFrom;
+use std::time::Instant;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tracing::Instrument;
+
+use crate::async_extent::AsyncFragmentFile;
+use crate::chromeos_update_engine::{
+    install_operation::Type, Extent, InstallOperation, PartitionUpdate,
+};
+use crate::extent::extents_size;
+use crate::payload::find_partition;
+use crate::trace::{operation_span, partition_span};
+use crate::{
+    apply_in_memory, blob_offset, buffer_len, check_block_size, validate_dst_extents, DumpContext,
+};
+use crate::{DeltaUpdateFile, DumpStats, OperationResult, PayloadError, PayloadHeader};
+
+/// A parsed payload with its async reader, like [`Payload`](crate::Payload).
+pub struct AsyncPayload<R> {
+    reader: R,
+    file: DeltaUpdateFile,
+    /// Partitions of major version 1 payloads, which are not in
+    /// `manifest.partitions`.
+    legacy_partitions: Option<Vec<PartitionUpdate>>,
+}
+
+impl<R: AsyncRead + AsyncSeek + Unpin> AsyncPayload<R> {
+    /// Parse the payload in `reader`, from its start, up to the metadata
+    /// signature like [`DeltaUpdateFile::read_metadata`]. For a payload in
+    /// an OTA zip file, pass an [`AsyncSectionFile`](crate::AsyncSectionFile)
+    /// of the `payload.bin` entry.
+    pub async fn parse(mut reader: R) -> Result<Self, PayloadError> {
+        reader.rewind().await?;
+        // The magic, the version and the size of the manifest, then the size
+        // of the metadata signature from version 2.
+        let mut metadata = vec![0; 20];
+        reader.read_exact(&mut metadata).await?;
+        let file_format_version = u64::from_be_bytes(metadata[4..12].try_into().unwrap());
+        let manifest_size = u64::from_be_bytes(metadata[12..20].try_into().unwrap());
+        let mut metadata_signature_size = 0;
+        if &metadata[..4] == b"CrAU" && file_format_version >= 2 {
+            let mut size = [0; 4];
+            reader.read_exact(&mut size).await?;
+            metadata.extend_from_slice(&size);
+            metadata_signature_size = u32::from_be_bytes(size);
+        }
+        // A huge size of the manifest stops at the end of the reader.
+        let rest = manifest_size.saturating_add(metadata_signature_size as u64);
+        (&mut reader).take(rest).read_to_end(&mut metadata).await?;
+
+        let header = PayloadHeader::parse_prefix(&mut &metadata[..])?;
+        Ok(Self::new(reader, header.into_delta_update_file(Vec::new())))
+    }
+}
+
+impl<R> AsyncPayload<R> {
+    /// Create a payload from its `reader` and `file` already parsed from it.
+    pub fn new(reader: R, file: DeltaUpdateFile) -> Self {
+        let legacy_partitions = match file.file_format_version {
+            version if version < 2 => Some(file.partitions().into_owned()),
+            _ => None,
+        };
+        Self {
+            reader,
+            file,
+            legacy_partitions,
+        }
+    }
+
+    /// The parsed payload.
+    pub fn delta_update_file(&self) -> &DeltaUpdateFile {
+        &self.file
+    }
+
+    /// Get a mutable reference to the reader of the payload.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// Consume the payload, returning its reader.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Offset of the data blobs in the payload.
+    pub fn blobs_offset(&self) -> u64 {
+        self.file.blobs_offset
+    }
+
+    /// Size of the blocks of the extents of the operations.
+    pub fn block_size(&self) -> u64 {
+        self.file.manifest.block_size() as u64
+    }
+
+    /// Partitions updated by this payload, see [`DeltaUpdateFile::partitions`].
+    pub fn partitions(&self) -> &[PartitionUpdate] {
+        self.legacy_partitions
+            .as_deref()
+            .unwrap_or(&self.file.manifest.partitions)
+    }
+
+    /// The partition named `name`.
+    pub fn partition(&self, name: &str) -> Option<&PartitionUpdate> {
+        self.partitions().iter().find(|p| p.partition_name == name)
+    }
+
+    /// Start dumping the partition `name` to `dst`, after checking its dst
+    /// extents with [`validate_dst_extents`]. Operations are applied one by
+    /// one by [`DumpPartition::next`].
+    ///
+    /// `old` is the image of the old partition, only needed by delta
+    /// payloads. `dst` must be readable because deprecated operations like
+    /// MOVE and BSDIFF read the partition being written, `old` is copied to it before
+    /// the first operation then.
+    pub fn dump_partition<'a, O, W>(
+        &'a mut self,
+        name: &str,
+        old: Option<&'a mut O>,
+        dst: &'a mut W,
+    ) -> Result<DumpPartition<'a, R, O, W>, PayloadError> {
+        let block_size = self.block_size();
+        let partition = find_partition(&self.legacy_partitions, &self.file, name)?;
+        check_block_size(block_size)
+            .and_then(|_| validate_dst_extents(partition, block_size))
+            .map_err(|e| e.in_partition(name))?;
+        let total_bytes = partition
+            .operations
+            .iter()
+            .flat_map(|operation| &operation.dst_extents)
+            .map(|extent| extent.num_blocks() * block_size)
+            .sum();
+        Ok(DumpPartition {
+            reader: &mut self.reader,
+            blobs_offset: self.file.blobs_offset,
+            block_size,
+            partition,
+            old,
+            dst,
+            next: 0,
+            failed: false,
+            start: Instant::now(),
+            stats: DumpStats::default(),
+            total_bytes,
+            span: partition_span(partition),
+        })
+    }
+}
+
+/// Progress of a [`DumpPartition`], after an operation is applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DumpProgress {
+    /// Index of the operation applied.
+    pub index: usize,
+    /// Number of operations of the partition.
+    pub operations: usize,
+    /// Bytes written so far, the size of the dst extents of the operations
+    /// applied.
+    pub bytes_written: u64,
+    /// Bytes written by all the operations.
+    pub total_bytes: u64,
+}
+
+/// A partition being dumped by [`AsyncPayload::dump_partition`], as a
+/// stream of [`DumpProgress`] events.
+///
+/// Each call to [`DumpPartition::next`] applies the next operation: its
+/// data is read from the payload and the src extents from `old` or `dst`,
+/// the operation is applied in memory on a blocking thread with
+/// [`tokio::task::spawn_blocking`], and the result is written to `dst`.
+/// Dropping it stops dumping after the last operation applied.
+///
+/// Unlike [`DeltaUpdateFile::dump_partition`], ZERO operations always
+/// write zeros, as `dst` may not be a fresh image.
+pub struct DumpPartition<'a, R, O, W> {
+    reader: &'a mut R,
+    blobs_offset: u64,
+    block_size: u64,
+    partition: &'a PartitionUpdate,
+    old: Option<&'a mut O>,
+    dst: &'a mut W,
+    /// Index of the next operation.
+    next: usize,
+    /// Whether an operation failed, which ends the stream.
+    failed: bool,
+    start: Instant,
+    stats: DumpStats,
+    total_bytes: u64,
+    span: tracing::Span,
+}
+
+impl<R, O, W> DumpPartition<'_, R, O, W>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+    O: AsyncRead + AsyncSeek + Unpin,
+    W: AsyncRead + AsyncWrite + AsyncSeek + Unpin,
+{
+    /// Apply the next operation, returning its progress, or `None` once all
+    /// operations are applied or one failed.
+    pub async fn next(&mut self) -> Option<Result<DumpProgress, PayloadError>> {
+        if self.failed {
+            return None;
+        }
+        let partition = self.partition;
+        let index = self.next;
+        let operation = partition.operations.get(index)?;
+        if index == 0 {
+            if let Err(e) = self.copy_old().await {
+                self.failed = true;
+                return Some(Err(e.in_partition(&partition.partition_name)));
+            }
+        }
+        let span = self.span.in_scope(|| operation_span(index, operation));
+        let result = match self.apply(operation).instrument(span).await {
+            Ok(result) => result,
+            Err(e) => {
+                self.failed = true;
+                return Some(Err(e
+                    .in_operation(index, operation.r#type)
+                    .in_partition(&partition.partition_name)));
+            }
+        };
+
+        self.next += 1;
+        self.stats.add(&result);
+        Some(Ok(DumpProgress {
+            index,
+            operations: partition.operations.len(),
+            bytes_written: self.stats.bytes_written,
+            total_bytes: self.total_bytes,
+        }))
+    }
+
+    /// Statistics of the operations applied so far.
+    pub fn stats(&self) -> DumpStats {
+        DumpStats {
+            elapsed: self.start.elapsed(),
+            ..self.stats.clone()
+        }
+    }
+
+    /// Copy `old` to `dst` if operations read the partition being written,
+    /// like [`DeltaUpdateFile::dump_partition_with`] does.
+    async fn copy_old(&mut self) -> Result<(), PayloadError> {
+        let reading = self
+            .partition
+            .operations
+            .iter()
+            .enumerate()
+            .find(|(_, operation)| matches!(operation.r#type(), Type::Move | Type::Bsdiff));
+        let Some((index, operation)) = reading else {
+            return Ok(());
+        };
+        let old = self.old.as_deref_mut().ok_or_else(|| {
+            PayloadError::MissingOldImage(operation.r#type()).in_operation(index, operation.r#type)
+        })?;
+        let size = self
+            .partition
+            .new_partition_info
+            .as_ref()
+            .and_then(|info| info.size)
+            .unwrap_or(u64::MAX);
+        old.rewind().await?;
+        self.dst.rewind().await?;
+        let mut old = old.take(size);
+        let mut buffer = vec![0; 1 << 16];
+        loop {
+            let n = old.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            self.dst.write_all(&buffer[..n]).await?;
+        }
+        Ok(())
+    }
+
+    async fn apply(
+        &mut self,
+        operation: &InstallOperation,
+    ) -> Result<OperationResult, PayloadError> {
+        let start = Instant::now();
+        let block_size = self.block_size;
+        // `operation.r#type()` falls back to REPLACE for unknown types, which
+        // must not be applied.
+        let op_type = Type::from_i32(operation.r#type)
+            .ok_or(PayloadError::UnsupportedOperation(operation.r#type))?;
+        let size = extents_size(&operation.dst_extents, block_size)?;
+        let (output, result) = match op_type {
+            Type::Discard => {
+                return Ok(OperationResult {
+                    bytes_written: 0,
+                    ..OperationResult::skipped(operation, block_size)
+                })
+            }
+            Type::Zero => (
+                vec![0; buffer_len(size)?],
+                OperationResult::skipped(operation, block_size),
+            ),
+            _ => {
+                let data = match (operation.data_offset, operation.data_length) {
+                    (Some(offset), Some(length)) => {
+                        let mut data = vec![0; buffer_len(length)?];
+                        self.reader
+                            .seek(SeekFrom::Start(blob_offset(self.blobs_offset, offset)?))
+                            .await?;
+                        self.reader.read_exact(&mut data).await?;
+                        data
+                    }
+                    _ => Vec::new(),
+                };
+                let src = match op_type {
+                    _ if operation.src_extents.is_empty() => None,
+                    Type::Move | Type::Bsdiff => Some(
+                        read_extents(&mut *self.dst, &operation.src_extents, block_size).await?,
+                    ),
+                    _ => match self.old.as_deref_mut() {
+                        Some(old) => {
+                            Some(read_extents(old, &operation.src_extents, block_size).await?)
+                        }
+                        None => return Err(PayloadError::MissingOldImage(op_type)),
+                    },
+                };
+                let operation = operation.clone();
+                let span = tracing::Span::current();
+                tokio::task::spawn_blocking(move || {
+                    span.in_scope(|| {
+                        apply_in_memory(
+                            &mut DumpContext::new(),
+                            &operation,
+                            &data,
+                            src.as_deref(),
+                            block_size,
+                        )
+                    })
+                })
+                .await
+                .map_err(std::io::Error::from)??
+            }
+        };
+
+        let mut dst = AsyncFragmentFile::new_from_extents(
+            &mut *self.dst,
+            &operation.dst_extents,
+            block_size,
+        )?;
+        dst.write_all(&output).await?;
+        dst.flush().await?;
+        Ok(OperationResult {
+            duration: start.elapsed(),
+            ..result
+        })
+    }
+}
+
+/// Read all the bytes of `extents` of `src`.
+async fn read_extents<T: AsyncRead + AsyncSeek + Unpin>(
+    src: &mut T,
+    extents: &[Extent],
+    block_size: u64,
+) -> std::io::Result<Vec<u8>> {
+    let mut src = AsyncFragmentFile::new_from_extents(src, extents, block_size)?;
+    let mut buffer = vec![0; buffer_len(src.size())?];
+    src.read_exact(&mut buffer).await?;
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use prost::Message;
+
+    use super::*;
+    use crate::chromeos_update_engine::DeltaArchiveManifest;
+    use crate::tests::extent;
+    use crate::{CreateOptions, PayloadBuilder};
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn dump() -> Result<(), Box<dyn std::error::Error>> {
+        let image: Vec<u8> = (0..3 * 4096u32).map(|i| (i % 251) as u8).collect();
+        let mut builder = PayloadBuilder::new(Cursor::new(Vec::new()), CreateOptions::default())?;
+        builder.add_partition("boot", &image[..])?;
+        let mut payload = Vec::new();
+        builder.finish(&mut Cursor::new(&mut payload))?;
+
+        block_on(async {
+            let mut payload = AsyncPayload::parse(Cursor::new(payload)).await?;
+            assert_eq!(payload.partitions().len(), 1);
+            let mut img = Cursor::new(Vec::new());
+            let mut dump =
+                payload.dump_partition("boot", None::<&mut Cursor<Vec<u8>>>, &mut img)?;
+            let mut events = Vec::new();
+            while let Some(progress) = dump.next().await {
+                events.push(progress?);
+            }
+            let last = events.last().unwrap();
+            assert_eq!(last.index + 1, last.operations);
+            assert_eq!(last.bytes_written, last.total_bytes);
+            assert!(events
+                .windows(2)
+                .all(|w| w[0].bytes_written < w[1].bytes_written));
+            assert_eq!(dump.stats().bytes_written, last.total_bytes);
+            assert_eq!(img.into_inner(), image);
+
+            assert!(matches!(
+                payload.dump_partition(
+                    "system",
+                    None::<&mut Cursor<Vec<u8>>>,
+                    &mut Cursor::new(Vec::<u8>::new())
+                ),
+                Err(PayloadError::PartitionNotFound(_))
+            ));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn source_copy() -> Result<(), Box<dyn std::error::Error>> {
+        let mut operation = InstallOperation {
+            src_extents: vec![extent(1, 1)],
+            dst_extents: vec![extent(0, 1)],
+            ..Default::default()
+        };
+        operation.set_type(Type::SourceCopy);
+        let partition = PartitionUpdate {
+            partition_name: "boot".to_string(),
+            operations: vec![operation],
+            ..Default::default()
+        };
+        let manifest = DeltaArchiveManifest {
+            block_size: Some(4096),
+            partitions: vec![partition],
+            ..Default::default()
+        }
+        .encode_to_vec();
+        let header = [
+            &b"CrAU"[..],
+            &2u64.to_be_bytes(),
+            &(manifest.len() as u64).to_be_bytes(),
+            &0u32.to_be_bytes(),
+        ];
+
+        block_on(async {
+            let mut payload = AsyncPayload::parse(Cursor::new(
+                [&header[..], &[&manifest[..]]].concat().concat(),
+            ))
+            .await?;
+            let mut old = Cursor::new([vec![1; 4096], vec![2; 4096]].concat());
+            let mut img = Cursor::new(Vec::new());
+            let mut dump = payload.dump_partition("boot", Some(&mut old), &mut img)?;
+            dump.next().await.unwrap()?;
+            assert!(dump.next().await.is_none());
+            assert_eq!(img.into_inner(), vec![2; 4096]);
+
+            let mut img = Cursor::new(Vec::new());
+            let mut dump =
+                payload.dump_partition("boot", None::<&mut Cursor<Vec<u8>>>, &mut img)?;
+            assert!(matches!(
+                dump.next().await,
+                Some(Err(PayloadError::Partition { .. }))
+            ));
+            assert!(dump.next().await.is_none());
+            Ok(())
+        })
+    }
+}
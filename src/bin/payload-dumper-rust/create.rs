@@ -0,0 +1,70 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use indicatif::{ProgressBar, ProgressStyle};
+use payload_dumper_rust::{CreateOptions, PayloadBuilder};
+
+use size::Size;
+
+use crate::error::{ErrorClass, Failure};
+use crate::temp_file::TempFile;
+
+/// Create an unsigned full payload at `out` from the partition `images`.
+/// The data blobs are written to a temporary file next to `out` first, as
+/// they come after the manifest.
+pub(crate) fn create_payload(
+    images: &[(String, PathBuf)],
+    out: &Path,
+    options: CreateOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for (i, (name, _)) in images.iter().enumerate() {
+        if images[..i].iter().any(|(other, _)| other == name) {
+            return Err(Failure::new(
+                ErrorClass::Usage,
+                format!("Partition {} is given more than once", name),
+            )
+            .into());
+        }
+    }
+
+    let blobs_path = TempFile(out.with_extension("blobs.tmp"));
+    let blobs = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&blobs_path.0)
+        .map_err(|e| Failure::about(&e, format!("{}: {}", blobs_path.0.display(), e)))?;
+    let mut builder = PayloadBuilder::new(blobs, options)?;
+    let style = ProgressStyle::default_bar()
+        .template("[{elapsed_precise}] {bar:40.cyan/blue} {bytes:>10}/{total_bytes:10} {msg}")?;
+    for (name, path) in images {
+        let image = File::open(path)
+            .map_err(|e| Failure::about(&e, format!("{}: {}", path.display(), e)))?;
+        let bar = ProgressBar::new(image.metadata()?.len()).with_style(style.clone());
+        bar.set_message(name.clone());
+        let partition = builder.add_partition(name, bar.wrap_read(BufReader::new(image)))?;
+        bar.finish_and_clear();
+        let size = partition
+            .new_partition_info
+            .as_ref()
+            .map_or(0, |info| info.size());
+        println!(
+            "{}: {}, {} operations",
+            name,
+            Size::from_bytes(size),
+            partition.operations.len()
+        );
+    }
+
+    let mut payload = BufWriter::new(
+        File::create(out).map_err(|e| Failure::about(&e, format!("{}: {}", out.display(), e)))?,
+    );
+    builder.finish(&mut payload)?;
+    payload.flush()?;
+    println!("Wrote {}", out.display());
+    Ok(())
+}
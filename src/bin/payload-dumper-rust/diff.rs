@@ -0,0 +1,93 @@
+use std::path::Path;
+
+use payload_dumper_rust::{diff_partitions, open_payload, Change, PayloadHeader};
+
+use size::Size;
+
+use crate::error::Failure;
+use crate::format::print_table;
+use crate::input::open_input;
+use crate::json::json_string;
+
+/// Print how the partitions of the payload at `new` differ from those of
+/// the payload at `old`, as a table or JSON.
+pub(crate) fn print_diff(
+    old: &Path,
+    new: &Path,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let read_header = |path: &Path| -> Result<PayloadHeader, Box<dyn std::error::Error>> {
+        let mut file = open_payload(open_input(path)?)
+            .map_err(|e| Failure::about(&e, format!("Failed to open {}: {}", path.display(), e)))?;
+        Ok(PayloadHeader::parse_prefix(&mut file)
+            .map_err(|e| Failure::about(&e, format!("{}: {}", path.display(), e)))?)
+    };
+    let (old, new) = (read_header(old)?, read_header(new)?);
+    let diff = diff_partitions(&old.partitions(), &new.partitions());
+
+    let size =
+        |size: Option<u64>| size.map_or("-".to_string(), |size| Size::from_bytes(size).to_string());
+    let delta = |delta: i64| match delta {
+        0 => "0".to_string(),
+        d if d > 0 => format!("+{}", Size::from_bytes(d)),
+        d => format!("-{}", Size::from_bytes(d.unsigned_abs())),
+    };
+    let json_number = |n: Option<u64>| n.map_or("null".to_string(), |n| n.to_string());
+    if json {
+        let partitions: Vec<_> = diff
+            .iter()
+            .map(|d| {
+                format!(
+                    "    {{\"name\": {}, \"status\": {}, \"old_size\": {}, \"new_size\": {}, \"old_data_length\": {}, \"new_data_length\": {}, \"data_length_delta\": {}}}",
+                    json_string(&d.name),
+                    json_string(&d.change.to_string()),
+                    json_number(d.old_size),
+                    json_number(d.new_size),
+                    d.old_data_length,
+                    d.new_data_length,
+                    d.data_length_delta()
+                )
+            })
+            .collect();
+        println!("{{");
+        println!("  \"partitions\": [\n{}\n  ]", partitions.join(",\n"));
+        println!("}}");
+        return Ok(());
+    }
+
+    let mut table = vec![[
+        "NAME".to_string(),
+        "STATUS".to_string(),
+        "OLD SIZE".to_string(),
+        "NEW SIZE".to_string(),
+        "OLD DATA".to_string(),
+        "NEW DATA".to_string(),
+        "DATA DELTA".to_string(),
+    ]];
+    for d in &diff {
+        table.push([
+            d.name.clone(),
+            d.change.to_string(),
+            size(d.old_size),
+            size(d.new_size),
+            size(Some(d.old_data_length).filter(|_| d.change != Change::Added)),
+            size(Some(d.new_data_length).filter(|_| d.change != Change::Removed)),
+            delta(d.data_length_delta()),
+        ]);
+    }
+    print_table(&table);
+
+    let count = |change: Change| diff.iter().filter(|d| d.change == change).count();
+    let mut summary = format!(
+        "{} added, {} removed, {} changed, {} unchanged",
+        count(Change::Added),
+        count(Change::Removed),
+        count(Change::Changed),
+        count(Change::Unchanged)
+    );
+    if count(Change::Unknown) > 0 {
+        summary += &format!(", {} without hashes to compare", count(Change::Unknown));
+    }
+    println!("{}", summary);
+    Ok(())
+}
@@ -0,0 +1,145 @@
+use payload_dumper_rust::{PayloadError, SignatureError};
+
+/// Class of an error, whose value is the exit code, see the README.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ErrorClass {
+    /// Any other error.
+    Other = 1,
+    /// Invalid arguments, or arguments not matching the payload.
+    Usage = 2,
+    /// The payload, or an image, is invalid or corrupt.
+    Format = 3,
+    /// An image or a signature doesn't verify.
+    Verification = 4,
+    /// Reading or writing a file failed.
+    Io = 5,
+    /// The payload needs a feature which isn't supported or built.
+    Unsupported = 6,
+}
+
+impl ErrorClass {
+    /// Class of `e`, a [`Failure`], [`PayloadError`], [`SignatureError`],
+    /// I/O or parse error, or any other.
+    pub(crate) fn of(e: &(dyn std::error::Error + 'static)) -> Self {
+        if let Some(failure) = e.downcast_ref::<Failure>() {
+            failure.class
+        } else if let Some(e) = e.downcast_ref::<PayloadError>() {
+            Self::of_payload(e)
+        } else if let Some(e) = e.downcast_ref::<SignatureError>() {
+            match e {
+                SignatureError::InvalidKey => ErrorClass::Usage,
+                SignatureError::Io(_) => ErrorClass::Io,
+                SignatureError::Decode(_) => ErrorClass::Format,
+                SignatureError::Unsigned | SignatureError::Mismatch => ErrorClass::Verification,
+            }
+        } else if e.is::<std::io::Error>() {
+            ErrorClass::Io
+        } else if e.is::<binrw::Error>() || e.is::<prost::DecodeError>() {
+            ErrorClass::Format
+        } else {
+            ErrorClass::Other
+        }
+    }
+
+    fn of_payload(e: &PayloadError) -> Self {
+        match e {
+            PayloadError::Io(_) => ErrorClass::Io,
+            PayloadError::PartitionNotFound(_)
+            | PayloadError::MissingOldImage(_)
+            | PayloadError::TooLarge { .. } => ErrorClass::Usage,
+            PayloadError::Parse(_)
+            | PayloadError::MissingData
+            | PayloadError::MissingDstExtents
+            | PayloadError::Decompression { .. }
+            | PayloadError::DecompressionLimit { .. }
+            | PayloadError::Patch(_)
+            | PayloadError::InvalidOperation(_)
+            | PayloadError::InvalidBlockSize(_)
+            | PayloadError::Truncated { .. }
+            | PayloadError::SizeMismatch { .. }
+            | PayloadError::ExtentOverflow { .. } => ErrorClass::Format,
+            PayloadError::HashMismatch { .. } => ErrorClass::Verification,
+            PayloadError::UnsupportedOperation(_) => ErrorClass::Unsupported,
+            PayloadError::Operation { source, .. } | PayloadError::Partition { source, .. } => {
+                Self::of_payload(source)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let class = match self {
+            ErrorClass::Other => "error",
+            ErrorClass::Usage => "usage",
+            ErrorClass::Format => "format",
+            ErrorClass::Verification => "verification",
+            ErrorClass::Io => "io",
+            ErrorClass::Unsupported => "unsupported",
+        };
+        write!(f, "{}", class)
+    }
+}
+
+/// An error as a message, with the class of the error it's from.
+#[derive(Debug, Clone)]
+pub(crate) struct Failure {
+    pub(crate) class: ErrorClass,
+    pub(crate) message: String,
+}
+
+impl Failure {
+    pub(crate) fn new(class: ErrorClass, message: impl Into<String>) -> Self {
+        Failure {
+            class,
+            message: message.into(),
+        }
+    }
+
+    /// `message` about `e`, in the class of `e`.
+    pub(crate) fn about(e: &(dyn std::error::Error + 'static), message: impl Into<String>) -> Self {
+        Failure::new(ErrorClass::of(e), message)
+    }
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for Failure {}
+
+impl From<PayloadError> for Failure {
+    fn from(e: PayloadError) -> Self {
+        Failure::new(ErrorClass::of_payload(&e), e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use payload_dumper_rust::chromeos_update_engine::install_operation::Type;
+
+    #[test]
+    fn error_classes() {
+        let hash_mismatch = PayloadError::HashMismatch {
+            expected: vec![0],
+            actual: vec![1],
+        };
+        let nested = hash_mismatch
+            .in_operation(3, Type::Replace as i32)
+            .in_partition("boot");
+        assert_eq!(ErrorClass::of(&nested), ErrorClass::Verification);
+        assert_eq!(
+            ErrorClass::of(&PayloadError::UnsupportedOperation(99)),
+            ErrorClass::Unsupported
+        );
+        let boxed: Box<dyn std::error::Error> = std::io::Error::other("disk full").into();
+        assert_eq!(ErrorClass::of(&*boxed), ErrorClass::Io);
+        let failure = Failure::about(&*boxed, "Failed to write boot.img: disk full");
+        assert_eq!(ErrorClass::of(&failure), ErrorClass::Io);
+        let boxed: Box<dyn std::error::Error> = "no class".into();
+        assert_eq!(ErrorClass::of(&*boxed), ErrorClass::Other);
+    }
+}
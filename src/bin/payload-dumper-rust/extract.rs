@@ -0,0 +1,2056 @@
+#[cfg(feature = "compress")]
+mod compress;
+mod plan;
+mod progress;
+mod resume;
+mod tar;
+
+use std::{
+    borrow::Cow,
+    fs::{File, OpenOptions},
+    io::{BufReader, BufWriter, IsTerminal, Read, Seek, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use payload_dumper_rust::{
+    check_block_size,
+    chromeos_update_engine::{install_operation::Type, Extent, PartitionInfo, PartitionUpdate},
+    create_image, dump_in_data_order, dump_streaming, hash_image, image_error, open_existing_image,
+    plan_chunks, sanitize_file_name, sequential_order, validate_dst_extents, validate_in_place,
+    verify_hash, verify_image, write_sparse_image, DeltaUpdateFile, DumpOptions, DumpStats,
+    ImageCompression, PayloadError, ReadWaits, SectionFile, SequentialWriter, TarWriter,
+};
+
+use size::Size;
+
+use crate::error::{ErrorClass, Failure};
+use crate::format::{hex, table_lines};
+use crate::info::{payload_summary, signature_to_string};
+use crate::input::{
+    find_input_payloads, map_input, open_located, read_stdin_header, Location, ReadSeek,
+};
+use crate::json::json_string;
+use crate::select::{glob_match, not_found};
+use crate::signals::{handle_interrupts, stopped, INTERRUPTED};
+use crate::temp_file::TempFile;
+use crate::{init_tracing, render_template, Args, Pipeline, ProgressMode, SummaryFormat};
+#[cfg(feature = "compress")]
+use compress::dump_compressed;
+use plan::{dry_run, OutputPlan};
+use progress::{progress_offsets, Bars, PartitionBar};
+use resume::{check_existing, keep_for_resume, ProgressFile};
+use tar::{create_tar, dump_tar_entry, TarOutput};
+
+/// Extract the partitions selected by `args`.
+pub(crate) fn extract(mut args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    init_tracing(args.verbose);
+    // `-` reads the payload from stdin, which can only be read forward, so
+    // the partitions are dumped in payload order.
+    let streaming = args.path == Path::new("-");
+    let mut stdin = BufReader::with_capacity(1 << 20, std::io::stdin().lock());
+    let mut location = None;
+    let mut payload = if streaming {
+        if args.payload_index != 0 {
+            return Err(Failure::new(
+                ErrorClass::Usage,
+                "--payload-index is not supported when reading the payload from stdin",
+            )
+            .into());
+        }
+        let header = read_stdin_header(&mut stdin, args.payload_offset)?;
+        // The payload signature at the end of the stream is not needed.
+        header.into_delta_update_file(Vec::new())
+    } else {
+        let (base, payloads) =
+            find_input_payloads(&args.path, args.payload_offset).map_err(|e| {
+                Failure::about(
+                    &*e,
+                    format!("Failed to open {}: {}", args.path.display(), e),
+                )
+            })?;
+        let found = payloads.get(args.payload_index).ok_or_else(|| {
+            let message = format!(
+                "--payload-index {} is out of range, {} has {} payloads",
+                args.payload_index,
+                args.path.display(),
+                payloads.len()
+            );
+            Failure::new(ErrorClass::Usage, message)
+        })?;
+        if payloads.len() > 1 {
+            args.log(format!(
+                "Payload {} of {} at offset {:#x}",
+                args.payload_index,
+                payloads.len(),
+                base + found.offset
+            ));
+        }
+        location = Some(Location {
+            offset: base + found.offset,
+            len: found.len,
+        });
+        let mut file = open_located(&args.path, location.unwrap()).map_err(|e| {
+            Failure::about(
+                &*e,
+                format!("Failed to open {}: {}", args.path.display(), e),
+            )
+        })?;
+        // The payload signature isn't needed, and it's at the end, so a
+        // truncated payload is reported by the check below instead.
+        let len = file.seek(std::io::SeekFrom::End(0))?;
+        file.rewind()?;
+        let payload = DeltaUpdateFile::read_metadata(&mut file)?;
+        // `--dry-run` reports the partitions with missing data instead.
+        if args.dry_run.is_none() {
+            payload
+                .validate_against_len(len)
+                .map_err(|e| Failure::about(&e, format!("{}: {}", args.path.display(), e)))?;
+        }
+        payload
+    };
+
+    if let Some(public_key) = &args.public_key {
+        let key = std::fs::read(public_key)?;
+        let (index, signature) = payload.find_metadata_signature(&key).map_err(|e| {
+            Failure::about(&e, format!("Metadata signature verification failed: {}", e))
+        })?;
+        args.log(format!(
+            "Metadata signature {}: OK",
+            signature_to_string(index, &signature)
+        ));
+    }
+    args.log(payload_summary(&payload));
+    match args.block_size {
+        Some(block_size) => {
+            args.log(format!("Block size overridden to {}", block_size));
+            payload.manifest.block_size = Some(block_size);
+        }
+        None => {
+            let block_size = payload.manifest.block_size();
+            check_block_size(block_size as u64)
+                .map_err(|e| Failure::about(&e, format!("{}, override it with --block-size", e)))?;
+            if block_size != 4096 {
+                eprintln!("Warning: block size {} is not the usual 4096", block_size);
+            }
+        }
+    }
+    args.max_timestamp = payload.manifest.max_timestamp;
+    if args.max_timestamp.is_none() && args.name_template.contains("{timestamp}") {
+        return Err(Failure::new(
+            ErrorClass::Usage,
+            "The payload has no max_timestamp for {timestamp} in --name-template",
+        )
+        .into());
+    }
+    let all_partitions = payload.partitions();
+
+    let partitions = all_partitions
+        .iter()
+        .map(partiotion_to_string)
+        .collect::<Vec<_>>()
+        .join(" ");
+    args.log(format!("Partitions: {}", partitions));
+
+    // Partitions not in a partial update are selected like the others, and
+    // copied from `--old` as they are.
+    let untouched = untouched_partitions(&args, &payload, &all_partitions)?;
+    let candidates = match untouched.is_empty() {
+        true => Cow::Borrowed(&all_partitions[..]),
+        false => Cow::Owned([&all_partitions[..], &untouched].concat()),
+    };
+    let (partitions, untouched): (Vec<_>, Vec<_>) = select_partitions(&args, &candidates)?
+        .into_iter()
+        .partition(|partition| {
+            all_partitions
+                .iter()
+                .any(|p| p.partition_name == partition.partition_name)
+        });
+    if partitions.is_empty() && !untouched.is_empty() && args.partitions.is_some() {
+        args.log("No selected partition is in the partial update");
+    }
+    if args.stdout && partitions.len() != 1 {
+        return Err(Failure::new(
+            ErrorClass::Usage,
+            "--stdout needs exactly one partition, select it with --partitions",
+        )
+        .into());
+    }
+    for (name, _) in &args.output_map {
+        if !partitions.iter().any(|p| &p.partition_name == name) {
+            return Err(Failure::new(
+                ErrorClass::Usage,
+                format!("Partition {} in --output-map is not dumped", name),
+            )
+            .into());
+        }
+    }
+    for (name, _) in &args.rename {
+        if !partitions.iter().any(|p| &p.partition_name == name) {
+            return Err(Failure::new(
+                ErrorClass::Usage,
+                format!("Partition {} in --rename is not dumped", name),
+            )
+            .into());
+        }
+    }
+    check_output_paths(&args, &[&partitions[..], &untouched].concat())?;
+    if streaming && args.resume {
+        return Err(Failure::new(
+            ErrorClass::Usage,
+            "--resume is not supported when reading the payload from stdin",
+        )
+        .into());
+    }
+    if streaming && args.in_place.is_some() {
+        return Err(Failure::new(
+            ErrorClass::Usage,
+            "--in-place is not supported when reading the payload from stdin",
+        )
+        .into());
+    }
+    if streaming && args.mmap {
+        return Err(Failure::new(
+            ErrorClass::Usage,
+            "--mmap is not supported when reading the payload from stdin",
+        )
+        .into());
+    }
+    #[cfg(not(feature = "compress"))]
+    if args.compress.is_some() {
+        return Err(Failure::new(
+            ErrorClass::Unsupported,
+            "Compressing images is not supported, rebuild with `--features compress`",
+        )
+        .into());
+    }
+    if streaming && args.compress.is_some() {
+        return Err(Failure::new(
+            ErrorClass::Usage,
+            "--compress is not supported when reading the payload from stdin",
+        )
+        .into());
+    }
+    if streaming && args.tar.is_some() {
+        return Err(Failure::new(
+            ErrorClass::Usage,
+            "--tar is not supported when reading the payload from stdin",
+        )
+        .into());
+    }
+    if streaming && args.dry_run.is_some() {
+        return Err(Failure::new(
+            ErrorClass::Usage,
+            "--dry-run is not supported when reading the payload from stdin",
+        )
+        .into());
+    }
+    if streaming && args.keep_going {
+        return Err(Failure::new(
+            ErrorClass::Usage,
+            "--keep-going is not supported when reading the payload from stdin",
+        )
+        .into());
+    }
+    if streaming && (args.op_range.is_some() || args.stop_on_op.is_some()) {
+        return Err(Failure::new(
+            ErrorClass::Usage,
+            "--op-range and --stop-on-op are not supported when reading the payload from stdin",
+        )
+        .into());
+    }
+    if streaming && args.sequential_read {
+        return Err(Failure::new(ErrorClass::Usage, "--sequential-read is not needed when reading the payload from stdin, which is read in order").into());
+    }
+    let partitions = check_existing(&args, &payload, partitions)?;
+    let untouched = check_untouched(&args, &payload, untouched)?;
+    let limited =
+        limit_operations(&args, &partitions).map_err(|e| Failure::new(ErrorClass::Usage, e))?;
+    let partitions = match &limited {
+        Some(limited) => limited.iter().collect(),
+        None => partitions,
+    };
+    if let Some(mode) = args.dry_run {
+        let location = location.expect("the payload is located when not streaming");
+        return dry_run(&args, &payload, &partitions, location, mode);
+    }
+
+    // A malformed manifest could write past the end of the images.
+    for partition in &partitions {
+        let gaps = validate_dst_extents(partition, payload.manifest.block_size() as u64)
+            .map_err(|e| e.in_partition(&partition.partition_name))?;
+        if args.verbose > 0 && !gaps.is_empty() {
+            args.log(format!(
+                "Partition {}: blocks {} are not written by any operation",
+                partition.partition_name,
+                extents_to_string(&gaps)
+            ));
+        }
+    }
+
+    // Fail early rather than when the disk is full.
+    let plan = OutputPlan::new(&args, &payload, &partitions);
+    args.log(&plan);
+    plan.check().map_err(|e| Failure::new(ErrorClass::Io, e))?;
+
+    let mut old_images = partitions
+        .iter()
+        .map(|partition| match args.in_place {
+            Some(_) => Ok(None),
+            None => open_old_image(args.old.as_deref(), partition),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Make sure the delta is applied to the build it was generated from
+    // before writing anything.
+    if !args.skip_source_check {
+        for (partition, old) in partitions.iter().zip(&mut old_images) {
+            if let Some(old) = old {
+                check_old_image(&payload, partition, old)?;
+            }
+        }
+    }
+    if args.in_place.is_some() {
+        prepare_in_place(&args, &payload, &partitions)?;
+    }
+
+    if !args.stdout && args.tar.is_none() && args.in_place.is_none() && !args.output.is_dir() {
+        std::fs::create_dir_all(&args.output)?;
+    }
+    // The entries are written one after the other.
+    let tar = match &args.tar {
+        Some(path) => {
+            args.threads = 1;
+            Some(Mutex::new(TarWriter::new(create_tar(path, args.force)?)))
+        }
+        None => None,
+    };
+    // Removed when done, after it's copied to stdout.
+    let stdout_image = args
+        .stdout
+        .then(|| TempFile(args.output_path(partitions[0]).0));
+
+    let style = ProgressStyle::default_bar().template(
+        "[{elapsed_precise}] {bar:40.cyan/blue} {bytes:>10}/{total_bytes:10} {bytes_per_sec:>12} ETA {eta:>4} {msg}",
+    )?;
+    // Bars would garble the logs of scripts and CI jobs.
+    let multi = match args.progress_mode() {
+        ProgressMode::Bar if std::io::stderr().is_terminal() => MultiProgress::new(),
+        _ => MultiProgress::with_draw_target(ProgressDrawTarget::hidden()),
+    };
+    let block_size = payload.manifest.block_size() as u64;
+    let total = multi.add(ProgressBar::new(
+        partitions
+            .iter()
+            .map(|partition| progress_offsets(partition, block_size)[partition.operations.len()])
+            .sum(),
+    ));
+    total.set_style(style.clone());
+    total.set_message("total");
+    let bars = Bars {
+        multi: &multi,
+        total: &total,
+        style: &style,
+        json: args.progress_mode() == ProgressMode::Json,
+        operations: args.partial() && !args.quiet,
+        block_size,
+    };
+
+    let dumped = partitions.clone();
+    handle_interrupts();
+    let started = Instant::now();
+    let (results, errors) = if streaming {
+        let results = dump_together(
+            &payload,
+            &partitions,
+            old_images,
+            &args,
+            &bars,
+            true,
+            |old, images, dense, progress| {
+                dump_streaming(
+                    &mut stdin,
+                    block_size,
+                    &partitions,
+                    old,
+                    images,
+                    dense,
+                    progress,
+                )
+            },
+        )?;
+        (results, Vec::new())
+    } else if args.sequential_read {
+        let location = location.expect("the payload is located when not streaming");
+        let mut input = open_located(&args.path, location).map_err(|e| {
+            Failure::about(
+                &*e,
+                format!("Failed to open {}: {}", args.path.display(), e),
+            )
+        })?;
+        let results = dump_together(
+            &payload,
+            &partitions,
+            old_images,
+            &args,
+            &bars,
+            false,
+            |old, images, dense, progress| {
+                dump_in_data_order(
+                    &mut input,
+                    payload.blobs_offset,
+                    block_size,
+                    &partitions,
+                    old,
+                    images,
+                    dense,
+                    progress,
+                )
+            },
+        )?;
+        (results, Vec::new())
+    } else {
+        let location = location.expect("the payload is located when not streaming");
+        let mapped = match args.mmap {
+            true => Some(map_input(&args.path, location).map_err(|e| {
+                Failure::about(&*e, format!("Failed to map {}: {}", args.path.display(), e))
+            })?),
+            false => None,
+        };
+        let mapped = mapped.as_ref().map(|mapped| mapped.as_slice());
+        dump_files(
+            &payload,
+            partitions,
+            old_images,
+            location,
+            mapped,
+            tar.as_ref(),
+            &args,
+            &bars,
+        )
+    };
+    if errors.is_empty() && results.iter().all(|result| result.status != Status::Error) {
+        total.finish();
+    } else {
+        total.abandon();
+    }
+    print_summary(&args, &results, started.elapsed());
+    // The images written so far are cleaned up, see `main` for the exit code.
+    if INTERRUPTED.load(Ordering::Relaxed) {
+        return Err("Interrupted".into());
+    }
+    if let Some(tar) = tar.filter(|_| errors.is_empty()) {
+        let path = args.tar.as_deref().expect("--tar is given");
+        tar.into_inner()
+            .unwrap()
+            .finish()
+            .map_err(|e| image_error(e, path))?;
+    }
+    if errors.is_empty() && results.iter().all(|result| result.status != Status::Error) {
+        copy_untouched(&args, &untouched)?;
+    }
+    if args.checksum_file {
+        write_checksums(&args, &dumped, &results)?;
+    }
+
+    // Exiting with the class of the first error.
+    if let Some(first) = errors.first() {
+        let messages: Vec<_> = errors.iter().map(|e| e.message.as_str()).collect();
+        return Err(Failure::new(first.class, messages.join("\n")).into());
+    }
+    // Partitions failed with `--keep-going`, whose errors are in the summary.
+    let errors: Vec<_> = results
+        .iter()
+        .filter(|result| result.status == Status::Error)
+        .collect();
+    if let Some(first) = errors.first() {
+        let names: Vec<_> = errors.iter().map(|result| result.name.as_str()).collect();
+        let class = first.error.as_ref().map_or(ErrorClass::Other, |e| e.class);
+        return Err(Failure::new(class, format!("Failed to dump {}", names.join(", "))).into());
+    }
+    let failed: Vec<_> = results
+        .iter()
+        .filter(|result| result.status == Status::Failed)
+        .map(|result| result.name.as_str())
+        .collect();
+    if !failed.is_empty() {
+        return Err(Failure::new(
+            ErrorClass::Verification,
+            format!("Verification failed for {}", failed.join(", ")),
+        )
+        .into());
+    }
+
+    stdout_image.map_or(Ok(()), |image| image.copy_to_stdout())
+}
+
+/// Whether `path` is a block device.
+#[cfg(unix)]
+fn is_block_device(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_block_device())
+}
+
+#[cfg(not(unix))]
+fn is_block_device(_path: &Path) -> bool {
+    false
+}
+
+/// Format `extents` as ranges of blocks, like `0-9, 12`.
+fn extents_to_string(extents: &[Extent]) -> String {
+    extents
+        .iter()
+        .map(|extent| match extent.num_blocks() {
+            1 => extent.start_block().to_string(),
+            n => format!("{}-{}", extent.start_block(), extent.start_block() + n - 1),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Dump `partitions` of `payload` to their images with `--threads`
+/// threads, or to the entries of `tar`, returning the result of each
+/// partition and the errors. The payload is read at `location` in the
+/// input, or from `mapped` if it's memory mapped.
+#[allow(clippy::too_many_arguments)]
+fn dump_files(
+    payload: &DeltaUpdateFile,
+    partitions: Vec<&PartitionUpdate>,
+    old_images: Vec<Option<File>>,
+    location: Location,
+    mapped: Option<&[u8]>,
+    tar: Option<&Mutex<TarOutput>>,
+    args: &Args,
+    bars: &Bars,
+) -> (Vec<PartitionResult>, Vec<Failure>) {
+    let names: Vec<_> = partitions
+        .iter()
+        .map(|p| p.partition_name.clone())
+        .collect();
+    let jobs = Mutex::new(partitions.into_iter().zip(old_images).enumerate());
+    let cancelled = AtomicBool::new(false);
+    let results = Mutex::new(Vec::new());
+
+    let errors: Vec<Failure> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..args.threads.max(1))
+            .map(|_| {
+                scope.spawn(|| -> Result<(), Failure> {
+                    // Each worker has its own handle of the payload, so
+                    // seeks don't interfere with each other.
+                    let mut input = match mapped {
+                        Some(mapped) => Input::Mapped(mapped),
+                        None => open_located(&args.path, location)
+                            .map(Input::File)
+                            .map_err(|e| {
+                                Failure::about(
+                                    &*e,
+                                    format!("Failed to open {}: {}", args.path.display(), e),
+                                )
+                            })?,
+                    };
+
+                    loop {
+                        let job = jobs.lock().unwrap().next();
+                        let (index, (partition, old)) = match job {
+                            Some(job) if !stopped(&cancelled) => job,
+                            _ => return Ok(()),
+                        };
+
+                        let start = Instant::now();
+                        let bar = bars.add(partition);
+                        let (img_path, mapped) = args.output_path(partition);
+                        let write_path = args.write_path(partition);
+                        // Removed when dropped, on failure or interruption.
+                        let temp = (write_path != img_path).then(|| TempFile(write_path.clone()));
+                        let progress_file = args.progress_file(payload, partition);
+                        let skipped = match &progress_file {
+                            Some(progress_file) if args.resume && write_path.exists() => {
+                                progress_file.load()
+                            }
+                            _ => None,
+                        };
+
+                        let first = args.op_range.as_ref().map_or(0, |range| range.start);
+                        let existing = mapped || skipped.is_some() || args.op_range.is_some();
+                        let options = DumpOptions {
+                            skip_operations: skipped.unwrap_or(first),
+                            in_place: args.in_place.is_some(),
+                            ..args.pipeline.dump_options(&img_path, mapped, existing)
+                        };
+                        let result = match (tar, args.compress) {
+                            (Some(tar), _) => dump_tar_entry(
+                                &mut input,
+                                payload,
+                                partition,
+                                old,
+                                &mut tar.lock().unwrap(),
+                                args,
+                                &options,
+                                &bar,
+                                &cancelled,
+                            )
+                            .map(|dumped| {
+                                dumped.map(|(stats, size, sha256)| (stats, Some((size, sha256))))
+                            }),
+                            #[cfg(feature = "compress")]
+                            (None, Some(compression)) => dump_compressed(
+                                &mut input,
+                                payload,
+                                partition,
+                                old,
+                                &write_path,
+                                compression.into(),
+                                &options,
+                                args.fsync,
+                                &bar,
+                                &cancelled,
+                            )
+                            .map(|dumped| {
+                                dumped.map(|(stats, size, sha256)| (stats, Some((size, sha256))))
+                            }),
+                            #[cfg(not(feature = "compress"))]
+                            (None, Some(_)) => {
+                                unreachable!("--compress needs the compress feature")
+                            }
+                            (None, None) => {
+                                open_output(partition, &write_path, existing).and_then(|mut img| {
+                                    let stats = dump_partition(
+                                        &mut input,
+                                        payload,
+                                        partition,
+                                        old,
+                                        &mut img,
+                                        &options,
+                                        progress_file.as_ref(),
+                                        &bar,
+                                        &cancelled,
+                                    )?;
+                                    // The new partition may be smaller than the old one.
+                                    let new_size =
+                                        partition.new_partition_info.as_ref().and_then(|i| i.size);
+                                    if let Some(size) = new_size
+                                        .filter(|_| stats.is_some() && args.in_place.is_some())
+                                    {
+                                        img.set_len(size).map_err(|e| {
+                                            image_error(e, &write_path)
+                                                .in_partition(&partition.partition_name)
+                                        })?;
+                                    }
+                                    if stats.is_some() && args.fsync {
+                                        img.sync_all().map_err(|e| {
+                                            image_error(e, &write_path)
+                                                .in_partition(&partition.partition_name)
+                                        })?;
+                                    }
+                                    Ok(stats.map(|stats| (stats, None)))
+                                })
+                            }
+                        };
+                        let (stats, hashed) = match result {
+                            Ok(Some(dumped)) => {
+                                bar.finish();
+                                dumped
+                            }
+                            Ok(None) => {
+                                bar.abandon();
+                                let next = bar.next();
+                                let left = match &progress_file {
+                                    Some(_) if temp.is_none() || args.resume => {
+                                        "resume with --resume".to_string()
+                                    }
+                                    _ if temp.is_some() => {
+                                        format!("{} removed", write_path.display())
+                                    }
+                                    _ => format!("{} left as is", write_path.display()),
+                                };
+                                keep_for_resume(args, temp, progress_file.as_ref());
+                                let operations = partition.operations.len();
+                                bars.multi.suspend(|| {
+                                    args.log(format!(
+                                        "{}: stopped at operation {} of {}, {}",
+                                        partition.partition_name, next, operations, left
+                                    ))
+                                });
+                                results.lock().unwrap().push((
+                                    index,
+                                    PartitionResult {
+                                        operations: next - options.skip_operations,
+                                        ..PartitionResult::new(
+                                            partition,
+                                            Status::Cancelled,
+                                            0,
+                                            start.elapsed(),
+                                        )
+                                    },
+                                ));
+                                return Ok(());
+                            }
+                            Err(e) if args.keep_going => {
+                                bar.abandon();
+                                if let Some(progress_file) = &progress_file {
+                                    progress_file.remove();
+                                }
+                                let image =
+                                    set_aside_failed_image(args, &write_path, &img_path, mapped);
+                                bars.multi
+                                    .suspend(|| args.report(format!("{}, {}", e, image)));
+                                // The summary has the name of the partition.
+                                let error = match e {
+                                    PayloadError::Partition { source, .. } => {
+                                        Failure::from(*source)
+                                    }
+                                    e => Failure::from(e),
+                                };
+                                results.lock().unwrap().push((
+                                    index,
+                                    PartitionResult {
+                                        error: Some(error),
+                                        ..PartitionResult::new(
+                                            partition,
+                                            Status::Error,
+                                            0,
+                                            start.elapsed(),
+                                        )
+                                    },
+                                ));
+                                continue;
+                            }
+                            Err(e) => {
+                                bar.abandon();
+                                keep_for_resume(args, temp, progress_file.as_ref());
+                                cancelled.store(true, Ordering::Relaxed);
+                                results.lock().unwrap().push((
+                                    index,
+                                    PartitionResult::new(
+                                        partition,
+                                        Status::Error,
+                                        0,
+                                        start.elapsed(),
+                                    ),
+                                ));
+                                return Err(e.into());
+                            }
+                        };
+
+                        let (message, status, sha256) = match hashed {
+                            _ if args.partial() => {
+                                let message = format!(
+                                    "{}: operations {}..{} applied, not verified",
+                                    partition.partition_name,
+                                    first,
+                                    partition.operations.len()
+                                );
+                                (message, Status::Unverified, None)
+                            }
+                            // Compressed images are hashed as they're written.
+                            Some((size, sha256)) => check_hash(partition, size, sha256),
+                            None => check_image(partition, &write_path, args.checksum_file),
+                        };
+                        // Images failing verification don't get the name of
+                        // the image.
+                        let message = match temp {
+                            Some(temp) if status == Status::Failed => {
+                                drop(temp);
+                                format!("{}, {} removed", message, write_path.display())
+                            }
+                            temp => {
+                                let finished = match args.sparse && !mapped {
+                                    true => write_sparse(payload, partition, &write_path),
+                                    false => Ok(()),
+                                };
+                                if let Err(e) =
+                                    finished.and_then(|_| finish_image(partition, temp, &img_path))
+                                {
+                                    cancelled.store(true, Ordering::Relaxed);
+                                    return Err(e.into());
+                                }
+                                message
+                            }
+                        };
+                        bars.multi.suspend(|| args.log(message));
+                        results.lock().unwrap().push((
+                            index,
+                            PartitionResult {
+                                sha256,
+                                read_waits: Some(stats.read_waits),
+                                ..PartitionResult::from_stats(partition, status, &stats)
+                            },
+                        ));
+                    }
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .filter_map(|worker| worker.join().unwrap().err())
+            .collect()
+    });
+
+    // Partitions not dumped after an error or an interruption.
+    let mut results = results.into_inner().unwrap();
+    for (index, name) in names.into_iter().enumerate() {
+        if !results.iter().any(|(i, _)| *i == index) {
+            let result = PartitionResult {
+                name,
+                status: Status::Cancelled,
+                size: 0,
+                operations: 0,
+                bytes_written: 0,
+                data_bytes_read: 0,
+                elapsed: Duration::ZERO,
+                sha256: None,
+                read_waits: None,
+                error: None,
+            };
+            results.push((index, result));
+        }
+    }
+    results.sort_by_key(|(index, _)| *index);
+    (
+        results.into_iter().map(|(_, result)| result).collect(),
+        errors,
+    )
+}
+
+/// Progress of the operations of several partitions applied together, by
+/// the index of the partition and of the operation, stopping if it returns
+/// false.
+type Progress<'a> = &'a mut dyn FnMut(usize, usize) -> bool;
+
+/// Dump `partitions` of `payload` together with `dump`, which applies their
+/// operations to the images, reading the data blobs from stdin or with
+/// `--sequential-read`, returning the result of each partition.
+///
+/// `dump` is given the old images, the images, whether ZERO operations are
+/// written, and the progress. The operations of each partition are applied
+/// in order if `in_order` is set, in the order of their data otherwise.
+fn dump_together<F>(
+    payload: &DeltaUpdateFile,
+    partitions: &[&PartitionUpdate],
+    mut old_images: Vec<Option<File>>,
+    args: &Args,
+    bars: &Bars,
+    in_order: bool,
+    dump: F,
+) -> Result<Vec<PartitionResult>, Box<dyn std::error::Error>>
+where
+    F: FnOnce(
+        &mut [Option<File>],
+        &mut [File],
+        bool,
+        Progress,
+    ) -> Result<Option<Vec<DumpStats>>, PayloadError>,
+{
+    let outputs: Vec<_> = partitions
+        .iter()
+        .map(|partition| args.output_path(partition))
+        .collect();
+    let write_paths: Vec<_> = partitions
+        .iter()
+        .map(|partition| args.write_path(partition))
+        .collect();
+    // Removed when dropped, on failure or interruption.
+    let mut temps: Vec<_> = write_paths
+        .iter()
+        .zip(&outputs)
+        .map(|(write_path, (img_path, _))| {
+            (write_path != img_path).then(|| TempFile(write_path.clone()))
+        })
+        .collect();
+    let mut images = partitions
+        .iter()
+        .zip(&write_paths)
+        .zip(&outputs)
+        .map(|((partition, path), (_, mapped))| open_output(partition, path, *mapped))
+        .collect::<Result<Vec<_>, _>>()?;
+    // Devices are not zeroed, so all ZERO operations are written if any is
+    // mapped.
+    let dense = args.pipeline.dense || !args.output_map.is_empty();
+
+    let partition_bars: Vec<_> = partitions
+        .iter()
+        .map(|partition| bars.add(partition))
+        .collect();
+
+    let start = Instant::now();
+    // Out of order, an operation is done when the next one starts.
+    let mut started = None;
+    let finished = dump(&mut old_images, &mut images, dense, &mut |i, index| {
+        if in_order {
+            partition_bars[i].set(index);
+        } else {
+            if let Some((i, index)) = started.replace((i, index)) {
+                partition_bars[i].done(index);
+            }
+            partition_bars[i].show(index);
+        }
+        !INTERRUPTED.load(Ordering::Relaxed)
+    })?;
+    let Some(stats) = finished else {
+        let elapsed = start.elapsed();
+        let mut results = Vec::new();
+        for (i, (partition, bar)) in partitions.iter().zip(&partition_bars).enumerate() {
+            bar.abandon();
+            let left = match temps[i].take() {
+                Some(_) => format!("{} removed", write_paths[i].display()),
+                None => format!("{} left as is", write_paths[i].display()),
+            };
+            let (next, operations) = (bar.next(), partition.operations.len());
+            let stopped = match in_order {
+                true => format!("stopped at operation {} of {}", next, operations),
+                false => format!("stopped after {} of {} operations", next, operations),
+            };
+            bars.multi.suspend(|| {
+                args.log(format!(
+                    "{}: {}, {}",
+                    partition.partition_name, stopped, left
+                ))
+            });
+            results.push(PartitionResult {
+                operations: next,
+                ..PartitionResult::new(partition, Status::Cancelled, 0, elapsed)
+            });
+        }
+        return Ok(results);
+    };
+    if let Some((i, index)) = started {
+        partition_bars[i].done(index);
+    }
+    let mut results = Vec::new();
+    for (i, ((partition, (img_path, mapped)), img)) in
+        partitions.iter().zip(&outputs).zip(&images).enumerate()
+    {
+        let write_path = &write_paths[i];
+        partition_bars[i].finish();
+        if args.fsync {
+            img.sync_all()
+                .map_err(|e| image_error(e, write_path).in_partition(&partition.partition_name))?;
+        }
+        let (message, status, sha256) = check_image(partition, write_path, args.checksum_file);
+        let message = match temps[i].take() {
+            Some(temp) if status == Status::Failed => {
+                drop(temp);
+                format!("{}, {} removed", message, write_path.display())
+            }
+            temp => {
+                if args.sparse && !*mapped {
+                    write_sparse(payload, partition, write_path)?;
+                }
+                finish_image(partition, temp, img_path)?;
+                message
+            }
+        };
+        bars.multi.suspend(|| args.log(message));
+        // The partitions are dumped together, they all take the whole time.
+        results.push(PartitionResult {
+            sha256,
+            ..PartitionResult::from_stats(partition, status, &stats[i])
+        });
+    }
+    Ok(results)
+}
+
+/// Status of a partition at the end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Status {
+    /// Dumped and matching the hash in the manifest.
+    Ok,
+    /// Dumped, but the manifest has no hash to verify it.
+    Unverified,
+    /// Dumped, but not matching the hash in the manifest.
+    Failed,
+    /// Failed to dump.
+    Error,
+    /// Not dumped, as the run was stopped.
+    Cancelled,
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = match self {
+            Status::Ok => "ok",
+            Status::Unverified => "unverified",
+            Status::Failed => "failed",
+            Status::Error => "error",
+            Status::Cancelled => "cancelled",
+        };
+        write!(f, "{}", status)
+    }
+}
+
+/// Result of dumping a partition, printed in the summary.
+struct PartitionResult {
+    name: String,
+    status: Status,
+    /// Size of the partition in the manifest.
+    size: u64,
+    /// Number of operations applied.
+    operations: usize,
+    bytes_written: u64,
+    /// Bytes of data read from the payload by the operations applied.
+    data_bytes_read: u64,
+    elapsed: Duration,
+    /// SHA-256 of the raw image, if it was hashed.
+    sha256: Option<[u8; 32]>,
+    /// How often the pipeline waited for the data to be read, if it dumped
+    /// the partition.
+    read_waits: Option<ReadWaits>,
+    /// Why it failed, with `--keep-going`.
+    error: Option<Failure>,
+}
+
+impl PartitionResult {
+    /// Bytes written per second.
+    fn throughput(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            secs if secs > 0.0 => self.bytes_written as f64 / secs,
+            _ => 0.0,
+        }
+    }
+
+    fn new(
+        partition: &PartitionUpdate,
+        status: Status,
+        bytes_written: u64,
+        elapsed: Duration,
+    ) -> Self {
+        Self {
+            name: partition.partition_name.clone(),
+            status,
+            size: partition
+                .new_partition_info
+                .as_ref()
+                .and_then(|i| i.size)
+                .unwrap_or(0),
+            operations: 0,
+            bytes_written,
+            data_bytes_read: 0,
+            elapsed,
+            sha256: None,
+            read_waits: None,
+            error: None,
+        }
+    }
+
+    /// Result of `partition` dumped with the statistics `stats`.
+    fn from_stats(partition: &PartitionUpdate, status: Status, stats: &DumpStats) -> Self {
+        Self {
+            operations: stats.operations.values().sum(),
+            data_bytes_read: stats.data_bytes_read,
+            ..Self::new(partition, status, stats.bytes_written, stats.elapsed)
+        }
+    }
+}
+
+/// Print the status, size, operations applied, bytes written, time taken,
+/// throughput and waits for the payload to be read of each partition, and
+/// the totals, with the `elapsed`
+/// time of the whole run as partitions may be dumped concurrently. The
+/// errors of partitions failed with `--keep-going` follow. It's printed
+/// even with `--quiet`, in the format of `--summary`.
+fn print_summary(args: &Args, results: &[PartitionResult], elapsed: Duration) {
+    let total = PartitionResult {
+        name: "total".to_string(),
+        // Not printed.
+        status: Status::Ok,
+        size: results.iter().map(|r| r.size).sum(),
+        operations: results.iter().map(|r| r.operations).sum(),
+        bytes_written: results.iter().map(|r| r.bytes_written).sum(),
+        data_bytes_read: results.iter().map(|r| r.data_bytes_read).sum(),
+        elapsed,
+        sha256: None,
+        read_waits: results
+            .iter()
+            .filter_map(|r| r.read_waits)
+            .reduce(|mut total, waits| {
+                total.add(&waits);
+                total
+            }),
+        error: None,
+    };
+    let read_waits = |result: &PartitionResult| match result.read_waits {
+        Some(waits) => format!(
+            ", \"read_waits\": {}, \"read_wait\": {:.3}",
+            waits.count,
+            waits.elapsed.as_secs_f64()
+        ),
+        None => String::new(),
+    };
+    if args.summary_format() == SummaryFormat::Json {
+        for result in results {
+            let error = match &result.error {
+                Some(error) => format!(", \"error\": {}", json_string(&error.message)),
+                None => String::new(),
+            };
+            eprintln!(
+                "{{\"event\": \"summary\", \"partition\": {}, \"status\": \"{}\", \"size\": {}, \"operations\": {}, \"bytes\": {}, \"data_bytes\": {}, \"elapsed\": {:.3}, \"throughput\": {:.0}{}{}}}",
+                json_string(&result.name),
+                result.status,
+                result.size,
+                result.operations,
+                result.bytes_written,
+                result.data_bytes_read,
+                result.elapsed.as_secs_f64(),
+                result.throughput(),
+                read_waits(result),
+                error
+            );
+        }
+        eprintln!(
+            "{{\"event\": \"summary_total\", \"partitions\": {}, \"size\": {}, \"operations\": {}, \"bytes\": {}, \"data_bytes\": {}, \"elapsed\": {:.3}, \"throughput\": {:.0}{}}}",
+            results.len(),
+            total.size,
+            total.operations,
+            total.bytes_written,
+            total.data_bytes_read,
+            total.elapsed.as_secs_f64(),
+            total.throughput(),
+            read_waits(&total)
+        );
+        return;
+    }
+    let mut rows = vec![[
+        "PARTITION".to_string(),
+        "STATUS".to_string(),
+        "SIZE".to_string(),
+        "OPS".to_string(),
+        "WRITTEN".to_string(),
+        "ELAPSED".to_string(),
+        "THROUGHPUT".to_string(),
+        "WAITS".to_string(),
+    ]];
+    let row = |result: &PartitionResult, status: String| {
+        [
+            result.name.clone(),
+            status,
+            Size::from_bytes(result.size).to_string(),
+            result.operations.to_string(),
+            Size::from_bytes(result.bytes_written).to_string(),
+            format!("{:.2}s", result.elapsed.as_secs_f64()),
+            format!("{}/s", Size::from_bytes(result.throughput() as u64)),
+            match result.read_waits {
+                Some(waits) => format!("{} ({:.2}s)", waits.count, waits.elapsed.as_secs_f64()),
+                None => "-".to_string(),
+            },
+        ]
+    };
+    rows.extend(
+        results
+            .iter()
+            .map(|result| row(result, result.status.to_string())),
+    );
+    rows.push(row(&total, String::new()));
+    for line in table_lines(&rows) {
+        args.report(line);
+    }
+    for result in results {
+        if let Some(error) = &result.error {
+            args.report(format!("{}: {}", result.name, error));
+        }
+    }
+}
+
+/// Rename the image written to `write_path` of a partition failed with
+/// `--keep-going` to `<image>.partial`, after its `img_path`, or delete it
+/// with `--delete-failed`, returning what happened to it. Images mapped to
+/// existing files or devices are left as they are.
+fn set_aside_failed_image(args: &Args, write_path: &Path, img_path: &Path, mapped: bool) -> String {
+    if mapped {
+        return format!("{} left as is", write_path.display());
+    }
+    let mut partial = img_path.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    let result = match args.delete_failed {
+        true => {
+            std::fs::remove_file(write_path).map(|_| format!("{} deleted", write_path.display()))
+        }
+        false => std::fs::rename(write_path, &partial)
+            .map(|_| format!("{} renamed to {}", write_path.display(), partial.display())),
+    };
+    match result {
+        Ok(message) => message,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => "no image written".to_string(),
+        Err(e) => format!("{} left as is: {}", write_path.display(), e),
+    }
+}
+
+/// Give the image of `partition` written to `temp`, if it's written to a
+/// temporary file, its name at `img_path`, once it's complete.
+fn finish_image(
+    partition: &PartitionUpdate,
+    temp: Option<TempFile>,
+    img_path: &Path,
+) -> Result<(), PayloadError> {
+    match temp {
+        Some(temp) => temp
+            .persist(img_path)
+            .map_err(|e| image_error(e, img_path).in_partition(&partition.partition_name)),
+        None => Ok(()),
+    }
+}
+
+/// Write `SHA256SUMS` with the hashes of the images of `partitions` written
+/// in this run, and `SHA256SUMS.expected` with the hashes in the manifest,
+/// to the output directory. Images converted to sparse images are hashed
+/// again, as written.
+fn write_checksums(
+    args: &Args,
+    partitions: &[&PartitionUpdate],
+    results: &[PartitionResult],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let dir = args.in_place.as_deref().unwrap_or(&args.output);
+    let (mut sums, mut expected) = (String::new(), String::new());
+    for (partition, result) in partitions.iter().zip(results) {
+        let mut sha256 = match result.sha256 {
+            Some(sha256) => sha256,
+            None => continue,
+        };
+        let (img_path, mapped) = args.output_path(partition);
+        if args.sparse && !mapped {
+            sha256 = File::open(&img_path)
+                .and_then(hash_image)
+                .map_err(|e| image_error(e, &img_path))?
+                .1;
+        }
+        // Mapped files may be anywhere.
+        let file_name = img_path
+            .strip_prefix(dir)
+            .unwrap_or(&img_path)
+            .display()
+            .to_string();
+        sums += &format!("{}  {}\n", hex(&sha256), file_name);
+        if let Some(hash) = partition
+            .new_partition_info
+            .as_ref()
+            .and_then(|i| i.hash.as_ref())
+        {
+            if !hash.is_empty() {
+                expected += &format!("{}  {}\n", hex(hash), file_name);
+            }
+        }
+    }
+
+    for (name, contents) in [("SHA256SUMS", sums), ("SHA256SUMS.expected", expected)] {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).map_err(|e| {
+            Failure::about(&e, format!("Failed to write {}: {}", path.display(), e))
+        })?;
+    }
+    Ok(())
+}
+
+/// Convert the dumped image of `partition` at `img_path` to an Android
+/// sparse image in place.
+fn write_sparse(
+    payload: &DeltaUpdateFile,
+    partition: &PartitionUpdate,
+    img_path: &Path,
+) -> Result<(), PayloadError> {
+    let block_size = payload.manifest.block_size() as u64;
+    let mut tmp_path = img_path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    // Removed if writing fails.
+    let tmp = TempFile(PathBuf::from(tmp_path));
+    let result = (|| {
+        let mut raw = File::open(img_path)?;
+        let size = match partition.new_partition_info.as_ref().and_then(|i| i.size) {
+            Some(size) => size,
+            None => raw.metadata()?.len(),
+        };
+        let chunks = plan_chunks(partition, size, block_size)?;
+        let mut out = BufWriter::new(File::create(&tmp.0)?);
+        write_sparse_image(&mut raw, &chunks, block_size, &mut out)?;
+        out.flush()?;
+        drop(out);
+        tmp.persist(img_path)
+    })();
+    result.map_err(|e| image_error(e, img_path).in_partition(&partition.partition_name))
+}
+
+/// Check the dumped image of `partition` at `img_path`, returning the
+/// message to print, its status and its SHA-256. Images which can't be
+/// verified are only hashed if `hash` is set. Only the size of the
+/// partition is checked, as devices may be larger.
+fn check_image(
+    partition: &PartitionUpdate,
+    img_path: &Path,
+    hash: bool,
+) -> (String, Status, Option<[u8; 32]>) {
+    let name = &partition.partition_name;
+    let info = partition.new_partition_info.clone().unwrap_or_default();
+    let verifiable = info.hash.as_ref().is_some_and(|h| !h.is_empty());
+    if !verifiable && !hash {
+        return (
+            format!("{}: no hash in manifest, not verified", name),
+            Status::Unverified,
+            None,
+        );
+    }
+    let hashed =
+        File::open(img_path).and_then(|img| hash_image(img.take(info.size.unwrap_or(u64::MAX))));
+    let (size, sha256) = match hashed {
+        Ok(hashed) => hashed,
+        Err(e) => {
+            return (
+                format!("{}: FAILED ({})", name, PayloadError::from(e)),
+                Status::Failed,
+                None,
+            )
+        }
+    };
+    check_hash(partition, size, sha256)
+}
+
+/// Check the `size` and `sha256` of the image of `partition` like
+/// [`check_image`]. Partitions of partial updates may be left unchanged,
+/// with the same old and new hashes, which is reported.
+fn check_hash(
+    partition: &PartitionUpdate,
+    size: u64,
+    sha256: [u8; 32],
+) -> (String, Status, Option<[u8; 32]>) {
+    let name = &partition.partition_name;
+    let info = partition.new_partition_info.clone().unwrap_or_default();
+    let verifiable = info.hash.as_ref().is_some_and(|h| !h.is_empty());
+    let unchanged = verifiable
+        && partition
+            .old_partition_info
+            .as_ref()
+            .is_some_and(|old| old.hash == info.hash);
+    match verify_hash(size, &sha256, &info) {
+        Ok(()) if !verifiable => (
+            format!("{}: no hash in manifest, not verified", name),
+            Status::Unverified,
+            Some(sha256),
+        ),
+        Ok(()) if unchanged => (
+            format!("{}: OK (unchanged)", name),
+            Status::Ok,
+            Some(sha256),
+        ),
+        Ok(()) => (format!("{}: OK", name), Status::Ok, Some(sha256)),
+        Err(e) => (
+            format!("{}: FAILED ({})", name, e),
+            Status::Failed,
+            Some(sha256),
+        ),
+    }
+}
+
+/// Select the partitions to dump from `all_partitions` by `--partitions` or
+/// `--exclude`.
+fn select_partitions<'a>(
+    args: &Args,
+    all_partitions: &'a [PartitionUpdate],
+) -> Result<Vec<&'a PartitionUpdate>, Box<dyn std::error::Error>> {
+    if let Some(patterns) = &args.partitions {
+        let mut result: Vec<&PartitionUpdate> = Vec::new();
+        let mut missing = Vec::new();
+        for pattern in patterns {
+            let matched: Vec<_> = all_partitions
+                .iter()
+                .filter(|p| glob_match(pattern, &p.partition_name))
+                .collect();
+            if matched.is_empty() {
+                missing.push(pattern.as_str());
+            }
+            for partition in matched {
+                if !result
+                    .iter()
+                    .any(|p| p.partition_name == partition.partition_name)
+                {
+                    result.push(partition);
+                }
+            }
+        }
+        if !missing.is_empty() {
+            let names: Vec<_> = all_partitions
+                .iter()
+                .map(|p| p.partition_name.as_str())
+                .collect();
+            return Err(not_found(&missing, &names).into());
+        }
+        return Ok(result);
+    }
+
+    // The same list is often used for devices with different partitions.
+    for name in &args.exclude {
+        if !all_partitions.iter().any(|p| &p.partition_name == name) {
+            eprintln!("Warning: partition {} in --exclude not found", name);
+        }
+    }
+    Ok(all_partitions
+        .iter()
+        .filter(|p| !args.exclude.contains(&p.partition_name))
+        .collect())
+}
+
+/// `partitions` with only their operations before the end of `--op-range`
+/// and up to `--stop-on-op`, if given, so the others are not applied.
+/// Fails if a partition has no operation at the start of `--op-range` or
+/// at `--stop-on-op`.
+fn limit_operations(
+    args: &Args,
+    partitions: &[&PartitionUpdate],
+) -> Result<Option<Vec<PartitionUpdate>>, String> {
+    if args.op_range.is_none() && args.stop_on_op.is_none() {
+        return Ok(None);
+    }
+    let range = args.op_range.clone().unwrap_or(0..usize::MAX);
+    let end = args
+        .stop_on_op
+        .map_or(range.end, |index| range.end.min(index.saturating_add(1)));
+    partitions
+        .iter()
+        .map(|partition| {
+            let name = &partition.partition_name;
+            let len = partition.operations.len();
+            for index in [Some(range.start), args.stop_on_op].into_iter().flatten() {
+                if index >= len {
+                    return Err(format!(
+                        "Partition {} has no operation {}, it has {}",
+                        name, index, len
+                    ));
+                }
+            }
+            if end <= range.start {
+                return Err(format!(
+                    "--stop-on-op {} is before --op-range {}..",
+                    end - 1,
+                    range.start
+                ));
+            }
+            let mut partition = (*partition).clone();
+            partition.operations.truncate(end);
+            args.log(format!(
+                "{}: applying operations {}..{} of {}",
+                name,
+                range.start,
+                partition.operations.len(),
+                len
+            ));
+            Ok(partition)
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+/// Check that the images of `partitions` have different paths, and warn
+/// about partition names which are changed to be safe file names.
+fn check_output_paths(
+    args: &Args,
+    partitions: &[&PartitionUpdate],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut paths: Vec<(PathBuf, &str)> = Vec::new();
+    for partition in partitions {
+        let name = &partition.partition_name;
+        let renamed = args.rename.iter().any(|(old, _)| old == name);
+        if !renamed && sanitize_file_name(name) != *name {
+            eprintln!(
+                "Warning: partition name {:?} is not a valid file name, using {}",
+                name,
+                sanitize_file_name(name)
+            );
+        }
+        let (path, _) = args.output_path(partition);
+        if let Some((_, other)) = paths.iter().find(|(other, _)| *other == path) {
+            return Err(Failure::new(
+                ErrorClass::Usage,
+                format!(
+                    "Partitions {} and {} are both written to {}",
+                    other,
+                    name,
+                    path.display()
+                ),
+            )
+            .into());
+        }
+        paths.push((path, name));
+    }
+    Ok(())
+}
+
+/// The payload read by a worker of [`dump_files`].
+enum Input<'a> {
+    File(SectionFile<Box<dyn ReadSeek>>),
+    Mapped(&'a [u8]),
+}
+
+/// Dump `partition` to `img`, returning `None` if cancelled. The progress
+/// is recorded in `progress_file`, which is removed when done.
+#[allow(clippy::too_many_arguments)]
+fn dump_partition<W: Read + Write + Seek + Send>(
+    input: &mut Input,
+    payload: &DeltaUpdateFile,
+    partition: &PartitionUpdate,
+    mut old: Option<File>,
+    img: &mut W,
+    options: &DumpOptions,
+    progress_file: Option<&ProgressFile>,
+    bar: &PartitionBar,
+    cancelled: &AtomicBool,
+) -> Result<Option<DumpStats>, PayloadError> {
+    let name = &partition.partition_name;
+    bar.resume(options.skip_operations);
+    if let Some(progress_file) = progress_file {
+        progress_file
+            .save(options.skip_operations, true)
+            .map_err(|e| PayloadError::from(e).in_partition(name))?;
+    }
+
+    // The client will perform each InstallOperation in order, beginning even
+    // before the entire delta file is downloaded (but after at least the
+    // protobuf is downloaded).
+    let progress = |index| {
+        bar.set(index);
+        if let Some(progress_file) = progress_file {
+            // Losing some progress is fine, the operations are applied again.
+            let _ = progress_file.save(index, false);
+        }
+        !stopped(cancelled)
+    };
+    let stats = match input {
+        Input::File(file) => {
+            payload.dump_partition_with(file, partition, old.as_mut(), img, options, progress)?
+        }
+        Input::Mapped(mapped) => payload.dump_partition_from_slice(
+            mapped,
+            partition,
+            old.as_mut(),
+            img,
+            options,
+            progress,
+        )?,
+    };
+    match (&stats, progress_file) {
+        (Some(_), Some(progress_file)) => progress_file.remove(),
+        // Stopped, the operations applied are all recorded to be resumed.
+        (None, Some(progress_file)) => {
+            img.flush()
+                .map_err(|e| PayloadError::from(e).in_partition(name))?;
+            progress_file
+                .save(bar.next(), true)
+                .map_err(|e| PayloadError::from(e).in_partition(name))?;
+        }
+        (_, None) => {}
+    }
+    Ok(stats)
+}
+
+/// Statistics of a partition dumped by [`dump_sequential`], the output,
+/// and the size and SHA-256 of the image.
+type SequentialDump<W> = (DumpStats, W, u64, [u8; 32]);
+
+/// Dump `partition` to `out` in order, for outputs which can't be seeked,
+/// returning its statistics, `out`, and the size and SHA-256 of the image,
+/// or `None` if it's cancelled. The blocks not written by any operation are
+/// written as zeros, up to the size of the partition.
+///
+/// The operations are applied straight to `out` in the order of their dst
+/// extents, see [`sequential_order`]. If they go back, the image is dumped
+/// to the temporary file at `tmp_path` first, which is then copied to
+/// `out`. `out_path` names `out` in errors.
+#[allow(clippy::too_many_arguments)]
+fn dump_sequential<W: Write + Send>(
+    input: &mut Input,
+    payload: &DeltaUpdateFile,
+    partition: &PartitionUpdate,
+    old: Option<File>,
+    out: W,
+    (out_path, tmp_path): (&Path, &Path),
+    options: &DumpOptions,
+    bar: &PartitionBar,
+    cancelled: &AtomicBool,
+) -> Result<Option<SequentialDump<W>>, PayloadError> {
+    let error =
+        |e: std::io::Error| image_error(e, out_path).in_partition(&partition.partition_name);
+    let size = partition
+        .new_partition_info
+        .as_ref()
+        .and_then(|i| i.size)
+        .unwrap_or(0);
+    let mut img = SequentialWriter::new(out);
+
+    let stats = match sequential_order(partition) {
+        Some(operations) => {
+            let sorted = PartitionUpdate {
+                operations,
+                ..partition.clone()
+            };
+            dump_partition(
+                input, payload, &sorted, old, &mut img, options, None, bar, cancelled,
+            )?
+        }
+        None => {
+            let tmp = TempFile(tmp_path.to_owned());
+            let mut raw = create_image(&tmp.0, partition)
+                .map_err(|e| e.in_partition(&partition.partition_name))?;
+            let stats = dump_partition(
+                input, payload, partition, old, &mut raw, options, None, bar, cancelled,
+            )?;
+            if stats.is_some() {
+                raw.rewind()
+                    .and_then(|_| std::io::copy(&mut BufReader::new(raw).take(size), &mut img))
+                    .map_err(|e| image_error(e, &tmp.0).in_partition(&partition.partition_name))?;
+            }
+            stats
+        }
+    };
+    let Some(stats) = stats else {
+        return Ok(None);
+    };
+    let (out, size, sha256) = img.finish(size).map_err(error)?;
+    Ok(Some((stats, out, size, sha256)))
+}
+
+impl Args {
+    /// Path of the image of `partition`, and whether it's mapped to an
+    /// existing file or device by `--output-map` or `--in-place`.
+    /// With `--stdout`, it's a temporary file copied to stdout when done,
+    /// and with `--tar`, the temporary file of partitions which can't be
+    /// written in order.
+    fn output_path(&self, partition: &PartitionUpdate) -> (PathBuf, bool) {
+        let name = &partition.partition_name;
+        if let Some(dir) = &self.in_place {
+            return (dir.join(format!("{}.img", sanitize_file_name(name))), true);
+        }
+        if self.stdout {
+            let file_name = format!(
+                "payload-dumper-{}-{}.img",
+                std::process::id(),
+                sanitize_file_name(name)
+            );
+            return (std::env::temp_dir().join(file_name), false);
+        }
+        if self.tar.is_some() {
+            let file_name = format!(
+                "payload-dumper-{}-{}",
+                std::process::id(),
+                self.file_name(partition)
+            );
+            return (std::env::temp_dir().join(file_name), false);
+        }
+        match self.output_map.iter().find(|(mapped, _)| mapped == name) {
+            Some((_, path)) => (path.clone(), true),
+            None => (self.output.join(self.file_name(partition)), false),
+        }
+    }
+
+    /// Path the image of `partition` is written to: `<image>.tmp` in the
+    /// output directory, renamed to the image once it's complete and
+    /// verified, so an interrupted run never leaves a truncated image under
+    /// its name. It's the image itself with `--no-atomic`, or if it's
+    /// mapped, temporary already, or partly written by `--op-range` or
+    /// `--stop-on-op`.
+    fn write_path(&self, partition: &PartitionUpdate) -> PathBuf {
+        let (path, mapped) = self.output_path(partition);
+        if self.no_atomic || mapped || self.stdout || self.tar.is_some() || self.partial() {
+            return path;
+        }
+        let mut tmp_path = path.into_os_string();
+        tmp_path.push(".tmp");
+        PathBuf::from(tmp_path)
+    }
+
+    /// File name of the image of `partition` in the output directory, from
+    /// `--name-template` and `--rename`.
+    fn file_name(&self, partition: &PartitionUpdate) -> String {
+        let name = &partition.partition_name;
+        let name = match self.rename.iter().find(|(old, _)| old == name) {
+            Some((_, new)) => new.clone(),
+            None => sanitize_file_name(name),
+        };
+        let size = partition.new_partition_info.as_ref().and_then(|i| i.size);
+        let file_name = render_template(&self.name_template, |key| match key {
+            "partition" => Ok(name.clone()),
+            "timestamp" => Ok(self.max_timestamp.unwrap_or_default().to_string()),
+            _ => Ok(size.unwrap_or_default().to_string()),
+        })
+        .expect("the template is checked when parsed");
+        match self.compress {
+            Some(compression) => format!(
+                "{}.{}",
+                file_name,
+                ImageCompression::from(compression).extension()
+            ),
+            None => file_name,
+        }
+    }
+
+    /// Progress file of `partition`, none with `--stdout` or `--tar` as the
+    /// image is temporary, `--in-place` as it can't be resumed, or
+    /// `--op-range` and `--stop-on-op` as the operations before are not
+    /// applied by this run.
+    fn progress_file(
+        &self,
+        payload: &DeltaUpdateFile,
+        partition: &PartitionUpdate,
+    ) -> Option<ProgressFile> {
+        if self.stdout || self.tar.is_some() || self.in_place.is_some() || self.partial() {
+            return None;
+        }
+        Some(ProgressFile {
+            path: self
+                .output
+                .join(format!("{}.progress", self.file_name(partition))),
+            metadata_hash: hex(&payload.metadata_hash),
+            last_saved: Mutex::new(None),
+        })
+    }
+
+    /// Whether only some of the operations are applied, by `--op-range` or
+    /// `--stop-on-op`, so the images are not complete.
+    fn partial(&self) -> bool {
+        self.op_range.is_some() || self.stop_on_op.is_some()
+    }
+
+    /// Print a message, unless `--quiet`.
+    fn log(&self, message: impl std::fmt::Display) {
+        if !self.quiet {
+            self.report(message);
+        }
+    }
+
+    /// Print a message even with `--quiet`, to stderr with `--stdout` or
+    /// `--tar` to stdout to keep the output clean.
+    fn report(&self, message: impl std::fmt::Display) {
+        match self.stdout || self.tar.as_deref() == Some(Path::new("-")) {
+            true => eprintln!("{}", message),
+            false => println!("{}", message),
+        }
+    }
+
+    /// Format of the summary, JSON by default with `--progress json`.
+    fn summary_format(&self) -> SummaryFormat {
+        match (self.summary, self.progress_mode()) {
+            (Some(format), _) => format,
+            (None, ProgressMode::Json) => SummaryFormat::Json,
+            (None, _) => SummaryFormat::Table,
+        }
+    }
+
+    /// How to report progress, nothing with `--quiet`.
+    fn progress_mode(&self) -> ProgressMode {
+        match self.quiet {
+            true => ProgressMode::None,
+            false => self.progress,
+        }
+    }
+}
+
+impl Pipeline {
+    /// Options to dump a partition to `path`, `mapped` to a file or device,
+    /// `existing` when it's opened without truncating, mapped, resumed or
+    /// with `--op-range`. Existing images are not zeroed, so ZERO operations
+    /// are written. Block devices get aligned writes.
+    fn dump_options(&self, path: &Path, mapped: bool, existing: bool) -> DumpOptions {
+        DumpOptions {
+            workers: self.workers,
+            in_flight: self.in_flight,
+            prefetch_bytes: self.prefetch_mb.saturating_mul(1 << 20),
+            dense: self.dense || existing,
+            skip_operations: 0,
+            in_place: false,
+            aligned_writes: mapped && is_block_device(path),
+        }
+    }
+}
+
+/// Open the image of `partition` at `path`, which is created unless it's
+/// an `existing` file or device, mapped or resumed.
+fn open_output(
+    partition: &PartitionUpdate,
+    path: &Path,
+    existing: bool,
+) -> Result<File, PayloadError> {
+    let img = match existing {
+        true => open_existing_image(path, partition),
+        false => create_image(path, partition),
+    };
+    img.map_err(|e| e.in_partition(&partition.partition_name))
+}
+
+/// Partitions with images in the `--old` directory which are not in the
+/// partial update `payload`, named after the images and of their size. They
+/// are left untouched by the update, so their old images are copied as they
+/// are. Empty unless the payload is a partial update and `--old` is given.
+fn untouched_partitions(
+    args: &Args,
+    payload: &DeltaUpdateFile,
+    all_partitions: &[PartitionUpdate],
+) -> Result<Vec<PartitionUpdate>, Box<dyn std::error::Error>> {
+    let old = match &args.old {
+        Some(old) if payload.is_partial_update() && args.in_place.is_none() => old,
+        _ => return Ok(Vec::new()),
+    };
+    let entries = std::fs::read_dir(old)
+        .map_err(|e| Failure::about(&e, format!("{}: {}", old.display(), e)))?;
+    let mut names = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let name = match (path.file_stem().and_then(|s| s.to_str()), path.extension()) {
+            (Some(name), Some(extension)) if extension == "img" && path.is_file() => name,
+            _ => continue,
+        };
+        if !all_partitions
+            .iter()
+            .any(|p| sanitize_file_name(&p.partition_name) == name)
+        {
+            names.push((name.to_string(), path.metadata()?.len()));
+        }
+    }
+    names.sort();
+    Ok(names
+        .into_iter()
+        .map(|(partition_name, size)| PartitionUpdate {
+            partition_name,
+            new_partition_info: Some(PartitionInfo {
+                size: Some(size),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .collect())
+}
+
+/// The `untouched` partitions to copy from `--old`: none if the images
+/// aren't written as they are, as with `--tar` or `--sparse`, and only those
+/// without a complete image in the output directory with `--skip-existing`,
+/// see [`check_existing`].
+fn check_untouched<'a>(
+    args: &Args,
+    payload: &DeltaUpdateFile,
+    untouched: Vec<&'a PartitionUpdate>,
+) -> Result<Vec<&'a PartitionUpdate>, Box<dyn std::error::Error>> {
+    if untouched.is_empty() {
+        return Ok(untouched);
+    }
+    let names: Vec<_> = untouched
+        .iter()
+        .map(|p| p.partition_name.as_str())
+        .collect();
+    if args.stdout
+        || args.tar.is_some()
+        || args.compress.is_some()
+        || args.sparse
+        || args.partial()
+        || args.dry_run.is_some()
+    {
+        args.log(format!(
+            "Not in the partial update, left alone: {}",
+            names.join(" ")
+        ));
+        return Ok(Vec::new());
+    }
+    check_existing(args, payload, untouched)
+}
+
+/// Copy the images of the `untouched` partitions, not in the partial
+/// update, from `--old` to the output directory.
+fn copy_untouched(
+    args: &Args,
+    untouched: &[&PartitionUpdate],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let old = match &args.old {
+        Some(old) => old,
+        None => return Ok(()),
+    };
+    for partition in untouched {
+        let name = &partition.partition_name;
+        let from = old.join(format!("{}.img", name));
+        let (to, _) = args.output_path(partition);
+        std::fs::copy(&from, &to).map_err(|e| image_error(e, &to).in_partition(name))?;
+        args.log(format!(
+            "{}: not in the partial update, copied from {}",
+            name,
+            from.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Open `<partition_name>.img` in the `old` directory if the partition has
+/// operations reading from the old partition.
+fn open_old_image(
+    old: Option<&Path>,
+    partition: &PartitionUpdate,
+) -> Result<Option<File>, Box<dyn std::error::Error>> {
+    let name = &partition.partition_name;
+    let needs_old = partition.operations.iter().any(|operation| {
+        matches!(
+            operation.r#type(),
+            Type::Move
+                | Type::Bsdiff
+                | Type::SourceCopy
+                | Type::SourceBsdiff
+                | Type::BrotliBsdiff
+                | Type::Puffdiff
+        )
+    });
+    if !needs_old {
+        return Ok(None);
+    }
+
+    let old = old.ok_or_else(|| {
+        let message = format!(
+            "Partition {} reads from the old partition, please specify the directory of old images with --old",
+            name
+        );
+        Failure::new(ErrorClass::Usage, message)
+    })?;
+    let path = old.join(format!("{}.img", sanitize_file_name(name)));
+    match File::open(&path) {
+        Ok(file) => Ok(Some(file)),
+        Err(e) => Err(Failure::about(
+            &e,
+            format!(
+                "Partition {} needs old image {}: {}",
+                name,
+                path.display(),
+                e
+            ),
+        )
+        .into()),
+    }
+}
+
+/// Check the old image of `partition` against `old_partition_info`.
+fn check_old_image(
+    payload: &DeltaUpdateFile,
+    partition: &PartitionUpdate,
+    old: &mut File,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let info = match &partition.old_partition_info {
+        Some(info) => info,
+        None => return Ok(()),
+    };
+
+    // Old images may be larger than the partition, e.g. when dumped from a
+    // block device, only the partition size is hashed like update_engine.
+    let size = info.size.unwrap_or(u64::MAX);
+    let result = verify_image(Read::by_ref(old).take(size), info);
+    old.rewind()?;
+
+    result.map_err(|e| {
+        let build = payload
+            .manifest
+            .old_image_info
+            .as_ref()
+            .and_then(|i| i.build_version.as_ref().or(i.version.as_ref()))
+            .map(|build| format!(", expected build {}", build))
+            .unwrap_or_default();
+        format!(
+            "Source partition {} hash mismatch{}: {}. Use --skip-source-check to apply anyway",
+            partition.partition_name, build, e
+        )
+        .into()
+    })
+}
+
+/// Check the images of `partitions` for `--in-place` before touching any of
+/// them: the payload must be applicable in place, and the images must match
+/// `old_partition_info` unless `--skip-source-check`. Then back them up with
+/// `--backup`, and grow them to the size of the new partitions.
+fn prepare_in_place(
+    args: &Args,
+    payload: &DeltaUpdateFile,
+    partitions: &[&PartitionUpdate],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let block_size = payload.manifest.block_size() as u64;
+    let mut images = Vec::new();
+    for partition in partitions {
+        let name = &partition.partition_name;
+        let (path, _) = args.output_path(partition);
+        validate_in_place(partition, block_size).map_err(|e| e.in_partition(name))?;
+        let mut img = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .map_err(|e| {
+                Failure::about(
+                    &e,
+                    format!(
+                        "Partition {} needs image {} to apply in place: {}",
+                        name,
+                        path.display(),
+                        e
+                    ),
+                )
+            })?;
+        if !args.skip_source_check {
+            check_old_image(payload, partition, &mut img)?;
+        }
+        images.push((partition, path, img));
+    }
+
+    for (partition, path, img) in images {
+        if args.backup {
+            let backup = path.with_extension("img.bak");
+            std::fs::copy(&path, &backup)
+                .map_err(|e| image_error(e, &backup).in_partition(&partition.partition_name))?;
+            args.log(format!(
+                "{}: backed up to {}",
+                partition.partition_name,
+                backup.display()
+            ));
+        }
+        let len = img.metadata()?.len();
+        match partition.new_partition_info.as_ref().and_then(|i| i.size) {
+            Some(size) if size > len => img.set_len(size).map_err(|e| image_error(e, &path))?,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn partiotion_to_string(
+    x: &payload_dumper_rust::chromeos_update_engine::PartitionUpdate,
+) -> String {
+    let name = &x.partition_name;
+    let part = x
+        .new_partition_info
+        .as_ref()
+        .and_then(|i| i.size)
+        .map(Size::from_bytes)
+        .map(|s| s.to_string())
+        .unwrap_or_else(|| "? MiB".to_string());
+
+    format!("{} ({})", name, part)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use payload_dumper_rust::chromeos_update_engine::PartitionInfo;
+    use sha2::{Digest, Sha256};
+
+    #[test]
+    fn unchanged_hash() {
+        let sha256: [u8; 32] = Sha256::digest(b"image").into();
+        let info = PartitionInfo {
+            size: Some(5),
+            hash: Some(sha256.to_vec()),
+        };
+        let mut partition = PartitionUpdate {
+            partition_name: "vendor".to_string(),
+            new_partition_info: Some(info.clone()),
+            ..Default::default()
+        };
+        assert_eq!(check_hash(&partition, 5, sha256).0, "vendor: OK");
+        partition.old_partition_info = Some(info);
+        let (message, status, _) = check_hash(&partition, 5, sha256);
+        assert_eq!(
+            (message.as_str(), status),
+            ("vendor: OK (unchanged)", Status::Ok)
+        );
+        assert_eq!(check_hash(&partition, 5, [0; 32]).1, Status::Failed);
+    }
+}
@@ -0,0 +1,57 @@
+use std::{fs::File, io::BufWriter, path::Path, sync::atomic::AtomicBool};
+
+#[cfg(feature = "compress")]
+use payload_dumper_rust::CompressWriter;
+use payload_dumper_rust::{
+    chromeos_update_engine::PartitionUpdate, image_error, DeltaUpdateFile, DumpOptions, DumpStats,
+    ImageCompression, PayloadError,
+};
+
+use super::progress::PartitionBar;
+use super::{dump_sequential, Input};
+
+/// Dump `partition` to `img_path` compressed with `compression`, returning
+/// its statistics, and the size and SHA-256 of the image, or `None` if it's
+/// cancelled.
+#[allow(clippy::too_many_arguments)]
+pub(super) fn dump_compressed(
+    input: &mut Input,
+    payload: &DeltaUpdateFile,
+    partition: &PartitionUpdate,
+    old: Option<File>,
+    img_path: &Path,
+    compression: ImageCompression,
+    options: &DumpOptions,
+    fsync: bool,
+    bar: &PartitionBar,
+    cancelled: &AtomicBool,
+) -> Result<Option<(DumpStats, u64, [u8; 32])>, PayloadError> {
+    let error =
+        |e: std::io::Error| image_error(e, img_path).in_partition(&partition.partition_name);
+    let out = BufWriter::new(File::create(img_path).map_err(error)?);
+    let compressor = CompressWriter::new(compression, out).map_err(error)?;
+    let mut tmp_path = img_path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let dumped = dump_sequential(
+        input,
+        payload,
+        partition,
+        old,
+        compressor,
+        (img_path, Path::new(&tmp_path)),
+        options,
+        bar,
+        cancelled,
+    )?;
+    let Some((stats, compressor, size, sha256)) = dumped else {
+        return Ok(None);
+    };
+    let out = compressor
+        .finish()
+        .and_then(|out| out.into_inner().map_err(|e| e.into_error()))
+        .map_err(error)?;
+    if fsync {
+        out.sync_all().map_err(error)?;
+    }
+    Ok(Some((stats, size, sha256)))
+}
@@ -0,0 +1,383 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek},
+    path::{Path, PathBuf},
+};
+
+use payload_dumper_rust::{
+    chromeos_update_engine::{install_operation::Type, PartitionUpdate},
+    hash_image, open_existing_image, validate_data_ranges, validate_dst_extents, validate_in_place,
+    DeltaUpdateFile, PayloadError,
+};
+
+use size::Size;
+
+use super::{check_old_image, open_old_image};
+use crate::error::{ErrorClass, Failure};
+use crate::format::table_lines;
+use crate::input::{open_located, Location};
+use crate::{Args, DryRun};
+
+/// Check that `partitions` can be dumped, without writing anything, see
+/// `--dry-run`. Prints whether each partition passes, with the first check
+/// it fails, and fails if any does or if the output can't be written.
+pub(super) fn dry_run(
+    args: &Args,
+    payload: &DeltaUpdateFile,
+    partitions: &[&PartitionUpdate],
+    location: Location,
+    mode: DryRun,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut input = match mode {
+        DryRun::Hash => Some(open_located(&args.path, location).map_err(|e| {
+            Failure::about(
+                &*e,
+                format!("Failed to open {}: {}", args.path.display(), e),
+            )
+        })?),
+        DryRun::Check => None,
+    };
+    let plan = OutputPlan::new(args, payload, partitions);
+    args.log(&plan);
+    let mut rows = vec![[
+        "PARTITION".to_string(),
+        "RESULT".to_string(),
+        "SIZE".to_string(),
+        "REASON".to_string(),
+    ]];
+    let mut failed = Vec::new();
+    for partition in partitions {
+        let size = partition
+            .new_partition_info
+            .as_ref()
+            .and_then(|i| i.size)
+            .unwrap_or(0);
+        let (result, reason) =
+            match dry_run_partition(args, payload, partition, location, input.as_mut()) {
+                Ok(()) => ("PASS", String::new()),
+                Err(e) => {
+                    failed.push(partition.partition_name.as_str());
+                    ("FAIL", e.to_string())
+                }
+            };
+        rows.push([
+            partition.partition_name.clone(),
+            result.to_string(),
+            Size::from_bytes(size).to_string(),
+            reason,
+        ]);
+    }
+    for line in table_lines(&rows) {
+        args.report(line.trim_end());
+    }
+
+    let output = check_output_dir(&plan);
+    if let Err(e) = &output {
+        args.report(format!("Output: FAIL ({})", e));
+    }
+    match failed.is_empty() {
+        true => output.map_err(|_| "Dry run failed for the output".into()),
+        false => Err(Failure::new(
+            ErrorClass::Verification,
+            format!("Dry run failed for {}", failed.join(", ")),
+        )
+        .into()),
+    }
+}
+
+/// Check `partition` for `--dry-run`, reading the data of its operations
+/// from `input` to check their hashes if given.
+fn dry_run_partition<R: Read + Seek>(
+    args: &Args,
+    payload: &DeltaUpdateFile,
+    partition: &PartitionUpdate,
+    location: Location,
+    input: Option<&mut R>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let block_size = payload.manifest.block_size() as u64;
+    validate_dst_extents(partition, block_size)?;
+    validate_data_ranges(partition, payload.blobs_offset, location.len)?;
+
+    let (img_path, mapped) = args.output_path(partition);
+    if args.in_place.is_some() {
+        validate_in_place(partition, block_size)?;
+        let mut img = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&img_path)
+            .map_err(|e| {
+                Failure::about(
+                    &e,
+                    format!(
+                        "needs image {} to apply in place: {}",
+                        img_path.display(),
+                        e
+                    ),
+                )
+            })?;
+        if !args.skip_source_check {
+            check_old_image(payload, partition, &mut img)?;
+        }
+    } else {
+        if let Some(mut old) = open_old_image(args.old.as_deref(), partition)? {
+            if !args.skip_source_check {
+                check_old_image(payload, partition, &mut old)?;
+            }
+        }
+        if mapped {
+            open_existing_image(&img_path, partition)?;
+        }
+    }
+
+    let Some(input) = input else {
+        return Ok(());
+    };
+    for (index, operation) in partition.operations.iter().enumerate() {
+        let Some(range) = payload.operation_blob_range(operation) else {
+            continue;
+        };
+        let expected = match operation.data_sha256_hash.as_deref() {
+            Some(hash) if !hash.is_empty() => hash,
+            _ => continue,
+        };
+        input.seek(std::io::SeekFrom::Start(range.start))?;
+        let (_, actual) = hash_image(Read::by_ref(input).take(range.end - range.start))?;
+        if actual != expected {
+            let error = PayloadError::HashMismatch {
+                expected: expected.to_vec(),
+                actual: actual.to_vec(),
+            };
+            return Err(error.in_operation(index, operation.r#type).into());
+        }
+    }
+    Ok(())
+}
+
+/// Check that the directory the images of `partitions` are written to is
+/// writable and has room for them, for `--dry-run`. It's created when
+/// dumping if it doesn't exist, so its closest existing parent is checked.
+fn check_output_dir(plan: &OutputPlan) -> Result<(), String> {
+    if let Some(dir) = &plan.dir {
+        let probe = dir.join(format!(".payload-dumper-dry-run-{}", std::process::id()));
+        File::create(&probe).map_err(|e| format!("{} is not writable: {}", dir.display(), e))?;
+        let _ = std::fs::remove_file(&probe);
+    }
+    plan.check()
+}
+
+/// The size of the images of the partitions to dump, and the space they
+/// need in the directory they're written to, planned before anything is
+/// written.
+pub(super) struct OutputPlan {
+    partitions: usize,
+    /// Sum of the sizes of the partitions.
+    size: u64,
+    /// Closest existing directory of the output, or `None` if the images
+    /// aren't written to a directory, like with `--stdout` or `--in-place`.
+    dir: Option<PathBuf>,
+    /// Bytes the images written to `dir` need.
+    needed: u64,
+    /// Bytes available in `dir`, if it's known.
+    available: Option<u64>,
+}
+
+impl OutputPlan {
+    pub(super) fn new(
+        args: &Args,
+        payload: &DeltaUpdateFile,
+        partitions: &[&PartitionUpdate],
+    ) -> Self {
+        let size = |partition: &PartitionUpdate| {
+            partition
+                .new_partition_info
+                .as_ref()
+                .and_then(|i| i.size)
+                .unwrap_or(0)
+        };
+        let mut plan = OutputPlan {
+            partitions: partitions.len(),
+            size: partitions
+                .iter()
+                .map(|partition| size(partition))
+                .fold(0, u64::saturating_add),
+            dir: None,
+            needed: 0,
+            available: None,
+        };
+        let dir = match &args.tar {
+            _ if args.stdout || args.in_place.is_some() => return plan,
+            Some(tar) if tar == Path::new("-") => return plan,
+            Some(tar) => tar.parent().unwrap_or(Path::new("")),
+            None => &args.output,
+        };
+        let dir = dir
+            .ancestors()
+            .map(|dir| {
+                if dir.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    dir
+                }
+            })
+            .find(|dir| dir.is_dir())
+            .unwrap_or(Path::new("."));
+
+        // Images have holes for ZERO operations and blocks not written,
+        // unless they're written as zeros.
+        let block_size = payload.manifest.block_size() as u64;
+        let dense = args.pipeline.dense || args.tar.is_some();
+        plan.needed = partitions
+            .iter()
+            .filter(|partition| !args.output_path(partition).1)
+            .map(|partition| {
+                let written: u64 = partition
+                    .operations
+                    .iter()
+                    .filter(|operation| dense || operation.r#type() != Type::Zero)
+                    .flat_map(|operation| &operation.dst_extents)
+                    .map(|extent| extent.num_blocks().saturating_mul(block_size))
+                    .fold(0, u64::saturating_add);
+                let needed = match dense {
+                    true => size(partition),
+                    false => written.min(size(partition)),
+                };
+                // Images already there, like with `--resume`, are written
+                // over. Complete images are only replaced when the new ones
+                // are, see `Args::write_path`.
+                let existing = match args.tar {
+                    Some(_) => 0,
+                    None => std::fs::metadata(args.write_path(partition)).map_or(0, |m| m.len()),
+                };
+                needed.saturating_sub(existing)
+            })
+            .fold(0, u64::saturating_add);
+        plan.available = available_space(dir);
+        plan.dir = Some(dir.to_owned());
+        plan
+    }
+
+    /// Fail if the images need more space than available.
+    pub(super) fn check(&self) -> Result<(), String> {
+        match (&self.dir, self.available) {
+            (Some(dir), Some(available)) if available < self.needed => Err(format!(
+                "{} has {} available, the images need up to {}",
+                dir.display(),
+                Size::from_bytes(available),
+                Size::from_bytes(self.needed)
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl std::fmt::Display for OutputPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Extracting {} partition{}, {} total",
+            self.partitions,
+            if self.partitions == 1 { "" } else { "s" },
+            Size::from_bytes(self.size)
+        )?;
+        if self.dir.is_some() && self.needed < self.size {
+            write!(f, ", {} needed", Size::from_bytes(self.needed))?;
+        }
+        match (&self.dir, self.available) {
+            (Some(dir), Some(available)) => write!(
+                f,
+                ", {} free on {}",
+                Size::from_bytes(available),
+                dir.display()
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Space available to unprivileged users in the filesystem of `path`, if
+/// it's known.
+#[cfg(unix)]
+fn available_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is NUL terminated and `stat` is only read when it's
+    // filled by a successful statvfs.
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return None;
+        }
+        stat.assume_init()
+    };
+    // The types of the fields differ between platforms.
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(windows)]
+fn available_space(path: &Path) -> Option<u64> {
+    use std::os::windows::ffi::OsStrExt;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetDiskFreeSpaceExW(
+            directory_name: *const u16,
+            free_bytes_available: *mut u64,
+            total_number_of_bytes: *mut u64,
+            total_number_of_free_bytes: *mut u64,
+        ) -> i32;
+    }
+
+    let path: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut available = 0u64;
+    // SAFETY: `path` is NUL terminated, and the totals which aren't needed
+    // can be null.
+    let ok = unsafe {
+        GetDiskFreeSpaceExW(
+            path.as_ptr(),
+            &mut available,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    (ok != 0).then_some(available)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn available_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_plan() {
+        let mut plan = OutputPlan {
+            partitions: 2,
+            size: 3 << 30,
+            dir: Some(PathBuf::from("/data")),
+            needed: 2 << 30,
+            available: Some(5 << 30),
+        };
+        assert_eq!(
+            plan.to_string(),
+            "Extracting 2 partitions, 3.00 GiB total, 2.00 GiB needed, 5.00 GiB free on /data"
+        );
+        assert!(plan.check().is_ok());
+        plan.available = Some(1 << 30);
+        assert!(plan.check().unwrap_err().contains("need up to 2.00 GiB"));
+
+        let plan = OutputPlan {
+            partitions: 1,
+            size: 4096,
+            dir: None,
+            needed: 0,
+            available: None,
+        };
+        assert_eq!(plan.to_string(), "Extracting 1 partition, 4.00 KiB total");
+        assert!(plan.check().is_ok());
+    }
+}
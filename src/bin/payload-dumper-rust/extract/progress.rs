@@ -0,0 +1,164 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use payload_dumper_rust::{chromeos_update_engine::PartitionUpdate, InstallOperationExt};
+
+use super::extents_to_string;
+use crate::format::type_name;
+use crate::json::json_string;
+
+/// Offsets of the operations of `partition` in its progress, in bytes, with
+/// the total at the end. Operations count as the size of their data, or the
+/// bytes they write if they have none, so the progress moves with the work
+/// rather than the number of operations.
+pub(super) fn progress_offsets(partition: &PartitionUpdate, block_size: u64) -> Vec<u64> {
+    let mut offsets = vec![0];
+    let mut offset = 0u64;
+    for operation in &partition.operations {
+        let length = match operation.data_length {
+            Some(length) if length > 0 => length,
+            _ => operation.dst_byte_len(block_size),
+        };
+        offset = offset.saturating_add(length);
+        offsets.push(offset);
+    }
+    offsets
+}
+
+/// The progress bars, with the bar of the total.
+pub(super) struct Bars<'a> {
+    pub(super) multi: &'a MultiProgress,
+    pub(super) total: &'a ProgressBar,
+    pub(super) style: &'a ProgressStyle,
+    /// Print a JSON line for each operation done, with `--progress json`.
+    pub(super) json: bool,
+    /// Print each operation before it's applied, with `--op-range` and
+    /// `--stop-on-op`.
+    pub(super) operations: bool,
+    pub(super) block_size: u64,
+}
+
+impl<'a> Bars<'a> {
+    /// Add the bar of `partition`.
+    pub(super) fn add(&self, partition: &'a PartitionUpdate) -> PartitionBar<'a> {
+        let offsets = progress_offsets(partition, self.block_size);
+        let bar = self
+            .multi
+            .add(ProgressBar::new(offsets[partition.operations.len()]));
+        bar.set_style(self.style.clone());
+        bar.set_message(partition.partition_name.clone());
+        PartitionBar {
+            bar,
+            total: self.total,
+            partition,
+            offsets,
+            json: self.json,
+            operations: self.operations,
+            block_size: self.block_size,
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// Progress bar of a partition, which also advances the bar of the total.
+pub(super) struct PartitionBar<'a> {
+    bar: ProgressBar,
+    total: &'a ProgressBar,
+    partition: &'a PartitionUpdate,
+    offsets: Vec<u64>,
+    json: bool,
+    operations: bool,
+    block_size: u64,
+    /// Index of the next operation to be done, or the number of operations
+    /// done when they're done out of order.
+    next: AtomicUsize,
+}
+
+impl PartitionBar<'_> {
+    /// Set the progress to before the operation at `index`, when the
+    /// operations before it are done.
+    pub(super) fn set(&self, index: usize) {
+        self.show(index);
+        let done = self.next.swap(index, Ordering::Relaxed)..index;
+        if self.json {
+            done.for_each(|index| self.print_done(index));
+        }
+        self.advance(self.offsets[index]);
+    }
+
+    /// Show the operation at `index` as the one being done, and print it
+    /// with `--op-range` and `--stop-on-op`.
+    pub(super) fn show(&self, index: usize) {
+        if let Some(operation) = self.partition.operations.get(index) {
+            self.bar.set_message(format!(
+                "{}: {:?}",
+                self.partition.partition_name,
+                operation.r#type()
+            ));
+            if self.operations {
+                self.bar.suspend(|| {
+                    eprintln!(
+                        "{}: operation {} {}, {} bytes of data, {} bytes written to {}",
+                        self.partition.partition_name,
+                        index,
+                        type_name(operation.r#type),
+                        operation.data_length(),
+                        operation.dst_byte_len(self.block_size),
+                        extents_to_string(&operation.dst_extents)
+                    )
+                });
+            }
+        }
+    }
+
+    /// Report the operation at `index` done, when the operations are done
+    /// out of order, with `--sequential-read`. `next` counts the operations
+    /// done then.
+    pub(super) fn done(&self, index: usize) {
+        if self.json {
+            self.print_done(index);
+        }
+        self.next.fetch_add(1, Ordering::Relaxed);
+        let size = self.offsets[index + 1] - self.offsets[index];
+        self.total.inc(size);
+        self.bar.inc(size);
+    }
+
+    /// Print the JSON line of the operation at `index` done.
+    fn print_done(&self, index: usize) {
+        let operation = &self.partition.operations[index];
+        eprintln!(
+            "{{\"event\": \"op_done\", \"partition\": {}, \"index\": {}, \"bytes\": {}}}",
+            json_string(&self.partition.partition_name),
+            index,
+            operation.dst_byte_len(self.block_size)
+        );
+    }
+
+    /// Set the progress to before the operation at `index`, which is resumed
+    /// from, so the operations before it are not reported as done.
+    pub(super) fn resume(&self, index: usize) {
+        self.next.store(index, Ordering::Relaxed);
+        self.advance(self.offsets[index]);
+    }
+
+    fn advance(&self, position: u64) {
+        self.total.inc(position.saturating_sub(self.bar.position()));
+        self.bar.set_position(position);
+    }
+
+    pub(super) fn finish(&self) {
+        self.set(self.partition.operations.len());
+        self.bar.finish();
+    }
+
+    pub(super) fn abandon(&self) {
+        self.bar.abandon();
+    }
+
+    /// Index of the next operation to be done, the first not done if the
+    /// partition is stopped.
+    pub(super) fn next(&self) -> usize {
+        self.next.load(Ordering::Relaxed)
+    }
+}
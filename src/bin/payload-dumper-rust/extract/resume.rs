@@ -0,0 +1,135 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use payload_dumper_rust::{chromeos_update_engine::PartitionUpdate, DeltaUpdateFile};
+
+use super::{check_image, Status};
+use crate::error::{ErrorClass, Failure};
+use crate::temp_file::TempFile;
+use crate::Args;
+
+/// Keep the temporary image `temp` of a partition which failed or was
+/// interrupted if it can be resumed with `--resume`, or remove it along with
+/// its progress file.
+pub(super) fn keep_for_resume(
+    args: &Args,
+    temp: Option<TempFile>,
+    progress_file: Option<&ProgressFile>,
+) {
+    match temp {
+        Some(temp) if args.resume => temp.keep(),
+        Some(_) => {
+            if let Some(progress_file) = progress_file {
+                progress_file.remove();
+            }
+        }
+        None => {}
+    }
+}
+
+/// Check for images in the output directory that would be overwritten,
+/// failing unless `--force` or `--skip-existing` is given. Returns the
+/// partitions to dump, without those with complete images if skipped.
+pub(super) fn check_existing<'a>(
+    args: &Args,
+    payload: &DeltaUpdateFile,
+    partitions: Vec<&'a PartitionUpdate>,
+) -> Result<Vec<&'a PartitionUpdate>, Box<dyn std::error::Error>> {
+    // `--op-range` applies operations to the existing images.
+    if args.force || args.stdout || args.tar.is_some() || args.op_range.is_some() {
+        return Ok(partitions);
+    }
+    let mut existing = Vec::new();
+    let mut result = Vec::new();
+    for partition in partitions {
+        let (img_path, mapped) = args.output_path(partition);
+        let resumed = args.resume
+            && args
+                .progress_file(payload, partition)
+                .is_some_and(|progress_file| progress_file.load().is_some());
+        // Mapped files and devices are meant to be overwritten.
+        if mapped || resumed || !img_path.exists() {
+            result.push(partition);
+        } else if !args.skip_existing {
+            existing.push(img_path.display().to_string());
+        } else if is_complete(partition, &img_path) {
+            args.log(format!(
+                "{}: {} exists, skipped",
+                partition.partition_name,
+                img_path.display()
+            ));
+        } else {
+            // Left by an interrupted run, dump it again.
+            result.push(partition);
+        }
+    }
+    if !existing.is_empty() {
+        let message = format!(
+            "Refusing to overwrite {}, pass --force to overwrite them or --skip-existing to skip complete ones",
+            existing.join(", ")
+        );
+        return Err(Failure::new(ErrorClass::Usage, message).into());
+    }
+    Ok(result)
+}
+
+/// Whether the image of `partition` at `img_path` is complete, of the size
+/// in the manifest and matching its hash, if any.
+fn is_complete(partition: &PartitionUpdate, img_path: &Path) -> bool {
+    let size = partition.new_partition_info.as_ref().and_then(|i| i.size);
+    let len = std::fs::metadata(img_path).map(|m| m.len());
+    match (size, len) {
+        (_, Err(_)) => false,
+        (Some(size), Ok(len)) if size != len => false,
+        _ => check_image(partition, img_path, false).1 != Status::Failed,
+    }
+}
+
+/// Progress of dumping a partition, recorded in `<partition>.img.progress`
+/// in the output directory to resume it with `--resume`.
+///
+/// It's the hash of the metadata of the payload, so progress of another
+/// payload is ignored, and the index of the next operation to apply. Only
+/// an interrupted process is covered: the image is not synced before the
+/// progress is recorded.
+pub(super) struct ProgressFile {
+    pub(super) path: PathBuf,
+    pub(super) metadata_hash: String,
+    pub(super) last_saved: Mutex<Option<Instant>>,
+}
+
+impl ProgressFile {
+    /// Number of operations applied by an interrupted run, if it dumped the
+    /// same payload.
+    pub(super) fn load(&self) -> Option<usize> {
+        let content = std::fs::read_to_string(&self.path).ok()?;
+        let (metadata_hash, next) = content.trim_end().split_once(' ')?;
+        if metadata_hash != self.metadata_hash {
+            return None;
+        }
+        next.parse().ok()
+    }
+
+    /// Record that the operations before `next` are applied, at most once a
+    /// second unless `force` is set.
+    pub(super) fn save(&self, next: usize, force: bool) -> std::io::Result<()> {
+        let mut last_saved = self.last_saved.lock().unwrap();
+        if !force && last_saved.is_some_and(|t| t.elapsed() < Duration::from_secs(1)) {
+            return Ok(());
+        }
+        // Replace it at once, so it's never half written.
+        let tmp_path = self.path.with_extension("progress.tmp");
+        std::fs::write(&tmp_path, format!("{} {}\n", self.metadata_hash, next))?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        *last_saved = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Remove the record after the partition is dumped.
+    pub(super) fn remove(&self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
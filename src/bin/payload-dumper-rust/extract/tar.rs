@@ -0,0 +1,88 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::atomic::AtomicBool,
+};
+
+use payload_dumper_rust::{
+    chromeos_update_engine::PartitionUpdate, image_error, DeltaUpdateFile, DumpOptions, DumpStats,
+    PayloadError, TarWriter,
+};
+
+use super::progress::PartitionBar;
+use super::{dump_sequential, Input};
+use crate::error::{ErrorClass, Failure};
+use crate::Args;
+
+/// Output of `--tar`, a file or stdout.
+pub(super) type TarOutput = TarWriter<BufWriter<Box<dyn Write + Send>>>;
+
+/// Create the archive of `--tar` at `path`, or write it to stdout if it's
+/// `-`. Existing files are only overwritten with `force`.
+pub(super) fn create_tar(
+    path: &Path,
+    force: bool,
+) -> Result<BufWriter<Box<dyn Write + Send>>, Box<dyn std::error::Error>> {
+    let out: Box<dyn Write + Send> = match path.to_str() {
+        Some("-") => Box::new(std::io::stdout()),
+        _ if path.exists() && !force => {
+            return Err(Failure::new(
+                ErrorClass::Usage,
+                format!(
+                    "{} already exists, pass --force to overwrite it",
+                    path.display()
+                ),
+            )
+            .into())
+        }
+        _ => Box::new(File::create(path).map_err(|e| image_error(e, path))?),
+    };
+    Ok(BufWriter::with_capacity(1 << 20, out))
+}
+
+/// Dump `partition` to an entry of `tar` named after its image, like
+/// `dump_compressed`. The entry has the size of the partition, with the
+/// blocks not written by any operation as zeros.
+#[allow(clippy::too_many_arguments)]
+pub(super) fn dump_tar_entry(
+    input: &mut Input,
+    payload: &DeltaUpdateFile,
+    partition: &PartitionUpdate,
+    old: Option<File>,
+    tar: &mut TarOutput,
+    args: &Args,
+    options: &DumpOptions,
+    bar: &PartitionBar,
+    cancelled: &AtomicBool,
+) -> Result<Option<(DumpStats, u64, [u8; 32])>, PayloadError> {
+    let tar_path = args.tar.as_deref().expect("--tar is given");
+    let error =
+        |e: std::io::Error| image_error(e, tar_path).in_partition(&partition.partition_name);
+    let size = partition
+        .new_partition_info
+        .as_ref()
+        .and_then(|i| i.size)
+        .unwrap_or(0);
+    let mtime = args.max_timestamp.unwrap_or(0).max(0) as u64;
+    let entry = tar
+        .append(&args.file_name(partition), size, mtime)
+        .map_err(error)?;
+    let (tmp_path, _) = args.output_path(partition);
+    let dumped = dump_sequential(
+        input,
+        payload,
+        partition,
+        old,
+        entry,
+        (tar_path, &tmp_path),
+        options,
+        bar,
+        cancelled,
+    )?;
+    let Some((stats, entry, size, sha256)) = dumped else {
+        return Ok(None);
+    };
+    entry.finish().map_err(error)?;
+    Ok(Some((stats, size, sha256)))
+}
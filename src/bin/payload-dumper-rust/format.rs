@@ -0,0 +1,80 @@
+use payload_dumper_rust::chromeos_update_engine::install_operation::Type;
+
+/// Lowercase hex of `bytes`, like hashes are printed.
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Name of the operation type `op_type`, or its number if unknown.
+pub(crate) fn type_name(op_type: i32) -> String {
+    Type::from_i32(op_type)
+        .map(|t| t.as_str_name().to_string())
+        .unwrap_or_else(|| op_type.to_string())
+}
+
+/// Format the Unix timestamp `secs` as a UTC date and time.
+pub(crate) fn format_timestamp(secs: i64) -> String {
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    // Civil date of the days since 1970-01-01, in 400-year eras starting on
+    // March 1st, see http://howardhinnant.github.io/date_algorithms.html.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Print `rows` as a table, with empty columns left out.
+pub(crate) fn print_table<const N: usize>(rows: &[[String; N]]) {
+    for line in table_lines(rows) {
+        println!("{}", line);
+    }
+}
+
+/// Lines of the table of `rows`, see [`print_table`].
+pub(crate) fn table_lines<const N: usize>(rows: &[[String; N]]) -> Vec<String> {
+    let mut widths = [0; N];
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    rows.iter()
+        .map(|row| {
+            let line = row
+                .iter()
+                .zip(widths)
+                .filter(|(_, width)| *width > 0)
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect::<Vec<_>>()
+                .join("  ");
+            line.trim_end().to_string()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_timestamp(1709597853), "2024-03-05 00:17:33 UTC");
+        assert_eq!(format_timestamp(951782400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(format_timestamp(-1), "1969-12-31 23:59:59 UTC");
+    }
+}
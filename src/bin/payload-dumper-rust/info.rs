@@ -0,0 +1,490 @@
+use std::path::Path;
+
+use payload_dumper_rust::{
+    chromeos_update_engine::signatures::Signature,
+    chromeos_update_engine::{Extent, ImageInfo},
+    extent_map, ContentType, DeltaUpdateFile, PayloadHeader, SectionFile, Signatures, SPARSE_HOLE,
+};
+
+use prost::Message;
+use sha2::{Digest, Sha256};
+use size::Size;
+
+use crate::format::{format_timestamp, hex, print_table, type_name};
+use crate::input::{open_input, read_input_headers};
+use crate::json::json_string;
+use crate::list::sniff_contents;
+use crate::select::not_found;
+
+/// Describe the signature at `index` of a Signatures message: its version,
+/// if any, its size, and the size without padding of EC signatures.
+pub(crate) fn signature_to_string(index: usize, signature: &Signature) -> String {
+    let mut details = Vec::new();
+    #[allow(deprecated)]
+    if let Some(version) = signature.version {
+        details.push(format!("version {}", version));
+    }
+    details.push(format!("{} bytes", signature.data().len()));
+    if let Some(size) = signature.unpadded_signature_size {
+        details.push(format!("{} unpadded", size));
+    }
+    format!("{} ({})", index, details.join(", "))
+}
+
+/// The signature at `index` of a Signatures message as JSON, like
+/// [`signature_to_string`].
+pub(crate) fn signature_json(index: usize, signature: &Signature) -> String {
+    #[allow(deprecated)]
+    let version = signature
+        .version
+        .map_or("null".to_string(), |v| v.to_string());
+    let unpadded = signature
+        .unpadded_signature_size
+        .map_or("null".to_string(), |size| size.to_string());
+    format!(
+        "{{\"index\": {}, \"version\": {}, \"size\": {}, \"unpadded_size\": {}}}",
+        index,
+        version,
+        signature.data().len(),
+        unpadded
+    )
+}
+
+/// Describe the serialized Signatures `message` as text, the SHA-256 of the
+/// message to compare signings, then a line for each signature, and as
+/// JSON. An empty message, of an unsigned payload, is `-` and `null`.
+fn signatures_fields(message: &[u8]) -> (String, String) {
+    if message.is_empty() {
+        return ("-".to_string(), "null".to_string());
+    }
+    let sha256 = hex(&Sha256::digest(message));
+    let signatures = match Signatures::decode(message) {
+        Ok(signatures) => signatures.signatures,
+        Err(e) => {
+            let error = format!("invalid Signatures message: {}", e);
+            let json = format!(
+                "{{\"sha256\": \"{}\", \"error\": {}}}",
+                sha256,
+                json_string(&error)
+            );
+            return (format!("sha256 {}\n{}", sha256, error), json);
+        }
+    };
+    let mut text = format!("sha256 {}", sha256);
+    for (index, signature) in signatures.iter().enumerate() {
+        text += &format!("\n{}", signature_to_string(index, signature));
+    }
+    let json: Vec<_> = signatures
+        .iter()
+        .enumerate()
+        .map(|(index, signature)| signature_json(index, signature))
+        .collect();
+    (
+        text,
+        format!(
+            "{{\"sha256\": \"{}\", \"signatures\": [{}]}}",
+            sha256,
+            json.join(", ")
+        ),
+    )
+}
+
+/// Print the versions, regions, signatures and build information of the
+/// payloads in the input `path`, as text or JSON.
+pub(crate) fn print_info(
+    path: &Path,
+    payload_offset: Option<u64>,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let payloads = read_input_headers(path, payload_offset)?;
+    let payload_signatures: Vec<_> = payloads
+        .iter()
+        .map(|(offset, header)| read_input_signatures(path, *offset, header))
+        .collect();
+    let contents: Vec<_> = payloads
+        .iter()
+        .map(|(offset, header)| sniff_contents(path, *offset, header))
+        .collect();
+    if json {
+        let payloads: Vec<_> = payloads
+            .iter()
+            .zip(&payload_signatures)
+            .zip(&contents)
+            .map(|(((offset, header), signatures), contents)| {
+                let fields: Vec<_> = info_fields(*offset, header, signatures.as_deref(), contents)
+                    .into_iter()
+                    .map(|(key, _, value)| format!("      {}: {}", json_string(key), value))
+                    .collect();
+                format!("    {{\n{}\n    }}", fields.join(",\n"))
+            })
+            .collect();
+        println!("{{\n  \"payloads\": [\n{}\n  ]\n}}", payloads.join(",\n"));
+        return Ok(());
+    }
+
+    for (index, (((offset, header), signatures), contents)) in payloads
+        .iter()
+        .zip(&payload_signatures)
+        .zip(&contents)
+        .enumerate()
+    {
+        if index > 0 {
+            println!();
+        }
+        if payloads.len() > 1 {
+            println!("Payload {}", index);
+        }
+        // Values of several lines continue on rows without a key.
+        let rows: Vec<_> = info_fields(*offset, header, signatures.as_deref(), contents)
+            .into_iter()
+            .flat_map(|(key, text, _)| {
+                let key = format!("{}:", key.replace('_', " "));
+                let lines: Vec<_> = text.lines().map(str::to_string).collect();
+                lines.into_iter().enumerate().map(move |(i, line)| match i {
+                    0 => [key.clone(), line],
+                    _ => [String::new(), line],
+                })
+            })
+            .collect();
+        print_table(&rows);
+    }
+    Ok(())
+}
+
+/// Read the serialized Signatures message at the end of the payload with
+/// `header` at `offset` in the input `path`, or `None` if it's stdin, where
+/// only the header is read, or the message can't be read, like from a
+/// truncated payload.
+fn read_input_signatures(path: &Path, offset: u64, header: &PayloadHeader) -> Option<Vec<u8>> {
+    if path == Path::new("-") {
+        return None;
+    }
+    let mut payload =
+        SectionFile::new(open_input(path).ok()?, offset, header.payload_size()).ok()?;
+    header.read_payload_signatures(&mut payload).ok()
+}
+
+/// Print the dst extents of the operations of the partition `name` of the
+/// payloads at `path`, as a table or JSON, see `info --show-extents`.
+pub(crate) fn print_extents(
+    path: &Path,
+    payload_offset: Option<u64>,
+    name: &str,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let payloads = read_input_headers(path, payload_offset)?;
+    let found: Vec<_> = payloads
+        .iter()
+        .enumerate()
+        .filter_map(|(index, (_, header))| {
+            let partition = header
+                .partitions()
+                .iter()
+                .find(|p| p.partition_name == name)
+                .cloned()?;
+            Some((index, header.manifest.block_size() as u64, partition))
+        })
+        .collect();
+    if found.is_empty() {
+        let names: Vec<_> = payloads
+            .iter()
+            .flat_map(|(_, header)| header.partitions().into_owned())
+            .map(|p| p.partition_name)
+            .collect();
+        let names: Vec<_> = names.iter().map(String::as_str).collect();
+        return Err(not_found(&[name], &names).into());
+    }
+
+    let range = |start: u64, end: u64| match start {
+        SPARSE_HOLE => format!("hole of {} blocks", end.wrapping_sub(start)),
+        _ => format!("{}..{}", start, end),
+    };
+    let extents = |extents: &[Extent]| -> Vec<(u64, u64)> {
+        extents
+            .iter()
+            .map(|e| {
+                (
+                    e.start_block(),
+                    e.start_block().wrapping_add(e.num_blocks()),
+                )
+            })
+            .collect()
+    };
+    if json {
+        let found: Vec<_> = found
+            .iter()
+            .map(|(index, block_size, partition)| {
+                let map = extent_map(partition, *block_size);
+                let operations: Vec<_> = partition
+                    .operations
+                    .iter()
+                    .enumerate()
+                    .map(|(i, operation)| {
+                        // As in the manifest, as holes have no end.
+                        let ranges: Vec<_> = operation
+                            .dst_extents
+                            .iter()
+                            .map(|e| format!("{{\"start_block\": {}, \"num_blocks\": {}}}", e.start_block(), e.num_blocks()))
+                            .collect();
+                        format!(
+                            "        {{\"index\": {}, \"type\": {}, \"dst_extents\": [{}]}}",
+                            i,
+                            json_string(&type_name(operation.r#type)),
+                            ranges.join(", ")
+                        )
+                    })
+                    .collect();
+                let overlaps: Vec<_> = map
+                    .overlaps
+                    .iter()
+                    .map(|overlap| {
+                        format!(
+                            "        {{\"operations\": [{}, {}], \"start_block\": {}, \"end_block\": {}}}",
+                            overlap.operations.0, overlap.operations.1, overlap.start_block, overlap.end_block
+                        )
+                    })
+                    .collect();
+                let list = |items: Vec<String>| match items.is_empty() {
+                    true => "[]".to_string(),
+                    false => format!("[\n{}\n      ]", items.join(",\n")),
+                };
+                format!(
+                    "    {{\n      \"payload\": {},\n      \"partition\": {},\n      \"block_size\": {},\n      \"operations\": {},\n      \"overlaps\": {},\n      \"covered_blocks\": {},\n      \"total_blocks\": {}\n    }}",
+                    index,
+                    json_string(name),
+                    block_size,
+                    list(operations),
+                    list(overlaps),
+                    map.covered_blocks,
+                    map.total_blocks.map_or("null".to_string(), |n| n.to_string())
+                )
+            })
+            .collect();
+        println!("{{\n  \"partitions\": [\n{}\n  ]\n}}", found.join(",\n"));
+        return Ok(());
+    }
+
+    for (i, (index, block_size, partition)) in found.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        if payloads.len() > 1 {
+            println!("Payload {}", index);
+        }
+        let mut rows = vec![[
+            "OPERATION".to_string(),
+            "TYPE".to_string(),
+            "DST EXTENTS".to_string(),
+        ]];
+        rows.extend(
+            partition
+                .operations
+                .iter()
+                .enumerate()
+                .map(|(i, operation)| {
+                    let ranges: Vec<_> = extents(&operation.dst_extents)
+                        .into_iter()
+                        .map(|(start, end)| range(start, end))
+                        .collect();
+                    [
+                        i.to_string(),
+                        type_name(operation.r#type),
+                        ranges.join(", "),
+                    ]
+                }),
+        );
+        print_table(&rows);
+
+        let map = extent_map(partition, *block_size);
+        for overlap in &map.overlaps {
+            println!(
+                "Overlap: operations {} and {} both write {}",
+                overlap.operations.0,
+                overlap.operations.1,
+                range(overlap.start_block, overlap.end_block)
+            );
+        }
+        match map.total_blocks {
+            Some(total) if total > 0 => println!(
+                "Covered: {} of {} blocks ({:.1}%)",
+                map.covered_blocks,
+                total,
+                map.covered_blocks as f64 * 100.0 / total as f64
+            ),
+            _ => println!(
+                "Covered: {} blocks, the size of the partition is unknown",
+                map.covered_blocks
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// The fields printed by `info` for the payload with `header` at `offset`,
+/// the serialized Signatures message at its end, if it was read, and the
+/// contents of its partitions, see [`sniff_contents`], with their text and
+/// JSON values.
+fn info_fields(
+    offset: u64,
+    header: &PayloadHeader,
+    payload_signatures: Option<&[u8]>,
+    contents: &[Option<ContentType>],
+) -> Vec<(&'static str, String, String)> {
+    let manifest = &header.manifest;
+    let number = |n: u64| (n.to_string(), n.to_string());
+    let bytes = |n: u64| match n < 1024 {
+        true => number(n),
+        false => (format!("{} ({})", n, Size::from_bytes(n)), n.to_string()),
+    };
+    let string = |s: Option<String>| match s {
+        Some(s) => (s.clone(), json_string(&s)),
+        None => ("-".to_string(), "null".to_string()),
+    };
+    let partitions = header.partitions();
+    let partitions_size = partitions
+        .iter()
+        .filter_map(|p| p.new_partition_info.as_ref().and_then(|i| i.size))
+        .sum();
+    let data_length = partitions
+        .iter()
+        .flat_map(|p| p.operations.iter())
+        .map(|op| op.data_length())
+        .sum();
+    let signatures = match (manifest.signatures_offset, manifest.signatures_size) {
+        (Some(offset), Some(size)) if size > 0 => Some(format!(
+            "{} bytes at {}",
+            size,
+            header.blobs_offset + offset
+        )),
+        _ => None,
+    };
+    let sniffed: Vec<_> = partitions
+        .iter()
+        .zip(contents)
+        .filter_map(|(p, content)| Some(format!("{}: {}", p.partition_name, content.as_ref()?)))
+        .collect();
+    let contents_json: Vec<_> = partitions
+        .iter()
+        .zip(contents)
+        .map(|(p, content)| {
+            let content = content.map_or("null".to_string(), |c| json_string(c.name()));
+            format!("{}: {}", json_string(&p.partition_name), content)
+        })
+        .collect();
+    let contents = match sniffed.is_empty() {
+        true => "-".to_string(),
+        false => sniffed.join("\n"),
+    };
+
+    let fields = [
+        ("offset", (format!("{:#x}", offset), offset.to_string())),
+        (
+            "type",
+            match header.is_partial_update() {
+                true => (
+                    format!("{} (partial update)", header.payload_type()),
+                    json_string(&header.payload_type().to_string()),
+                ),
+                false => string(Some(header.payload_type().to_string())),
+            },
+        ),
+        ("file_format_version", number(header.file_format_version)),
+        ("minor_version", number(manifest.minor_version() as u64)),
+        ("block_size", number(manifest.block_size() as u64)),
+        (
+            "partial_update",
+            match manifest.partial_update() {
+                true => ("yes".to_string(), "true".to_string()),
+                false => ("no".to_string(), "false".to_string()),
+            },
+        ),
+        (
+            "max_timestamp",
+            match manifest.max_timestamp {
+                Some(t) => (format_timestamp(t), t.to_string()),
+                None => ("-".to_string(), "null".to_string()),
+            },
+        ),
+        (
+            "security_patch_level",
+            string(manifest.security_patch_level.clone()),
+        ),
+        (
+            "old_build",
+            string(
+                manifest
+                    .old_image_info
+                    .as_ref()
+                    .and_then(image_info_to_string),
+            ),
+        ),
+        (
+            "new_build",
+            string(
+                manifest
+                    .new_image_info
+                    .as_ref()
+                    .and_then(image_info_to_string),
+            ),
+        ),
+        ("manifest_size", bytes(header.manifest_size)),
+        (
+            "metadata_signature_size",
+            bytes(header.metadata_signature_size as u64),
+        ),
+        (
+            "metadata_signatures_message",
+            signatures_fields(&header.metadata_signature_message),
+        ),
+        ("blobs_offset", number(header.blobs_offset)),
+        ("payload_signatures", string(signatures)),
+        (
+            "payload_signatures_message",
+            signatures_fields(payload_signatures.unwrap_or_default()),
+        ),
+        ("payload_size", bytes(header.payload_size())),
+        ("partitions", number(partitions.len() as u64)),
+        ("partitions_size", bytes(partitions_size)),
+        ("data_length", bytes(data_length)),
+        (
+            "contents",
+            (contents, format!("{{{}}}", contents_json.join(", "))),
+        ),
+    ];
+    fields
+        .into_iter()
+        .map(|(key, (text, json))| (key, text, json))
+        .collect()
+}
+
+/// Describe the board, channel and version in `info`, if there are any.
+fn image_info_to_string(info: &ImageInfo) -> Option<String> {
+    let version = info.build_version.as_ref().or(info.version.as_ref());
+    let channel = info.build_channel.as_ref().or(info.channel.as_ref());
+    let parts: Vec<_> = [info.board.as_ref(), channel, version]
+        .into_iter()
+        .flatten()
+        .filter(|s| !s.is_empty())
+        .map(String::as_str)
+        .collect();
+    (!parts.is_empty()).then(|| parts.join(" "))
+}
+
+/// Describe the type, minor version, maximum timestamp and block size of
+/// `payload`.
+pub(crate) fn payload_summary(payload: &DeltaUpdateFile) -> String {
+    let manifest = &payload.manifest;
+    let mut summary = format!(
+        "Payload: {} (minor_version={})",
+        payload.payload_type(),
+        manifest.minor_version()
+    );
+    if payload.is_partial_update() {
+        summary += ", partial update";
+    }
+    if let Some(max_timestamp) = manifest.max_timestamp {
+        summary += &format!(", max_timestamp={}", format_timestamp(max_timestamp));
+    }
+    summary += &format!(", block_size={}", manifest.block_size());
+    summary
+}
@@ -0,0 +1,156 @@
+use std::{
+    fs::File,
+    io::{BufReader, Read, Seek},
+    path::Path,
+};
+
+use payload_dumper_rust::{
+    find_payloads, open_payload, AsSlice, FoundPayload, PayloadHeader, SectionFile,
+};
+
+use crate::error::{ErrorClass, Failure};
+
+pub(crate) trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// Open the input `path`, which may be an HTTP(S) URL.
+pub(crate) fn open_input(path: &Path) -> Result<Box<dyn ReadSeek>, Box<dyn std::error::Error>> {
+    let url = path
+        .to_str()
+        .filter(|path| path.starts_with("http://") || path.starts_with("https://"));
+    match url {
+        #[cfg(feature = "http")]
+        Some(url) => Ok(Box::new(payload_dumper_rust::HttpReader::new(url)?)),
+        #[cfg(not(feature = "http"))]
+        Some(_) => Err(Failure::new(
+            ErrorClass::Unsupported,
+            "Reading from URLs is not supported, rebuild with `--features http`",
+        )
+        .into()),
+        None => Ok(Box::new(File::open(path)?)),
+    }
+}
+
+/// Where the selected payload is in the input, see `--payload-index`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Location {
+    pub(crate) offset: u64,
+    pub(crate) len: u64,
+}
+
+/// Find the payloads in the input `path`, starting at `payload_offset` or
+/// at the payload of an OTA zip file. Returns the offset of the first
+/// payload in the input, and the payloads with offsets relative to it.
+pub(crate) fn find_input_payloads(
+    path: &Path,
+    payload_offset: Option<u64>,
+) -> Result<(u64, Vec<FoundPayload>), Box<dyn std::error::Error>> {
+    let mut input = open_input(path)?;
+    let mut file = match payload_offset {
+        Some(offset) => {
+            let len = input.seek(std::io::SeekFrom::End(0))?;
+            if offset >= len {
+                return Err(Failure::new(
+                    ErrorClass::Usage,
+                    format!(
+                        "--payload-offset {} is past the end of the file ({} bytes)",
+                        offset, len
+                    ),
+                )
+                .into());
+            }
+            SectionFile::new(input, offset, len - offset)?
+        }
+        None => open_payload(input)?,
+    };
+    let payloads = find_payloads(&mut file)?;
+    Ok((file.offset(), payloads))
+}
+
+/// Skip `payload_offset` bytes of `stdin` and parse the header of the
+/// payload after them.
+pub(crate) fn read_stdin_header<R: Read>(
+    stdin: &mut R,
+    payload_offset: Option<u64>,
+) -> Result<PayloadHeader, Box<dyn std::error::Error>> {
+    skip_stdin(stdin, payload_offset)?;
+    Ok(PayloadHeader::parse_prefix(stdin)?)
+}
+
+/// Skip the `payload_offset` bytes before the payload in `stdin`.
+pub(crate) fn skip_stdin<R: Read>(
+    stdin: &mut R,
+    payload_offset: Option<u64>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(offset) = payload_offset {
+        let skipped = std::io::copy(&mut Read::by_ref(stdin).take(offset), &mut std::io::sink())?;
+        if skipped != offset {
+            return Err(Failure::new(
+                ErrorClass::Usage,
+                format!("--payload-offset {} is past the end of stdin", offset),
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Find the payloads in the input `path`, `-` for stdin where only the
+/// first one is read, and their offsets in the input.
+pub(crate) fn read_input_headers(
+    path: &Path,
+    payload_offset: Option<u64>,
+) -> Result<Vec<(u64, PayloadHeader)>, Box<dyn std::error::Error>> {
+    if path == Path::new("-") {
+        let mut stdin = BufReader::new(std::io::stdin().lock());
+        let header = read_stdin_header(&mut stdin, payload_offset)?;
+        return Ok(vec![(payload_offset.unwrap_or(0), header)]);
+    }
+    let (base, payloads) = find_input_payloads(path, payload_offset)
+        .map_err(|e| Failure::about(&*e, format!("Failed to open {}: {}", path.display(), e)))?;
+    Ok(payloads
+        .into_iter()
+        .map(|found| (base + found.offset, found.header))
+        .collect())
+}
+
+/// Open the payload at `location` in the input `path`.
+pub(crate) fn open_located(
+    path: &Path,
+    location: Location,
+) -> Result<SectionFile<Box<dyn ReadSeek>>, Box<dyn std::error::Error>> {
+    Ok(SectionFile::new(
+        open_input(path)?,
+        location.offset,
+        location.len,
+    )?)
+}
+
+/// Memory map the payload at `location` in the input `path`, see `--mmap`.
+pub(crate) fn map_input(
+    path: &Path,
+    location: Location,
+) -> Result<Box<dyn AsSlice + Sync>, Box<dyn std::error::Error>> {
+    if path
+        .to_str()
+        .is_some_and(|path| path.starts_with("http://") || path.starts_with("https://"))
+    {
+        return Err(Failure::new(ErrorClass::Unsupported, "only local files can be mapped").into());
+    }
+    #[cfg(feature = "mmap")]
+    return Ok(Box::new(SectionFile::new(
+        payload_dumper_rust::map_file(path)?,
+        location.offset,
+        location.len,
+    )?));
+    #[cfg(not(feature = "mmap"))]
+    {
+        let _ = location;
+        Err(Failure::new(
+            ErrorClass::Unsupported,
+            "Memory mapping is not supported, rebuild with `--features mmap`",
+        )
+        .into())
+    }
+}
@@ -0,0 +1,260 @@
+use std::path::Path;
+
+use payload_dumper_rust::{
+    chromeos_update_engine::{PartitionInfo, PartitionUpdate},
+    sniff_partition, ContentType, PayloadHeader, SectionFile,
+};
+
+use size::Size;
+
+use crate::format::{print_table, type_name};
+use crate::input::{open_input, read_input_headers};
+use crate::json::json_string;
+
+/// List the partitions of the payloads in the input `path`, as tables or
+/// JSON.
+pub(crate) fn list_payloads(
+    path: &Path,
+    payload_offset: Option<u64>,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let payloads = read_input_headers(path, payload_offset)?;
+    let contents: Vec<_> = payloads
+        .iter()
+        .map(|(offset, header)| sniff_contents(path, *offset, header))
+        .collect();
+    if json {
+        let size = |info: Option<&PartitionInfo>| {
+            info.and_then(|i| i.size)
+                .map_or("null".to_string(), |s| s.to_string())
+        };
+        let payloads: Vec<_> = payloads
+            .iter()
+            .zip(&contents)
+            .map(|((offset, header), contents)| {
+                let partitions: Vec<_> = header
+                    .partitions()
+                    .iter()
+                    .zip(contents)
+                    .map(|(p, content)| {
+                        format!(
+                            "        {{\"name\": {}, \"version\": {}, \"size\": {}, \"old_size\": {}, \"operations\": {}, \"content\": {}}}",
+                            json_string(&p.partition_name),
+                            p.version.as_deref().map_or("null".to_string(), json_string),
+                            size(p.new_partition_info.as_ref()),
+                            size(p.old_partition_info.as_ref()),
+                            p.operations.len(),
+                            content.map_or("null".to_string(), |c| json_string(c.name()))
+                        )
+                    })
+                    .collect();
+                format!(
+                    "    {{\n      \"offset\": {},\n      \"type\": \"{}\",\n      \"file_format_version\": {},\n      \"minor_version\": {},\n      \"partitions\": [\n{}\n      ]\n    }}",
+                    offset,
+                    header.payload_type(),
+                    header.file_format_version,
+                    header.manifest.minor_version(),
+                    partitions.join(",\n")
+                )
+            })
+            .collect();
+        println!("{{\n  \"payloads\": [\n{}\n  ]\n}}", payloads.join(",\n"));
+        return Ok(());
+    }
+
+    for (index, ((offset, header), contents)) in payloads.iter().zip(&contents).enumerate() {
+        if index > 0 {
+            println!();
+        }
+        if payloads.len() > 1 {
+            let size = Size::from_bytes(header.payload_size());
+            println!("Payload {} at offset {:#x} ({})", index, offset, size);
+        }
+        list_partitions(header, contents);
+    }
+    Ok(())
+}
+
+/// What the partitions of the payload with `header` at `offset` in the
+/// input `path` likely hold, see [`sniff_partition`], `None` where it can't
+/// be told, and for all of them from stdin, where only the header is read.
+pub(crate) fn sniff_contents(
+    path: &Path,
+    offset: u64,
+    header: &PayloadHeader,
+) -> Vec<Option<ContentType>> {
+    let partitions = header.partitions();
+    let payload = match path == Path::new("-") {
+        true => None,
+        false => open_input(path)
+            .ok()
+            .and_then(|input| SectionFile::new(input, offset, header.payload_size()).ok()),
+    };
+    let Some(mut payload) = payload else {
+        return vec![None; partitions.len()];
+    };
+    partitions
+        .iter()
+        .map(|partition| {
+            sniff_partition(&mut payload, header.blobs_offset, partition)
+                .ok()
+                .flatten()
+        })
+        .collect()
+}
+
+/// Print a table of the partitions in the payload with `header`, with
+/// their `contents`, see [`sniff_contents`].
+fn list_partitions(header: &PayloadHeader, contents: &[Option<ContentType>]) {
+    let partitions = header.partitions();
+    let delta = header.is_delta();
+    println!(
+        "{} payload{}, version {}.{}",
+        if delta { "Delta" } else { "Full" },
+        if header.is_partial_update() {
+            " (partial update)"
+        } else {
+            ""
+        },
+        header.file_format_version,
+        header.manifest.minor_version(),
+    );
+
+    let size = |info: Option<&PartitionInfo>| {
+        info.and_then(|i| i.size)
+            .map(|s| Size::from_bytes(s).to_string())
+            .unwrap_or_else(|| "-".to_string())
+    };
+    let mut rows = vec![[
+        "NAME".to_string(),
+        "VERSION".to_string(),
+        "SIZE".to_string(),
+        "OLD SIZE".to_string(),
+        "OPS".to_string(),
+        "TYPES".to_string(),
+        "CONTENT".to_string(),
+    ]];
+    for (partition, content) in partitions.iter().zip(contents) {
+        rows.push([
+            partition.partition_name.clone(),
+            partition.version.clone().unwrap_or_else(|| "-".to_string()),
+            size(partition.new_partition_info.as_ref()),
+            size(partition.old_partition_info.as_ref()),
+            partition.operations.len().to_string(),
+            dominant_types(partition),
+            content.map_or("-".to_string(), |c| c.to_string()),
+        ]);
+    }
+    // Older payloads have no versions.
+    if partitions.iter().all(|p| p.version.is_none()) {
+        rows.iter_mut().for_each(|row| row[1].clear());
+    }
+    if !delta {
+        rows.iter_mut().for_each(|row| row[3].clear());
+    }
+    // Nothing can be told from stdin, or from delta operations.
+    if contents.iter().all(Option::is_none) {
+        rows.iter_mut().for_each(|row| row[6].clear());
+    }
+    print_table(&rows);
+
+    list_apex(header);
+    list_dynamic_partitions(header, &partitions);
+}
+
+/// Print the APEX packages in the payload, if any.
+fn list_apex(header: &PayloadHeader) {
+    if header.manifest.apex_info.is_empty() {
+        return;
+    }
+    println!();
+    let mut rows = vec![[
+        "APEX".to_string(),
+        "VERSION".to_string(),
+        "COMPRESSED".to_string(),
+        "DECOMPRESSED SIZE".to_string(),
+    ]];
+    for apex in &header.manifest.apex_info {
+        rows.push([
+            apex.package_name().to_string(),
+            apex.version
+                .map(|v| v.to_string())
+                .unwrap_or_else(|| "-".to_string()),
+            if apex.is_compressed() { "yes" } else { "no" }.to_string(),
+            match apex.decompressed_size {
+                Some(size) => Size::from_bytes(size).to_string(),
+                None => "-".to_string(),
+            },
+        ]);
+    }
+    print_table(&rows);
+}
+
+/// Print the dynamic partition groups with their maximum sizes and the
+/// sizes used by their partitions, and the Virtual A/B settings, if any.
+fn list_dynamic_partitions(header: &PayloadHeader, partitions: &[PartitionUpdate]) {
+    let Some(metadata) = &header.manifest.dynamic_partition_metadata else {
+        return;
+    };
+    println!();
+    println!("Dynamic partition groups:");
+    for group in &metadata.groups {
+        let used: u64 = partitions
+            .iter()
+            .filter(|p| group.partition_names.contains(&p.partition_name))
+            .filter_map(|p| p.new_partition_info.as_ref()?.size)
+            .sum();
+        let max = match group.size {
+            Some(size) => Size::from_bytes(size).to_string(),
+            None => "-".to_string(),
+        };
+        println!(
+            "  {} (max {}, used {}): {}",
+            group.name,
+            max,
+            Size::from_bytes(used),
+            group.partition_names.join(" ")
+        );
+    }
+    if let Some(snapshot_enabled) = metadata.snapshot_enabled {
+        println!(
+            "Snapshot: {}",
+            if snapshot_enabled {
+                "enabled"
+            } else {
+                "disabled"
+            }
+        );
+    }
+    if let Some(vabc_enabled) = metadata.vabc_enabled {
+        println!(
+            "VABC: {}",
+            if vabc_enabled { "enabled" } else { "disabled" }
+        );
+    }
+    if let Some(compression) = &metadata.vabc_compression_param {
+        println!("VABC compression: {}", compression);
+    }
+    if let Some(cow_version) = metadata.cow_version {
+        println!("COW version: {}", cow_version);
+    }
+}
+
+/// The most used operation types of `partition`, with their counts.
+fn dominant_types(partition: &PartitionUpdate) -> String {
+    let mut counts: Vec<(i32, usize)> = Vec::new();
+    for operation in &partition.operations {
+        match counts.iter_mut().find(|(t, _)| *t == operation.r#type) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((operation.r#type, 1)),
+        }
+    }
+    counts.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+
+    counts
+        .iter()
+        .take(3)
+        .map(|(t, count)| format!("{} ({})", type_name(*t), count))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,881 @@
+mod create;
+mod diff;
+mod error;
+mod extract;
+mod format;
+mod info;
+mod input;
+mod json;
+mod list;
+mod metadata;
+mod properties;
+mod raw_ops;
+mod select;
+mod signals;
+mod stats;
+mod temp_file;
+mod trim;
+mod verify;
+
+use std::{path::PathBuf, process::ExitCode, sync::atomic::Ordering};
+
+use payload_dumper_rust::{
+    check_block_size, sanitize_file_name, Compression, CreateOptions, ImageCompression,
+};
+
+use clap::{Parser, Subcommand};
+use tracing_subscriber::fmt::format::FmtSpan;
+
+use crate::create::create_payload;
+use crate::diff::print_diff;
+use crate::error::{ErrorClass, Failure};
+use crate::extract::extract;
+use crate::info::{print_extents, print_info};
+use crate::list::list_payloads;
+use crate::metadata::dump_metadata;
+use crate::properties::{print_properties, verify_properties};
+use crate::raw_ops::dump_raw_ops;
+use crate::signals::INTERRUPTED;
+use crate::stats::print_stats;
+use crate::trim::trim;
+use crate::verify::verify;
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Options of `extract`, which runs without a subcommand
+    #[clap(flatten)]
+    extract: Args,
+
+    /// Print JSON instead of text, for `extract` the same as `--progress
+    /// json`
+    #[clap(long, global = true)]
+    json: bool,
+}
+
+// Options of `extract`. Flattened structs have no doc comments, which would
+// replace the about of the command.
+#[derive(clap::Args, Debug)]
+struct Args {
+    /// Path to the update file
+    #[clap(default_value = "payload.bin", value_parser)]
+    path: PathBuf,
+
+    /// Directory to output the dump
+    #[clap(default_value = "output", short, long, value_parser)]
+    output: PathBuf,
+
+    /// Write a partition to an existing file or block device instead of the
+    /// output directory, without truncating it
+    #[clap(long, value_name = "NAME=PATH", value_parser = parse_name_path)]
+    output_map: Vec<(String, PathBuf)>,
+
+    /// Name the image of a partition in the output directory after another
+    /// name, like `boot=boot_a`
+    #[clap(long, value_name = "OLD=NEW", value_parser = parse_rename)]
+    rename: Vec<(String, String)>,
+
+    /// File name of the images in the output directory, where `{partition}`
+    /// is the name of the partition, `{timestamp}` the max_timestamp of the
+    /// payload and `{size}` the size of the partition
+    #[clap(long, default_value = "{partition}.img", value_parser = parse_name_template)]
+    name_template: String,
+
+    /// Compress the images as they're written, named with the extension of
+    /// the compression after the name from `--name-template`. Needs the
+    /// `compress` feature
+    #[clap(
+        long,
+        value_enum,
+        conflicts_with_all = ["output_map", "stdout", "sparse", "resume", "skip_existing", "checksum_file"]
+    )]
+    compress: Option<OutputCompression>,
+
+    /// The max_timestamp of the payload, for `{timestamp}` in
+    /// `--name-template`.
+    #[clap(skip)]
+    max_timestamp: Option<i64>,
+
+    /// Apply a delta payload in place to the images of the old partitions
+    /// in this directory, `<partition>.img`, instead of writing new images
+    #[clap(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = ["output", "output_map", "rename", "name_template", "old", "stdout", "sparse", "resume", "compress"]
+    )]
+    in_place: Option<PathBuf>,
+
+    /// Copy each image to `<partition>.img.bak` before applying the payload
+    /// in place
+    #[clap(long, requires = "in_place")]
+    backup: bool,
+
+    /// Overwrite existing images in the output directory
+    #[clap(long, conflicts_with = "skip_existing")]
+    force: bool,
+
+    /// Skip partitions whose images in the output directory are complete,
+    /// dumping the others again
+    #[clap(long)]
+    skip_existing: bool,
+
+    /// Resume partitions interrupted in a previous run, from the progress
+    /// recorded in `<partition>.img.progress` in the output directory
+    #[clap(long, conflicts_with = "stdout")]
+    resume: bool,
+
+    /// When a partition fails, go on with the others instead of stopping,
+    /// and rename its image to `<image>.partial`. The summary lists the
+    /// error of each failed partition
+    #[clap(long, conflicts_with_all = ["stdout", "tar"])]
+    keep_going: bool,
+
+    /// Delete the images of the partitions failed with `--keep-going`
+    /// instead of renaming them
+    #[clap(long, requires = "keep_going")]
+    delete_failed: bool,
+
+    /// Write the images in the output directory under their name from the
+    /// start, instead of to `<image>.tmp` renamed when they're complete and
+    /// verified
+    #[clap(long)]
+    no_atomic: bool,
+
+    /// Print only errors and the summary
+    #[clap(short, long)]
+    quiet: bool,
+
+    /// Print details, like the blocks of the partitions not written by any
+    /// operation. `-v` also logs each partition dumped on stderr with the
+    /// time it took, and `-vv` each operation
+    #[clap(short, long, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// How to report the progress, bars are only drawn on terminals
+    #[clap(long, value_enum, default_value_t = ProgressMode::Bar)]
+    progress: ProgressMode,
+
+    /// Format of the summary printed at the end, JSON lines on stderr by
+    /// default with `--progress json`, a table otherwise
+    #[clap(long, value_enum, value_name = "FORMAT")]
+    summary: Option<SummaryFormat>,
+
+    /// Flush the images to the disk after writing them
+    #[clap(long)]
+    fsync: bool,
+
+    /// Write the SHA-256 of the images written to `SHA256SUMS` in the
+    /// output directory, and the hashes in the manifest to
+    /// `SHA256SUMS.expected`, in the format of `sha256sum`
+    #[clap(long, conflicts_with = "stdout")]
+    checksum_file: bool,
+
+    /// Memory map the payload instead of reading it, which saves syscalls
+    /// on local disks but may be slower on network filesystems
+    #[clap(long)]
+    mmap: bool,
+
+    /// Read the data of the partitions front to back, applying their
+    /// operations in the order of their data, with all the images open at
+    /// once. It saves seeks on spinning disks, network filesystems and
+    /// URLs, but the partitions are dumped together on a single thread
+    #[clap(
+        long,
+        conflicts_with_all = ["mmap", "in_place", "stdout", "tar", "compress", "resume", "keep_going", "op_range", "stop_on_op"]
+    )]
+    sequential_read: bool,
+
+    /// Write the image of the only selected partition to stdout, messages
+    /// are written to stderr
+    #[clap(long, conflicts_with_all = ["output_map", "sparse"])]
+    stdout: bool,
+
+    /// Write the images to a tar archive, `--tar=PATH`, or to stdout with
+    /// `--tar` or `--tar=-`, instead of the output directory
+    #[clap(
+        long,
+        value_name = "PATH",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "-",
+        conflicts_with_all = ["output_map", "in_place", "stdout", "sparse", "resume", "skip_existing", "checksum_file", "compress", "fsync"]
+    )]
+    tar: Option<PathBuf>,
+
+    /// Check that the partitions can be dumped without writing anything:
+    /// the operations, the ranges of their data, the old images and the
+    /// output. `--dry-run=hash` also reads the data and checks its hashes
+    #[clap(long, value_enum, value_name = "MODE", num_args = 0..=1, require_equals = true, default_missing_value = "check")]
+    dry_run: Option<DryRun>,
+
+    /// Apply only the operations from START to before END of each partition,
+    /// `START..END`, `START..` or `..END`, to its existing image, which is
+    /// not truncated. Each operation is printed as it's applied, to debug
+    /// the operations failing on a partially written image
+    #[clap(
+        long,
+        value_name = "START..END",
+        value_parser = parse_op_range,
+        conflicts_with_all = ["in_place", "stdout", "tar", "compress", "sparse", "resume", "skip_existing", "checksum_file", "dry_run"]
+    )]
+    op_range: Option<std::ops::Range<usize>>,
+
+    /// Stop after applying the operation at this index of each partition,
+    /// which is printed like with `--op-range`
+    #[clap(long, value_name = "N", conflicts_with_all = ["stdout", "tar", "compress", "sparse", "resume", "checksum_file", "dry_run"])]
+    stop_on_op: Option<usize>,
+
+    /// Partitions to dump, `*` and `?` match any characters and a character
+    #[clap(short, long)]
+    partitions: Option<Vec<String>>,
+
+    /// Partitions not to dump, all others are dumped
+    #[clap(
+        short = 'x',
+        long,
+        value_delimiter = ',',
+        conflicts_with = "partitions"
+    )]
+    exclude: Vec<String>,
+
+    /// List the partitions in the payload without extracting anything, the
+    /// same as the `list` subcommand
+    #[clap(short, long)]
+    list: bool,
+
+    /// Index of the payload to extract from files with several payloads
+    /// back to back, which are shown by `list`
+    #[clap(long, default_value_t = 0)]
+    payload_index: usize,
+
+    /// Offset of the payload in the file, for payloads embedded in a larger
+    /// file, decimal or hexadecimal with `0x`
+    #[clap(long, value_parser = parse_offset)]
+    payload_offset: Option<u64>,
+
+    /// Directory containing old partition images, needed by delta payloads.
+    /// The images of partitions not in a partial update are copied to the
+    /// output directory as they are
+    #[clap(long, value_parser)]
+    old: Option<PathBuf>,
+
+    /// Do not check old partition images against the hashes in the payload
+    #[clap(long)]
+    skip_source_check: bool,
+
+    /// Write Android sparse images, which can be flashed with fastboot
+    #[clap(long)]
+    sparse: bool,
+
+    /// Number of partitions to dump concurrently
+    #[clap(short, long, default_value_t = 1)]
+    threads: usize,
+
+    #[clap(flatten)]
+    pipeline: Pipeline,
+
+    /// Verify the metadata signature with this PEM or DER encoded public key
+    #[clap(long, value_parser)]
+    public_key: Option<PathBuf>,
+
+    /// Use this block size instead of the one in the manifest, for payloads
+    /// with an unusual or wrong one
+    #[clap(long, value_parser = parse_block_size)]
+    block_size: Option<u32>,
+}
+
+// Options of the pipeline dumping each partition.
+#[derive(clap::Args, Debug)]
+struct Pipeline {
+    /// Number of threads decompressing the data of each partition
+    #[clap(long, default_value_t = default_workers())]
+    workers: usize,
+
+    /// Maximum number of operations of each partition read but not written yet
+    #[clap(long, default_value_t = 16)]
+    in_flight: usize,
+
+    /// Maximum MiB of data of each partition read ahead of the threads
+    /// decompressing it; the summary shows how often they waited for it
+    #[clap(long, value_name = "MIB", default_value_t = 64)]
+    prefetch_mb: u64,
+
+    /// Write zeros for ZERO operations instead of leaving holes in the images
+    #[clap(long)]
+    dense: bool,
+}
+
+/// How to report the progress.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum ProgressMode {
+    /// Progress bars on stderr.
+    Bar,
+    /// A JSON line on stderr for each operation done.
+    Json,
+    /// Nothing.
+    None,
+}
+
+/// Parse a `NAME=PATH` argument of `--output-map` or `create`.
+fn parse_name_path(value: &str) -> Result<(String, PathBuf), String> {
+    match value.split_once('=') {
+        Some((name, path)) if !name.is_empty() && !path.is_empty() => {
+            Ok((name.to_string(), path.into()))
+        }
+        _ => Err(format!("expected NAME=PATH, got {}", value)),
+    }
+}
+
+/// Parse the argument of `--block-size`, a non-zero power of two.
+fn parse_block_size(value: &str) -> Result<u32, String> {
+    let block_size: u32 = value.parse().map_err(|e| format!("{}", e))?;
+    check_block_size(block_size as u64).map_err(|e| e.to_string())?;
+    Ok(block_size)
+}
+
+/// Parse `OLD=NEW` of `--rename`, where `NEW` must be a file name.
+fn parse_rename(value: &str) -> Result<(String, String), String> {
+    let (old, new) = value.split_once('=').ok_or("expected OLD=NEW")?;
+    if new.is_empty() || sanitize_file_name(new) != new {
+        return Err(format!("{} is not a valid file name", new));
+    }
+    Ok((old.to_string(), new.to_string()))
+}
+
+/// Parse `--name-template`, which must have only known placeholders and
+/// give a file name, not a path.
+fn parse_name_template(value: &str) -> Result<String, String> {
+    let rendered = render_template(value, |key| match key {
+        "partition" | "timestamp" | "size" => Ok("x".to_string()),
+        key => Err(format!("unknown placeholder {{{}}}", key)),
+    })?;
+    if sanitize_file_name(&rendered) != rendered {
+        return Err("the template must give a file name, not a path".to_string());
+    }
+    Ok(value.to_string())
+}
+
+/// Replace the `{key}` placeholders in `template` with `value(key)`, in a
+/// single pass so values are never expanded again.
+fn render_template(
+    template: &str,
+    value: impl Fn(&str) -> Result<String, String>,
+) -> Result<String, String> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or("unclosed { in the template")?
+            + start;
+        rendered += &rest[..start];
+        rendered += &value(&rest[start + 1..end])?;
+        rest = &rest[end + 1..];
+    }
+    if rest.contains('}') {
+        return Err("unopened } in the template".to_string());
+    }
+    Ok(rendered + rest)
+}
+
+/// Parse `START..END` of `--op-range`, where either bound may be omitted.
+fn parse_op_range(value: &str) -> Result<std::ops::Range<usize>, String> {
+    let (start, end) = value.split_once("..").ok_or("expected START..END")?;
+    let bound = |bound: &str, default| match bound {
+        "" => Ok(default),
+        bound => bound.parse().map_err(|e| format!("{}: {}", bound, e)),
+    };
+    let (start, end) = (bound(start, 0)?, bound(end, usize::MAX)?);
+    if start >= end {
+        return Err(format!("{} is an empty range", value));
+    }
+    Ok(start..end)
+}
+
+/// Parse the argument of `--payload-offset`, decimal or hexadecimal.
+fn parse_offset(value: &str) -> Result<u64, String> {
+    let parsed = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|e| format!("{}", e))
+}
+
+fn default_workers() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Extract the partitions of the payload, which also runs without a
+    /// subcommand
+    Extract(Box<Args>),
+    /// List the partitions in the payload without extracting anything
+    List {
+        /// Path to the update file, `-` for stdin
+        #[clap(default_value = "payload.bin", value_parser)]
+        path: PathBuf,
+
+        /// Offset of the payload in the file, decimal or hexadecimal with
+        /// `0x`
+        #[clap(long, value_parser = parse_offset)]
+        payload_offset: Option<u64>,
+    },
+    /// Print the versions, regions, signatures and build information of the
+    /// payload
+    Info {
+        /// Path to the update file
+        #[clap(default_value = "payload.bin", value_parser)]
+        path: PathBuf,
+
+        /// Offset of the payload in the file, decimal or hexadecimal with
+        /// `0x`
+        #[clap(long, value_parser = parse_offset)]
+        payload_offset: Option<u64>,
+
+        /// Print the dst extents of each operation of the partition instead,
+        /// with the blocks written by more than one operation and how much
+        /// of the partition is written
+        #[clap(long, value_name = "PARTITION")]
+        show_extents: Option<String>,
+    },
+    /// Verify the metadata and payload signatures without extracting anything
+    Verify {
+        /// Path to the update file
+        #[clap(default_value = "payload.bin", value_parser)]
+        path: PathBuf,
+
+        /// PEM or DER encoded public key of the signer
+        #[clap(long, value_parser)]
+        public_key: PathBuf,
+    },
+    /// Print the regions of the payload and write the raw manifest and
+    /// signatures to files
+    Metadata {
+        /// Path to the update file
+        #[clap(default_value = "payload.bin", value_parser)]
+        path: PathBuf,
+
+        /// Directory to write manifest.pb, metadata_signature.pb and
+        /// payload_signature.pb to
+        #[clap(long, default_value = "metadata", value_parser)]
+        out_dir: PathBuf,
+    },
+    /// Print the hashes and sizes of the payload and its metadata in the
+    /// format of payload_properties.txt, for sideloading with
+    /// update_engine_client
+    Properties {
+        /// Path to the update file, `-` for stdin
+        #[clap(default_value = "payload.bin", value_parser)]
+        path: PathBuf,
+
+        /// Offset of the payload in the file, decimal or hexadecimal with
+        /// `0x`
+        #[clap(long, value_parser = parse_offset)]
+        payload_offset: Option<u64>,
+
+        /// Write the properties to this file instead of printing them
+        #[clap(long, value_parser)]
+        out: Option<PathBuf>,
+    },
+    /// Check the values of payload_properties.txt against the payload,
+    /// reporting each one that doesn't match
+    VerifyProperties {
+        /// Path to the update file, `-` for stdin
+        #[clap(value_parser)]
+        path: PathBuf,
+
+        /// Path to payload_properties.txt, by default the one in the OTA zip
+        /// file
+        #[clap(value_parser)]
+        properties: Option<PathBuf>,
+
+        /// Offset of the payload in the file, decimal or hexadecimal with
+        /// `0x`
+        #[clap(long, value_parser = parse_offset)]
+        payload_offset: Option<u64>,
+    },
+    /// Print the number, data size and blocks written of the operations of
+    /// each type in each partition, from the manifest alone
+    Stats {
+        /// Path to the update file
+        #[clap(default_value = "payload.bin", value_parser)]
+        path: PathBuf,
+    },
+    /// Compare the partitions of two payloads from their manifests alone
+    Diff {
+        /// Path to the old update file
+        #[clap(value_parser)]
+        old: PathBuf,
+
+        /// Path to the new update file
+        #[clap(value_parser)]
+        new: PathBuf,
+    },
+    /// Write a new unsigned payload with only some of the partitions
+    Trim {
+        /// Path to the update file
+        #[clap(default_value = "payload.bin", value_parser)]
+        path: PathBuf,
+
+        /// Path of the payload to write
+        #[clap(long, value_parser)]
+        out: PathBuf,
+
+        /// Partitions to keep, `*` and `?` match any characters and a
+        /// character
+        #[clap(short, long, required = true)]
+        partitions: Vec<String>,
+    },
+    /// Write the data of operations as it is in the payload, without
+    /// applying them, with their metadata in a JSON file next to it
+    RawOps {
+        /// Path to the update file
+        #[clap(default_value = "payload.bin", value_parser)]
+        path: PathBuf,
+
+        /// Partitions of the operations, `*` and `?` match any characters
+        /// and a character
+        #[clap(short, long, required = true)]
+        partitions: Vec<String>,
+
+        /// Indexes of the operations in each partition, all operations with
+        /// data if not given
+        #[clap(long, value_delimiter = ',')]
+        ops: Vec<usize>,
+
+        /// Directory to write `<partition>.op<index>.<type>.bin` and `.json`
+        /// to
+        #[clap(long, default_value = "raw", value_parser)]
+        out: PathBuf,
+    },
+    /// Create an unsigned full payload from partition images
+    Create {
+        /// Images of the partitions, in payload order
+        #[clap(value_name = "NAME=PATH", required = true, value_parser = parse_name_path)]
+        images: Vec<(String, PathBuf)>,
+
+        /// Path of the payload to write
+        #[clap(long, value_parser)]
+        out: PathBuf,
+
+        /// Compression of the data of the operations, data which doesn't
+        /// get smaller is stored uncompressed
+        #[clap(long, value_enum, default_value_t = CreateCompression::Xz)]
+        compression: CreateCompression,
+
+        /// Maximum number of blocks written by each operation
+        #[clap(long, default_value_t = 512, value_parser = clap::value_parser!(u64).range(1..))]
+        blocks_per_operation: u64,
+
+        /// Size of the blocks of the partitions
+        #[clap(long, default_value_t = 4096, value_parser = parse_block_size)]
+        block_size: u32,
+    },
+}
+
+/// Format of the summary, see `--summary`.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum SummaryFormat {
+    /// A table, with a line of the totals.
+    Table,
+    /// A JSON line on stderr for each partition, and one of the totals.
+    Json,
+}
+
+/// What `--dry-run` checks.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum DryRun {
+    /// The manifest, the old images and the output, without reading the
+    /// data of the operations.
+    Check,
+    /// Also read the data of the operations and check their hashes.
+    Hash,
+}
+
+/// Compression of the images extracted with `--compress`.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputCompression {
+    /// Zstandard, `.zst`.
+    Zstd,
+    /// Gzip, `.gz`.
+    Gzip,
+    /// Xz, `.xz`.
+    Xz,
+}
+
+impl From<OutputCompression> for ImageCompression {
+    fn from(compression: OutputCompression) -> Self {
+        match compression {
+            OutputCompression::Zstd => ImageCompression::Zstd,
+            OutputCompression::Gzip => ImageCompression::Gzip,
+            OutputCompression::Xz => ImageCompression::Xz,
+        }
+    }
+}
+
+/// Compression of the data of the operations of `create`.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum CreateCompression {
+    /// REPLACE_XZ operations.
+    Xz,
+    /// REPLACE operations.
+    None,
+}
+
+fn main() -> ExitCode {
+    let result = run();
+    if INTERRUPTED.load(Ordering::Relaxed) {
+        // As if killed by SIGINT, like shells report it.
+        eprintln!("Interrupted");
+        return ExitCode::from(130);
+    }
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            let class = ErrorClass::of(&*e);
+            eprintln!("Error ({}): {}", class, e);
+            ExitCode::from(class as u8)
+        }
+    }
+}
+
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    let json = cli.json;
+    let no_json = |command: &str| -> Result<(), Box<dyn std::error::Error>> {
+        match json {
+            true => Err(Failure::new(
+                ErrorClass::Usage,
+                format!("{} doesn't support --json", command),
+            )
+            .into()),
+            false => Ok(()),
+        }
+    };
+
+    let mut args = match cli.command {
+        None => cli.extract,
+        Some(Command::Extract(args)) => *args,
+        Some(Command::List {
+            path,
+            payload_offset,
+        }) => return list_payloads(&path, payload_offset, json),
+        Some(Command::Info {
+            path,
+            payload_offset,
+            show_extents: Some(name),
+        }) => return print_extents(&path, payload_offset, &name, json),
+        Some(Command::Info {
+            path,
+            payload_offset,
+            ..
+        }) => return print_info(&path, payload_offset, json),
+        Some(Command::Verify { path, public_key }) => return verify(&path, &public_key, json),
+        Some(Command::Metadata { path, out_dir }) => {
+            no_json("metadata")?;
+            return dump_metadata(&path, &out_dir);
+        }
+        Some(Command::Properties {
+            path,
+            payload_offset,
+            out,
+        }) => return print_properties(&path, payload_offset, out.as_deref(), json),
+        Some(Command::VerifyProperties {
+            path,
+            properties,
+            payload_offset,
+        }) => return verify_properties(&path, properties.as_deref(), payload_offset, json),
+        Some(Command::Stats { path }) => return print_stats(&path, json),
+        Some(Command::Diff { old, new }) => return print_diff(&old, &new, json),
+        Some(Command::Trim {
+            path,
+            out,
+            partitions,
+        }) => {
+            no_json("trim")?;
+            return trim(&path, &out, &partitions);
+        }
+        Some(Command::RawOps {
+            path,
+            partitions,
+            ops,
+            out,
+        }) => {
+            no_json("raw-ops")?;
+            return dump_raw_ops(&path, &out, &partitions, &ops);
+        }
+        Some(Command::Create {
+            images,
+            out,
+            compression,
+            blocks_per_operation,
+            block_size,
+        }) => {
+            no_json("create")?;
+            let options = CreateOptions {
+                block_size,
+                blocks_per_operation,
+                compression: match compression {
+                    CreateCompression::Xz => Some(Compression::Xz),
+                    CreateCompression::None => None,
+                },
+            };
+            return create_payload(&images, &out, options);
+        }
+    };
+    if args.list {
+        return list_payloads(&args.path, args.payload_offset, json);
+    }
+    if json {
+        args.progress = ProgressMode::Json;
+    }
+    extract(args)
+}
+
+/// Log the spans of the library on stderr when they close, with the time
+/// they took: the partitions with `-v`, and the operations too with `-vv`.
+fn init_tracing(verbose: u8) {
+    let level = match verbose {
+        0 => return,
+        1 => tracing::Level::INFO,
+        _ => tracing::Level::DEBUG,
+    };
+    tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_target(false)
+        .with_writer(std::io::stderr)
+        .init();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+    use std::path::Path;
+
+    #[test]
+    fn offset() {
+        assert_eq!(parse_offset("4096"), Ok(4096));
+        assert_eq!(parse_offset("0x1000"), Ok(4096));
+        assert_eq!(parse_offset("0X1a"), Ok(26));
+        assert!(parse_offset("0x").is_err());
+        assert!(parse_offset("-1").is_err());
+    }
+
+    #[test]
+    fn op_range() {
+        assert_eq!(parse_op_range("1870..1880"), Ok(1870..1880));
+        assert_eq!(parse_op_range("3.."), Ok(3..usize::MAX));
+        assert_eq!(parse_op_range("..2"), Ok(0..2));
+        assert!(parse_op_range("2..2").is_err());
+        assert!(parse_op_range("5").is_err());
+        assert!(parse_op_range("a..b").is_err());
+    }
+
+    #[test]
+    fn file_names() {
+        let template = |template: &str| {
+            render_template(template, |key| match key {
+                "partition" => Ok("{size}".to_string()),
+                _ => Ok("4096".to_string()),
+            })
+        };
+        assert_eq!(
+            template("{partition}-{size}.img"),
+            Ok("{size}-4096.img".to_string())
+        );
+        assert!(template("{partition").is_err());
+        assert!(template("partition}").is_err());
+
+        assert!(parse_name_template("{partition}-{timestamp}.bin").is_ok());
+        assert!(parse_name_template("{build}.img").is_err());
+        assert!(parse_name_template("../{partition}.img").is_err());
+        assert_eq!(
+            parse_rename("boot=boot_a"),
+            Ok(("boot".to_string(), "boot_a".to_string()))
+        );
+        assert!(parse_rename("boot=../boot").is_err());
+        assert!(parse_rename("boot=").is_err());
+    }
+
+    #[test]
+    fn cli() {
+        let parse = |args: &[&str]| Cli::try_parse_from(["payload-dumper-rust"].iter().chain(args));
+        Cli::command().debug_assert();
+
+        // The legacy invocation extracts without a subcommand.
+        let cli = parse(&["ota.zip", "-o", "out", "-p", "boot"]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(cli.extract.path, Path::new("ota.zip"));
+        assert_eq!(cli.extract.output, Path::new("out"));
+        assert_eq!(cli.extract.partitions, Some(vec!["boot".to_string()]));
+
+        let cli = parse(&["extract", "ota.zip", "-o", "out", "-p", "boot"]).unwrap();
+        let Some(Command::Extract(args)) = cli.command else {
+            panic!("not extract")
+        };
+        assert_eq!(
+            (args.path.as_path(), args.output.as_path()),
+            (Path::new("ota.zip"), Path::new("out"))
+        );
+        assert_eq!(args.partitions, Some(vec!["boot".to_string()]));
+
+        let cli = parse(&["--json", "--list"]).unwrap();
+        assert!(cli.json && cli.extract.list);
+        assert_eq!(cli.extract.path, Path::new("payload.bin"));
+
+        let cli = parse(&["list", "ota.zip", "--json", "--payload-offset", "0x10"]).unwrap();
+        let Some(Command::List {
+            path,
+            payload_offset,
+        }) = cli.command
+        else {
+            panic!("not list")
+        };
+        assert_eq!(
+            (path.as_path(), payload_offset, cli.json),
+            (Path::new("ota.zip"), Some(16), true)
+        );
+
+        let cli = parse(&["stats", "--json"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Stats { .. })) && cli.json);
+        assert!(matches!(
+            parse(&["info"]).unwrap().command,
+            Some(Command::Info { .. })
+        ));
+
+        // After options of `extract`, a subcommand name is a path.
+        let cli = parse(&["-o", "out", "list"]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(cli.extract.path, Path::new("list"));
+        // Options of `extract` aren't options of the other subcommands.
+        assert!(parse(&["list", "-o", "out"]).is_err());
+
+        // `--tar` only takes a path with `=`, so it can come before the
+        // payload.
+        let cli = parse(&["--tar", "payload.bin"]).unwrap();
+        assert_eq!(cli.extract.tar.as_deref(), Some(Path::new("-")));
+        assert_eq!(cli.extract.path, Path::new("payload.bin"));
+        let cli = parse(&["payload.bin", "--tar=out.tar"]).unwrap();
+        assert_eq!(cli.extract.tar.as_deref(), Some(Path::new("out.tar")));
+        assert!(parse(&["payload.bin", "--tar", "--stdout"]).is_err());
+
+        let cli = parse(&["--dry-run", "payload.bin"]).unwrap();
+        assert_eq!(
+            (cli.extract.dry_run, cli.extract.path.as_path()),
+            (Some(DryRun::Check), Path::new("payload.bin"))
+        );
+        assert_eq!(
+            parse(&["--dry-run=hash"]).unwrap().extract.dry_run,
+            Some(DryRun::Hash)
+        );
+    }
+}
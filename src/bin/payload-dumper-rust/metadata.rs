@@ -0,0 +1,60 @@
+use std::{io::Seek, path::Path};
+
+use payload_dumper_rust::{open_payload, DeltaUpdateFile};
+
+use crate::error::Failure;
+use crate::input::open_input;
+
+/// Print the offsets and sizes of the regions of the payload at `path`, and
+/// write the serialized manifest and signatures as they are in the payload
+/// to `out_dir`.
+pub(crate) fn dump_metadata(path: &Path, out_dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = open_payload(open_input(path)?)
+        .map_err(|e| Failure::about(&e, format!("Failed to open {}: {}", path.display(), e)))?;
+    let payload = DeltaUpdateFile::parse(&mut file)?;
+
+    let header_size = payload.metadata_size - payload.manifest_size;
+    let signature_size = payload.metadata_signature_message.len() as u64;
+    let mut regions = vec![
+        ("header", 0, header_size),
+        ("manifest", header_size, payload.manifest_size),
+        ("metadata signature", payload.metadata_size, signature_size),
+    ];
+    match (
+        payload.manifest.signatures_offset,
+        payload.manifest.signatures_size,
+    ) {
+        (Some(offset), Some(size)) => {
+            regions.push(("data blobs", payload.blobs_offset, offset));
+            regions.push(("payload signature", payload.blobs_offset + offset, size));
+        }
+        _ => {
+            let size = file.seek(std::io::SeekFrom::End(0))? - payload.blobs_offset;
+            regions.push(("data blobs", payload.blobs_offset, size));
+        }
+    }
+    println!("{:<20} {:>12} {:>12}", "Region", "Offset", "Size");
+    for (name, offset, size) in regions {
+        println!("{:<20} {:>12} {:>12}", name, offset, size);
+    }
+
+    std::fs::create_dir_all(out_dir)?;
+    let files = [
+        ("manifest.pb", &payload.manifest_data),
+        ("metadata_signature.pb", &payload.metadata_signature_message),
+        (
+            "payload_signature.pb",
+            &payload.payload_signatures_message_data,
+        ),
+    ];
+    for (name, data) in files {
+        if data.is_empty() {
+            continue;
+        }
+        let path = out_dir.join(name);
+        std::fs::write(&path, data)
+            .map_err(|e| Failure::about(&e, format!("{}: {}", path.display(), e)))?;
+        println!("Wrote {}", path.display());
+    }
+    Ok(())
+}
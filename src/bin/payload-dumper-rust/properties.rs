@@ -0,0 +1,152 @@
+use std::{
+    io::{BufReader, Read, Seek},
+    path::Path,
+};
+
+use payload_dumper_rust::{open_payload, PayloadProperties, SectionFile};
+
+use crate::error::{ErrorClass, Failure};
+use crate::format::hex;
+use crate::input::{open_input, skip_stdin};
+
+/// Print the properties of the payload in the input `path`, `-` for stdin,
+/// as payload_properties.txt or JSON, or write them to `out`.
+pub(crate) fn print_properties(
+    path: &Path,
+    payload_offset: Option<u64>,
+    out: Option<&Path>,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let properties = read_input_properties(path, payload_offset)?;
+    if let Some(out) = out {
+        std::fs::write(out, properties.to_string())
+            .map_err(|e| Failure::about(&e, format!("{}: {}", out.display(), e)))?;
+    }
+    match (json, out) {
+        (true, _) => println!(
+            "{{\"file_hash\": \"{}\", \"file_size\": {}, \"metadata_hash\": \"{}\", \"metadata_size\": {}}}",
+            hex(&properties.file_hash),
+            properties.file_size,
+            hex(&properties.metadata_hash),
+            properties.metadata_size
+        ),
+        (false, Some(out)) => println!("Wrote {}", out.display()),
+        (false, None) => print!("{}", properties),
+    }
+    Ok(())
+}
+
+/// Compute the properties of the payload in the input `path`, `-` for
+/// stdin, reading it once.
+fn read_input_properties(
+    path: &Path,
+    payload_offset: Option<u64>,
+) -> Result<PayloadProperties, Box<dyn std::error::Error>> {
+    if path == Path::new("-") {
+        let mut stdin = BufReader::new(std::io::stdin().lock());
+        skip_stdin(&mut stdin, payload_offset)?;
+        return Ok(PayloadProperties::read(&mut stdin)?);
+    }
+    let mut input = open_input(path)?;
+    let file = match payload_offset {
+        Some(offset) => {
+            let len = input.seek(std::io::SeekFrom::End(0))?;
+            if offset >= len {
+                return Err(Failure::new(
+                    ErrorClass::Usage,
+                    format!(
+                        "--payload-offset {} is past the end of the file ({} bytes)",
+                        offset, len
+                    ),
+                )
+                .into());
+            }
+            SectionFile::new(input, offset, len - offset)?
+        }
+        None => open_payload(input)
+            .map_err(|e| Failure::about(&e, format!("Failed to open {}: {}", path.display(), e)))?,
+    };
+    Ok(PayloadProperties::read(&mut BufReader::new(file))?)
+}
+
+/// Compare the payload properties in the file `properties`, or in the
+/// payload_properties.txt of the OTA zip file `path`, with the payload in
+/// the input `path`, printing whether each one matches as text or JSON.
+pub(crate) fn verify_properties(
+    path: &Path,
+    properties: Option<&Path>,
+    payload_offset: Option<u64>,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let text = match properties {
+        Some(properties) => std::fs::read_to_string(properties)
+            .map_err(|e| Failure::about(&e, format!("{}: {}", properties.display(), e)))?,
+        None => read_zip_properties(path)?,
+    };
+    let name = properties.map_or("payload_properties.txt".into(), |p| p.display().to_string());
+    let expected: PayloadProperties = text
+        .parse()
+        .map_err(|e| Failure::new(ErrorClass::Format, format!("{}: {}", name, e)))?;
+    let actual = read_input_properties(path, payload_offset)?;
+
+    let fields: Vec<_> = expected.fields().into_iter().zip(actual.fields()).collect();
+    let mismatches = fields
+        .iter()
+        .filter(|((_, expected), (_, actual))| expected != actual)
+        .count();
+    if json {
+        let fields: Vec<_> = fields
+            .iter()
+            .map(|((key, expected), (_, actual))| {
+                format!(
+                    "    {{\"key\": \"{}\", \"expected\": \"{}\", \"actual\": \"{}\", \"ok\": {}}}",
+                    key,
+                    expected,
+                    actual,
+                    expected == actual
+                )
+            })
+            .collect();
+        println!("{{\n  \"properties\": [\n{}\n  ]\n}}", fields.join(",\n"));
+    } else {
+        for ((key, expected), (_, actual)) in &fields {
+            match expected == actual {
+                true => println!("{}: OK", key),
+                false => println!("{}: MISMATCH, expected {}, got {}", key, expected, actual),
+            }
+        }
+    }
+    if mismatches > 0 {
+        let message = format!(
+            "{} of {} properties don't match the payload",
+            mismatches,
+            fields.len()
+        );
+        return Err(Failure::new(ErrorClass::Verification, message).into());
+    }
+    Ok(())
+}
+
+/// Read payload_properties.txt, stored in the OTA zip file `path` next to
+/// payload.bin.
+fn read_zip_properties(path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+    let missing = || {
+        Failure::new(
+            ErrorClass::Usage,
+            format!(
+                "{} has no payload_properties.txt, pass the properties file",
+                path.display()
+            ),
+        )
+    };
+    if path == Path::new("-") {
+        return Err(missing().into());
+    }
+    let mut input = open_input(path)?;
+    let (offset, len) =
+        payload_dumper_rust::find_stored_entry(&mut input, "payload_properties.txt")
+            .map_err(|_| missing())?;
+    let mut text = String::new();
+    SectionFile::new(input, offset, len)?.read_to_string(&mut text)?;
+    Ok(text)
+}
@@ -0,0 +1,150 @@
+use std::{io::Read, path::Path};
+
+use payload_dumper_rust::{
+    chromeos_update_engine::Extent, hash_image, open_payload, sanitize_file_name, DeltaUpdateFile,
+    PayloadError, SectionFile,
+};
+
+use crate::error::Failure;
+use crate::format::{hex, type_name};
+use crate::input::open_input;
+use crate::json::json_string;
+use crate::select::match_partitions;
+
+/// Write the data of the operations `ops` of the partitions matching
+/// `patterns` in the payload at `path` to `out_dir`, exactly as it is in
+/// the payload, or of all their operations with data if `ops` is empty.
+/// The metadata of each operation is written to a JSON file with the same
+/// name, with the SHA-256 of the data.
+pub(crate) fn dump_raw_ops(
+    path: &Path,
+    out_dir: &Path,
+    patterns: &[String],
+    ops: &[usize],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = open_payload(open_input(path)?)
+        .map_err(|e| Failure::about(&e, format!("Failed to open {}: {}", path.display(), e)))?;
+    let payload = DeltaUpdateFile::read_metadata(&mut file)?;
+    let partitions = payload.partitions();
+    let names = match_partitions(&partitions, patterns)?;
+    std::fs::create_dir_all(out_dir)
+        .map_err(|e| Failure::about(&e, format!("{}: {}", out_dir.display(), e)))?;
+
+    let extents_json = |extents: &[Extent]| {
+        let extents: Vec<_> = extents
+            .iter()
+            .map(|e| {
+                format!(
+                    "{{\"start_block\": {}, \"num_blocks\": {}}}",
+                    e.start_block(),
+                    e.num_blocks()
+                )
+            })
+            .collect();
+        format!("[{}]", extents.join(", "))
+    };
+    for name in names {
+        let partition = partitions
+            .iter()
+            .find(|p| p.partition_name == name)
+            .expect("the name is matched");
+        let indexes: Vec<usize> = match ops {
+            [] => (0..partition.operations.len()).collect(),
+            ops => ops.to_vec(),
+        };
+        for index in indexes {
+            let operation = partition.operations.get(index).ok_or_else(|| {
+                format!(
+                    "Partition {} has no operation {}, it has {}",
+                    name,
+                    index,
+                    partition.operations.len()
+                )
+            })?;
+            let op_type = type_name(operation.r#type);
+            let (Some(_), Some(length)) = (operation.data_offset, operation.data_length) else {
+                if !ops.is_empty() {
+                    println!("{}: operation {} ({}) has no data", name, index, op_type);
+                }
+                continue;
+            };
+
+            let error = |e: std::io::Error| {
+                PayloadError::from(e)
+                    .in_operation(index, operation.r#type)
+                    .in_partition(name)
+            };
+            let mut data = Vec::new();
+            let range = payload.operation_blob_range(operation).ok_or_else(|| {
+                error(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "data offset overflows",
+                ))
+            })?;
+            SectionFile::new(&mut file, range.start, length)
+                .and_then(|mut section| section.read_to_end(&mut data))
+                .map_err(error)?;
+            if data.len() as u64 != length {
+                return Err(error(std::io::ErrorKind::UnexpectedEof.into()).into());
+            }
+            let (_, sha256) = hash_image(&data[..]).map_err(error)?;
+            let expected = operation
+                .data_sha256_hash
+                .as_deref()
+                .filter(|hash| !hash.is_empty());
+            if expected.is_some_and(|expected| expected != sha256) {
+                eprintln!(
+                    "Warning: {}: the data of operation {} doesn't match its hash in the manifest",
+                    name, index
+                );
+            }
+
+            let stem = format!(
+                "{}.op{:04}.{}",
+                sanitize_file_name(name),
+                index,
+                op_type.to_ascii_lowercase()
+            );
+            let bin = out_dir.join(format!("{}.bin", stem));
+            std::fs::write(&bin, &data)
+                .map_err(|e| Failure::about(&e, format!("{}: {}", bin.display(), e)))?;
+            let json = [
+                format!("  \"partition\": {}", json_string(name)),
+                format!("  \"index\": {}", index),
+                format!("  \"type\": {}", json_string(&op_type)),
+                format!("  \"data_offset\": {}", operation.data_offset()),
+                format!("  \"data_length\": {}", length),
+                format!(
+                    "  \"src_extents\": {}",
+                    extents_json(&operation.src_extents)
+                ),
+                format!(
+                    "  \"src_length\": {}",
+                    operation
+                        .src_length
+                        .map_or("null".to_string(), |l| l.to_string())
+                ),
+                format!(
+                    "  \"dst_extents\": {}",
+                    extents_json(&operation.dst_extents)
+                ),
+                format!(
+                    "  \"dst_length\": {}",
+                    operation
+                        .dst_length
+                        .map_or("null".to_string(), |l| l.to_string())
+                ),
+                format!(
+                    "  \"data_sha256_hash\": {}",
+                    expected.map_or("null".to_string(), |h| json_string(&hex(h)))
+                ),
+                format!("  \"sha256\": {}", json_string(&hex(&sha256))),
+            ];
+            let json_path = out_dir.join(format!("{}.json", stem));
+            std::fs::write(&json_path, format!("{{\n{}\n}}\n", json.join(",\n")))
+                .map_err(|e| Failure::about(&e, format!("{}: {}", json_path.display(), e)))?;
+            println!("Wrote {}", bin.display());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,188 @@
+use payload_dumper_rust::chromeos_update_engine::PartitionUpdate;
+
+use crate::error::{ErrorClass, Failure};
+
+/// Match `name` against the glob `pattern`, where `*` matches any
+/// characters and `?` matches a character.
+pub(crate) fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Position after the last `*`, and the position in `name` it's
+    // matched up to, to backtrack to.
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                star = Some((p, n));
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p;
+                    n = star_n + 1;
+                    star = Some((star_p, n));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Number of characters to insert, delete or replace to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    // Distances from the prefix of `a` so far to each prefix of `b`.
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let replaced = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = replaced.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Names in `names` close to `pattern`, which matches none of them: those
+/// it's a prefix of, or a few edits away from, closest first, at most 3.
+fn suggestions<'a>(pattern: &str, names: &[&'a str]) -> Vec<&'a str> {
+    let max_distance = (pattern.chars().count() / 3).max(1);
+    let mut close: Vec<_> = names
+        .iter()
+        .filter_map(|&name| {
+            let distance = edit_distance(pattern, name);
+            (distance <= max_distance || name.starts_with(pattern)).then_some((distance, name))
+        })
+        .collect();
+    close.sort();
+    close.into_iter().take(3).map(|(_, name)| name).collect()
+}
+
+/// Error for the partitions `missing` in `names`, with the partitions they
+/// may be typos of.
+pub(crate) fn not_found(missing: &[&str], names: &[&str]) -> Failure {
+    let quote = |names: &[&str]| {
+        names
+            .iter()
+            .map(|name| format!("'{}'", name))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let mut message = String::new();
+    for pattern in missing {
+        message += &format!("Partition '{}' not found. ", pattern);
+        let close = suggestions(pattern, names);
+        if !close.is_empty() {
+            message += &format!("Did you mean {}? ", quote(&close));
+        }
+    }
+    message += &format!("Available: {}", names.join(", "));
+    Failure::new(ErrorClass::Usage, message)
+}
+
+/// Names of the `partitions` matching `patterns`, in the order of the
+/// patterns. Each pattern must match a partition.
+pub(crate) fn match_partitions<'a>(
+    partitions: &'a [PartitionUpdate],
+    patterns: &[String],
+) -> Result<Vec<&'a str>, Failure> {
+    let all: Vec<_> = partitions
+        .iter()
+        .map(|p| p.partition_name.as_str())
+        .collect();
+    let mut names = Vec::new();
+    let mut missing = Vec::new();
+    for pattern in patterns {
+        let matched: Vec<_> = all
+            .iter()
+            .copied()
+            .filter(|name| glob_match(pattern, name))
+            .collect();
+        if matched.is_empty() {
+            missing.push(pattern.as_str());
+        }
+        for name in matched {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    match missing.is_empty() {
+        true => Ok(names),
+        false => Err(not_found(&missing, &all)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("boot", "boot"));
+        assert!(!glob_match("boot", "boot_a"));
+        assert!(glob_match("system*", "system"));
+        assert!(glob_match("system*", "system_ext"));
+        assert!(glob_match("*_dlkm", "vendor_dlkm"));
+        assert!(!glob_match("*_dlkm", "vendor_dlkm_a"));
+        assert!(glob_match("*a*b*", "xaxxbx"));
+        assert!(glob_match("od?", "odm"));
+        assert!(!glob_match("od?", "od"));
+    }
+
+    #[test]
+    fn partition_suggestions() {
+        assert_eq!(edit_distance("systm", "system"), 1);
+        assert_eq!(edit_distance("vendor_boot", "vendor_dlkm"), 4);
+        assert_eq!(edit_distance("", "boot"), 4);
+
+        let names = [
+            "boot",
+            "dtbo",
+            "init_boot",
+            "odm",
+            "odm_dlkm",
+            "product",
+            "system",
+            "system_dlkm",
+            "system_ext",
+            "vbmeta",
+            "vbmeta_system",
+            "vbmeta_vendor",
+            "vendor",
+            "vendor_boot",
+            "vendor_dlkm",
+            "vendor_kernel_boot",
+        ];
+        assert_eq!(suggestions("systm", &names), ["system"]);
+        assert_eq!(
+            suggestions("system_", &names),
+            ["system", "system_ext", "system_dlkm"]
+        );
+        assert_eq!(suggestions("vendorboot", &names), ["vendor_boot"]);
+        assert_eq!(
+            suggestions("vbmeta_", &names),
+            ["vbmeta", "vbmeta_system", "vbmeta_vendor"]
+        );
+        assert_eq!(suggestions("bot", &names), ["boot"]);
+        assert!(suggestions("recovery", &names).is_empty());
+
+        let error = not_found(&["systm", "recovery"], &names[..3]);
+        assert_eq!(error.class, ErrorClass::Usage);
+        assert_eq!(
+            error.message,
+            "Partition 'systm' not found. Partition 'recovery' not found. Available: boot, dtbo, init_boot"
+        );
+        assert!(not_found(&["bboot"], &names)
+            .message
+            .starts_with("Partition 'bboot' not found. Did you mean 'boot'? "));
+    }
+}
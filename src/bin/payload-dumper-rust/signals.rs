@@ -0,0 +1,56 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set when the run is interrupted by Ctrl-C, or SIGTERM, so the partitions
+/// being dumped stop and their temporary images are removed.
+pub(crate) static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether dumping should stop, as another partition failed, see
+/// `cancelled`, or the run was interrupted.
+pub(crate) fn stopped(cancelled: &AtomicBool) -> bool {
+    cancelled.load(Ordering::Relaxed) || INTERRUPTED.load(Ordering::Relaxed)
+}
+
+/// Set `INTERRUPTED` on Ctrl-C and SIGTERM instead of exiting right away.
+/// Interrupting again exits.
+#[cfg(unix)]
+pub(crate) fn handle_interrupts() {
+    extern "C" fn interrupted(_signal: libc::c_int) {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            // SAFETY: _exit is async-signal-safe.
+            unsafe { libc::_exit(130) }
+        }
+    }
+
+    // SAFETY: the handler only touches an atomic and calls _exit.
+    unsafe {
+        libc::signal(libc::SIGINT, interrupted as *const () as libc::sighandler_t);
+        libc::signal(
+            libc::SIGTERM,
+            interrupted as *const () as libc::sighandler_t,
+        );
+    }
+}
+
+#[cfg(windows)]
+pub(crate) fn handle_interrupts() {
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn SetConsoleCtrlHandler(
+            handler: Option<unsafe extern "system" fn(u32) -> i32>,
+            add: i32,
+        ) -> i32;
+    }
+
+    unsafe extern "system" fn interrupted(_event: u32) -> i32 {
+        // Handled the first time, the default handler exits the next.
+        i32::from(!INTERRUPTED.swap(true, Ordering::SeqCst))
+    }
+
+    // SAFETY: the handler only touches an atomic.
+    unsafe {
+        SetConsoleCtrlHandler(Some(interrupted), 1);
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn handle_interrupts() {}
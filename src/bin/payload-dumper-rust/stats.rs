@@ -0,0 +1,153 @@
+use std::path::Path;
+
+use payload_dumper_rust::{
+    cow_estimate, open_payload, operation_stats, OperationStats, PayloadHeader,
+};
+
+use size::Size;
+
+use crate::error::Failure;
+use crate::format::{print_table, type_name};
+use crate::input::open_input;
+use crate::json::json_string;
+
+/// Print the statistics of the operations of each type in each partition
+/// of the payload at `path`, with a total, and the estimated size of the
+/// COW of each partition for Virtual A/B, as tables or JSON.
+pub(crate) fn print_stats(path: &Path, json: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = open_payload(open_input(path)?)
+        .map_err(|e| Failure::about(&e, format!("Failed to open {}: {}", path.display(), e)))?;
+    let header = PayloadHeader::parse_prefix(&mut file)?;
+    let block_size = header.manifest.block_size() as u64;
+
+    let mut rows = Vec::new();
+    let mut total = OperationStats::default();
+    let mut cow = Vec::new();
+    for partition in header.partitions().iter() {
+        for (op_type, stats) in operation_stats(partition) {
+            total.add(&stats);
+            rows.push((partition.partition_name.clone(), type_name(op_type), stats));
+        }
+        cow.push((
+            partition.partition_name.clone(),
+            cow_estimate(partition, block_size),
+        ));
+    }
+    let cow_total = cow
+        .iter()
+        .map(|(_, estimate)| estimate.size())
+        .fold(0, u64::saturating_add);
+    let cow_approximated = cow.iter().any(|(_, estimate)| estimate.is_approximation());
+
+    let ratio = |stats: &OperationStats| stats.ratio(block_size);
+    if json {
+        let fields = |stats: &OperationStats| {
+            format!(
+                "\"count\": {}, \"data_length\": {}, \"dst_blocks\": {}, \"ratio\": {}",
+                stats.count,
+                stats.data_length,
+                stats.dst_blocks,
+                ratio(stats).map_or("null".to_string(), |r| r.to_string())
+            )
+        };
+        let operations: Vec<_> = rows
+            .iter()
+            .map(|(partition, op_type, stats)| {
+                format!(
+                    "    {{\"partition\": {}, \"type\": {}, {}}}",
+                    json_string(partition),
+                    json_string(op_type),
+                    fields(stats)
+                )
+            })
+            .collect();
+        println!("{{");
+        println!("  \"block_size\": {},", block_size);
+        println!("  \"operations\": [\n{}\n  ],", operations.join(",\n"));
+        println!("  \"total\": {{{}}},", fields(&total));
+        let partitions: Vec<_> = cow
+            .iter()
+            .map(|(partition, estimate)| {
+                format!(
+                    "      {{\"partition\": {}, \"estimate_cow_size\": {}, \"approximation\": {}, \"size\": {}, \"approximated\": {}}}",
+                    json_string(partition),
+                    estimate.manifest.map_or("null".to_string(), |size| size.to_string()),
+                    estimate.approximation,
+                    estimate.size(),
+                    estimate.is_approximation()
+                )
+            })
+            .collect();
+        println!("  \"cow\": {{");
+        println!("    \"partitions\": [\n{}\n    ],", partitions.join(",\n"));
+        println!("    \"total\": {},", cow_total);
+        println!("    \"approximated\": {}", cow_approximated);
+        println!("  }}");
+        println!("}}");
+        return Ok(());
+    }
+
+    let row = |partition: &str, op_type: &str, stats: &OperationStats| {
+        [
+            partition.to_string(),
+            op_type.to_string(),
+            stats.count.to_string(),
+            Size::from_bytes(stats.data_length).to_string(),
+            stats.dst_blocks.to_string(),
+            ratio(stats).map_or("-".to_string(), |r| format!("{:.3}", r)),
+        ]
+    };
+    let mut table = vec![[
+        "PARTITION".to_string(),
+        "TYPE".to_string(),
+        "COUNT".to_string(),
+        "DATA".to_string(),
+        "DST BLOCKS".to_string(),
+        "RATIO".to_string(),
+    ]];
+    table.extend(
+        rows.iter()
+            .map(|(partition, op_type, stats)| row(partition, op_type, stats)),
+    );
+    table.push(row("TOTAL", "", &total));
+    print_table(&table);
+
+    // The size of the COW, the estimate of the manifest if any, otherwise
+    // the approximation marked with `~`.
+    let size = |size: u64, approximated: bool| match approximated {
+        true => format!("~{}", Size::from_bytes(size)),
+        false => Size::from_bytes(size).to_string(),
+    };
+    let mut table = vec![[
+        "PARTITION".to_string(),
+        "MANIFEST".to_string(),
+        "APPROXIMATION".to_string(),
+        "COW SIZE".to_string(),
+    ]];
+    table.extend(cow.iter().map(|(partition, estimate)| {
+        [
+            partition.clone(),
+            estimate
+                .manifest
+                .map_or("-".to_string(), |size| Size::from_bytes(size).to_string()),
+            Size::from_bytes(estimate.approximation).to_string(),
+            size(estimate.size(), estimate.is_approximation()),
+        ]
+    }));
+    table.push([
+        "TOTAL".to_string(),
+        String::new(),
+        String::new(),
+        size(cow_total, cow_approximated),
+    ]);
+    println!();
+    print_table(&table);
+    if cow_approximated {
+        println!();
+        println!(
+            "~ approximated without estimate_cow_size in the manifest, as the bytes written by"
+        );
+        println!("  operations other than SOURCE_COPY, uncompressed");
+    }
+    Ok(())
+}
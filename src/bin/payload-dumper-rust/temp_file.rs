@@ -0,0 +1,41 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// Temporary file, removed when dropped, like the image dumped for
+/// `--stdout` or the data blobs of `create`.
+pub(crate) struct TempFile(pub(crate) PathBuf);
+
+impl TempFile {
+    /// Rename the file to `path` instead of removing it.
+    pub(crate) fn persist(mut self, path: &Path) -> std::io::Result<()> {
+        std::fs::rename(&self.0, path)?;
+        self.0 = PathBuf::new();
+        Ok(())
+    }
+
+    /// Leave the file where it is instead of removing it.
+    pub(crate) fn keep(mut self) {
+        self.0 = PathBuf::new();
+    }
+
+    /// Copy the image to stdout, since stdout can't be seeked to apply the
+    /// operations directly.
+    pub(crate) fn copy_to_stdout(self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut img = File::open(&self.0)?;
+        let mut stdout = BufWriter::with_capacity(1 << 20, std::io::stdout().lock());
+        std::io::copy(&mut img, &mut stdout)?;
+        stdout.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.0.as_os_str().is_empty() {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+}
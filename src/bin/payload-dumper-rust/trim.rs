@@ -0,0 +1,55 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, Write},
+    path::Path,
+};
+
+use payload_dumper_rust::{open_payload, trim_payload, DeltaUpdateFile};
+
+use size::Size;
+
+use crate::error::Failure;
+use crate::input::open_input;
+use crate::select::match_partitions;
+
+/// Write the partitions of the payload at `path` matching `patterns` to a
+/// new payload at `out`.
+pub(crate) fn trim(
+    path: &Path,
+    out: &Path,
+    patterns: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = open_payload(open_input(path)?)
+        .map_err(|e| Failure::about(&e, format!("Failed to open {}: {}", path.display(), e)))?;
+    let payload = DeltaUpdateFile::read_metadata(&mut file)?;
+    let len = file.seek(std::io::SeekFrom::End(0))?;
+    payload
+        .validate_against_len(len)
+        .map_err(|e| Failure::about(&e, format!("{}: {}", path.display(), e)))?;
+
+    let names = match_partitions(&payload.manifest.partitions, patterns)?;
+    let mut writer = BufWriter::new(
+        File::create(out).map_err(|e| Failure::about(&e, format!("{}: {}", out.display(), e)))?,
+    );
+    let manifest = trim_payload(&payload, &mut file, &names, &mut writer)?;
+    writer.flush()?;
+    for partition in &manifest.partitions {
+        let size = partition
+            .new_partition_info
+            .as_ref()
+            .map_or(0, |info| info.size());
+        println!(
+            "{}: {}, {} operations",
+            partition.partition_name,
+            Size::from_bytes(size),
+            partition.operations.len()
+        );
+    }
+    if payload.manifest.signatures_offset.is_some()
+        || !payload.metadata_signature_message.is_empty()
+    {
+        println!("Note: the signatures of the payload can't be kept, the new payload is unsigned");
+    }
+    println!("Wrote {}", out.display());
+    Ok(())
+}
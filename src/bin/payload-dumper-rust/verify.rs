@@ -0,0 +1,73 @@
+use std::path::Path;
+
+use indicatif::{ProgressBar, ProgressStyle};
+use payload_dumper_rust::{open_payload, DeltaUpdateFile, SignatureError};
+
+use crate::error::{ErrorClass, Failure};
+use crate::info::{signature_json, signature_to_string};
+use crate::input::open_input;
+
+/// Verify the metadata and payload signatures of the payload at `path`,
+/// printing the signatures found as text or JSON.
+pub(crate) fn verify(
+    path: &Path,
+    public_key: &Path,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let key = std::fs::read(public_key)?;
+    let mut file = open_payload(open_input(path)?)
+        .map_err(|e| Failure::about(&e, format!("Failed to open {}: {}", path.display(), e)))?;
+    let payload = DeltaUpdateFile::parse(&mut file)?;
+
+    let (index, signature) = payload.find_metadata_signature(&key).map_err(|e| {
+        Failure::about(&e, format!("Metadata signature verification failed: {}", e))
+    })?;
+    let metadata_signature = (index, signature);
+    if !json {
+        println!(
+            "Metadata signature {}: OK",
+            signature_to_string(index, &metadata_signature.1)
+        );
+    }
+
+    if payload.payload_signatures_message_data.is_empty() {
+        return Err(Failure::new(
+            ErrorClass::Verification,
+            "Payload signature verification failed: no signature",
+        )
+        .into());
+    }
+    let signatures_offset = payload.manifest.signatures_offset.unwrap_or_default();
+    let bar = ProgressBar::new(payload.blobs_offset + signatures_offset);
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] {bar:40.cyan/blue} {bytes:>7}/{total_bytes:7} {msg}")?,
+    );
+    bar.set_message("Hashing payload");
+    let hash = payload.payload_hash(&mut bar.wrap_read(&mut file));
+    bar.finish_and_clear();
+
+    let (index, signature) = hash
+        .map_err(SignatureError::Io)
+        .and_then(|hash| payload.find_payload_signature(&hash, &key))
+        .map_err(|e| Failure::about(&e, format!("Payload signature verification failed: {}", e)))?;
+    if !json {
+        println!(
+            "Payload signature {}: OK",
+            signature_to_string(index, &signature)
+        );
+        return Ok(());
+    }
+
+    println!("{{");
+    println!(
+        "  \"metadata_signature\": {},",
+        signature_json(metadata_signature.0, &metadata_signature.1)
+    );
+    println!(
+        "  \"payload_signature\": {}",
+        signature_json(index, &signature)
+    );
+    println!("}}");
+    Ok(())
+}
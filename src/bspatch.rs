@@ -0,0 +1,394 @@
+use std::io::{Error, ErrorKind, Result, Write};
+
+/// Apply a bsdiff patch to `old`, returning the new data.
+///
+/// Both the original bsdiff 4.0 format and Android's BSDF2 format are
+/// supported. They are represented by this struct pseudocode:
+/// ```text
+/// struct bsdiff_patch {
+///   // "BSDIFF40", or "BSDF2" followed by one compressor type byte for
+///   // each of the three blocks: 0 for none, 1 for bzip2, 2 for brotli.
+///   char magic[8];
+///   int64 ctrl_len;  // Size of the compressed control block
+///   int64 diff_len;  // Size of the compressed diff block
+///   int64 new_size;  // Size of the new file
+///
+///   // All three blocks are compressed with bzip2 in BSDIFF40.
+///   char ctrl[ctrl_len];
+///   char diff[diff_len];
+///   char extra[];
+/// };
+/// ```
+/// The control block is a list of `(x, y, z)` triples: add `x` bytes from
+/// the diff block to `x` bytes from the old file, copy `y` bytes from the
+/// extra block, then seek forward `z` bytes in the old file.
+///
+/// Patches whose new file is larger than `max_size` are rejected before
+/// anything is allocated, and the blocks are decompressed no further than
+/// such a file needs.
+pub fn bspatch(old: &[u8], patch: &[u8], max_size: usize) -> Result<Vec<u8>> {
+    if patch.len() < 32 {
+        return Err(corrupt("patch too short"));
+    }
+
+    let compressors = match &patch[..8] {
+        b"BSDIFF40" => [Compressor::Bz2; 3],
+        [b'B', b'S', b'D', b'F', b'2', types @ ..] => {
+            let mut compressors = [Compressor::None; 3];
+            for (compressor, &t) in compressors.iter_mut().zip(types) {
+                *compressor = Compressor::from_type(t)?;
+            }
+            compressors
+        }
+        _ => return Err(corrupt("unknown magic")),
+    };
+
+    let ctrl_len = offtin_len(&patch[8..16])?;
+    let diff_len = offtin_len(&patch[16..24])?;
+    let new_size = offtin_len(&patch[24..32])?;
+    if new_size > max_size {
+        return Err(corrupt(&format!(
+            "new size {} is larger than {} bytes",
+            new_size, max_size
+        )));
+    }
+
+    let ctrl_end = 32usize
+        .checked_add(ctrl_len)
+        .filter(|&end| end <= patch.len())
+        .ok_or_else(|| corrupt("control block out of range"))?;
+    let diff_end = ctrl_end
+        .checked_add(diff_len)
+        .filter(|&end| end <= patch.len())
+        .ok_or_else(|| corrupt("diff block out of range"))?;
+
+    // Each triple adds at least one byte, except the last one.
+    let ctrl_limit = new_size.saturating_add(1).saturating_mul(24);
+    let ctrl = compressors[0].decompress(&patch[32..ctrl_end], ctrl_limit)?;
+    let diff = compressors[1].decompress(&patch[ctrl_end..diff_end], new_size)?;
+    let extra = compressors[2].decompress(&patch[diff_end..], new_size)?;
+
+    apply(old, new_size, &ctrl, &diff, &extra)
+}
+
+/// Compression of a block in a BSDF2 patch.
+#[derive(Debug, Clone, Copy)]
+enum Compressor {
+    None,
+    Bz2,
+    Brotli,
+}
+
+impl Compressor {
+    fn from_type(t: u8) -> Result<Self> {
+        match t {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Bz2),
+            2 => Ok(Self::Brotli),
+            _ => Err(corrupt(&format!("unknown compressor type {}", t))),
+        }
+    }
+
+    /// Decompress `data`, which must not decompress to more than `limit`
+    /// bytes.
+    fn decompress(self, data: &[u8], limit: usize) -> Result<Vec<u8>> {
+        let mut out = LimitedWriter {
+            out: Vec::new(),
+            limit,
+            exceeded: false,
+        };
+        match self {
+            Self::None => out.write_all(data).map_err(|_| out.limit_error())?,
+            Self::Bz2 => crate::decompress::bzip2(data, &mut out).map_err(|e| {
+                if out.exceeded {
+                    return out.limit_error();
+                }
+                corrupt(&format!("bzip2 error: {}", e))
+            })?,
+            Self::Brotli => brotli::BrotliDecompress(&mut &data[..], &mut out).map_err(|e| {
+                if out.exceeded {
+                    return out.limit_error();
+                }
+                corrupt(&format!("brotli error: {}", e))
+            })?,
+        }
+        Ok(out.out)
+    }
+}
+
+/// A [`Vec`] writer refusing to grow past `limit` bytes, setting `exceeded`
+/// instead.
+struct LimitedWriter {
+    out: Vec<u8>,
+    limit: usize,
+    exceeded: bool,
+}
+
+impl LimitedWriter {
+    fn limit_error(&self) -> Error {
+        corrupt(&format!(
+            "block decompresses to more than {} bytes",
+            self.limit
+        ))
+    }
+}
+
+impl Write for LimitedWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if buf.len() > self.limit - self.out.len() {
+            self.exceeded = true;
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "decompression limit exceeded",
+            ));
+        }
+        self.out.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn apply(
+    old: &[u8],
+    new_size: usize,
+    mut ctrl: &[u8],
+    mut diff: &[u8],
+    mut extra: &[u8],
+) -> Result<Vec<u8>> {
+    let mut new = Vec::with_capacity(new_size);
+    let mut old_pos: i64 = 0;
+
+    while new.len() < new_size {
+        if ctrl.len() < 24 {
+            return Err(corrupt("control block truncated"));
+        }
+        let x = offtin_len(&ctrl[0..8])?;
+        let y = offtin_len(&ctrl[8..16])?;
+        let z = offtin(&ctrl[16..24]);
+        ctrl = &ctrl[24..];
+
+        if x > diff.len() || y > extra.len() || new.len() + x + y > new_size {
+            return Err(corrupt("control triple out of range"));
+        }
+
+        // Add old data to diff data, bytes outside of old are treated as zero.
+        // `x` is at most the diff block length, so it fits in an `i64`.
+        let next_pos = old_pos
+            .checked_add(x as i64)
+            .ok_or_else(|| corrupt("old position overflow"))?;
+        new.extend(diff[..x].iter().enumerate().map(|(i, d)| {
+            let o = usize::try_from(old_pos + i as i64)
+                .ok()
+                .and_then(|pos| old.get(pos))
+                .copied()
+                .unwrap_or(0);
+            d.wrapping_add(o)
+        }));
+        diff = &diff[x..];
+        old_pos = next_pos;
+
+        new.extend_from_slice(&extra[..y]);
+        extra = &extra[y..];
+        old_pos = old_pos
+            .checked_add(z)
+            .ok_or_else(|| corrupt("old position overflow"))?;
+    }
+
+    Ok(new)
+}
+
+/// Read an `int64` in bsdiff's sign-magnitude little endian encoding.
+fn offtin(buf: &[u8]) -> i64 {
+    let mut bytes: [u8; 8] = buf[..8].try_into().unwrap();
+    let negative = bytes[7] & 0x80 != 0;
+    bytes[7] &= 0x7f;
+
+    let value = i64::from_le_bytes(bytes);
+    if negative {
+        -value
+    } else {
+        value
+    }
+}
+
+/// Read an `int64` that is used as a length, which must not be negative.
+fn offtin_len(buf: &[u8]) -> Result<usize> {
+    usize::try_from(offtin(buf)).map_err(|_| corrupt("invalid length"))
+}
+
+fn corrupt(message: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("corrupt bsdiff patch: {}", message),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offtout(x: i64) -> [u8; 8] {
+        let mut bytes = x.unsigned_abs().to_le_bytes();
+        if x < 0 {
+            bytes[7] |= 0x80;
+        }
+        bytes
+    }
+
+    fn bzip2(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        libribzip2::stream::encode_stream(data, &mut out, 1, libribzip2::EncodingStrategy::Single);
+        out
+    }
+
+    #[test]
+    fn offtin_sign_magnitude() {
+        assert_eq!(offtin(&offtout(0)), 0);
+        assert_eq!(offtin(&offtout(1234)), 1234);
+        assert_eq!(offtin(&offtout(-1234)), -1234);
+    }
+
+    #[test]
+    fn apply_patch() -> Result<()> {
+        let old = b"the quick brown fox".to_vec();
+        // Uppercase "the", insert "xy", skip the space, then copy "quick".
+        let ctrl = [
+            offtout(3),
+            offtout(2),
+            offtout(1),
+            offtout(5),
+            offtout(0),
+            offtout(0),
+        ]
+        .concat();
+        let diff = [224, 224, 224, 0, 0, 0, 0, 0];
+        let extra = b"xy";
+
+        let ctrl = bzip2(&ctrl);
+        let diff = bzip2(&diff);
+        let extra = bzip2(extra);
+        let patch = [
+            &b"BSDIFF40"[..],
+            &offtout(ctrl.len() as i64),
+            &offtout(diff.len() as i64),
+            &offtout(10),
+            &ctrl,
+            &diff,
+            &extra,
+        ]
+        .concat();
+
+        assert_eq!(bspatch(&old, &patch, 1 << 20)?, b"THExyquick");
+        assert!(bspatch(&old, &patch[..40], 1 << 20).is_err());
+        Ok(())
+    }
+
+    /// Wrap `data` in a brotli stream made of one uncompressed meta-block.
+    fn brotli_stored(data: &[u8]) -> Vec<u8> {
+        assert!(!data.is_empty() && data.len() <= 1 << 16);
+        // WBITS = 16, ISLAST = 0, MNIBBLES = 4, MLEN - 1, ISUNCOMPRESSED = 1
+        let header = ((data.len() as u32 - 1) << 4) | 1 << 20;
+        // ISLAST = 1, ISLASTEMPTY = 1
+        [&header.to_le_bytes()[..3], data, &[0b11]].concat()
+    }
+
+    #[test]
+    fn apply_bsdf2_patch() -> Result<()> {
+        let old = vec![0x11; 16];
+        let new = [vec![0x12; 8], b"brotli".to_vec(), vec![0x11; 4]].concat();
+
+        // Patch the first 8 bytes, insert 6 bytes, then copy the last 4 bytes.
+        let ctrl = [
+            offtout(8),
+            offtout(6),
+            offtout(4),
+            offtout(4),
+            offtout(0),
+            offtout(0),
+        ]
+        .concat();
+        let diff = brotli_stored(&[1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0]);
+        let extra = brotli_stored(b"brotli");
+        let patch = [
+            &b"BSDF2"[..],
+            &[0, 2, 2],
+            &offtout(ctrl.len() as i64),
+            &offtout(diff.len() as i64),
+            &offtout(new.len() as i64),
+            &ctrl,
+            &diff,
+            &extra,
+        ]
+        .concat();
+
+        assert_eq!(bspatch(&old, &patch, 1 << 20)?, new);
+
+        let mut unknown = patch.clone();
+        unknown[7] = 3;
+        assert!(bspatch(&old, &unknown, 1 << 20).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn old_position_overflow() {
+        // Seek to the end of the `i64` range, then add past it.
+        let ctrl = [
+            offtout(0),
+            offtout(0),
+            offtout(i64::MAX - 1),
+            offtout(4),
+            offtout(0),
+            offtout(0),
+        ]
+        .concat();
+        let patch = [
+            &b"BSDF2"[..],
+            &[0, 0, 0],
+            &offtout(ctrl.len() as i64),
+            &offtout(4),
+            &offtout(4),
+            &ctrl,
+            &[0; 4],
+        ]
+        .concat();
+
+        let error = bspatch(&[0; 4], &patch, 4096).unwrap_err();
+        assert!(
+            error.to_string().contains("old position overflow"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn limit_new_size() {
+        let ctrl = [offtout(0), offtout(0), offtout(0)].concat();
+        let header = |new_size: i64| {
+            [
+                &b"BSDF2"[..],
+                &[0, 0, 0],
+                &offtout(24),
+                &offtout(0),
+                &offtout(new_size),
+            ]
+            .concat()
+        };
+
+        // A huge new size is rejected before it is allocated.
+        let patch = [header(i64::MAX), ctrl.clone()].concat();
+        let error = bspatch(&[], &patch, 4096).unwrap_err();
+        assert!(
+            error.to_string().contains("larger than 4096 bytes"),
+            "{}",
+            error
+        );
+
+        // The extra block of a patch is limited to its new size.
+        let patch = [header(4), ctrl, vec![0; 5]].concat();
+        let error = bspatch(&[], &patch, 4096).unwrap_err();
+        assert!(error.to_string().contains("more than 4 bytes"), "{}", error);
+    }
+}
@@ -0,0 +1,283 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use sha2::{Digest, Sha256};
+
+/// Compression of extracted images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageCompression {
+    Zstd,
+    Gzip,
+    Xz,
+}
+
+impl ImageCompression {
+    /// Extension of the compressed files, without the dot.
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageCompression::Zstd => "zst",
+            ImageCompression::Gzip => "gz",
+            ImageCompression::Xz => "xz",
+        }
+    }
+}
+
+/// A writer compressing to `W` with an [`ImageCompression`], at the default
+/// level of libzstd, zlib and liblzma. [`CompressWriter::finish`] writes the
+/// end of the compressed data.
+#[cfg(feature = "compress")]
+pub struct CompressWriter<W: Write> {
+    encoder: Encoder<W>,
+}
+
+#[cfg(feature = "compress")]
+enum Encoder<W: Write> {
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+    Gzip(flate2::write::GzEncoder<W>),
+    Xz(xz2::write::XzEncoder<W>),
+}
+
+#[cfg(feature = "compress")]
+impl<W: Write> CompressWriter<W> {
+    pub fn new(compression: ImageCompression, inner: W) -> std::io::Result<Self> {
+        let encoder = match compression {
+            ImageCompression::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(
+                inner,
+                zstd::DEFAULT_COMPRESSION_LEVEL,
+            )?),
+            ImageCompression::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+                inner,
+                flate2::Compression::default(),
+            )),
+            ImageCompression::Xz => Encoder::Xz(xz2::write::XzEncoder::new(inner, 6)),
+        };
+        Ok(Self { encoder })
+    }
+
+    /// Compress the rest of the data and write the end of the compressed
+    /// data, returning the inner writer.
+    pub fn finish(self) -> std::io::Result<W> {
+        match self.encoder {
+            Encoder::Zstd(writer) => writer.finish(),
+            Encoder::Gzip(writer) => writer.finish(),
+            Encoder::Xz(writer) => writer.finish(),
+        }
+    }
+}
+
+#[cfg(feature = "compress")]
+impl<W: Write> Write for CompressWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match &mut self.encoder {
+            Encoder::Zstd(writer) => writer.write(buf),
+            Encoder::Gzip(writer) => writer.write(buf),
+            Encoder::Xz(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.encoder {
+            Encoder::Zstd(writer) => writer.flush(),
+            Encoder::Gzip(writer) => writer.flush(),
+            Encoder::Xz(writer) => writer.flush(),
+        }
+    }
+}
+
+/// A writer of the image of a partition to `W`, which can't seek, like a
+/// `CompressWriter`, for operations sorted with
+/// [`sequential_order`](crate::sequential_order).
+///
+/// Seeking forward is allowed, the blocks skipped are written as zeros
+/// when something is written after them, or by
+/// [`SequentialWriter::finish`]. Writing before the end of what's written
+/// and reading fail, so the operations must be applied to the blocks in
+/// order. The size and SHA-256 of the image are computed as it's written.
+pub struct SequentialWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+    /// Bytes written to `inner`.
+    written: u64,
+    /// Position of the next write.
+    pos: u64,
+}
+
+impl<W: Write> SequentialWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            written: 0,
+            pos: 0,
+        }
+    }
+
+    /// Write zeros up to `pos`.
+    fn fill(&mut self, pos: u64) -> std::io::Result<()> {
+        static ZEROS: [u8; 1 << 16] = [0; 1 << 16];
+        while self.written < pos {
+            let n = (pos - self.written).min(ZEROS.len() as u64) as usize;
+            self.inner.write_all(&ZEROS[..n])?;
+            self.hasher.update(&ZEROS[..n]);
+            self.written += n as u64;
+        }
+        Ok(())
+    }
+
+    /// Write zeros up to `size`, the size of the image, returning the inner
+    /// writer, and the size and SHA-256 of the image.
+    pub fn finish(mut self, size: u64) -> std::io::Result<(W, u64, [u8; 32])> {
+        self.fill(size.max(self.pos))?;
+        Ok((self.inner, self.written, self.hasher.finalize().into()))
+    }
+}
+
+impl<W: Write> Write for SequentialWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.pos < self.written {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!(
+                    "write at {} before the end of the image written so far, {}",
+                    self.pos, self.written
+                ),
+            ));
+        }
+        self.fill(self.pos)?;
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.written += n as u64;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Seek for SequentialWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+            SeekFrom::End(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "seek from the end of the image",
+                ));
+            }
+        };
+        self.pos = pos.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+impl<W: Write> Read for SequentialWriter<W> {
+    fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "read from an image written sequentially",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "compress")]
+    fn image() -> Vec<u8> {
+        let text: Vec<u8> = (0..3000u32)
+            .flat_map(|i| format!("line {} of the image\n", i % 97).into_bytes())
+            .collect();
+        let noise: Vec<u8> = (0..70000u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        [
+            &text[..],
+            &[0; 300000],
+            &noise,
+            &[7; 5],
+            &[0xff; 200000],
+            b"end",
+        ]
+        .concat()
+    }
+
+    #[cfg(feature = "compress")]
+    fn compress(compression: ImageCompression, data: &[u8]) -> Vec<u8> {
+        let mut writer = CompressWriter::new(compression, Vec::new()).unwrap();
+        // Odd sizes, to cross the buffers of the encoders.
+        for piece in data.chunks(10007) {
+            writer.write_all(piece).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    #[cfg(feature = "compress")]
+    fn round_trip() {
+        let data = image();
+        for compression in [
+            ImageCompression::Zstd,
+            ImageCompression::Gzip,
+            ImageCompression::Xz,
+        ] {
+            let compressed = compress(compression, &data);
+            assert!(
+                compressed.len() < data.len() / 4,
+                "{:?}: {}",
+                compression,
+                compressed.len()
+            );
+            let mut out = Vec::new();
+            match compression {
+                ImageCompression::Zstd => {
+                    ruzstd::StreamingDecoder::new(&compressed[..])
+                        .unwrap()
+                        .read_to_end(&mut out)
+                        .unwrap();
+                }
+                ImageCompression::Gzip => {
+                    flate2::read::GzDecoder::new(&compressed[..])
+                        .read_to_end(&mut out)
+                        .unwrap();
+                }
+                ImageCompression::Xz => {
+                    crate::decompress::decompress(
+                        crate::Compression::Xz,
+                        &compressed[..],
+                        &mut out,
+                        &mut Default::default(),
+                    )
+                    .unwrap();
+                }
+            }
+            assert!(out == data, "{:?}", compression);
+        }
+    }
+
+    #[test]
+    fn sequential() {
+        let mut writer = SequentialWriter::new(Vec::new());
+        writer.seek(SeekFrom::Start(4)).unwrap();
+        writer.write_all(b"abcd").unwrap();
+        writer.seek(SeekFrom::Current(4)).unwrap();
+        writer.write_all(b"ef").unwrap();
+        assert!(writer.read(&mut [0; 1]).is_err());
+        writer.seek(SeekFrom::Start(9)).unwrap();
+        assert_eq!(
+            writer.write(b"g").unwrap_err().kind(),
+            std::io::ErrorKind::Unsupported
+        );
+        let (data, size, hash) = writer.finish(20).unwrap();
+        let expected = [&[0; 4][..], b"abcd", &[0; 4], b"ef", &[0; 6]].concat();
+        assert_eq!((&data, size), (&expected, 20));
+        assert_eq!(hash[..], Sha256::digest(&expected)[..]);
+    }
+}
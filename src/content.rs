@@ -0,0 +1,209 @@
+use std::fmt;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+
+use crate::chromeos_update_engine::{install_operation::Type, PartitionUpdate};
+use crate::operation::{ExtentExt, InstallOperationExt};
+use crate::{decompress, Compression, PayloadError};
+
+/// Bytes at the beginning of a partition looked at by [`sniff_partition`],
+/// enough for the superblocks at 1 KiB.
+pub const SNIFF_SIZE: usize = 4096;
+
+/// What a partition likely holds, told by the magic numbers at its
+/// beginning, see [`ContentType::detect`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentType {
+    Ext4,
+    Erofs,
+    F2fs,
+    /// Android boot image, starting with `ANDROID!`.
+    BootImage,
+    /// Android Verified Boot metadata, starting with `AVB0`.
+    Vbmeta,
+    /// Only zeros.
+    Zero,
+    Unknown,
+}
+
+/// Magic numbers of the content types, as `(offset, magic)`.
+const MAGICS: [(ContentType, usize, &[u8]); 5] = [
+    (ContentType::BootImage, 0, b"ANDROID!"),
+    (ContentType::Vbmeta, 0, b"AVB0"),
+    // `s_magic` of the superblock at 1024.
+    (ContentType::Ext4, 1024 + 0x38, &0xef53u16.to_le_bytes()),
+    (ContentType::Erofs, 1024, &0xe0f5_e1e2u32.to_le_bytes()),
+    (ContentType::F2fs, 1024, &0xf2f5_2010u32.to_le_bytes()),
+];
+
+impl ContentType {
+    /// Tell the type of the content starting with `prefix`, which should be
+    /// [`SNIFF_SIZE`] bytes unless the content is shorter.
+    pub fn detect(prefix: &[u8]) -> Self {
+        let found = MAGICS
+            .iter()
+            .find(|(_, offset, magic)| prefix.get(*offset..offset + magic.len()) == Some(magic));
+        match found {
+            Some((content, _, _)) => *content,
+            None if !prefix.is_empty() && prefix.iter().all(|&b| b == 0) => ContentType::Zero,
+            None => ContentType::Unknown,
+        }
+    }
+
+    /// Short name, as shown by `--list` and `info`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ContentType::Ext4 => "ext4",
+            ContentType::Erofs => "erofs",
+            ContentType::F2fs => "f2fs",
+            ContentType::BootImage => "boot",
+            ContentType::Vbmeta => "vbmeta",
+            ContentType::Zero => "zero",
+            ContentType::Unknown => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for ContentType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Tell what `partition` likely holds from the first [`SNIFF_SIZE`] bytes
+/// of the operation writing its first block, read from the payload in
+/// `reader`, whose data blobs start at `blobs_offset`, and decompressed no
+/// further. It's `None` if that operation has no data of its own, like
+/// in a delta payload, or there's none.
+pub fn sniff_partition<R: Read + Seek>(
+    reader: &mut R,
+    blobs_offset: u64,
+    partition: &PartitionUpdate,
+) -> Result<Option<ContentType>, PayloadError> {
+    let operation = partition.operations.iter().find(|operation| {
+        let first = operation
+            .dst_extents
+            .iter()
+            .find(|extent| !extent.is_sparse_hole() && extent.num_blocks() > 0);
+        first.is_some_and(|extent| extent.start_block() == 0)
+    });
+    let Some(operation) = operation else {
+        return Ok(None);
+    };
+    let kind = match operation.r#type() {
+        Type::Zero | Type::Discard => return Ok(Some(ContentType::Zero)),
+        Type::Replace => None,
+        Type::ReplaceBz => Some(Compression::Bzip2),
+        Type::ReplaceXz => Some(Compression::Xz),
+        Type::ReplaceZstd => Some(Compression::Zstd),
+        _ => return Ok(None),
+    };
+    let range = operation
+        .blob_range(blobs_offset)
+        .ok_or(PayloadError::MissingData)?;
+    reader.seek(SeekFrom::Start(range.start))?;
+    let data = Read::by_ref(reader).take(range.end - range.start);
+    let prefix = match kind {
+        None => {
+            let mut prefix = Vec::new();
+            data.take(SNIFF_SIZE as u64).read_to_end(&mut prefix)?;
+            prefix
+        }
+        Some(kind) => decompress::decompress_prefix(kind, BufReader::new(data), SNIFF_SIZE)
+            .map_err(|source| PayloadError::Decompression { kind, source })?,
+    };
+    Ok(Some(ContentType::detect(&prefix)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chromeos_update_engine::InstallOperation;
+    use crate::tests::extent;
+
+    /// A superblock at 1024 with `magic` at `offset` in it.
+    fn superblock(offset: usize, magic: &[u8]) -> Vec<u8> {
+        let mut image = vec![0x5a; SNIFF_SIZE];
+        image[1024 + offset..1024 + offset + magic.len()].copy_from_slice(magic);
+        image
+    }
+
+    #[test]
+    fn detect() {
+        let detect = ContentType::detect;
+        assert_eq!(detect(b"ANDROID!\x00\x10"), ContentType::BootImage);
+        assert_eq!(detect(b"AVB0\x00\x00\x00\x01"), ContentType::Vbmeta);
+        assert_eq!(detect(&superblock(0x38, &[0x53, 0xef])), ContentType::Ext4);
+        assert_eq!(
+            detect(&superblock(0, &[0xe2, 0xe1, 0xf5, 0xe0])),
+            ContentType::Erofs
+        );
+        assert_eq!(
+            detect(&superblock(0, &[0x10, 0x20, 0xf5, 0xf2])),
+            ContentType::F2fs
+        );
+        assert_eq!(detect(&[0; 100]), ContentType::Zero);
+        // Too short for the superblock.
+        assert_eq!(
+            detect(&superblock(0x38, &[0x53, 0xef])[..1080]),
+            ContentType::Unknown
+        );
+        assert_eq!(detect(&[]), ContentType::Unknown);
+    }
+
+    #[test]
+    fn sniff() {
+        let boot = b"ANDROID!".repeat(1 << 10);
+        let mut xz = Vec::new();
+        lzma_rs::xz_compress(&mut &boot[..], &mut xz).unwrap();
+        let mut blobs = vec![0; 16];
+        blobs.extend_from_slice(&xz);
+        let operation = |r#type: Type, start_block, data_length| {
+            let mut operation = InstallOperation {
+                data_offset: Some(0),
+                data_length,
+                dst_extents: vec![extent(start_block, 2)],
+                ..Default::default()
+            };
+            operation.set_type(r#type);
+            operation
+        };
+        let sniff = |operations: Vec<InstallOperation>| {
+            let partition = PartitionUpdate {
+                operations,
+                ..Default::default()
+            };
+            sniff_partition(&mut std::io::Cursor::new(&blobs), 16, &partition)
+        };
+
+        // The operation writing block 0 is looked at, not the first one.
+        let xz_len = Some(xz.len() as u64);
+        let operations = vec![
+            operation(Type::Replace, 2, Some(16)),
+            operation(Type::ReplaceXz, 0, xz_len),
+        ];
+        assert_eq!(sniff(operations).unwrap(), Some(ContentType::BootImage));
+        assert_eq!(
+            sniff(vec![operation(Type::Replace, 0, Some(16))]).unwrap(),
+            Some(ContentType::Unknown)
+        );
+        assert_eq!(
+            sniff(vec![operation(Type::Zero, 0, None)]).unwrap(),
+            Some(ContentType::Zero)
+        );
+        assert_eq!(
+            sniff(vec![operation(Type::SourceCopy, 0, None)]).unwrap(),
+            None
+        );
+        assert_eq!(
+            sniff(vec![operation(Type::Replace, 1, Some(16))]).unwrap(),
+            None
+        );
+        assert!(matches!(
+            sniff(vec![operation(Type::ReplaceBz, 0, xz_len)]),
+            Err(PayloadError::Decompression {
+                kind: Compression::Bzip2,
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,429 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use prost::Message;
+use sha2::{Digest, Sha256};
+
+use crate::chromeos_update_engine::{
+    install_operation::Type, DeltaArchiveManifest, Extent, InstallOperation, PartitionInfo,
+    PartitionUpdate,
+};
+use crate::{check_block_size, Compression, DeltaUpdateFile, PayloadError};
+
+/// Options of [`PayloadBuilder`].
+#[derive(Debug, Clone)]
+pub struct CreateOptions {
+    /// Size of the blocks of the partitions.
+    pub block_size: u32,
+    /// Maximum number of blocks written by each operation.
+    pub blocks_per_operation: u64,
+    /// Compression of the data of the operations, only xz is supported.
+    /// Data which doesn't get smaller is stored uncompressed.
+    pub compression: Option<Compression>,
+}
+
+impl Default for CreateOptions {
+    fn default() -> Self {
+        Self {
+            block_size: 4096,
+            blocks_per_operation: 512,
+            compression: Some(Compression::Xz),
+        }
+    }
+}
+
+/// Builds an unsigned full payload, major version 2, from partition images,
+/// the counterpart of [`DeltaUpdateFile`](crate::DeltaUpdateFile).
+///
+/// The data blobs are written to `blobs` as the partitions are added, as
+/// their offsets must be known before the manifest, which comes first in
+/// the payload. [`PayloadBuilder::finish`] then writes the whole payload.
+pub struct PayloadBuilder<B> {
+    blobs: B,
+    blobs_len: u64,
+    options: CreateOptions,
+    partitions: Vec<PartitionUpdate>,
+}
+
+impl<B: Read + Write + Seek> PayloadBuilder<B> {
+    /// Create a builder writing the data blobs to `blobs`, which should be
+    /// empty, e.g. a temporary file.
+    pub fn new(blobs: B, options: CreateOptions) -> Result<Self, PayloadError> {
+        check_block_size(options.block_size as u64)?;
+        if options.blocks_per_operation == 0 {
+            return Err(PayloadError::InvalidOperation(
+                "blocks per operation must not be 0".to_string(),
+            ));
+        }
+        if let Some(kind @ (Compression::Bzip2 | Compression::Zstd)) = options.compression {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("{} compression is not supported", kind),
+            )
+            .into());
+        }
+        Ok(Self {
+            blobs,
+            blobs_len: 0,
+            options,
+            partitions: Vec::new(),
+        })
+    }
+
+    /// Add the partition `name` with the image read from `image`, whose size
+    /// must be a multiple of the block size.
+    ///
+    /// The image is split in operations of at most `blocks_per_operation`
+    /// blocks each. Chunks of zeros become ZERO operations, the others
+    /// REPLACE_XZ or REPLACE operations.
+    pub fn add_partition<R: Read>(
+        &mut self,
+        name: &str,
+        mut image: R,
+    ) -> Result<&PartitionUpdate, PayloadError> {
+        let block_size = self.options.block_size as u64;
+        let chunk_size = self.options.blocks_per_operation.saturating_mul(block_size);
+        let mut hasher = Sha256::new();
+        let mut operations = Vec::new();
+        let mut size = 0;
+        let mut chunk = Vec::new();
+        loop {
+            chunk.clear();
+            Read::by_ref(&mut image)
+                .take(chunk_size)
+                .read_to_end(&mut chunk)?;
+            if chunk.is_empty() {
+                break;
+            }
+            if !(chunk.len() as u64).is_multiple_of(block_size) {
+                let size = size + chunk.len() as u64;
+                let message = format!(
+                    "image of {} bytes is not a multiple of the block size {}",
+                    size, block_size
+                );
+                return Err(PayloadError::InvalidOperation(message).in_partition(name));
+            }
+            hasher.update(&chunk);
+            let dst_extent = Extent {
+                start_block: Some(size / block_size),
+                num_blocks: Some(chunk.len() as u64 / block_size),
+            };
+            size += chunk.len() as u64;
+            let operation = self
+                .operation(&chunk, dst_extent)
+                .map_err(|e| e.in_partition(name))?;
+            operations.push(operation);
+        }
+
+        self.partitions.push(PartitionUpdate {
+            partition_name: name.to_string(),
+            new_partition_info: Some(PartitionInfo {
+                size: Some(size),
+                hash: Some(hasher.finalize().to_vec()),
+            }),
+            operations,
+            ..Default::default()
+        });
+        Ok(self.partitions.last().unwrap())
+    }
+
+    /// The operation writing `data` to `dst_extent`, appending its data to
+    /// the blobs.
+    fn operation(
+        &mut self,
+        data: &[u8],
+        dst_extent: Extent,
+    ) -> Result<InstallOperation, PayloadError> {
+        let mut operation = InstallOperation {
+            dst_extents: vec![dst_extent],
+            ..Default::default()
+        };
+        if data.iter().all(|&b| b == 0) {
+            operation.set_type(Type::Zero);
+            return Ok(operation);
+        }
+
+        let compressed = match self.options.compression {
+            Some(_) => crate::xz::compress(data).map_err(std::io::Error::other)?,
+            None => Vec::new(),
+        };
+        let (op_type, blob) = match compressed.len() {
+            len if len > 0 && len < data.len() => (Type::ReplaceXz, &compressed[..]),
+            _ => (Type::Replace, data),
+        };
+        operation.set_type(op_type);
+        operation.data_offset = Some(self.blobs_len);
+        operation.data_length = Some(blob.len() as u64);
+        operation.data_sha256_hash = Some(Sha256::digest(blob).to_vec());
+        self.blobs.write_all(blob)?;
+        self.blobs_len += blob.len() as u64;
+        Ok(operation)
+    }
+
+    /// The manifest of the partitions added so far.
+    pub fn manifest(&self) -> DeltaArchiveManifest {
+        DeltaArchiveManifest {
+            block_size: Some(self.options.block_size),
+            minor_version: Some(0),
+            partitions: self.partitions.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Write the payload to `out`: the header, the manifest and the data
+    /// blobs read back from `blobs`. Returns the manifest.
+    pub fn finish<W: Write>(mut self, out: &mut W) -> Result<DeltaArchiveManifest, PayloadError> {
+        let manifest = self.manifest();
+        write_header(out, &manifest)?;
+        self.blobs.rewind()?;
+        let copied = std::io::copy(&mut Read::by_ref(&mut self.blobs).take(self.blobs_len), out)?;
+        if copied != self.blobs_len {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "data blobs are truncated",
+            )
+            .into());
+        }
+        Ok(manifest)
+    }
+}
+
+/// Write a new unsigned payload to `out` with only the `partitions` of
+/// `payload`, read from `src`, whose position 0 is the beginning of the
+/// payload. Returns the new manifest.
+///
+/// The data of the operations of the partitions is copied as is, in the
+/// order of the original payload, and their `data_offset` rewritten. The
+/// rest of the manifest is kept, except the signatures, which can't be
+/// kept as the payload changes, and the other partitions in the dynamic
+/// partition groups. Only major version 2 payloads are supported.
+pub fn trim_payload<R: Read + Seek, W: Write>(
+    payload: &DeltaUpdateFile,
+    src: &mut R,
+    partitions: &[&str],
+    out: &mut W,
+) -> Result<DeltaArchiveManifest, PayloadError> {
+    if payload.file_format_version != 2 {
+        let message = format!(
+            "payload major version {} can't be trimmed",
+            payload.file_format_version
+        );
+        return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, message).into());
+    }
+    let mut manifest = payload.manifest.clone();
+    manifest.signatures_offset = None;
+    manifest.signatures_size = None;
+    manifest.partitions = partitions
+        .iter()
+        .map(|&name| {
+            let partition = payload
+                .manifest
+                .partitions
+                .iter()
+                .find(|p| p.partition_name == name);
+            partition
+                .cloned()
+                .ok_or_else(|| PayloadError::PartitionNotFound(name.to_string()))
+        })
+        .collect::<Result<_, _>>()?;
+    if let Some(metadata) = &mut manifest.dynamic_partition_metadata {
+        for group in &mut metadata.groups {
+            group
+                .partition_names
+                .retain(|name| partitions.contains(&name.as_str()));
+        }
+    }
+
+    // The new offset of the data of each operation, shared by operations
+    // with the same data.
+    let mut blobs = BTreeMap::new();
+    for operation in manifest.partitions.iter().flat_map(|p| &p.operations) {
+        if let (Some(offset), Some(length)) = (operation.data_offset, operation.data_length) {
+            blobs.insert((offset, length), 0);
+        }
+    }
+    let mut blobs_len = 0u64;
+    for ((_, length), new_offset) in &mut blobs {
+        *new_offset = blobs_len;
+        blobs_len += *length;
+    }
+    for operation in manifest
+        .partitions
+        .iter_mut()
+        .flat_map(|p| &mut p.operations)
+    {
+        if let (Some(offset), Some(length)) = (operation.data_offset, operation.data_length) {
+            operation.data_offset = Some(blobs[&(offset, length)]);
+        }
+    }
+
+    write_header(out, &manifest)?;
+    for &(offset, length) in blobs.keys() {
+        let start = crate::blob_offset(payload.blobs_offset, offset)?;
+        src.seek(SeekFrom::Start(start))?;
+        let copied = std::io::copy(&mut Read::by_ref(src).take(length), out)?;
+        if copied != length {
+            return Err(PayloadError::Truncated {
+                needed: start.saturating_add(length),
+                len: start + copied,
+            });
+        }
+    }
+    Ok(manifest)
+}
+
+/// Write the header of an unsigned major version 2 payload with
+/// `manifest` to `out`, up to the data blobs.
+fn write_header<W: Write>(out: &mut W, manifest: &DeltaArchiveManifest) -> std::io::Result<()> {
+    crate::header::write_header(out, 2, &manifest.encode_to_vec(), &[])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chromeos_update_engine::{DynamicPartitionGroup, DynamicPartitionMetadata};
+    use crate::verify_image;
+    use std::io::Cursor;
+
+    #[test]
+    fn round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        // Compressible, zero and random-looking chunks of 2 blocks, and a
+        // last chunk of 1 block.
+        let mut state = 1u32;
+        let noise: Vec<u8> = (0..4096)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect();
+        let boot = [&[7u8; 2048][..], &[0; 2048], &noise[..2048], &noise[..1024]].concat();
+        let system = noise.clone();
+
+        for compression in [Some(Compression::Xz), None] {
+            let options = CreateOptions {
+                block_size: 1024,
+                blocks_per_operation: 2,
+                compression,
+            };
+            let mut builder = PayloadBuilder::new(Cursor::new(Vec::new()), options)?;
+            assert_eq!(
+                builder.add_partition("boot", &boot[..])?.operations.len(),
+                4
+            );
+            builder.add_partition("system", &system[..])?;
+            let mut data = Vec::new();
+            builder.finish(&mut data)?;
+
+            let mut src = Cursor::new(&data);
+            let payload = DeltaUpdateFile::parse(&mut src)?;
+            payload.validate_against_len(data.len() as u64)?;
+            assert!(!payload.is_delta());
+            let types: Vec<_> = payload.manifest.partitions[0]
+                .operations
+                .iter()
+                .map(|op| op.r#type())
+                .collect();
+            let replace = match compression {
+                Some(_) => Type::ReplaceXz,
+                None => Type::Replace,
+            };
+            assert_eq!(types, [replace, Type::Zero, Type::Replace, Type::Replace]);
+
+            for (partition, image) in payload.manifest.partitions.iter().zip([&boot, &system]) {
+                let info = partition.new_partition_info.as_ref().unwrap();
+                let mut dst = Cursor::new(vec![0u8; info.size() as usize]);
+                payload.dump_partition(&mut src, partition, &mut dst)?;
+                assert_eq!(dst.get_ref(), image);
+                verify_image(&dst.get_ref()[..], info)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn trim() -> Result<(), Box<dyn std::error::Error>> {
+        let images = [
+            ("boot", [1u8; 2048]),
+            ("system", [2; 2048]),
+            ("vendor", [3; 2048]),
+        ];
+        let options = CreateOptions {
+            block_size: 1024,
+            blocks_per_operation: 1,
+            compression: None,
+        };
+        let mut builder = PayloadBuilder::new(Cursor::new(Vec::new()), options)?;
+        for (name, image) in &images {
+            builder.add_partition(name, &image[..])?;
+        }
+        let mut data = Vec::new();
+        builder.finish(&mut data)?;
+        let mut src = Cursor::new(&data);
+        let mut payload = DeltaUpdateFile::parse(&mut src)?;
+        payload.manifest.signatures_offset = Some(0);
+        payload.manifest.signatures_size = Some(16);
+        payload.manifest.dynamic_partition_metadata = Some(DynamicPartitionMetadata {
+            groups: vec![DynamicPartitionGroup {
+                name: "group".to_string(),
+                partition_names: vec!["system".to_string(), "vendor".to_string()],
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+
+        let mut trimmed = Vec::new();
+        trim_payload(&payload, &mut src, &["vendor", "boot"], &mut trimmed)?;
+        let mut src = Cursor::new(&trimmed);
+        let payload = DeltaUpdateFile::parse(&mut src)?;
+        assert_eq!(trimmed.len() as u64, payload.blobs_offset + 4 * 1024);
+        assert_eq!(payload.manifest.signatures_offset, None);
+        let names: Vec<_> = payload
+            .manifest
+            .partitions
+            .iter()
+            .map(|p| p.partition_name.as_str())
+            .collect();
+        assert_eq!(names, ["vendor", "boot"]);
+        let groups = &payload
+            .manifest
+            .dynamic_partition_metadata
+            .as_ref()
+            .unwrap()
+            .groups;
+        assert_eq!(groups[0].partition_names, ["vendor"]);
+        for (partition, image) in payload
+            .manifest
+            .partitions
+            .iter()
+            .zip([&images[2].1, &images[0].1])
+        {
+            let mut dst = Cursor::new(vec![0u8; 2048]);
+            payload.dump_partition(&mut src, partition, &mut dst)?;
+            assert_eq!(dst.get_ref(), image);
+        }
+
+        let error = trim_payload(&payload, &mut src, &["odm"], &mut Vec::new()).unwrap_err();
+        assert_eq!(error.to_string(), "partition odm not found");
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_images() {
+        let options = CreateOptions {
+            block_size: 4,
+            ..Default::default()
+        };
+        let mut builder = PayloadBuilder::new(Cursor::new(Vec::new()), options).unwrap();
+        let error = builder.add_partition("boot", &[1u8; 6][..]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "partition boot: image of 6 bytes is not a multiple of the block size 4"
+        );
+
+        let options = CreateOptions {
+            compression: Some(Compression::Zstd),
+            ..Default::default()
+        };
+        assert!(PayloadBuilder::new(Cursor::new(Vec::new()), options).is_err());
+    }
+}
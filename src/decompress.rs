@@ -0,0 +1,187 @@
+use std::error::Error;
+use std::io::{BufRead, Write};
+
+#[cfg(not(feature = "xz-native"))]
+use lzma_rs::decompress::raw::Lzma2Decoder;
+
+use crate::Compression;
+
+type Result<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+
+/// State of the decoders kept from a stream to the next, so it's allocated
+/// once, see [`DumpContext`](crate::DumpContext). The bzip2 and zstd
+/// decoders have none that can be reused, nor has liblzma.
+#[derive(Debug, Default)]
+pub(crate) struct Decoders {
+    #[cfg(not(feature = "xz-native"))]
+    lzma2: Option<Lzma2Decoder>,
+}
+
+/// Decompress the `kind` compressed stream in `data` to `out`.
+///
+/// The backends are pure Rust, unless native ones are enabled by a feature,
+/// like libbzip2 with `bzip2-native` and liblzma with `xz-native`. They're
+/// chosen here, so the operations using them don't change.
+pub(crate) fn decompress<R: BufRead, W: Write>(
+    kind: Compression,
+    data: R,
+    out: W,
+    decoders: &mut Decoders,
+) -> Result<()> {
+    match kind {
+        Compression::Bzip2 => bzip2(data, out),
+        Compression::Xz => xz(data, out, decoders),
+        Compression::Zstd => zstd(data, out),
+    }
+}
+
+/// Decompress the first `len` bytes of the `kind` compressed stream in
+/// `data`, or all of it if it's shorter, without decompressing the rest.
+/// Errors after the first `len` bytes are not reported.
+pub(crate) fn decompress_prefix<R: BufRead>(
+    kind: Compression,
+    data: R,
+    len: usize,
+) -> Result<Vec<u8>> {
+    let mut out = PrefixWriter {
+        out: Vec::with_capacity(len),
+        len,
+    };
+    match decompress(kind, data, &mut out, &mut Decoders::default()) {
+        Err(_) if out.out.len() == len => Ok(out.out),
+        result => result.map(|()| out.out),
+    }
+}
+
+/// A [`Vec`] writer keeping the first `len` bytes, failing past them so
+/// the decoder stops.
+struct PrefixWriter {
+    out: Vec<u8>,
+    len: usize,
+}
+
+impl Write for PrefixWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = buf.len().min(self.len - self.out.len());
+        self.out.extend_from_slice(&buf[..n]);
+        // Decoders may not retry short writes, so what fits is kept and
+        // the write fails.
+        if n < buf.len() {
+            return Err(std::io::Error::other("prefix decompressed"));
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Decompress the bzip2 stream in `data` to `out`.
+///
+/// The decoder only tells that the stream is invalid, so the header is
+/// checked first to tell data that is not bzip2 at all from a corrupt
+/// stream.
+pub(crate) fn bzip2<R: BufRead, W: Write>(mut data: R, out: W) -> Result<()> {
+    let header = data.fill_buf()?;
+    match header {
+        [b'B', b'Z', b'h', b'1'..=b'9', ..] => {}
+        [b'B', b'Z', b'h', level, ..] => {
+            return Err(format!("invalid block size {:#04x}", level).into())
+        }
+        _ if header.len() < 4 => return Err("truncated header".into()),
+        _ => return Err(format!("invalid magic {:02x?}", &header[..3]).into()),
+    }
+    decode_bzip2(data, out)
+}
+
+/// Decompress the bzip2 stream in `data` to `out` with libbzip2.
+#[cfg(feature = "bzip2-native")]
+fn decode_bzip2<R: BufRead, W: Write>(data: R, mut out: W) -> Result<()> {
+    let mut decoder = bzip2::bufread::BzDecoder::new(data);
+    std::io::copy(&mut decoder, &mut out)?;
+    Ok(())
+}
+
+/// Decompress the bzip2 stream in `data` to `out` with libribzip2.
+#[cfg(not(feature = "bzip2-native"))]
+fn decode_bzip2<R: BufRead, W: Write>(data: R, out: W) -> Result<()> {
+    libribzip2::stream::decode_stream(data, out).map_err(|()| "corrupt stream".into())
+}
+
+/// Decompress the xz streams in `data` to `out` with liblzma.
+#[cfg(feature = "xz-native")]
+fn xz<R: BufRead, W: Write>(data: R, mut out: W, _decoders: &mut Decoders) -> Result<()> {
+    let mut decoder = xz2::bufread::XzDecoder::new_multi_decoder(data);
+    std::io::copy(&mut decoder, &mut out)?;
+    Ok(())
+}
+
+/// Decompress the xz streams in `data` to `out` with the LZMA2 decoder of
+/// `decoders`, see [`crate::xz`].
+#[cfg(not(feature = "xz-native"))]
+fn xz<R: BufRead, W: Write>(data: R, out: W, decoders: &mut Decoders) -> Result<()> {
+    let lzma2 = decoders.lzma2.get_or_insert_with(Lzma2Decoder::new);
+    crate::xz::decompress(data, out, lzma2)
+}
+
+/// Decompress the zstd frames in `data` to `out`.
+fn zstd<R: BufRead, W: Write>(mut data: R, mut out: W) -> Result<()> {
+    let mut decoder = ruzstd::StreamingDecoder::new(&mut data)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    std::io::copy(&mut decoder, &mut out)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let plain: Vec<u8> = (0..4096u32).map(|i| (i % 251) as u8).collect();
+
+        let mut bz = Vec::new();
+        libribzip2::stream::encode_stream(
+            &mut &plain[..],
+            &mut bz,
+            1,
+            libribzip2::EncodingStrategy::Single,
+        );
+        let mut xz = Vec::new();
+        lzma_rs::xz_compress(&mut &plain[..], &mut xz).unwrap();
+
+        // Decoders are reused after errors too.
+        let mut decoders = Decoders::default();
+        for (kind, compressed) in [(Compression::Bzip2, bz), (Compression::Xz, xz)] {
+            for _ in 0..2 {
+                let mut out = Vec::new();
+                decompress(kind, &compressed[..], &mut out, &mut decoders).unwrap();
+                assert_eq!(out, plain, "{:?}", kind);
+                assert!(
+                    decompress(kind, &plain[..], &mut Vec::new(), &mut decoders).is_err(),
+                    "{:?}",
+                    kind
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn prefix() {
+        let plain: Vec<u8> = (0..1 << 20).map(|i: u32| (i % 251) as u8).collect();
+        let mut xz = Vec::new();
+        lzma_rs::xz_compress(&mut &plain[..], &mut xz).unwrap();
+
+        assert_eq!(
+            decompress_prefix(Compression::Xz, &xz[..], 4096).unwrap(),
+            plain[..4096]
+        );
+        // Shorter streams are decompressed whole, and their errors reported.
+        assert_eq!(
+            decompress_prefix(Compression::Xz, &xz[..], 2 << 20).unwrap(),
+            plain
+        );
+        assert!(decompress_prefix(Compression::Xz, &plain[..], 4096).is_err());
+    }
+}
@@ -0,0 +1,185 @@
+use crate::chromeos_update_engine::{PartitionInfo, PartitionUpdate};
+
+/// How a partition differs between two payloads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    /// Only in the new payload.
+    Added,
+    /// Only in the old payload.
+    Removed,
+    /// The size or the hash of the new partition differs.
+    Changed,
+    /// The size and the hash of the new partition are the same.
+    Unchanged,
+    /// The size is the same, but a hash is missing to compare.
+    Unknown,
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Change::Added => "added",
+            Change::Removed => "removed",
+            Change::Changed => "changed",
+            Change::Unchanged => "unchanged",
+            Change::Unknown => "unknown",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A partition compared between two payloads, from the manifests alone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionDiff {
+    /// Name of the partition.
+    pub name: String,
+    /// How the partition differs.
+    pub change: Change,
+    /// Size of the partition in the old payload, if it's there.
+    pub old_size: Option<u64>,
+    /// Size of the partition in the new payload, if it's there.
+    pub new_size: Option<u64>,
+    /// Bytes of data of the partition in the old payload, the sum of
+    /// `data_length` of its operations.
+    pub old_data_length: u64,
+    /// Bytes of data of the partition in the new payload.
+    pub new_data_length: u64,
+}
+
+impl PartitionDiff {
+    /// Bytes of data more in the new payload than in the old one.
+    pub fn data_length_delta(&self) -> i64 {
+        self.new_data_length as i64 - self.old_data_length as i64
+    }
+}
+
+/// Compare the partitions of an `old` and a `new` payload by the size and
+/// hash in their `new_partition_info`. The partitions of `new` come first,
+/// in order, then those removed from `old`.
+pub fn diff_partitions(old: &[PartitionUpdate], new: &[PartitionUpdate]) -> Vec<PartitionDiff> {
+    let removed = old
+        .iter()
+        .filter(|p| find(new, &p.partition_name).is_none());
+    new.iter()
+        .chain(removed)
+        .map(|partition| {
+            let name = &partition.partition_name;
+            let (old, new) = (find(old, name), find(new, name));
+            let change = match (old, new) {
+                (None, _) => Change::Added,
+                (_, None) => Change::Removed,
+                _ => compare(info(old), info(new)),
+            };
+            PartitionDiff {
+                name: name.clone(),
+                change,
+                old_size: info(old).and_then(|info| info.size),
+                new_size: info(new).and_then(|info| info.size),
+                old_data_length: data_length(old),
+                new_data_length: data_length(new),
+            }
+        })
+        .collect()
+}
+
+/// The partition named `name` in `partitions`.
+fn find<'a>(partitions: &'a [PartitionUpdate], name: &str) -> Option<&'a PartitionUpdate> {
+    partitions.iter().find(|p| p.partition_name == name)
+}
+
+/// The `new_partition_info` of `partition`.
+fn info(partition: Option<&PartitionUpdate>) -> Option<&PartitionInfo> {
+    partition.and_then(|p| p.new_partition_info.as_ref())
+}
+
+/// Compare the infos of a partition in two payloads.
+fn compare(old: Option<&PartitionInfo>, new: Option<&PartitionInfo>) -> Change {
+    let (old, new) = (
+        old.cloned().unwrap_or_default(),
+        new.cloned().unwrap_or_default(),
+    );
+    if old.size != new.size {
+        return Change::Changed;
+    }
+    match (old.hash, new.hash) {
+        (Some(old), Some(new)) if !old.is_empty() && !new.is_empty() => match old == new {
+            true => Change::Unchanged,
+            false => Change::Changed,
+        },
+        _ => Change::Unknown,
+    }
+}
+
+/// Bytes of data of the operations of `partition`.
+fn data_length(partition: Option<&PartitionUpdate>) -> u64 {
+    partition.map_or(0, |p| p.operations.iter().map(|op| op.data_length()).sum())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chromeos_update_engine::InstallOperation;
+
+    fn partition(name: &str, size: u64, hash: Option<&[u8]>, data_length: u64) -> PartitionUpdate {
+        PartitionUpdate {
+            partition_name: name.to_string(),
+            new_partition_info: Some(PartitionInfo {
+                size: Some(size),
+                hash: hash.map(|hash| hash.to_vec()),
+            }),
+            operations: vec![InstallOperation {
+                data_length: Some(data_length),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn diff() {
+        let old = [
+            partition("boot", 8, Some(b"a"), 100),
+            partition("system", 16, Some(b"b"), 200),
+            partition("odm", 8, Some(b"c"), 10),
+            partition("vendor", 8, None, 50),
+            partition("product", 8, Some(b"d"), 30),
+        ];
+        let new = [
+            partition("system", 16, Some(b"b"), 250),
+            partition("boot", 8, Some(b"x"), 120),
+            partition("vendor", 8, Some(b"e"), 50),
+            partition("dtbo", 4, Some(b"f"), 5),
+            partition("product", 16, Some(b"d"), 60),
+        ];
+        let diff = diff_partitions(&old, &new);
+        let changes: Vec<_> = diff.iter().map(|d| (d.name.as_str(), d.change)).collect();
+        assert_eq!(
+            changes,
+            [
+                ("system", Change::Unchanged),
+                ("boot", Change::Changed),
+                ("vendor", Change::Unknown),
+                ("dtbo", Change::Added),
+                ("product", Change::Changed),
+                ("odm", Change::Removed),
+            ]
+        );
+        assert_eq!(diff[0].data_length_delta(), 50);
+        assert_eq!(
+            (
+                diff[3].old_size,
+                diff[3].new_size,
+                diff[3].data_length_delta()
+            ),
+            (None, Some(4), 5)
+        );
+        assert_eq!(
+            (
+                diff[5].old_size,
+                diff[5].new_size,
+                diff[5].data_length_delta()
+            ),
+            (Some(8), None, -10)
+        );
+    }
+}
@@ -0,0 +1,407 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::chromeos_update_engine::{install_operation::Type, PartitionUpdate};
+use crate::pipeline::{run, ReadWaits, Source};
+use crate::trace::partition_span;
+use crate::{
+    validate_dst_extents, validate_in_place, DeltaUpdateFile, OperationResult, PayloadError,
+};
+
+/// Statistics of a dumped partition, the [`OperationResult`] of its
+/// operations added up.
+#[derive(Debug, Clone, Default)]
+pub struct DumpStats {
+    /// Bytes written to the image, the size of the dst extents of the
+    /// operations applied, all but the skipped ones.
+    pub bytes_written: u64,
+    /// Bytes of attached data read from the payload.
+    pub data_bytes_read: u64,
+    /// Number of operations applied of each type, skipped ZERO operations
+    /// included.
+    pub operations: BTreeMap<Type, usize>,
+    /// Number of operations whose data was checked against its hash.
+    pub hashes_verified: usize,
+    /// Time taken to apply the operations.
+    pub elapsed: Duration,
+    /// Time taken by each operation, added up. More than `elapsed` if the
+    /// data is decompressed by several workers.
+    pub operation_time: Duration,
+    /// How often the workers waited for the data of the next operation to
+    /// be read. Many waits mean reading the payload is the bottleneck, which
+    /// a larger [`DumpOptions::prefetch_bytes`] may help with.
+    pub read_waits: ReadWaits,
+}
+
+/// Options of [`DeltaUpdateFile::dump_partition_with`].
+#[derive(Debug, Clone)]
+pub struct DumpOptions {
+    /// Number of threads decompressing the data of the partition.
+    pub workers: usize,
+    /// Maximum number of operations read but not written yet.
+    pub in_flight: usize,
+    /// Maximum number of bytes of data of the operations read into memory
+    /// but not written yet, so reading the payload goes on while the
+    /// workers decompress. An operation with more data is read once
+    /// nothing else is.
+    pub prefetch_bytes: u64,
+    /// Write zeros for ZERO operations. Otherwise they are skipped, which
+    /// keeps a fresh image like the one from [`create_image`] sparse, but
+    /// leaves the previous contents of other outputs, like block devices.
+    pub dense: bool,
+    /// Number of operations at the beginning already applied to the image,
+    /// e.g. by an interrupted dump, which are skipped.
+    pub skip_operations: usize,
+    /// Apply the operations in place, the image holds the old partition,
+    /// which operations read instead of the old image, see
+    /// [`dump_operation_in_place`](crate::dump_operation_in_place).
+    pub in_place: bool,
+    /// Write the dst extents in multiples of the block size from a buffer
+    /// aligned to it, as block devices opened with `O_DIRECT` need. Each
+    /// operation is applied in memory, then written.
+    pub aligned_writes: bool,
+}
+
+impl DumpStats {
+    /// Add the result of an operation applied.
+    pub fn add(&mut self, result: &OperationResult) {
+        self.bytes_written += result.bytes_written;
+        self.data_bytes_read += result.data_bytes_read;
+        *self.operations.entry(result.op_type).or_default() += 1;
+        self.hashes_verified += usize::from(result.hash_verified);
+        self.operation_time += result.duration;
+    }
+}
+
+impl Default for DumpOptions {
+    fn default() -> Self {
+        Self {
+            workers: 1,
+            in_flight: 16,
+            prefetch_bytes: 64 << 20,
+            dense: false,
+            skip_operations: 0,
+            in_place: false,
+            aligned_writes: false,
+        }
+    }
+}
+
+impl DeltaUpdateFile {
+    /// Dump `partition` of the payload read from `src` to `dst`.
+    ///
+    /// Blocks not written by any operation are left as is, so `dst` should
+    /// be zeroed and of the size in `new_partition_info`, like the image
+    /// created by [`create_image`]. Partitions reading from the old
+    /// partition need [`DeltaUpdateFile::dump_partition_with`].
+    pub fn dump_partition<R, W>(
+        &self,
+        src: &mut R,
+        partition: &PartitionUpdate,
+        dst: &mut W,
+    ) -> Result<DumpStats, PayloadError>
+    where
+        R: Read + Seek,
+        W: Read + Write + Seek + Send,
+    {
+        let stats = self.dump_partition_with(
+            src,
+            partition,
+            None::<&mut File>,
+            dst,
+            &DumpOptions::default(),
+            |_| true,
+        )?;
+        Ok(stats.expect("not stopped"))
+    }
+
+    /// Dump `partition` like [`DeltaUpdateFile::dump_partition`], reading
+    /// from `old`, the image of the old partition, if it's a delta.
+    ///
+    /// MOVE and BSDIFF operations of major version 1 payloads read the
+    /// partition being written, so `old` is copied to `dst` first, unless
+    /// the operations are applied in place.
+    ///
+    /// `progress` is called with the index of each operation before it's
+    /// applied, dumping stops if it returns false, and `None` is returned.
+    pub fn dump_partition_with<R, O, W, F>(
+        &self,
+        src: &mut R,
+        partition: &PartitionUpdate,
+        old: Option<&mut O>,
+        dst: &mut W,
+        options: &DumpOptions,
+        progress: F,
+    ) -> Result<Option<DumpStats>, PayloadError>
+    where
+        R: Read + Seek,
+        O: Read + Seek + Send,
+        W: Read + Write + Seek + Send,
+        F: Fn(usize) -> bool + Sync,
+    {
+        let block_size = self.manifest.block_size() as u64;
+        partition_stats(partition, block_size, options, |stats| {
+            let mut old = old;
+            copy_old(partition, old.as_deref_mut(), dst, options)?;
+            let source = Source::Reader(src);
+            let operations = &partition.operations;
+            run(
+                source,
+                self.blobs_offset,
+                old,
+                dst,
+                operations,
+                block_size,
+                options,
+                progress,
+                stats,
+            )
+        })
+    }
+
+    /// Dump `partition` like [`DeltaUpdateFile::dump_partition_with`], from
+    /// the payload in memory in `src`, see [`dump_operations_from_slice`](crate::dump_operations_from_slice).
+    pub fn dump_partition_from_slice<O, W, F>(
+        &self,
+        src: &[u8],
+        partition: &PartitionUpdate,
+        old: Option<&mut O>,
+        dst: &mut W,
+        options: &DumpOptions,
+        progress: F,
+    ) -> Result<Option<DumpStats>, PayloadError>
+    where
+        O: Read + Seek + Send,
+        W: Read + Write + Seek + Send,
+        F: Fn(usize) -> bool + Sync,
+    {
+        let block_size = self.manifest.block_size() as u64;
+        partition_stats(partition, block_size, options, |stats| {
+            let mut old = old;
+            copy_old(partition, old.as_deref_mut(), dst, options)?;
+            let source = Source::<std::io::Empty>::Slice(src);
+            let operations = &partition.operations;
+            run(
+                source,
+                self.blobs_offset,
+                old,
+                dst,
+                operations,
+                block_size,
+                options,
+                progress,
+                stats,
+            )
+        })
+    }
+
+    /// Dump all partitions of the payload read from `src` to
+    /// `<partition_name>.img` in `out_dir`, which is created if missing. The
+    /// names are made safe file names with [`sanitize_file_name`], and
+    /// nothing is written if two of them end up the same.
+    ///
+    /// Delta payloads are not supported, as they need the old images.
+    pub fn dump_all<R: Read + Seek>(
+        &self,
+        src: &mut R,
+        out_dir: &Path,
+    ) -> Result<Vec<(String, DumpStats)>, PayloadError> {
+        let partitions = self.partitions();
+        let mut paths: Vec<PathBuf> = Vec::with_capacity(partitions.len());
+        for partition in partitions.iter() {
+            let name = &partition.partition_name;
+            let path = out_dir.join(format!("{}.img", sanitize_file_name(name)));
+            if let Some(other) = paths.iter().position(|other| *other == path) {
+                return Err(std::io::Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!(
+                        "partitions {} and {} are both written to {}",
+                        partitions[other].partition_name,
+                        name,
+                        path.display()
+                    ),
+                )
+                .into());
+            }
+            paths.push(path);
+        }
+
+        std::fs::create_dir_all(out_dir)?;
+        partitions
+            .iter()
+            .zip(paths)
+            .map(|(partition, path)| {
+                let name = &partition.partition_name;
+                let mut img = create_image(&path, partition).map_err(|e| e.in_partition(name))?;
+                let stats = self.dump_partition(src, partition, &mut img)?;
+                Ok((name.clone(), stats))
+            })
+            .collect()
+    }
+}
+
+/// Apply the operations of `partition` with `dump`, after checking their
+/// dst extents with [`validate_dst_extents`], and with
+/// [`validate_in_place`] if they're applied in place, returning their
+/// statistics, or `None` if it returns false because it was stopped.
+/// `dump` adds the results of the operations and the waits of the pipeline
+/// to the [`DumpStats`] it's given.
+fn partition_stats(
+    partition: &PartitionUpdate,
+    block_size: u64,
+    options: &DumpOptions,
+    dump: impl FnOnce(&mut DumpStats) -> Result<bool, PayloadError>,
+) -> Result<Option<DumpStats>, PayloadError> {
+    let _span = partition_span(partition).entered();
+    let start = Instant::now();
+    let mut stats = DumpStats::default();
+    let done = validate_dst_extents(partition, block_size)
+        .and_then(|_| match options.in_place {
+            true => validate_in_place(partition, block_size),
+            false => Ok(()),
+        })
+        .and_then(|_| dump(&mut stats))
+        .map_err(|e| e.in_partition(&partition.partition_name))?;
+    if !done {
+        return Ok(None);
+    }
+    stats.elapsed = start.elapsed();
+    Ok(Some(stats))
+}
+
+/// Copy `old` to `dst` if operations of `partition` read the partition
+/// being written, MOVE of major version 1 payloads, which expect the old
+/// partition there with what earlier operations wrote over it. Nothing is
+/// copied in place, where `dst` is the old partition, nor when resuming,
+/// where `dst` holds the copy already.
+pub(crate) fn copy_old<O: Read + Seek, W: Write + Seek>(
+    partition: &PartitionUpdate,
+    old: Option<&mut O>,
+    dst: &mut W,
+    options: &DumpOptions,
+) -> Result<(), PayloadError> {
+    let reading = partition
+        .operations
+        .iter()
+        .enumerate()
+        .find(|(_, operation)| matches!(operation.r#type(), Type::Move | Type::Bsdiff));
+    let Some((index, operation)) = reading else {
+        return Ok(());
+    };
+    if options.in_place || options.skip_operations > 0 {
+        return Ok(());
+    }
+    let old = old.ok_or_else(|| {
+        PayloadError::MissingOldImage(operation.r#type()).in_operation(index, operation.r#type)
+    })?;
+    // An old image larger than the new partition is cut to its size.
+    let size = partition
+        .new_partition_info
+        .as_ref()
+        .and_then(|info| info.size)
+        .unwrap_or(u64::MAX);
+    old.rewind()?;
+    dst.rewind()?;
+    std::io::copy(&mut Read::by_ref(old).take(size), dst)?;
+    Ok(())
+}
+
+/// Make the partition name `name` from an untrusted manifest safe to use as
+/// a file name, so the image can't be written outside the directory it's
+/// joined to. Path separators, NUL and other control characters become
+/// `_`, as do the dots of `.` and `..`. On Windows, the characters not
+/// allowed in file names are replaced too, and device names like `CON` or
+/// `com1.img` get a `_` prefix.
+pub fn sanitize_file_name(name: &str) -> String {
+    if name.chars().all(|c| c == '.') {
+        return "_".repeat(name.len().max(1));
+    }
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' => '_',
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' if cfg!(windows) => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    match cfg!(windows) && is_windows_device_name(&name) {
+        true => format!("_{}", name),
+        false => name,
+    }
+}
+
+/// Whether Windows opens a device for the file `name`, whatever its
+/// extension.
+fn is_windows_device_name(name: &str) -> bool {
+    let stem = name
+        .split('.')
+        .next()
+        .unwrap_or_default()
+        .trim_end()
+        .to_ascii_uppercase();
+    match stem.as_str() {
+        "CON" | "PRN" | "AUX" | "NUL" | "CONIN$" | "CONOUT$" => true,
+        stem => {
+            let digit = stem
+                .strip_prefix("COM")
+                .or_else(|| stem.strip_prefix("LPT"));
+            digit.is_some_and(|digit| digit.len() == 1 && digit.as_bytes()[0].is_ascii_digit())
+        }
+    }
+}
+
+/// Error reading or writing the image at `path`, with the path in the
+/// message.
+pub fn image_error(error: std::io::Error, path: &Path) -> PayloadError {
+    std::io::Error::new(error.kind(), format!("{}: {}", path.display(), error)).into()
+}
+
+/// Create the image of `partition` at `path`, zeroed and of the size in
+/// `new_partition_info`. It's opened for reading too, because deprecated
+/// operations like MOVE read from the image being written.
+pub fn create_image(path: &Path, partition: &PartitionUpdate) -> Result<File, PayloadError> {
+    let error = |e| image_error(e, path);
+    let img = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .map_err(error)?;
+    // Blocks not written by any operation are zeros, as the hash in
+    // new_partition_info covers the whole partition.
+    if let Some(size) = partition.new_partition_info.as_ref().and_then(|i| i.size) {
+        img.set_len(size).map_err(error)?;
+    }
+    Ok(img)
+}
+
+/// Open the existing file or block device at `path` to write the image of
+/// `partition` to, without truncating it. Fails if it's smaller than the
+/// size in `new_partition_info`.
+///
+/// The previous contents are kept, so dump with [`DumpOptions::dense`] to
+/// zero the blocks of ZERO operations.
+pub fn open_existing_image(path: &Path, partition: &PartitionUpdate) -> Result<File, PayloadError> {
+    let error = |e| image_error(e, path);
+    let mut img = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(error)?;
+    // The length in the metadata of block devices is 0.
+    let len = img.seek(SeekFrom::End(0)).map_err(error)?;
+    if let Some(size) = partition.new_partition_info.as_ref().and_then(|i| i.size) {
+        if len < size {
+            return Err(error(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} bytes, smaller than the partition ({} bytes)", len, size),
+            )));
+        }
+    }
+    Ok(img)
+}
@@ -0,0 +1,202 @@
+use std::fmt;
+
+use crate::chromeos_update_engine::install_operation::Type;
+use crate::hex;
+
+/// Error parsing a payload or applying its operations.
+#[derive(Debug)]
+pub enum PayloadError {
+    /// I/O error reading the payload or an old image, or writing a new image.
+    Io(std::io::Error),
+    /// The payload header or manifest can not be parsed.
+    Parse(binrw::Error),
+    /// The payload has no partition with this name.
+    PartitionNotFound(String),
+    /// The operation has no attached data.
+    MissingData,
+    /// The operation has no dst extents.
+    MissingDstExtents,
+    /// The operation reads from the old partition, but no old image is given.
+    MissingOldImage(Type),
+    /// The operation type is unknown, or not implemented.
+    UnsupportedOperation(i32),
+    /// The attached data can not be decompressed.
+    Decompression {
+        kind: Compression,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// The attached data decompresses to more than `limit` bytes, the size
+    /// of the dst extents and one block of padding.
+    DecompressionLimit { kind: Compression, limit: u64 },
+    /// The attached bsdiff patch is corrupt.
+    Patch(std::io::Error),
+    /// The fields of the operation are inconsistent.
+    InvalidOperation(String),
+    /// The block size is zero or not a power of two.
+    InvalidBlockSize(u64),
+    /// The payload is `len` bytes, shorter than the `needed` bytes its
+    /// manifest refers to.
+    Truncated { needed: u64, len: u64 },
+    /// Data is not of the expected size.
+    SizeMismatch { expected: u64, actual: u64 },
+    /// The operation produced more data than fits in its dst extents.
+    ExtentOverflow { size: u64, produced: u64 },
+    /// Data does not have the expected SHA-256 hash.
+    HashMismatch { expected: Vec<u8>, actual: Vec<u8> },
+    /// The partition is `size` bytes, more than the `max` bytes allowed to
+    /// read it in memory.
+    TooLarge { size: u64, max: u64 },
+    /// Error applying the operation at `index` of a partition.
+    Operation {
+        index: usize,
+        op_type: i32,
+        source: Box<PayloadError>,
+    },
+    /// Error dumping the partition `name`.
+    Partition {
+        name: String,
+        source: Box<PayloadError>,
+    },
+}
+
+/// Compression of the attached data of an operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Bzip2,
+    Xz,
+    Zstd,
+}
+
+impl PayloadError {
+    /// Attach the `index` and type of the failed `operation`.
+    pub fn in_operation(self, index: usize, op_type: i32) -> Self {
+        PayloadError::Operation {
+            index,
+            op_type,
+            source: Box::new(self),
+        }
+    }
+
+    /// Attach the name of the partition being dumped.
+    pub fn in_partition(self, name: &str) -> Self {
+        PayloadError::Partition {
+            name: name.to_string(),
+            source: Box::new(self),
+        }
+    }
+}
+
+/// Name of an operation type, or its number if unknown.
+pub(crate) fn type_name(op_type: i32) -> String {
+    Type::from_i32(op_type)
+        .map(|t| t.as_str_name().to_string())
+        .unwrap_or_else(|| op_type.to_string())
+}
+
+impl fmt::Display for PayloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PayloadError::Io(e) => write!(f, "{}", e),
+            PayloadError::Parse(e) => write!(f, "invalid payload: {}", e),
+            PayloadError::PartitionNotFound(name) => write!(f, "partition {} not found", name),
+            PayloadError::MissingData => write!(f, "no data"),
+            PayloadError::MissingDstExtents => write!(f, "no dst extents"),
+            PayloadError::MissingOldImage(t) => {
+                write!(f, "{} requires the old partition image", t.as_str_name())
+            }
+            PayloadError::UnsupportedOperation(t) => {
+                write!(f, "operation type {} is not supported", type_name(*t))
+            }
+            PayloadError::Decompression { kind, source } => write!(f, "{} error: {}", kind, source),
+            PayloadError::DecompressionLimit { kind, limit } => {
+                write!(f, "{} data decompresses to more than {} bytes", kind, limit)
+            }
+            PayloadError::Patch(e) => write!(f, "{}", e),
+            PayloadError::InvalidOperation(message) => write!(f, "{}", message),
+            PayloadError::Truncated { needed, len } => write!(
+                f,
+                "payload appears truncated: need at least {} bytes, file is {} bytes",
+                needed, len
+            ),
+            PayloadError::InvalidBlockSize(size) => {
+                write!(
+                    f,
+                    "invalid block size {}, it must be a non-zero power of two",
+                    size
+                )
+            }
+            PayloadError::SizeMismatch { expected, actual } => {
+                write!(f, "size mismatch: expected {}, got {}", expected, actual)
+            }
+            PayloadError::ExtentOverflow { size, produced } => write!(
+                f,
+                "produced more data than its destination extents: {} bytes for {} bytes of extents",
+                produced, size
+            ),
+            PayloadError::HashMismatch { expected, actual } => write!(
+                f,
+                "hash mismatch: expected {}, got {}",
+                hex(expected),
+                hex(actual)
+            ),
+            PayloadError::TooLarge { size, max } => {
+                write!(
+                    f,
+                    "{} bytes is larger than the limit of {} bytes in memory",
+                    size, max
+                )
+            }
+            PayloadError::Operation {
+                index,
+                op_type,
+                source,
+            } => write!(
+                f,
+                "operation {} (type {}): {}",
+                index,
+                type_name(*op_type),
+                source
+            ),
+            PayloadError::Partition { name, source } => write!(f, "partition {}: {}", name, source),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::Bzip2 => write!(f, "bzip2"),
+            Compression::Xz => write!(f, "xz"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+impl std::error::Error for PayloadError {
+    /// The error wrapped by `Io`, `Parse`, `Decompression` and `Patch`.
+    /// `Operation` and `Partition` only add context to the message, so they
+    /// forward the source of the error they wrap.
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PayloadError::Io(e) | PayloadError::Patch(e) => Some(e),
+            PayloadError::Parse(e) => Some(e),
+            PayloadError::Decompression { source, .. } => Some(source.as_ref()),
+            PayloadError::Operation { source, .. } | PayloadError::Partition { source, .. } => {
+                source.source()
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for PayloadError {
+    fn from(e: std::io::Error) -> Self {
+        PayloadError::Io(e)
+    }
+}
+
+impl From<binrw::Error> for PayloadError {
+    fn from(e: binrw::Error) -> Self {
+        PayloadError::Parse(e)
+    }
+}
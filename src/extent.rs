@@ -1,42 +1,190 @@
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{BufRead, Cursor, Read, Seek, SeekFrom, Write};
 
 use crate::chromeos_update_engine;
 
+/// Size of the buffers of [`BufRead`] readers, allocated on the first
+/// `fill_buf`.
+const READ_BUFFER_SIZE: usize = 64 << 10;
+
+/// Bytes read ahead by `fill_buf`, the inner reader being past them.
+#[derive(Default)]
+struct ReadBuffer {
+    data: Vec<u8>,
+    start: usize,
+    end: usize,
+}
+
+impl ReadBuffer {
+    fn buffered(&self) -> &[u8] {
+        &self.data[self.start..self.end]
+    }
+
+    fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// Forget the bytes read ahead, returning how many there were.
+    fn discard(&mut self) -> u64 {
+        let discarded = self.end - self.start;
+        self.start = 0;
+        self.end = 0;
+        discarded as u64
+    }
+
+    /// Copy the buffered bytes to `buf`, returning how many.
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let n = buf.len().min(self.end - self.start);
+        buf[..n].copy_from_slice(&self.data[self.start..self.start + n]);
+        self.start += n;
+        n
+    }
+
+    /// Space for at most `limit` bytes, which are read into it and then
+    /// given to `filled`.
+    fn space(&mut self, limit: u64) -> &mut [u8] {
+        if self.data.len() < READ_BUFFER_SIZE {
+            self.data.resize(READ_BUFFER_SIZE, 0);
+        }
+        self.start = 0;
+        self.end = 0;
+        let n = limit.min(self.data.len() as u64) as usize;
+        &mut self.data[..n]
+    }
+
+    fn filled(&mut self, n: usize) {
+        self.end = n;
+    }
+}
+
+/// A section of `length` bytes starting at `offset` in `inner`.
+///
+/// It's also a [`BufRead`], which never reads past the end of the section,
+/// so it needs no [`BufReader`](std::io::BufReader) around it.
 pub struct SectionFile<T> {
     inner: T,
     offset: u64,
     length: u64,
 
+    /// Position in the section, of the bytes read ahead if any.
     pos: u64,
+    buffer: ReadBuffer,
 }
 
-
 impl<T: Seek> SectionFile<T> {
     pub fn new(mut inner: T, offset: u64, length: u64) -> std::io::Result<Self> {
+        if offset.checked_add(length).is_none() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("section of {} bytes at offset {} overflows", length, offset),
+            ));
+        }
         inner.seek(SeekFrom::Start(offset))?;
 
         Ok(Self {
             inner,
-            offset, 
+            offset,
             length,
 
             pos: 0,
+            buffer: ReadBuffer::default(),
         })
     }
 
-    pub fn new_from_extent(inner: T, extent: chromeos_update_engine::Extent, block_size: u64) -> std::io::Result<Self> {
-        Self::new(inner, extent.start_block() * block_size, extent.num_blocks() * block_size)
+    #[allow(dead_code)]
+    pub fn new_from_extent(
+        inner: T,
+        extent: chromeos_update_engine::Extent,
+        block_size: u64,
+    ) -> std::io::Result<Self> {
+        let fragment = Fragment::from_extent(&extent, block_size)?;
+        Self::new(inner, fragment.offset, fragment.size)
+    }
+}
+
+impl<T> SectionFile<T> {
+    /// Offset of the section in the inner reader.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Read ahead into `buffer`, given back by [`Self::into_read_buffer`]
+    /// of another section, instead of allocating a new one.
+    pub(crate) fn with_read_buffer(mut self, buffer: Vec<u8>) -> Self {
+        self.buffer = ReadBuffer {
+            data: buffer,
+            start: 0,
+            end: 0,
+        };
+        self
+    }
+
+    /// The buffer the bytes are read ahead into, to read another section.
+    pub(crate) fn into_read_buffer(self) -> Vec<u8> {
+        self.buffer.data
+    }
+}
+
+impl<T: AsSlice> SectionFile<T> {
+    /// The bytes of the section, borrowed from `inner` without reading. It's
+    /// shorter than the section if `inner` ends before it.
+    pub fn as_slice(&self) -> &[u8] {
+        let data = self.inner.as_slice();
+        let start = self.offset.min(data.len() as u64) as usize;
+        let end = self
+            .offset
+            .saturating_add(self.length)
+            .min(data.len() as u64) as usize;
+        &data[start..end]
+    }
+}
+
+/// A reader whose contents are all in memory, like a [`Cursor`] or a memory
+/// mapped file, so they can be borrowed instead of read.
+pub trait AsSlice {
+    /// The whole contents, regardless of the position.
+    fn as_slice(&self) -> &[u8];
+}
+
+impl<T: AsRef<[u8]>> AsSlice for Cursor<T> {
+    fn as_slice(&self) -> &[u8] {
+        self.get_ref().as_ref()
+    }
+}
+
+impl<T: AsSlice> AsSlice for SectionFile<T> {
+    fn as_slice(&self) -> &[u8] {
+        SectionFile::as_slice(self)
+    }
+}
+
+impl<T: AsSlice + ?Sized> AsSlice for &T {
+    fn as_slice(&self) -> &[u8] {
+        (**self).as_slice()
+    }
+}
+
+impl<T: AsSlice + ?Sized> AsSlice for &mut T {
+    fn as_slice(&self) -> &[u8] {
+        (**self).as_slice()
     }
 }
 
 impl<T: Seek> Seek for SectionFile<T> {
     fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
         let pos = match pos {
-            SeekFrom::Start(pos) => pos,
-            SeekFrom::Current(pos) => (self.pos as i64 + pos) as u64,
-            SeekFrom::End(pos) => (self.length as i64 + pos) as u64,
-        };
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::Current(pos) => self.pos.checked_add_signed(pos),
+            SeekFrom::End(pos) => self.length.checked_add_signed(pos),
+        }
+        .filter(|&pos| pos <= self.length)
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek outside of the section",
+            )
+        })?;
 
+        self.buffer.discard();
         self.pos = self.inner.seek(SeekFrom::Start(self.offset + pos))? - self.offset;
         Ok(self.pos)
     }
@@ -44,16 +192,53 @@ impl<T: Seek> Seek for SectionFile<T> {
 
 impl<T: Read + Seek> Read for SectionFile<T> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let to_read = std::cmp::min(buf.len() as u64, self.length - self.pos) as usize;
+        if !self.buffer.is_empty() {
+            let read = self.buffer.read(buf);
+            self.pos += read as u64;
+            return Ok(read);
+        }
+        let to_read =
+            std::cmp::min(buf.len() as u64, self.length.saturating_sub(self.pos)) as usize;
+        if to_read == 0 {
+            return Ok(0);
+        }
         let read = self.inner.read(&mut buf[..to_read])?;
         self.pos += read as u64;
         Ok(read)
     }
 }
 
+impl<T: Read + Seek> BufRead for SectionFile<T> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        if self.buffer.is_empty() {
+            let space = self.buffer.space(self.length.saturating_sub(self.pos));
+            let read = match space.len() {
+                0 => 0,
+                _ => self.inner.read(space)?,
+            };
+            self.buffer.filled(read);
+        }
+        Ok(self.buffer.buffered())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        let amt = amt.min(self.buffer.end - self.buffer.start);
+        self.buffer.start += amt;
+        self.pos += amt as u64;
+    }
+}
+
 impl<T: Write + Seek> Write for SectionFile<T> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let to_write = std::cmp::min(buf.len() as u64, self.length - self.pos) as usize;
+        // The inner writer is past the bytes read ahead.
+        if self.buffer.discard() > 0 {
+            self.inner.seek(SeekFrom::Start(self.offset + self.pos))?;
+        }
+        let to_write =
+            std::cmp::min(buf.len() as u64, self.length.saturating_sub(self.pos)) as usize;
+        if to_write == 0 {
+            return Ok(0);
+        }
         let write = self.inner.write(&buf[..to_write])?;
         self.pos += write as u64;
         Ok(write)
@@ -64,6 +249,23 @@ impl<T: Write + Seek> Write for SectionFile<T> {
     }
 }
 
+/// Error of a write past the end of a [`FragmentFile`], with the number of
+/// bytes that didn't fit.
+#[derive(Debug)]
+pub(crate) struct Overflow(pub u64);
+
+impl std::fmt::Display for Overflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} bytes past the end of the extents", self.0)
+    }
+}
+
+impl std::error::Error for Overflow {}
+
+/// `start_block` of the extents of holes in old payloads, which MOVE and
+/// BSDIFF operations may read as zeros. Writes to holes are discarded.
+pub const SPARSE_HOLE: u64 = u64::MAX;
+
 #[derive(Debug, Clone)]
 pub struct Fragment {
     pub offset: u64,
@@ -71,12 +273,136 @@ pub struct Fragment {
 }
 
 impl Fragment {
-    pub fn from_extent(extent: &crate::chromeos_update_engine::Extent, block_size: u64) -> Self {
-        Self {
-            offset: block_size * extent.start_block(),
-            size: block_size * extent.num_blocks(),
+    /// The bytes of `extent`, failing if its offset or end overflows, so
+    /// crafted extents can't wrap around to unintended positions.
+    ///
+    /// The offset of holes, whose `start_block` is [`SPARSE_HOLE`], is
+    /// [`SPARSE_HOLE`] too.
+    pub fn from_extent(
+        extent: &crate::chromeos_update_engine::Extent,
+        block_size: u64,
+    ) -> std::io::Result<Self> {
+        let size = extent.num_blocks().checked_mul(block_size);
+        let offset = match extent.start_block() {
+            SPARSE_HOLE => Some(SPARSE_HOLE),
+            start_block => start_block
+                .checked_mul(block_size)
+                .filter(|&offset| size.is_some_and(|size| offset.checked_add(size).is_some())),
+        };
+        match offset.zip(size) {
+            Some((offset, size)) => Ok(Self { offset, size }),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "extent start_block {:#X} num_blocks {} with block_size {} overflows",
+                    extent.start_block(),
+                    extent.num_blocks(),
+                    block_size
+                ),
+            )),
+        }
+    }
+}
+
+/// `fragments` with runs of physically contiguous fragments, and of
+/// holes, merged into single fragments, so they're read and written
+/// without a seek in between. Fails if the total size overflows.
+pub(crate) fn coalesce(fragments: &[Fragment]) -> std::io::Result<Vec<Fragment>> {
+    let mut merged: Vec<Fragment> = Vec::with_capacity(fragments.len());
+    for fragment in fragments {
+        match merged.last_mut() {
+            Some(last)
+                if (last.offset == SPARSE_HOLE && fragment.offset == SPARSE_HOLE)
+                    || (last.offset != SPARSE_HOLE
+                        && last.offset.checked_add(last.size) == Some(fragment.offset)) =>
+            {
+                last.size = last.size.checked_add(fragment.size).ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "total size of the extents overflows",
+                    )
+                })?;
+            }
+            _ => merged.push(fragment.clone()),
+        }
+    }
+    Ok(merged)
+}
+
+/// Total size of `extents` in bytes, failing if it overflows.
+pub(crate) fn extents_size(
+    extents: &[chromeos_update_engine::Extent],
+    block_size: u64,
+) -> std::io::Result<u64> {
+    extents.iter().try_fold(0u64, |size, extent| {
+        size.checked_add(Fragment::from_extent(extent, block_size)?.size)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "total size of the extents overflows",
+                )
+            })
+    })
+}
+
+/// Copy the bytes of `src_extents` of `src` to `dst_extents` of `dst`, a
+/// pair of physically contiguous ranges at a time, returning the number of
+/// bytes copied, which is less than the size of `dst_extents` if `src` ends
+/// first. Holes in `src_extents` are copied as zeros, and the bytes copied
+/// to holes in `dst_extents` are skipped.
+///
+/// Each range is copied with [`std::io::copy`], which on Linux copies
+/// between files with `copy_file_range`, without reading them in user
+/// space, and as a reflink on filesystems like btrfs and XFS. It falls
+/// back to reading and writing the bytes when it's not supported.
+pub(crate) fn copy_extents<R: Read + Seek, W: Write + Seek>(
+    src: &mut R,
+    src_extents: &[chromeos_update_engine::Extent],
+    dst: &mut W,
+    dst_extents: &[chromeos_update_engine::Extent],
+    block_size: u64,
+) -> std::io::Result<u64> {
+    let fragments = |extents: &[chromeos_update_engine::Extent]| {
+        let fragments = extents
+            .iter()
+            .map(|extent| Fragment::from_extent(extent, block_size))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        coalesce(&fragments)
+    };
+    let (src_fragments, dst_fragments) = (fragments(src_extents)?, fragments(dst_extents)?);
+
+    let (mut i, mut j) = (0, 0);
+    // Bytes of the current fragments copied.
+    let (mut src_pos, mut dst_pos) = (0, 0);
+    let mut copied = 0;
+    while let (Some(from), Some(to)) = (src_fragments.get(i), dst_fragments.get(j)) {
+        let size = (from.size - src_pos).min(to.size - dst_pos);
+        let n = match (from.offset, to.offset) {
+            (_, SPARSE_HOLE) => size,
+            (SPARSE_HOLE, to_offset) => {
+                dst.seek(SeekFrom::Start(to_offset + dst_pos))?;
+                std::io::copy(&mut std::io::repeat(0).take(size), dst)?
+            }
+            (from_offset, to_offset) => {
+                src.seek(SeekFrom::Start(from_offset + src_pos))?;
+                dst.seek(SeekFrom::Start(to_offset + dst_pos))?;
+                std::io::copy(&mut Read::by_ref(src).take(size), dst)?
+            }
+        };
+        copied += n;
+        if n < size {
+            break;
+        }
+        src_pos += size;
+        dst_pos += size;
+        if src_pos == from.size {
+            (i, src_pos) = (i + 1, 0);
+        }
+        if dst_pos == to.size {
+            (j, dst_pos) = (j + 1, 0);
         }
     }
+    Ok(copied)
 }
 
 struct FragmentNode {
@@ -85,16 +411,85 @@ struct FragmentNode {
     pub start_pos: u64,
 }
 
+impl FragmentNode {
+    #[inline]
+    fn is_hole(&self) -> bool {
+        self.offset == SPARSE_HOLE
+    }
+}
+
+/// The fragments of `inner` read or written as one contiguous file.
+///
+/// With aligned writes, writes are buffered in a buffer aligned to the
+/// block size and written in multiples of it, up to the end of each
+/// fragment, as block devices opened with `O_DIRECT` need. A partial block
+/// left at a flush is padded with zeros. Flush before seeking or reading,
+/// and when done, as the buffer is not written on drop.
+///
+/// It's also a [`BufRead`], which reads ahead up to the end of the current
+/// fragment at most, so the underlying file is only seeked between them.
 pub struct FragmentFile<T> {
     inner: T,
     index: usize,
+    /// Position in the current fragment, of the bytes read ahead if any.
     fragment_pos: u64,
     size: u64,
     fragments: Vec<FragmentNode>,
+    /// Writes not written to `inner` yet, with aligned writes.
+    buffer: Option<AlignedBuffer>,
+    read_buffer: ReadBuffer,
+}
+
+/// Size of the buffer of aligned writes, rounded to the block size.
+const ALIGNED_BUFFER_SIZE: usize = 1 << 20;
+
+/// A buffer whose data starts at a multiple of `align` in memory.
+struct AlignedBuffer {
+    data: Vec<u8>,
+    /// Offset of the aligned start in `data`.
+    start: usize,
+    len: usize,
+    capacity: usize,
+    align: usize,
+}
+
+impl AlignedBuffer {
+    /// A buffer of at least `capacity` bytes, rounded up to a multiple of
+    /// `align`, which is a power of two.
+    fn new(capacity: usize, align: usize) -> Self {
+        let capacity = capacity.max(1).next_multiple_of(align);
+        let data = vec![0; capacity + align];
+        let start = data.as_ptr().align_offset(align);
+        Self {
+            data,
+            start,
+            len: 0,
+            capacity,
+            align,
+        }
+    }
+
+    /// Append as much of `buf` as fits in `limit` bytes, returning the
+    /// number of bytes appended.
+    fn extend(&mut self, buf: &[u8], limit: usize) -> usize {
+        let n = buf.len().min(limit.min(self.capacity) - self.len);
+        let end = self.start + self.len;
+        self.data[end..end + n].copy_from_slice(&buf[..n]);
+        self.len += n;
+        n
+    }
+
+    /// The buffered bytes padded with zeros to a multiple of `align`, but
+    /// not past `limit` bytes, which is at least the bytes buffered.
+    fn padded(&mut self, limit: usize) -> &[u8] {
+        let len = self.len.next_multiple_of(self.align).min(limit);
+        self.data[self.start + self.len..self.start + len].fill(0);
+        &self.data[self.start..self.start + len]
+    }
 }
 
 impl<T: Seek> FragmentFile<T> {
-    pub fn new(mut inner: T, fragments: &[Fragment]) -> std::io::Result<Self> {
+    pub fn new(inner: T, fragments: &[Fragment]) -> std::io::Result<Self> {
         if fragments.is_empty() {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
@@ -102,33 +497,63 @@ impl<T: Seek> FragmentFile<T> {
             ));
         }
 
-        inner.seek(SeekFrom::Start(fragments[0].offset))?;
-        let fragments = fragments
+        let mut size = 0u64;
+        let fragments = coalesce(fragments)?
             .iter()
-            .scan(0u64, |acc, fragment| {
-                let ret = FragmentNode {
+            .map(|fragment| {
+                let node = FragmentNode {
                     offset: fragment.offset,
                     size: fragment.size,
-                    start_pos: *acc,
+                    start_pos: size,
                 };
-                *acc += fragment.size;
-
-                Some(ret)
+                size = size.checked_add(fragment.size).ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "total size of the extents overflows",
+                    )
+                })?;
+                Ok(node)
             })
-            .collect::<Vec<_>>();
+            .collect::<std::io::Result<Vec<_>>>()?;
 
-        Ok(Self {
+        let mut file = Self {
             inner,
             index: 0,
             fragment_pos: 0,
-            size: fragments.iter().map(|node| node.size).sum(),
+            size,
             fragments,
-        })
+            buffer: None,
+            read_buffer: ReadBuffer::default(),
+        };
+        file.inner_seek()?;
+        Ok(file)
+    }
+
+    pub fn new_from_extents(
+        inner: T,
+        extents: &[chromeos_update_engine::Extent],
+        block_size: u64,
+    ) -> std::io::Result<Self> {
+        Self::new_from_extents_with(inner, extents, block_size, false)
     }
 
-    pub fn new_from_extents(inner: T, extents: &[chromeos_update_engine::Extent], block_size: u64) -> std::io::Result<Self> {
-        let fragments: Vec<_> = extents.iter().map(|extent| Fragment::from_extent(extent, block_size)).collect();
-        Self::new(inner, &fragments)
+    /// The `extents` of `inner`, with aligned writes to `block_size` if
+    /// `aligned_writes` is set.
+    pub fn new_from_extents_with(
+        inner: T,
+        extents: &[chromeos_update_engine::Extent],
+        block_size: u64,
+        aligned_writes: bool,
+    ) -> std::io::Result<Self> {
+        let fragments = extents
+            .iter()
+            .map(|extent| Fragment::from_extent(extent, block_size))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let mut file = Self::new(inner, &fragments)?;
+        if aligned_writes {
+            file.buffer = Some(AlignedBuffer::new(ALIGNED_BUFFER_SIZE, block_size as usize));
+        }
+        Ok(file)
     }
 
     #[inline]
@@ -159,8 +584,13 @@ impl<T: Seek> FragmentFile<T> {
         self.fragment().offset + self.fragment_pos
     }
 
+    /// Seek the underlying file to the current position, unless it's in a
+    /// hole.
     #[inline]
     fn inner_seek(&mut self) -> std::io::Result<u64> {
+        if self.fragment().is_hole() {
+            return Ok(self.pos());
+        }
         let inner_pos = self.inner.seek(SeekFrom::Start(self.inner_pos()))?;
         debug_assert!(inner_pos == self.inner_pos());
         Ok(self.pos())
@@ -169,21 +599,98 @@ impl<T: Seek> FragmentFile<T> {
     #[inline]
     fn next_fragment(&mut self) -> std::io::Result<()> {
         self.index += 1;
+        self.fragment_pos = 0;
         if self.eof() {
             return Ok(());
         }
 
-        self.fragment_pos = 0;
         self.inner_seek()?;
         Ok(())
     }
 
+    /// Error of the underlying file ending before the current fragment.
+    fn inner_eof_error(&mut self, kind: std::io::ErrorKind, operation: &str) -> std::io::Error {
+        let message = format!(
+            "failed to {} at offset {} (position {} of the extents), the file is too short",
+            operation,
+            self.inner_pos(),
+            self.pos()
+        );
+        std::io::Error::new(kind, message)
+    }
+
+    /// Fail if aligned writes are buffered, which `operation` would lose.
+    fn check_unbuffered(&self, operation: &str) -> std::io::Result<()> {
+        match &self.buffer {
+            Some(buffer) if buffer.len > 0 => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "failed to {}, the aligned writes are not flushed",
+                    operation
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// The position, past the end `fragment_pos` is the offset from the end.
+    /// Buffered writes count as written.
     #[inline]
     fn pos(&mut self) -> u64 {
         if self.eof() {
-            return self.size;
+            return self.size + self.fragment_pos;
         }
-        self.fragment().start_pos + self.fragment_pos
+        let buffered = self.buffer.as_ref().map_or(0, |buffer| buffer.len as u64);
+        self.fragment().start_pos + self.fragment_pos + buffered
+    }
+}
+
+impl<T: Seek + Write> FragmentFile<T> {
+    /// Buffer `buf` up to the end of the current fragment, writing the
+    /// buffer when it's full or reaches the end of the fragment.
+    fn write_aligned(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let remaining = self.fragment_remaining().min(usize::MAX as u64) as usize;
+        if self.fragment().is_hole() {
+            let written = remaining.min(buf.len());
+            self.fragment_pos += written as u64;
+            if self.fragment_eof() {
+                self.next_fragment()?;
+            }
+            return Ok(written);
+        }
+
+        let buffer = self.buffer.as_mut().expect("writes are aligned");
+        let written = buffer.extend(buf, remaining);
+        if buffer.len == buffer.capacity || buffer.len == remaining {
+            self.write_buffer()?;
+        }
+        Ok(written)
+    }
+
+    /// Write the buffered bytes at the current position of the fragment,
+    /// padding a partial block with zeros.
+    fn write_buffer(&mut self) -> std::io::Result<()> {
+        let Some(buffer) = self.buffer.as_mut().filter(|buffer| buffer.len > 0) else {
+            return Ok(());
+        };
+        let remaining =
+            (self.fragments[self.index].size - self.fragment_pos).min(usize::MAX as u64) as usize;
+        let data = buffer.padded(remaining);
+        let len = data.len();
+        if let Err(e) = self.inner.write_all(data) {
+            return Err(match e.kind() {
+                std::io::ErrorKind::WriteZero => {
+                    self.inner_eof_error(std::io::ErrorKind::WriteZero, "write")
+                }
+                _ => e,
+            });
+        }
+        buffer.len = 0;
+        self.fragment_pos += len as u64;
+        if self.fragment_eof() {
+            self.next_fragment()?;
+        }
+        Ok(())
     }
 }
 
@@ -192,6 +699,15 @@ impl<T> FragmentFile<T> {
     pub fn size(&self) -> u64 {
         self.size
     }
+
+    /// Get a mutable reference to the underlying file.
+    ///
+    /// Seek this `FragmentFile` before using it again if the position of
+    /// the underlying file is changed.
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
 }
 
 impl<T: Seek> Seek for FragmentFile<T> {
@@ -199,34 +715,56 @@ impl<T: Seek> Seek for FragmentFile<T> {
         if pos == SeekFrom::Current(0) {
             return Ok(self.pos());
         }
+        self.check_unbuffered("seek")?;
 
         let pos = match pos {
-            SeekFrom::Start(pos) => pos,
-            SeekFrom::Current(pos) => (self.pos() as i64 + pos) as u64,
-            SeekFrom::End(pos) => (self.size as i64 + pos) as u64,
-        };
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::Current(pos) => self.pos().checked_add_signed(pos),
+            SeekFrom::End(pos) => self.size.checked_add_signed(pos),
+        }
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )
+        })?;
 
-        let (index, fragment) = self
-            .fragments
-            .iter()
-            .enumerate()
-            .take_while(|(_, FragmentNode { start_pos, .. })| start_pos <= &pos)
-            .last()
-            .unwrap();
-            // .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid seek"))?;
+        self.read_buffer.discard();
+        // At or past the end, reads return 0 and writes fail.
+        if pos >= self.size {
+            self.index = self.fragments.len();
+            self.fragment_pos = pos - self.size;
+            return Ok(pos);
+        }
 
+        // The last fragment starting at or before `pos`, which is not empty.
+        let index = self.fragments.partition_point(|node| node.start_pos <= pos) - 1;
         self.index = index;
-        self.fragment_pos = pos - fragment.start_pos;
+        self.fragment_pos = pos - self.fragments[index].start_pos;
         self.inner_seek()
     }
 }
 
 impl<T: Seek + Read> Read for FragmentFile<T> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let mut read = 0;
+        self.check_unbuffered("read")?;
+        let mut read = self.read_buffer.read(buf);
+        self.fragment_pos += read as u64;
+        if !self.eof() && self.fragment_eof() {
+            self.next_fragment()?;
+        }
         while read < buf.len() && !self.eof() {
-            let to_read = std::cmp::min(self.fragment_remaining() as usize, buf.len() - read);
-            let read_now = self.inner.read(&mut buf[read..read + to_read])?;
+            let to_read =
+                std::cmp::min(self.fragment_remaining(), (buf.len() - read) as u64) as usize;
+            let read_now = if self.fragment().is_hole() {
+                buf[read..read + to_read].fill(0);
+                to_read
+            } else {
+                self.inner.read(&mut buf[read..read + to_read])?
+            };
+            if read_now == 0 && to_read > 0 {
+                return Err(self.inner_eof_error(std::io::ErrorKind::UnexpectedEof, "read"));
+            }
             read += read_now;
             self.fragment_pos += read_now as u64;
 
@@ -239,12 +777,70 @@ impl<T: Seek + Read> Read for FragmentFile<T> {
     }
 }
 
+impl<T: Seek + Read> BufRead for FragmentFile<T> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.check_unbuffered("read")?;
+        if self.read_buffer.is_empty() {
+            // Left at the end of a fragment by `consume`.
+            if !self.eof() && self.fragment_eof() {
+                self.next_fragment()?;
+            }
+            if self.eof() {
+                return Ok(&[]);
+            }
+            let remaining = self.fragment_remaining();
+            let is_hole = self.fragment().is_hole();
+            let space = self.read_buffer.space(remaining);
+            let read = match is_hole {
+                true => {
+                    space.fill(0);
+                    space.len()
+                }
+                false => self.inner.read(space)?,
+            };
+            if read == 0 {
+                return Err(self.inner_eof_error(std::io::ErrorKind::UnexpectedEof, "read"));
+            }
+            self.read_buffer.filled(read);
+        }
+        Ok(self.read_buffer.buffered())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        let amt = amt.min(self.read_buffer.end - self.read_buffer.start);
+        self.read_buffer.start += amt;
+        self.fragment_pos += amt as u64;
+    }
+}
+
 impl<T: Seek + Write> Write for FragmentFile<T> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // The inner file is past the bytes read ahead.
+        if self.read_buffer.discard() > 0 && !self.eof() {
+            self.inner_seek()?;
+        }
+        if self.eof() && !buf.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::WriteZero,
+                Overflow(buf.len() as u64),
+            ));
+        }
+        if self.buffer.is_some() && !buf.is_empty() {
+            return self.write_aligned(buf);
+        }
+
         let mut written = 0;
         while written < buf.len() && !self.eof() {
-            let to_write = std::cmp::min(self.fragment_remaining() as usize, buf.len() - written);
-            let written_now = self.inner.write(&buf[written..written + to_write])?;
+            let to_write =
+                std::cmp::min(self.fragment_remaining(), (buf.len() - written) as u64) as usize;
+            let written_now = if self.fragment().is_hole() {
+                to_write
+            } else {
+                self.inner.write(&buf[written..written + to_write])?
+            };
+            if written_now == 0 && to_write > 0 {
+                return Err(self.inner_eof_error(std::io::ErrorKind::WriteZero, "write"));
+            }
             written += written_now;
             self.fragment_pos += written_now as u64;
 
@@ -257,15 +853,226 @@ impl<T: Seek + Write> Write for FragmentFile<T> {
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
+        self.write_buffer()?;
+        self.inner.flush()
+    }
+}
+
+/// A writer buffering the writes to a [`FragmentFile`] up to the end of its
+/// current fragment, so the small writes of decompressors don't each become
+/// a write to the underlying file, which is seeked between fragments. Writes
+/// of at least a full buffer go to the [`FragmentFile`] directly.
+///
+/// The buffer is not written on drop, call [`Write::flush`] when done.
+pub(crate) struct FragmentWriter<T> {
+    inner: FragmentFile<T>,
+    buffer: Vec<u8>,
+    capacity: usize,
+}
+
+impl<T: Write + Seek> FragmentWriter<T> {
+    /// Buffer up to `capacity` bytes of the writes to `inner` in `buffer`,
+    /// which may be given back by [`Self::into_buffer`] of another writer,
+    /// so it's allocated once.
+    pub fn with_buffer(inner: FragmentFile<T>, capacity: usize, mut buffer: Vec<u8>) -> Self {
+        buffer.clear();
+        Self {
+            inner,
+            buffer,
+            capacity,
+        }
+    }
+
+    /// The buffer of the writes, which must be flushed first, to write to
+    /// other fragments.
+    pub fn into_buffer(self) -> Vec<u8> {
+        debug_assert!(self.buffer.is_empty(), "writes not flushed");
+        self.buffer
+    }
+
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn flush_buffer(&mut self) -> std::io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.inner.write_all(&self.buffer)?;
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+}
+
+impl<T: Write + Seek> Write for FragmentWriter<T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // `inner` is at the start of the buffer, which is written when it
+        // reaches the end of the fragment, so it's never split.
+        let limit = self.inner.fragment_remaining().min(self.capacity as u64) as usize;
+        if self.buffer.is_empty() && buf.len() >= limit {
+            return self.inner.write(buf);
+        }
+
+        let buffered = buf.len().min(limit - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..buffered]);
+        if self.buffer.len() == limit {
+            self.flush_buffer()?;
+        }
+        Ok(buffered)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.flush_buffer()?;
         self.inner.flush()
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
+    use crate::tests::extent;
     use std::io::Cursor;
 
+    /// A file of `size` bytes, each the remainder of its offset divided by
+    /// 251, generated when read, to test offsets past 4 GiB without storing
+    /// them. Offsets truncated to 32 bits read other bytes.
+    pub(crate) struct Pattern {
+        pub(crate) pos: u64,
+        pub(crate) size: u64,
+    }
+
+    impl Pattern {
+        pub(crate) fn byte(pos: u64) -> u8 {
+            (pos % 251) as u8
+        }
+    }
+
+    impl Read for Pattern {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = (buf.len() as u64).min(self.size.saturating_sub(self.pos)) as usize;
+            for (i, byte) in buf[..len].iter_mut().enumerate() {
+                *byte = Pattern::byte(self.pos + i as u64);
+            }
+            self.pos += len as u64;
+            Ok(len)
+        }
+    }
+
+    impl Seek for Pattern {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.pos = match pos {
+                SeekFrom::Start(pos) => Some(pos),
+                SeekFrom::Current(pos) => self.pos.checked_add_signed(pos),
+                SeekFrom::End(pos) => self.size.checked_add_signed(pos),
+            }
+            .ok_or(std::io::ErrorKind::InvalidInput)?;
+            Ok(self.pos)
+        }
+    }
+
+    #[test]
+    fn past_4_gib() -> std::io::Result<()> {
+        const GIB_4: u64 = 1 << 32;
+        let pattern =
+            |start: u64, len: u64| (start..start + len).map(Pattern::byte).collect::<Vec<_>>();
+        // A block past 4 GiB, one before, and a fragment of more than 4 GiB.
+        let extents = [
+            extent(GIB_4 / 4096 + 3, 1),
+            extent(1, 1),
+            extent(GIB_4 / 4096, GIB_4 / 4096 + 2),
+        ];
+        let inner = Pattern {
+            pos: 0,
+            size: 3 * GIB_4,
+        };
+        let mut file = FragmentFile::new_from_extents(inner, &extents, 4096)?;
+        assert_eq!(file.size(), 2 * 4096 + GIB_4 + 8192);
+
+        let mut buf = vec![0; 8192 + 8];
+        file.read_exact(&mut buf)?;
+        assert_eq!(
+            buf,
+            [
+                pattern(GIB_4 + 3 * 4096, 4096),
+                pattern(4096, 4096),
+                pattern(GIB_4, 8)
+            ]
+            .concat()
+        );
+        file.seek(SeekFrom::End(-8))?;
+        let mut end = Vec::new();
+        file.read_to_end(&mut end)?;
+        assert_eq!(end, pattern(2 * GIB_4 + 8192 - 8, 8));
+
+        // Nothing is borrowed past the end of the data in memory.
+        let section = SectionFile::new(Cursor::new(vec![1u8; 16]), GIB_4 + 4, 8)?;
+        assert_eq!(section.as_slice(), b"");
+        Ok(())
+    }
+
+    #[test]
+    fn section_seek() -> std::io::Result<()> {
+        let vec = (0..16).collect::<Vec<u8>>();
+        let mut section = SectionFile::new(Cursor::new(vec), 4, 8)?;
+
+        assert_eq!(section.seek(SeekFrom::End(-2))?, 6);
+        assert_eq!(section.seek(SeekFrom::Current(-6))?, 0);
+        let mut buf = [0; 3];
+        assert_eq!(section.read(&mut buf)?, 3);
+        assert_eq!(buf, [4, 5, 6]);
+
+        // At the end, nothing is read or written.
+        assert_eq!(section.seek(SeekFrom::End(0))?, 8);
+        assert_eq!(section.read(&mut buf)?, 0);
+        assert_eq!(section.write(&buf)?, 0);
+
+        // Outside of the section.
+        for pos in [SeekFrom::End(1), SeekFrom::Current(-9), SeekFrom::Start(9)] {
+            let error = section.seek(pos).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput, "{:?}", pos);
+        }
+        assert_eq!(section.stream_position()?, 8);
+        Ok(())
+    }
+
+    #[test]
+    fn section_buf_read() -> std::io::Result<()> {
+        let mut vec = (0..16).collect::<Vec<u8>>();
+        let mut section = SectionFile::new(Cursor::new(&mut vec), 4, 8)?;
+
+        // Not buffered past the end of the section.
+        assert_eq!(section.fill_buf()?, [4, 5, 6, 7, 8, 9, 10, 11]);
+        section.consume(3);
+        assert_eq!(section.stream_position()?, 3);
+        let mut buf = [0; 2];
+        section.read_exact(&mut buf)?;
+        assert_eq!(buf, [7, 8]);
+        assert_eq!(section.fill_buf()?, [9, 10, 11]);
+
+        // Writes go after the bytes consumed, not after those read ahead.
+        section.write_all(&[0xff])?;
+        section.seek(SeekFrom::Start(5))?;
+        assert_eq!(section.fill_buf()?, [0xff, 10, 11]);
+        section.consume(3);
+        assert!(section.fill_buf()?.is_empty());
+        assert_eq!(vec[8..13], [8, 0xff, 10, 11, 12]);
+        Ok(())
+    }
+
+    #[test]
+    fn section_as_slice() -> std::io::Result<()> {
+        let vec = (0..16).collect::<Vec<u8>>();
+        assert_eq!(
+            SectionFile::new(Cursor::new(&vec), 4, 3)?.as_slice(),
+            [4, 5, 6]
+        );
+        // Nested sections, and sections past the end of the data.
+        let outer = SectionFile::new(Cursor::new(&vec), 8, 8)?;
+        assert_eq!(SectionFile::new(outer, 6, 4)?.as_slice(), [14, 15]);
+        assert_eq!(SectionFile::new(Cursor::new(&vec), 20, 4)?.as_slice(), []);
+        Ok(())
+    }
+
     #[test]
     fn fragment() -> std::io::Result<()> {
         let mut vec = (0..31).collect::<Vec<_>>();
@@ -297,4 +1104,352 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn fragment_hole() -> std::io::Result<()> {
+        let mut vec = (0..16).collect::<Vec<u8>>();
+        let extents = [(2, 1), (SPARSE_HOLE, 2), (0, 1)]
+            .map(|(start_block, num_blocks)| extent(start_block, num_blocks));
+        let mut file = FragmentFile::new_from_extents(Cursor::new(&mut vec), &extents, 4)?;
+        assert_eq!(file.size(), 16);
+
+        // Holes are read as zeros.
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        assert_eq!(buf, [8, 9, 10, 11, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3]);
+        file.seek(SeekFrom::Start(6))?;
+        let mut buf = [1; 4];
+        file.read_exact(&mut buf)?;
+        assert_eq!(buf, [0; 4]);
+
+        // Writes to holes are discarded.
+        file.rewind()?;
+        file.write_all(&[0xff; 16])?;
+        let mut expected = (0..16).collect::<Vec<u8>>();
+        expected[0..4].fill(0xff);
+        expected[8..12].fill(0xff);
+        assert_eq!(vec, expected);
+
+        // Starting with a hole, the underlying file is not seeked.
+        let extents = [extent(SPARSE_HOLE, 1)];
+        let mut file = FragmentFile::new_from_extents(Cursor::new(Vec::new()), &extents, 4096)?;
+        file.write_all(&[1; 4096])?;
+        assert!(file.get_mut().get_ref().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn fragment_buf_read() -> std::io::Result<()> {
+        let mut vec = (0..16).collect::<Vec<u8>>();
+        let extents = [(2, 1), (SPARSE_HOLE, 1), (0, 1)]
+            .map(|(start_block, num_blocks)| extent(start_block, num_blocks));
+        let mut file = FragmentFile::new_from_extents(Cursor::new(&mut vec), &extents, 4)?;
+
+        // Each fragment is buffered on its own, the next one only once the
+        // current one is consumed.
+        assert_eq!(file.fill_buf()?, [8, 9, 10, 11]);
+        file.consume(3);
+        assert_eq!(file.fill_buf()?, [11]);
+        file.consume(1);
+        assert_eq!(file.stream_position()?, 4);
+        assert_eq!(file.fill_buf()?, [0; 4]);
+        file.consume(2);
+        let mut buf = [1; 4];
+        file.read_exact(&mut buf)?;
+        assert_eq!(buf, [0, 0, 0, 1]);
+        assert_eq!(file.fill_buf()?, [2, 3]);
+        file.consume(2);
+        assert!(file.fill_buf()?.is_empty());
+
+        // Seeking and writing forget the bytes read ahead.
+        file.seek(SeekFrom::Start(1))?;
+        assert_eq!(file.fill_buf()?, [9, 10, 11]);
+        file.consume(1);
+        file.write_all(&[0xff])?;
+        file.seek(SeekFrom::Start(0))?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        assert_eq!(buf, [8, 9, 0xff, 11, 0, 0, 0, 0, 0, 1, 2, 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn fragment_short_inner() {
+        let fragments = [
+            Fragment { offset: 0, size: 4 },
+            Fragment { offset: 8, size: 4 },
+        ];
+        // Reading or writing used to loop forever at the end of the
+        // underlying file.
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut fvec = FragmentFile::new(Cursor::new(vec![1u8; 10]), &fragments).unwrap();
+            let read = fvec.read(&mut [0; 8]).unwrap_err();
+            let mut buf = [0u8; 10];
+            let mut fvec = FragmentFile::new(Cursor::new(&mut buf[..]), &fragments).unwrap();
+            let written = fvec.write(&[2; 8]).unwrap_err();
+            tx.send((read, written)).unwrap();
+        });
+        let (read, written) = rx
+            .recv_timeout(std::time::Duration::from_secs(10))
+            .expect("hung");
+        assert_eq!(read.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(
+            read.to_string(),
+            "failed to read at offset 10 (position 6 of the extents), the file is too short"
+        );
+        assert_eq!(written.kind(), std::io::ErrorKind::WriteZero);
+    }
+
+    /// A cursor counting the writes to it.
+    struct CountingCursor {
+        cursor: Cursor<Vec<u8>>,
+        writes: usize,
+    }
+
+    impl Write for CountingCursor {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.writes += 1;
+            self.cursor.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for CountingCursor {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.cursor.seek(pos)
+        }
+    }
+
+    #[test]
+    fn fragment_writer() -> std::io::Result<()> {
+        let fragments = [
+            Fragment {
+                offset: 4096,
+                size: 3000,
+            },
+            Fragment {
+                offset: 0,
+                size: 1000,
+            },
+        ];
+        let data: Vec<u8> = (0..4000u32).map(|i| i as u8).collect();
+        let mut expected = vec![0u8; 8192];
+        expected[4096..7096].copy_from_slice(&data[..3000]);
+        expected[..1000].copy_from_slice(&data[3000..]);
+
+        let write = |capacity: Option<usize>| -> std::io::Result<usize> {
+            let inner = CountingCursor {
+                cursor: Cursor::new(vec![0; 8192]),
+                writes: 0,
+            };
+            let mut fvec = FragmentFile::new(inner, &fragments)?;
+            match capacity {
+                Some(capacity) => {
+                    let mut writer = FragmentWriter::with_buffer(fvec, capacity, Vec::new());
+                    data.chunks(7)
+                        .try_for_each(|chunk| writer.write_all(chunk))?;
+                    writer.write_all(&[1]).unwrap_err();
+                    writer.flush()?;
+                    fvec = writer.inner;
+                }
+                None => data.chunks(7).try_for_each(|chunk| fvec.write_all(chunk))?,
+            }
+            let inner = fvec.get_mut();
+            assert_eq!(inner.cursor.get_ref(), &expected);
+            Ok(inner.writes)
+        };
+
+        assert_eq!(write(None)?, 573);
+        // Split at 2048 and at the end of the first fragment.
+        assert_eq!(write(Some(2048))?, 3);
+        assert_eq!(write(Some(1 << 20))?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn fragment_coalesce() -> std::io::Result<()> {
+        // 1000 contiguous extents of one block, then a hole and a block
+        // before them.
+        let mut extents: Vec<_> = (0..1000).map(|i| extent(10 + i, 1)).collect();
+        extents.extend([extent(SPARSE_HOLE, 1), extent(SPARSE_HOLE, 2), extent(2, 1)]);
+        let data: Vec<u8> = (0..1010 * 4u32).map(|i| (i % 251) as u8).collect();
+
+        let mut fvec = FragmentFile::new_from_extents(Cursor::new(data.clone()), &extents, 4)?;
+        assert_eq!(fvec.fragments.len(), 3);
+        assert_eq!(fvec.size(), 1004 * 4);
+        let mut read = Vec::new();
+        fvec.read_to_end(&mut read)?;
+        let expected = [&data[40..4040], &[0; 12], &data[8..12]].concat();
+        assert_eq!(read, expected);
+
+        // Seek into the middle of the run and across to the hole.
+        assert_eq!(fvec.seek(SeekFrom::Start(2001))?, 2001);
+        let mut buf = [0; 8];
+        fvec.read_exact(&mut buf)?;
+        assert_eq!(buf, expected[2001..2009]);
+        fvec.seek(SeekFrom::Start(3998))?;
+        fvec.read_exact(&mut buf)?;
+        assert_eq!(buf, expected[3998..4006]);
+
+        // Writes land at the same bytes as with separate fragments.
+        fvec.rewind()?;
+        fvec.write_all(&vec![0xaa; 4016])?;
+        let inner = fvec.get_mut().get_ref();
+        assert_eq!(&inner[..8], &data[..8]);
+        assert_eq!(&inner[8..12], &[0xaa; 4]);
+        assert_eq!(&inner[12..40], &data[12..40]);
+        assert!(inner[40..4040].iter().all(|&b| b == 0xaa));
+        Ok(())
+    }
+
+    #[test]
+    fn copy_extent_pairs() -> std::io::Result<()> {
+        let data: Vec<u8> = (0..32).collect();
+        // The extents are split at different blocks, with holes on both
+        // sides.
+        let src_extents = [extent(4, 3), extent(SPARSE_HOLE, 1), extent(0, 2)];
+        let dst_extents = [extent(0, 1), extent(SPARSE_HOLE, 2), extent(5, 3)];
+        let expected = [&data[16..20], &[0xff; 16], &[0; 4], &data[..8]].concat();
+
+        let mut dst = Cursor::new(vec![0xff; 32]);
+        assert_eq!(
+            copy_extents(
+                &mut Cursor::new(&data),
+                &src_extents,
+                &mut dst,
+                &dst_extents,
+                4
+            )?,
+            24
+        );
+        assert_eq!(dst.into_inner(), expected);
+
+        // Files, which Linux copies with copy_file_range.
+        let dir = crate::tests::TempDir::new("copy-extents");
+        let (src_path, dst_path) = (dir.0.join("src"), dir.0.join("dst"));
+        std::fs::write(&src_path, &data)?;
+        std::fs::write(&dst_path, [0xff; 32])?;
+        let mut src = std::fs::File::open(&src_path)?;
+        let mut dst = std::fs::OpenOptions::new().write(true).open(&dst_path)?;
+        let copied = copy_extents(&mut src, &src_extents, &mut dst, &dst_extents, 4);
+        let written = std::fs::read(&dst_path);
+        assert_eq!(copied?, 24);
+        assert_eq!(written?, expected);
+
+        // The copy stops where the source ends.
+        let mut dst = Cursor::new(vec![0; 32]);
+        assert_eq!(
+            copy_extents(
+                &mut Cursor::new(&data[..18]),
+                &[extent(4, 2)],
+                &mut dst,
+                &[extent(0, 2)],
+                4
+            )?,
+            2
+        );
+        Ok(())
+    }
+
+    /// A cursor checking that writes are aligned to 512 bytes, in memory
+    /// and in the cursor.
+    struct AlignedCursor(Cursor<Vec<u8>>);
+
+    impl Write for AlignedCursor {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            assert_eq!(buf.as_ptr() as usize % 512, 0);
+            assert_eq!(buf.len() % 512, 0);
+            assert_eq!(self.0.position() % 512, 0);
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for AlignedCursor {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    #[test]
+    fn fragment_aligned() -> std::io::Result<()> {
+        let extents = [extent(3, 2), extent(SPARSE_HOLE, 1), extent(0, 1)];
+        let data: Vec<u8> = (0..1948u32).map(|i| (i % 251) as u8).collect();
+        let inner = AlignedCursor(Cursor::new(vec![0xff; 2560]));
+        let mut fvec = FragmentFile::new_from_extents_with(inner, &extents, 512, true)?;
+        data.chunks(7).try_for_each(|chunk| fvec.write_all(chunk))?;
+        assert_eq!(fvec.stream_position()?, 1948);
+        assert_eq!(
+            fvec.seek(SeekFrom::Start(0)).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+        fvec.flush()?;
+        assert_eq!(fvec.stream_position()?, 2048);
+        fvec.write_all(&[1]).unwrap_err();
+
+        // The last block is padded with zeros.
+        let mut expected = vec![0xff; 2560];
+        expected[1536..2560].copy_from_slice(&data[..1024]);
+        expected[..412].copy_from_slice(&data[1536..]);
+        expected[412..512].fill(0);
+        assert_eq!(fvec.get_mut().0.get_ref(), &expected);
+        Ok(())
+    }
+
+    #[test]
+    fn fragment_seek() -> std::io::Result<()> {
+        let vec = (0..31).collect::<Vec<u8>>();
+        let fragments = [
+            Fragment { offset: 0, size: 5 },
+            Fragment {
+                offset: 20,
+                size: 2,
+            },
+            Fragment {
+                offset: 10,
+                size: 3,
+            },
+        ];
+        let mut fvec = FragmentFile::new(Cursor::new(vec), &fragments)?;
+        let read_byte = |fvec: &mut FragmentFile<_>| -> std::io::Result<Option<u8>> {
+            let mut buf = [0];
+            Ok((fvec.read(&mut buf)? == 1).then_some(buf[0]))
+        };
+
+        assert_eq!(fvec.seek(SeekFrom::Start(0))?, 0);
+        assert_eq!(read_byte(&mut fvec)?, Some(0));
+        // Fragment boundaries, at the start of the next fragment.
+        assert_eq!(fvec.seek(SeekFrom::Start(5))?, 5);
+        assert_eq!(read_byte(&mut fvec)?, Some(20));
+        assert_eq!(fvec.seek(SeekFrom::Start(7))?, 7);
+        assert_eq!(read_byte(&mut fvec)?, Some(10));
+        // Backwards across fragments.
+        assert_eq!(fvec.seek(SeekFrom::Current(-5))?, 3);
+        assert_eq!(read_byte(&mut fvec)?, Some(3));
+        assert_eq!(fvec.seek(SeekFrom::End(-1))?, 9);
+        assert_eq!(read_byte(&mut fvec)?, Some(12));
+        assert_eq!(fvec.stream_position()?, 10);
+
+        // Past the end, reads return nothing and writes fail.
+        assert_eq!(fvec.seek(SeekFrom::End(3))?, 13);
+        assert_eq!(fvec.stream_position()?, 13);
+        assert_eq!(read_byte(&mut fvec)?, None);
+        let error = fvec.write(&[1, 2]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::WriteZero);
+        assert_eq!(error.to_string(), "2 bytes past the end of the extents");
+        assert_eq!(fvec.seek(SeekFrom::Current(-4))?, 9);
+        assert_eq!(read_byte(&mut fvec)?, Some(12));
+
+        // Before the start.
+        let error = fvec.seek(SeekFrom::Current(-11)).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        Ok(())
+    }
 }
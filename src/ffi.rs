@@ -0,0 +1,354 @@
+//! C API, with the `ffi` feature, declared in `include/payload_dumper.h`.
+//!
+//! Functions return [`PAYLOAD_OK`] or a negative error code, or null, and
+//! the message of the last error of the calling thread is returned by
+//! [`payload_last_error_message`]. Panics are caught in each function and
+//! reported as [`PAYLOAD_ERROR_PANIC`], they never unwind into C.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::fs::File;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
+
+use crate::{create_image, open_payload, DeltaUpdateFile, DumpOptions, PayloadError, SectionFile};
+
+/// Success.
+pub const PAYLOAD_OK: c_int = 0;
+/// The payload can't be read, or the partition can't be extracted.
+pub const PAYLOAD_ERROR: c_int = -1;
+/// An argument is null, not UTF-8, or not a partition of the payload.
+pub const PAYLOAD_ERROR_INVALID_ARGUMENT: c_int = -2;
+/// The progress callback stopped the extraction.
+pub const PAYLOAD_ERROR_CANCELLED: c_int = -3;
+/// A bug, the function panicked.
+pub const PAYLOAD_ERROR_PANIC: c_int = -4;
+
+/// Called with the number of operations of the partition applied and their
+/// total, maybe from another thread. Returning zero stops the extraction.
+pub type PayloadProgressCallback =
+    Option<extern "C" fn(done: u64, total: u64, userdata: *mut c_void) -> c_int>;
+
+/// A payload opened with [`payload_open`], freed with [`payload_close`].
+pub struct Payload {
+    payload: crate::Payload<SectionFile<File>>,
+    /// Names of the partitions, returned by [`payload_partition_name`].
+    names: Vec<CString>,
+}
+
+/// An error code and its message.
+struct Error(c_int, String);
+
+impl From<PayloadError> for Error {
+    fn from(error: PayloadError) -> Self {
+        Error(PAYLOAD_ERROR, error.to_string())
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Run `f`, catching panics, and record the message if it fails.
+fn catch<T>(f: impl FnOnce() -> Result<T, Error>) -> Result<T, c_int> {
+    let result = catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|panic| Err(Error(PAYLOAD_ERROR_PANIC, panic_message(panic))));
+    result.map_err(|Error(code, message)| {
+        let message = CString::new(message.replace('\0', " ")).expect("NULs are replaced");
+        LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+        code
+    })
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    let message = match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => panic
+            .downcast_ref::<&str>()
+            .copied()
+            .unwrap_or("unknown panic")
+            .to_string(),
+    };
+    format!("panicked: {}", message)
+}
+
+/// The string at `ptr`, the argument `name`.
+///
+/// # Safety
+///
+/// `ptr` must be null or a NUL terminated string.
+unsafe fn str_arg<'a>(ptr: *const c_char, name: &str) -> Result<&'a str, Error> {
+    if ptr.is_null() {
+        return Err(Error(
+            PAYLOAD_ERROR_INVALID_ARGUMENT,
+            format!("{} is null", name),
+        ));
+    }
+    CStr::from_ptr(ptr).to_str().map_err(|_| {
+        Error(
+            PAYLOAD_ERROR_INVALID_ARGUMENT,
+            format!("{} is not UTF-8", name),
+        )
+    })
+}
+
+/// The payload at `ptr`.
+///
+/// # Safety
+///
+/// `ptr` must be null or returned by [`payload_open`] and not closed.
+unsafe fn payload_arg<'a>(ptr: *mut Payload) -> Result<&'a mut Payload, Error> {
+    ptr.as_mut().ok_or_else(|| {
+        Error(
+            PAYLOAD_ERROR_INVALID_ARGUMENT,
+            "payload is null".to_string(),
+        )
+    })
+}
+
+/// Open the payload, or the OTA zip file with the payload, at `path`.
+/// Returns null if it fails.
+///
+/// # Safety
+///
+/// `path` must be null or a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn payload_open(path: *const c_char) -> *mut Payload {
+    catch(|| {
+        let path = str_arg(path, "path")?;
+        let error = |e: &dyn std::fmt::Display| Error(PAYLOAD_ERROR, format!("{}: {}", path, e));
+        let mut reader = File::open(path)
+            .and_then(open_payload)
+            .map_err(|e| error(&e))?;
+        // The payload signature isn't needed to extract.
+        let file = DeltaUpdateFile::read_metadata(&mut reader).map_err(|e| error(&e))?;
+        let payload = crate::Payload::new(reader, file);
+        let names = payload
+            .partitions()
+            .iter()
+            .map(|p| CString::new(p.partition_name.replace('\0', "_")).expect("NULs are replaced"))
+            .collect();
+        Ok(Box::into_raw(Box::new(Payload { payload, names })))
+    })
+    .unwrap_or(std::ptr::null_mut())
+}
+
+/// Number of partitions of `payload`, 0 if it's null.
+///
+/// # Safety
+///
+/// `payload` must be null or returned by [`payload_open`] and not closed.
+#[no_mangle]
+pub unsafe extern "C" fn payload_partition_count(payload: *mut Payload) -> usize {
+    catch(|| Ok(payload_arg(payload)?.names.len())).unwrap_or(0)
+}
+
+/// Name of the partition `index` of `payload`, valid until it's closed.
+/// Returns null if `index` is out of range.
+///
+/// # Safety
+///
+/// `payload` must be null or returned by [`payload_open`] and not closed.
+#[no_mangle]
+pub unsafe extern "C" fn payload_partition_name(
+    payload: *mut Payload,
+    index: usize,
+) -> *const c_char {
+    catch(|| {
+        let names = &payload_arg(payload)?.names;
+        let name = names.get(index).ok_or_else(|| {
+            Error(
+                PAYLOAD_ERROR_INVALID_ARGUMENT,
+                format!(
+                    "partition index {} is out of range, the payload has {} partitions",
+                    index,
+                    names.len()
+                ),
+            )
+        })?;
+        Ok(name.as_ptr())
+    })
+    .unwrap_or(std::ptr::null())
+}
+
+/// Extract the partition `name` of `payload` to a new image at `out_path`,
+/// calling `progress`, if not null, with `userdata` as operations are
+/// applied. Only full payloads can be extracted, as the old images of
+/// delta payloads can't be given.
+///
+/// # Safety
+///
+/// `payload` must be null or returned by [`payload_open`] and not closed,
+/// and `name` and `out_path` null or NUL terminated strings. `userdata` is
+/// only passed to `progress`.
+#[no_mangle]
+pub unsafe extern "C" fn payload_extract_partition(
+    payload: *mut Payload,
+    name: *const c_char,
+    out_path: *const c_char,
+    progress: PayloadProgressCallback,
+    userdata: *mut c_void,
+) -> c_int {
+    /// `userdata`, which the caller lets the callback use from any thread.
+    struct UserData(*mut c_void);
+    unsafe impl Sync for UserData {}
+    impl UserData {
+        fn get(&self) -> *mut c_void {
+            self.0
+        }
+    }
+
+    catch(|| {
+        let payload = &mut payload_arg(payload)?.payload;
+        let name = str_arg(name, "name")?;
+        let out_path = Path::new(str_arg(out_path, "out_path")?);
+        let partition = payload.partition(name).ok_or_else(|| {
+            Error(
+                PAYLOAD_ERROR_INVALID_ARGUMENT,
+                PayloadError::PartitionNotFound(name.to_string()).to_string(),
+            )
+        })?;
+        let total = partition.operations.len() as u64;
+
+        let mut img = create_image(out_path, partition)?;
+        let userdata = UserData(userdata);
+        let report =
+            |done: u64| progress.is_none_or(|progress| progress(done, total, userdata.get()) != 0);
+        let stats = payload.dump_partition_with(
+            name,
+            None::<&mut File>,
+            &mut img,
+            &DumpOptions::default(),
+            |index| report(index as u64),
+        )?;
+        match stats {
+            Some(_) if report(total) => Ok(PAYLOAD_OK),
+            _ => Err(Error(
+                PAYLOAD_ERROR_CANCELLED,
+                format!("extracting {} was cancelled", name),
+            )),
+        }
+    })
+    .unwrap_or_else(|code| code)
+}
+
+/// Message of the last error on this thread, or null if there was none.
+/// It's valid until the next call failing on this thread.
+#[no_mangle]
+pub extern "C" fn payload_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// Close `payload`, which may be null.
+///
+/// # Safety
+///
+/// `payload` must be null or returned by [`payload_open`] and not closed.
+#[no_mangle]
+pub unsafe extern "C" fn payload_close(payload: *mut Payload) {
+    if !payload.is_null() {
+        let _ = catch(|| {
+            drop(Box::from_raw(payload));
+            Ok(())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+    use crate::tests::TempDir;
+    use crate::{CreateOptions, PayloadBuilder};
+
+    extern "C" fn count(done: u64, total: u64, userdata: *mut c_void) -> c_int {
+        let calls = unsafe { &*(userdata as *const AtomicU64) };
+        assert!(done <= total);
+        calls.fetch_add(1, Ordering::Relaxed);
+        1
+    }
+
+    extern "C" fn stop(_: u64, _: u64, _: *mut c_void) -> c_int {
+        0
+    }
+
+    fn last_error() -> String {
+        let message = payload_last_error_message();
+        assert!(!message.is_null());
+        unsafe { CStr::from_ptr(message) }
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn extract() -> Result<(), Box<dyn std::error::Error>> {
+        let image: Vec<u8> = (0..8192u32).map(|i| (i % 251) as u8).collect();
+        let mut builder = PayloadBuilder::new(Cursor::new(Vec::new()), CreateOptions::default())?;
+        builder.add_partition("boot", &image[..])?;
+        let dir = TempDir::new("ffi");
+        let path = dir.0.join("payload.bin");
+        builder.finish(&mut File::create(&path)?)?;
+        let c_path = CString::new(path.to_str().unwrap())?;
+        let out = dir.0.join("boot.img");
+        let c_out = CString::new(out.to_str().unwrap())?;
+
+        unsafe {
+            assert!(payload_open(std::ptr::null()).is_null());
+            assert_eq!(last_error(), "path is null");
+            let missing = CString::new(dir.0.join("missing.bin").to_str().unwrap())?;
+            assert!(payload_open(missing.as_ptr()).is_null());
+            assert!(last_error().contains("missing.bin"));
+
+            let payload = payload_open(c_path.as_ptr());
+            assert!(!payload.is_null());
+            assert_eq!(payload_partition_count(payload), 1);
+            assert_eq!(
+                CStr::from_ptr(payload_partition_name(payload, 0)).to_str()?,
+                "boot"
+            );
+            assert!(payload_partition_name(payload, 1).is_null());
+
+            let calls = AtomicU64::new(0);
+            let userdata = &calls as *const AtomicU64 as *mut c_void;
+            let code = payload_extract_partition(
+                payload,
+                c"boot".as_ptr(),
+                c_out.as_ptr(),
+                Some(count),
+                userdata,
+            );
+            assert_eq!(code, PAYLOAD_OK);
+            assert_eq!(std::fs::read(&out)?, image);
+            assert!(calls.load(Ordering::Relaxed) >= 2);
+
+            let code = payload_extract_partition(
+                payload,
+                c"boot".as_ptr(),
+                c_out.as_ptr(),
+                Some(stop),
+                std::ptr::null_mut(),
+            );
+            assert_eq!(code, PAYLOAD_ERROR_CANCELLED);
+            let code = payload_extract_partition(
+                payload,
+                c"system".as_ptr(),
+                c_out.as_ptr(),
+                None,
+                std::ptr::null_mut(),
+            );
+            assert_eq!(code, PAYLOAD_ERROR_INVALID_ARGUMENT);
+            assert_eq!(last_error(), "partition system not found");
+            payload_close(payload);
+            payload_close(std::ptr::null_mut());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,273 @@
+use std::borrow::Cow;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+
+use binrw::meta::{EndianKind, WriteEndian};
+use binrw::{BinResult, BinWrite, Endian};
+use prost::Message;
+
+use crate::chromeos_update_engine::{DeltaArchiveManifest, PartitionUpdate, Signatures};
+use crate::{DeltaUpdateFile, PayloadError, PayloadType, SignatureError};
+
+/// The contiguous prefix of a payload, everything before the data blobs.
+///
+/// Unlike [`DeltaUpdateFile`], the payload signature at the end of the data
+/// blobs is not read, so the header can be parsed from a stream without
+/// seeking, and the payload signature is read with
+/// [`PayloadHeader::read_payload_signatures`] only when needed.
+#[derive(Debug, Clone)]
+pub struct PayloadHeader {
+    /// Payload major version.
+    pub file_format_version: u64,
+    /// Size of protobuf DeltaArchiveManifest.
+    pub manifest_size: u64,
+    /// Size of metadata signature, 0 if file_format_version < 2.
+    pub metadata_signature_size: u32,
+    /// DeltaArchiveManifest protobuf serialized, as is in the payload. It's
+    /// read once, and kept for hashes and signatures.
+    pub manifest_data: Vec<u8>,
+    /// The decoded `manifest_data`.
+    pub manifest: DeltaArchiveManifest,
+    /// Size of the metadata, from the beginning of the payload to the end of
+    /// the manifest.
+    pub metadata_size: u64,
+    /// SHA-256 hash of the metadata, which is signed by
+    /// `metadata_signature_message`.
+    pub metadata_hash: [u8; 32],
+    /// Serialized Signatures message of the metadata.
+    pub metadata_signature_message: Vec<u8>,
+    /// Offset of the data blobs, from the beginning of the payload.
+    pub blobs_offset: u64,
+}
+
+impl PayloadHeader {
+    /// Parse the header from `reader`, which is at the beginning of the
+    /// payload. Only the header is read, leaving `reader` at the data blobs.
+    pub fn parse_prefix<R: Read>(reader: &mut R) -> Result<Self, PayloadError> {
+        let mut header = [0; 20];
+        reader.read_exact(&mut header)?;
+        if &header[..4] != b"CrAU" {
+            return Err(binrw::Error::BadMagic {
+                pos: 0,
+                found: Box::new(header[..4].to_vec()),
+            }
+            .into());
+        }
+        let file_format_version = u64::from_be_bytes(header[4..12].try_into().unwrap());
+        let manifest_size = u64::from_be_bytes(header[12..20].try_into().unwrap());
+        let mut metadata_signature_size = 0;
+        let mut header_size = 20;
+        if file_format_version >= 2 {
+            let mut size = [0; 4];
+            reader.read_exact(&mut size)?;
+            metadata_signature_size = u32::from_be_bytes(size);
+            header_size += 4;
+        }
+
+        let manifest_data = crate::read_manifest(reader, manifest_size)?;
+        let manifest =
+            DeltaArchiveManifest::decode(&manifest_data[..]).map_err(|e| binrw::Error::Custom {
+                pos: header_size,
+                err: Box::new(e),
+            })?;
+
+        // Like the manifest, grow the buffer with the bytes read instead of
+        // trusting the size.
+        let mut metadata_signature_message = Vec::new();
+        reader
+            .take(metadata_signature_size.into())
+            .read_to_end(&mut metadata_signature_message)?;
+        if metadata_signature_message.len() != metadata_signature_size as usize {
+            return Err(
+                Error::new(ErrorKind::UnexpectedEof, "metadata signature is truncated").into(),
+            );
+        }
+
+        let metadata_size = header_size + manifest_size;
+        Ok(Self {
+            file_format_version,
+            manifest_size,
+            metadata_signature_size,
+            metadata_hash: crate::metadata_hash(
+                file_format_version,
+                metadata_signature_size,
+                &manifest_data,
+            ),
+            manifest_data,
+            manifest,
+            metadata_size,
+            metadata_signature_message,
+            blobs_offset: metadata_size + metadata_signature_size as u64,
+        })
+    }
+
+    /// Partitions updated by this payload, see [`DeltaUpdateFile::partitions`].
+    pub fn partitions(&self) -> Cow<'_, [PartitionUpdate]> {
+        crate::manifest_partitions(self.file_format_version, &self.manifest)
+    }
+
+    /// Whether this is a full or a delta payload, see
+    /// [`DeltaUpdateFile::payload_type`].
+    pub fn payload_type(&self) -> PayloadType {
+        PayloadType::of(&self.manifest)
+    }
+
+    /// Whether this is a delta payload, which needs the old partitions.
+    pub fn is_delta(&self) -> bool {
+        self.payload_type() == PayloadType::Delta
+    }
+
+    /// Whether this is a partial update, see
+    /// [`DeltaUpdateFile::is_partial_update`].
+    pub fn is_partial_update(&self) -> bool {
+        self.manifest.partial_update()
+    }
+
+    /// Size of the payload, see [`DeltaUpdateFile::payload_size`].
+    pub fn payload_size(&self) -> u64 {
+        crate::payload_size(self.file_format_version, &self.manifest, self.blobs_offset)
+    }
+
+    /// Decode `metadata_signature_message`, see
+    /// [`DeltaUpdateFile::metadata_signatures`].
+    pub fn metadata_signatures(&self) -> Result<Signatures, SignatureError> {
+        crate::signature::decode_signatures(&self.metadata_signature_message)
+    }
+
+    /// Read the serialized Signatures message of the payload from `reader`,
+    /// whose position 0 is the beginning of the payload. It is empty if the
+    /// payload is not signed.
+    pub fn read_payload_signatures<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+    ) -> Result<Vec<u8>, PayloadError> {
+        let (offset, size) = match (
+            self.manifest.signatures_offset,
+            self.manifest.signatures_size,
+        ) {
+            (Some(offset), Some(size)) => (offset, size),
+            _ => return Ok(Vec::new()),
+        };
+        reader.seek(SeekFrom::Start(crate::blob_offset(
+            self.blobs_offset,
+            offset,
+        )?))?;
+        let mut message = Vec::new();
+        Read::by_ref(reader).take(size).read_to_end(&mut message)?;
+        if message.len() as u64 != size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "payload signature is truncated",
+            )
+            .into());
+        }
+        Ok(message)
+    }
+
+    /// Convert to a [`DeltaUpdateFile`] with the payload signature read by
+    /// [`PayloadHeader::read_payload_signatures`].
+    pub fn into_delta_update_file(
+        self,
+        payload_signatures_message_data: Vec<u8>,
+    ) -> DeltaUpdateFile {
+        DeltaUpdateFile {
+            file_format_version: self.file_format_version,
+            manifest_size: self.manifest_size,
+            metadata_signature_size: self.metadata_signature_size,
+            manifest_data: self.manifest_data,
+            manifest: self.manifest,
+            metadata_size: self.metadata_size,
+            metadata_hash: self.metadata_hash,
+            metadata_signature_message: self.metadata_signature_message,
+            blobs_offset: self.blobs_offset,
+            payload_signatures_message_data,
+        }
+    }
+}
+
+/// Writes the header, up to the data blobs, as [`PayloadHeader::parse_prefix`]
+/// reads it.
+///
+/// `manifest_data` is written as is if `manifest` is what it decodes to, so
+/// an unmodified header is written back byte for byte, otherwise `manifest`
+/// is encoded again. `manifest_size` and `metadata_signature_size` are those
+/// of the manifest and the metadata signature written, the other sizes and
+/// offsets are not written. A metadata signature no longer matches a
+/// modified manifest, clear `metadata_signature_message` then. Payloads are
+/// big endian, whatever the endian given.
+impl BinWrite for PayloadHeader {
+    type Args<'a> = ();
+
+    fn write_options<W: Write + Seek>(&self, writer: &mut W, _: Endian, _: ()) -> BinResult<()> {
+        let manifest = manifest_bytes(&self.manifest_data, &self.manifest);
+        Ok(write_header(
+            writer,
+            self.file_format_version,
+            &manifest,
+            &self.metadata_signature_message,
+        )?)
+    }
+}
+
+impl WriteEndian for PayloadHeader {
+    const ENDIAN: EndianKind = EndianKind::Endian(Endian::Big);
+}
+
+/// Writes the header of the payload like [`PayloadHeader`] does. The payload
+/// signature is not written, it's at the end of the data blobs.
+impl BinWrite for DeltaUpdateFile {
+    type Args<'a> = ();
+
+    fn write_options<W: Write + Seek>(&self, writer: &mut W, _: Endian, _: ()) -> BinResult<()> {
+        let manifest = manifest_bytes(&self.manifest_data, &self.manifest);
+        Ok(write_header(
+            writer,
+            self.file_format_version,
+            &manifest,
+            &self.metadata_signature_message,
+        )?)
+    }
+}
+
+impl WriteEndian for DeltaUpdateFile {
+    const ENDIAN: EndianKind = EndianKind::Endian(Endian::Big);
+}
+
+/// The manifest to write, `manifest_data` if `manifest` is what it decodes
+/// to, or `manifest` encoded again.
+fn manifest_bytes<'a>(manifest_data: &'a [u8], manifest: &DeltaArchiveManifest) -> Cow<'a, [u8]> {
+    match DeltaArchiveManifest::decode(manifest_data) {
+        Ok(decoded) if decoded == *manifest => Cow::Borrowed(manifest_data),
+        _ => Cow::Owned(manifest.encode_to_vec()),
+    }
+}
+
+/// Write the header of a payload of major version `file_format_version`
+/// with the serialized `manifest` and `metadata_signature`, up to the data
+/// blobs. Major version 1 payloads have no metadata signature.
+pub(crate) fn write_header<W: Write>(
+    out: &mut W,
+    file_format_version: u64,
+    manifest: &[u8],
+    metadata_signature: &[u8],
+) -> std::io::Result<()> {
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, message);
+    out.write_all(b"CrAU")?;
+    out.write_all(&file_format_version.to_be_bytes())?;
+    out.write_all(&(manifest.len() as u64).to_be_bytes())?;
+    if file_format_version >= 2 {
+        let size = u32::try_from(metadata_signature.len()).map_err(|_| {
+            invalid(format!(
+                "metadata signature of {} bytes is too large",
+                metadata_signature.len()
+            ))
+        })?;
+        out.write_all(&size.to_be_bytes())?;
+    } else if !metadata_signature.is_empty() {
+        return Err(invalid(format!(
+            "payload major version {} has no metadata signature",
+            file_format_version
+        )));
+    }
+    out.write_all(manifest)?;
+    out.write_all(metadata_signature)
+}
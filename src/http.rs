@@ -0,0 +1,292 @@
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom};
+
+use reqwest::blocking::Client;
+use reqwest::header::{CONTENT_RANGE, RANGE};
+use reqwest::StatusCode;
+
+/// Bytes fetched by each request at least, so small reads of the parser
+/// and the decompressors don't each pay a round trip.
+const READ_AHEAD: u64 = 1 << 20;
+
+/// Number of fetched ranges kept in the cache.
+const CACHED_RANGES: usize = 8;
+
+/// A file read over HTTP(S) with range requests.
+///
+/// Recently fetched ranges are cached, so only the parts actually read,
+/// like the manifest and the data of the selected partitions, are
+/// transferred.
+pub struct HttpReader {
+    client: Client,
+    url: String,
+    size: u64,
+    pos: u64,
+    /// Fetched ranges as `(offset, data)`, most recently used first.
+    cache: VecDeque<(u64, Vec<u8>)>,
+}
+
+impl HttpReader {
+    /// Open the file at `url`. The server must support range requests.
+    pub fn new(url: &str) -> Result<Self> {
+        let mut reader = Self {
+            client: Client::new(),
+            url: url.to_string(),
+            size: 0,
+            pos: 0,
+            cache: VecDeque::new(),
+        };
+        reader.size = reader.fetch(0, READ_AHEAD)?;
+        Ok(reader)
+    }
+
+    /// Size of the file.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Fetch up to `length` bytes at `offset` into the cache, returning the
+    /// size of the file from the `Content-Range` header.
+    fn fetch(&mut self, offset: u64, length: u64) -> Result<u64> {
+        let response = self
+            .client
+            .get(&self.url)
+            .header(RANGE, format!("bytes={}-{}", offset, offset + length - 1))
+            .send()
+            .map_err(Error::other)?;
+
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!(
+                    "{}: server responded {} to a range request",
+                    self.url,
+                    response.status()
+                ),
+            ));
+        }
+        // Content-Range: bytes <first>-<last>/<size>
+        let size = response
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit_once('/'))
+            .and_then(|(_, size)| size.parse().ok())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "invalid Content-Range header"))?;
+        let data = response.bytes().map_err(Error::other)?.to_vec();
+
+        self.cache.truncate(CACHED_RANGES - 1);
+        self.cache.push_front((offset, data));
+        Ok(size)
+    }
+
+    /// Move the cached range containing `pos` to the front of the cache,
+    /// returning false if there's none.
+    fn cached(&mut self, pos: u64) -> bool {
+        let index = self
+            .cache
+            .iter()
+            .position(|(offset, data)| (*offset..*offset + data.len() as u64).contains(&pos));
+        match index.and_then(|index| self.cache.remove(index)) {
+            Some(range) => {
+                self.cache.push_front(range);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Read for HttpReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.pos >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        if !self.cached(self.pos) {
+            let length = (buf.len() as u64).max(READ_AHEAD).min(self.size - self.pos);
+            self.fetch(self.pos, length)?;
+        }
+
+        let (offset, data) = &self.cache[0];
+        let available = &data[(self.pos - offset) as usize..];
+        if available.is_empty() {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "server returned a short range",
+            ));
+        }
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for HttpReader {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.size.checked_add_signed(delta),
+        };
+        self.pos = pos.ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    const MIB: u64 = 1 << 20;
+
+    /// Ranges requested from [`serve`] as `(offset, length)`.
+    type Requests = Arc<Mutex<Vec<(u64, u64)>>>;
+
+    /// Serve `data` on a local port, answering range requests with at most
+    /// `limit` bytes, or with the whole file and `200 OK` if `ranges` is
+    /// false. Returns the URL and the requested ranges.
+    fn serve(data: Vec<u8>, ranges: bool, limit: u64) -> (String, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/payload.bin", listener.local_addr().unwrap());
+        let requests = Requests::default();
+        let requested = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut range = None;
+                for line in BufReader::new(&stream).lines() {
+                    let line = line.unwrap();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("range") {
+                            let (first, last) =
+                                value.trim()["bytes=".len()..].split_once('-').unwrap();
+                            range =
+                                Some((first.parse::<u64>().unwrap(), last.parse::<u64>().unwrap()));
+                        }
+                    }
+                }
+                let (first, last) = range.unwrap();
+                requests.lock().unwrap().push((first, last - first + 1));
+
+                let size = data.len() as u64;
+                let (head, body) = if ranges {
+                    let end = (last + 1).min(size).min(first.saturating_add(limit));
+                    let head = format!(
+                        "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\n",
+                        first,
+                        end.max(first + 1) - 1,
+                        size
+                    );
+                    (head, &data[first as usize..end as usize])
+                } else {
+                    ("HTTP/1.1 200 OK\r\n".to_string(), &data[..])
+                };
+                let _ = write!(
+                    stream,
+                    "{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                    head,
+                    body.len()
+                );
+                let _ = stream.write_all(body);
+            }
+        });
+        (url, requested)
+    }
+
+    fn data(len: u64) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn read_at(reader: &mut HttpReader, pos: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        reader.seek(SeekFrom::Start(pos)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn read_ahead() {
+        let data = data(3 * MIB);
+        let (url, requests) = serve(data.clone(), true, u64::MAX);
+        let mut reader = HttpReader::new(&url).unwrap();
+        assert_eq!(reader.size(), 3 * MIB);
+
+        // Small reads in the first megabyte are served by the first request.
+        assert_eq!(read_at(&mut reader, 0, 16), data[..16]);
+        assert_eq!(read_at(&mut reader, 500_000, 16), data[500_000..500_016]);
+        assert_eq!(*requests.lock().unwrap(), [(0, MIB)]);
+
+        // Reads larger than the read-ahead fetch all of it, and ranges are
+        // cut at the end of the file.
+        let len = MIB as usize + 4;
+        assert_eq!(read_at(&mut reader, MIB, len), data[MIB as usize..][..len]);
+        let pos = 5 * MIB / 2;
+        assert_eq!(read_at(&mut reader, pos, 16), data[pos as usize..][..16]);
+        assert_eq!(
+            *requests.lock().unwrap(),
+            [(0, MIB), (MIB, MIB + 4), (pos, MIB / 2)]
+        );
+        reader.seek(SeekFrom::End(0)).unwrap();
+        assert_eq!(reader.read(&mut [0; 16]).unwrap(), 0);
+    }
+
+    #[test]
+    fn cache() {
+        let data = data(9 * MIB);
+        let (url, requests) = serve(data.clone(), true, u64::MAX);
+        let mut reader = HttpReader::new(&url).unwrap();
+        for i in 1..CACHED_RANGES as u64 {
+            assert_eq!(read_at(&mut reader, i * MIB, 1), [data[(i * MIB) as usize]]);
+        }
+        // Using the first range keeps it when the ninth range is fetched,
+        // the least recently used one is dropped instead.
+        assert_eq!(read_at(&mut reader, 10, 1), [data[10]]);
+        assert_eq!(read_at(&mut reader, 8 * MIB, 1), [data[(8 * MIB) as usize]]);
+        assert_eq!(requests.lock().unwrap().len(), 9);
+        assert_eq!(read_at(&mut reader, 20, 1), [data[20]]);
+        assert_eq!(requests.lock().unwrap().len(), 9);
+        assert_eq!(read_at(&mut reader, MIB + 1, 1), [data[MIB as usize + 1]]);
+        assert_eq!(requests.lock().unwrap()[9], (MIB + 1, MIB));
+    }
+
+    #[test]
+    fn short_ranges() {
+        let data = data(1000);
+        let (url, requests) = serve(data.clone(), true, 300);
+        let mut reader = HttpReader::new(&url).unwrap();
+        assert_eq!(reader.size(), 1000);
+        // Each short range is followed by a request for the rest.
+        assert_eq!(read_at(&mut reader, 200, 500), data[200..700]);
+        assert_eq!(
+            *requests.lock().unwrap(),
+            [(0, MIB), (300, 700), (600, 400)]
+        );
+
+        let (url, _) = serve(data, true, 0);
+        let mut reader = HttpReader::new(&url).unwrap();
+        let error = reader.read(&mut [0; 16]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn no_range_support() {
+        let (url, _) = serve(data(1000), false, u64::MAX);
+        let error = HttpReader::new(&url).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::Unsupported);
+        assert!(error.to_string().contains("200 OK"), "{}", error);
+    }
+}
@@ -1,14 +1,99 @@
+#[cfg(feature = "async")]
+mod async_extent;
+#[cfg(feature = "async")]
+mod async_payload;
+mod bspatch;
+mod compress;
+mod content;
+mod create;
+mod decompress;
+mod diff;
+mod dump;
+mod error;
 mod extent;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod header;
+#[cfg(feature = "http")]
+mod http;
+#[cfg(feature = "mmap")]
+mod mmap;
+mod operation;
+mod payload;
+mod pipeline;
+mod properties;
+#[cfg(feature = "puffdiff")]
+mod puffin;
+#[cfg(feature = "python")]
+mod python;
+mod reader;
+mod scan;
+mod signature;
+mod simg;
+mod stats;
+mod stream;
+mod tar;
+mod trace;
+mod validate;
+mod verify;
+// With liblzma, only the writer is used, the decoder is left for tests.
+#[cfg_attr(feature = "xz-native", allow(dead_code))]
+mod xz;
+mod zip;
 
-use std::io::{SeekFrom, Read, Seek, Write, BufReader};
-use binrw::{binrw, BinRead, BinResult, parser};
-use chromeos_update_engine::DeltaArchiveManifest;
-use extent::SectionFile;
+use binrw::{parser, BinRead, BinResult};
+use chromeos_update_engine::{DeltaArchiveManifest, InstallOperation, PartitionUpdate};
 use prost::Message;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::io::{BufReader, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::time::{Duration, Instant};
 
-use crate::extent::{FragmentFile};
+use crate::extent::{FragmentFile, FragmentWriter, Overflow};
+pub use chromeos_update_engine::{signatures::Signature, Signatures};
+
+#[cfg(feature = "async")]
+pub use async_extent::{AsyncFragmentFile, AsyncSectionFile};
+#[cfg(feature = "async")]
+pub use async_payload::{AsyncPayload, DumpPartition, DumpProgress};
+#[cfg(feature = "compress")]
+pub use compress::CompressWriter;
+pub use compress::{ImageCompression, SequentialWriter};
+pub use content::{sniff_partition, ContentType, SNIFF_SIZE};
+pub use create::{trim_payload, CreateOptions, PayloadBuilder};
+pub use diff::{diff_partitions, Change, PartitionDiff};
+pub use dump::{
+    create_image, image_error, open_existing_image, sanitize_file_name, DumpOptions, DumpStats,
+};
+pub use error::{Compression, PayloadError};
+pub use extent::{AsSlice, SectionFile, SPARSE_HOLE};
+pub use header::PayloadHeader;
+#[cfg(feature = "http")]
+pub use http::HttpReader;
+#[cfg(feature = "mmap")]
+pub use mmap::map_file;
+pub use operation::{ExtentExt, InstallOperationExt};
+pub use payload::{Payload, ReadOptions};
+pub use pipeline::{dump_operations, dump_operations_from_slice, ReadWaits};
+pub use properties::{PayloadProperties, PropertiesError};
+pub use reader::PartitionReader;
+pub use scan::{find_payloads, FoundPayload};
+pub use signature::SignatureError;
+pub use simg::{plan_chunks, write_sparse_image, SparseChunk};
+pub use stats::{
+    cow_estimate, extent_map, operation_stats, CowEstimate, ExtentMap, OperationStats, Overlap,
+};
+pub use stream::{dump_in_data_order, dump_streaming};
+pub use tar::{TarEntry, TarWriter};
+pub use validate::{
+    sequential_order, validate_data_ranges, validate_dst_extents, validate_in_place,
+};
+pub use verify::{hash_image, verify_hash, verify_image, verify_partition};
+pub use zip::find_stored_entry;
 
 // Include the `chromeos_update_engine` module, which is generated from update_metadata.proto.
+// prost copies the indentation of the lists in its comments.
+#[allow(clippy::doc_overindented_list_items)]
 pub mod chromeos_update_engine {
     include!(concat!(env!("OUT_DIR"), "/chromeos_update_engine.rs"));
 }
@@ -51,7 +136,7 @@ pub mod chromeos_update_engine {
 /// };
 /// ```
 #[derive(BinRead, Debug)]
-#[br(big, magic = b"CrAU")]
+#[br(big, magic = b"CrAU", import(skip_payload_signatures: bool))]
 #[allow(dead_code)]
 pub struct DeltaUpdateFile {
     /// Payload major version.
@@ -62,10 +147,21 @@ pub struct DeltaUpdateFile {
     /// Only present if file_format_version >= 2.
     #[br(if(file_format_version >= 2))]
     pub metadata_signature_size: u32,
-    /// DeltaArchiveManifest protobuf serialized, not compressed.
-    #[br(count = manifest_size, 
-         try_map = |x: Vec<u8>| DeltaArchiveManifest::decode(&x[..]))]
+    /// DeltaArchiveManifest protobuf serialized, not compressed, as is in
+    /// the payload. It's read once, and kept for hashes and signatures.
+    #[br(parse_with = read_manifest_data, args(manifest_size))]
+    pub manifest_data: Vec<u8>,
+    /// The decoded `manifest_data`.
+    #[br(try_calc = DeltaArchiveManifest::decode(&manifest_data[..]))]
     pub manifest: DeltaArchiveManifest,
+    /// Size of the metadata, from the beginning of the payload to the end of
+    /// the manifest.
+    #[br(calc = if file_format_version >= 2 { 24 } else { 20 } + manifest_size)]
+    pub metadata_size: u64,
+    /// SHA-256 hash of the metadata, which is signed by
+    /// `metadata_signature_message`.
+    #[br(calc = metadata_hash(file_format_version, metadata_signature_size, &manifest_data))]
+    pub metadata_hash: [u8; 32],
     /// The signature of the metadata (from the beginning of the payload up to
     /// this location, not including the signature itself). This is a serialized
     /// Signatures message.
@@ -79,33 +175,504 @@ pub struct DeltaUpdateFile {
     /// The signature of the entire payload, everything up to this location,
     /// except that metadata_signature_message is skipped to simplify signing
     /// process.
-    /// 
+    ///
     /// We don't use `payload_signatures_message_size` because we need calculate
     /// the size of blobs in advance. And I can't find this size in my payload.
-    #[br(if(manifest.signatures_offset.is_some() && manifest.signatures_size.is_some()), 
-         seek_before = SeekFrom::Current(manifest.signatures_offset.unwrap() as i64),
-         count = manifest.signatures_size.unwrap())]
+    ///
+    /// Not read if the payload is parsed by [`DeltaUpdateFile::read_metadata`].
+    #[br(if(!skip_payload_signatures && manifest.signatures_offset.is_some() && manifest.signatures_size.is_some()),
+         parse_with = read_blob,
+         args(manifest.signatures_offset.unwrap_or_default(), manifest.signatures_size.unwrap_or_default()))]
     pub payload_signatures_message_data: Vec<u8>,
 }
 
+impl DeltaUpdateFile {
+    /// Parse the payload, including the payload signature at the end of the
+    /// data blobs.
+    pub fn parse<R: Read + Seek>(reader: &mut R) -> Result<Self, PayloadError> {
+        Ok(Self::read(reader)?)
+    }
+
+    /// Parse the payload up to the metadata signature, without reading the
+    /// payload signature at the end of the data blobs. This works even if
+    /// the data blobs are truncated.
+    pub fn read_metadata<R: Read + Seek>(reader: &mut R) -> Result<Self, PayloadError> {
+        Ok(Self::read_args(reader, (true,))?)
+    }
+
+    /// Range of the data of `operation` in the payload, see
+    /// [`InstallOperationExt::blob_range`].
+    pub fn operation_blob_range(
+        &self,
+        operation: &InstallOperation,
+    ) -> Option<std::ops::Range<u64>> {
+        operation.blob_range(self.blobs_offset)
+    }
+
+    /// Partitions updated by this payload.
+    ///
+    /// Major version 1 payloads store the operations of the rootfs and the
+    /// kernel in their own fields instead of `partitions`, they are returned
+    /// as partitions named `root` and `kernel`.
+    pub fn partitions(&self) -> Cow<'_, [PartitionUpdate]> {
+        manifest_partitions(self.file_format_version, &self.manifest)
+    }
+
+    /// Check that a payload of `len` bytes holds the data of all operations
+    /// and the payload signature, so a truncated payload is reported before
+    /// anything is extracted instead of failing halfway.
+    pub fn validate_against_len(&self, len: u64) -> Result<(), PayloadError> {
+        let needed = self.payload_size();
+        if needed > len {
+            return Err(PayloadError::Truncated { needed, len });
+        }
+        Ok(())
+    }
+
+    /// Size of the payload, up to the end of the data of the last operation
+    /// or of the payload signature, whichever is last.
+    pub fn payload_size(&self) -> u64 {
+        payload_size(self.file_format_version, &self.manifest, self.blobs_offset)
+    }
+
+    /// Whether this is a full or a delta payload.
+    pub fn payload_type(&self) -> PayloadType {
+        PayloadType::of(&self.manifest)
+    }
+
+    /// Whether this is a delta payload, which needs the old partitions.
+    pub fn is_delta(&self) -> bool {
+        self.payload_type() == PayloadType::Delta
+    }
+
+    /// Whether this is a partial update, which only updates some of the
+    /// partitions of the device and leaves the others untouched.
+    pub fn is_partial_update(&self) -> bool {
+        self.manifest.partial_update()
+    }
+
+    /// Verify `metadata_signature_message` with the PEM or DER encoded RSA or
+    /// EC public `key`.
+    pub fn verify_metadata_signature(&self, key: &[u8]) -> Result<(), SignatureError> {
+        self.find_metadata_signature(key).map(|_| ())
+    }
+
+    /// Decode `metadata_signature_message`, which has no signature if the
+    /// payload is not signed.
+    pub fn metadata_signatures(&self) -> Result<Signatures, SignatureError> {
+        signature::decode_signatures(&self.metadata_signature_message)
+    }
+
+    /// Decode `payload_signatures_message_data`, which has no signature if
+    /// the payload is not signed or it's parsed by
+    /// [`DeltaUpdateFile::read_metadata`].
+    pub fn payload_signatures(&self) -> Result<Signatures, SignatureError> {
+        signature::decode_signatures(&self.payload_signatures_message_data)
+    }
+
+    /// Find the signature in `metadata_signature_message` made by the PEM or
+    /// DER encoded RSA or EC public `key`, returning its index and itself.
+    pub fn find_metadata_signature(
+        &self,
+        key: &[u8],
+    ) -> Result<(usize, Signature), SignatureError> {
+        signature::find_signature(&self.metadata_signature_message, &self.metadata_hash, key)
+    }
+
+    /// SHA-256 hash of the payload read from `reader`, which is signed by
+    /// `payload_signatures_message_data`. This covers everything before the
+    /// payload signature, except `metadata_signature_message`.
+    pub fn payload_hash<R: Read + Seek>(&self, reader: &mut R) -> std::io::Result<[u8; 32]> {
+        let signatures_offset = self
+            .manifest
+            .signatures_offset
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "payload is not signed"))?;
+        let start =
+            self.blobs_offset - self.metadata_signature_message.len() as u64 - self.metadata_size;
+
+        let mut hasher = Sha256::new();
+        reader.seek(SeekFrom::Start(start))?;
+        let mut hashed = std::io::copy(
+            &mut Read::by_ref(reader).take(self.metadata_size),
+            &mut hasher,
+        )?;
+        reader.seek(SeekFrom::Start(self.blobs_offset))?;
+        let mut blobs =
+            BufReader::with_capacity(1 << 20, Read::by_ref(reader).take(signatures_offset));
+        hashed += std::io::copy(&mut blobs, &mut hasher)?;
+
+        if Some(hashed) != self.metadata_size.checked_add(signatures_offset) {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "payload is truncated"));
+        }
+        Ok(hasher.finalize().into())
+    }
+
+    /// Verify `payload_signatures_message_data` of the payload read from
+    /// `reader` with the PEM or DER encoded RSA or EC public `key`.
+    pub fn verify_payload_signature<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        key: &[u8],
+    ) -> Result<(), SignatureError> {
+        if self.payload_signatures_message_data.is_empty() {
+            return Err(SignatureError::Unsigned);
+        }
+        let hash = self.payload_hash(reader).map_err(SignatureError::Io)?;
+        self.find_payload_signature(&hash, key).map(|_| ())
+    }
+
+    /// Find the signature in `payload_signatures_message_data` made by the
+    /// PEM or DER encoded RSA or EC public `key` over `payload_hash`,
+    /// returning its index and itself.
+    pub fn find_payload_signature(
+        &self,
+        payload_hash: &[u8],
+        key: &[u8],
+    ) -> Result<(usize, Signature), SignatureError> {
+        signature::find_signature(&self.payload_signatures_message_data, payload_hash, key)
+    }
+}
+
+/// Type of a payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadType {
+    /// Full payload, which can update from any version.
+    Full,
+    /// Delta payload, which can only update from a specific version.
+    Delta,
+}
+
+impl PayloadType {
+    /// Type of a payload with `manifest`: minor version 0 is a full
+    /// payload, everything else is a delta payload.
+    fn of(manifest: &DeltaArchiveManifest) -> Self {
+        match manifest.minor_version() {
+            0 => PayloadType::Full,
+            _ => PayloadType::Delta,
+        }
+    }
+}
+
+impl std::fmt::Display for PayloadType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PayloadType::Full => write!(f, "full"),
+            PayloadType::Delta => write!(f, "delta"),
+        }
+    }
+}
+
+/// Partitions of a payload with `manifest`, see [`DeltaUpdateFile::partitions`].
+#[allow(deprecated)]
+fn manifest_partitions(
+    file_format_version: u64,
+    manifest: &DeltaArchiveManifest,
+) -> Cow<'_, [PartitionUpdate]> {
+    if file_format_version >= 2 {
+        return Cow::Borrowed(&manifest.partitions);
+    }
+
+    let root = PartitionUpdate {
+        partition_name: "root".to_string(),
+        old_partition_info: manifest.old_rootfs_info.clone(),
+        new_partition_info: manifest.new_rootfs_info.clone(),
+        operations: manifest.install_operations.clone(),
+        ..Default::default()
+    };
+    let kernel = PartitionUpdate {
+        partition_name: "kernel".to_string(),
+        old_partition_info: manifest.old_kernel_info.clone(),
+        new_partition_info: manifest.new_kernel_info.clone(),
+        operations: manifest.kernel_install_operations.clone(),
+        ..Default::default()
+    };
+
+    Cow::Owned(
+        [root, kernel]
+            .into_iter()
+            .filter(|partition| !partition.operations.is_empty())
+            .collect(),
+    )
+}
+
+/// Size of a payload with `manifest` whose data blobs are at `blobs_offset`,
+/// see [`DeltaUpdateFile::payload_size`].
+fn payload_size(
+    file_format_version: u64,
+    manifest: &DeltaArchiveManifest,
+    blobs_offset: u64,
+) -> u64 {
+    let data_end = manifest_partitions(file_format_version, manifest)
+        .iter()
+        .flat_map(|partition| &partition.operations)
+        .filter_map(|operation| {
+            Some(
+                operation
+                    .data_offset?
+                    .saturating_add(operation.data_length?),
+            )
+        })
+        .max()
+        .unwrap_or(0);
+    let signatures_end = match (manifest.signatures_offset, manifest.signatures_size) {
+        (Some(offset), Some(size)) => offset.saturating_add(size),
+        _ => 0,
+    };
+    blobs_offset.saturating_add(data_end.max(signatures_end))
+}
+
+/// Open the payload in `reader`, which is either a payload file or an OTA
+/// zip file with `payload.bin` stored uncompressed inside.
+pub fn open_payload<R: Read + Seek>(mut reader: R) -> std::io::Result<SectionFile<R>> {
+    let mut magic = [0; 4];
+    reader.rewind()?;
+    let is_payload = reader.read_exact(&mut magic).is_ok() && &magic == b"CrAU";
+
+    let (offset, length) = if is_payload {
+        (0, reader.seek(SeekFrom::End(0))?)
+    } else {
+        zip::find_stored_entry(&mut reader, "payload.bin").map_err(|e| match e.kind() {
+            ErrorKind::InvalidData => {
+                Error::new(ErrorKind::InvalidData, "not a payload or an OTA zip file")
+            }
+            _ => e,
+        })?
+    };
+    SectionFile::new(reader, offset, length)
+}
+
 #[parser(reader)]
 fn current_pos() -> BinResult<u64> {
     Ok(reader.stream_position()?)
 }
 
-pub fn dump_operation<R: Read + Seek, W: Write + Seek>(
-    src: &mut R, 
-    src_blobs_offset: u64, 
-    dst: &mut W, 
-    operation: &chromeos_update_engine::InstallOperation,
-    block_size: u64) -> Result<(), Box<dyn std::error::Error>> {
+/// Read the `size` bytes at `offset` of the data blobs, which start at the
+/// current position.
+#[parser(reader)]
+fn read_blob(offset: u64, size: u64) -> BinResult<Vec<u8>> {
+    let pos = reader.stream_position()?;
+    let start = blob_offset(pos, offset).map_err(|e| binrw::Error::Custom {
+        pos,
+        err: Box::new(e),
+    })?;
+    reader.seek(SeekFrom::Start(start))?;
+    let mut data = Vec::new();
+    Read::by_ref(reader).take(size).read_to_end(&mut data)?;
+    if data.len() as u64 != size {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "payload signature is truncated").into());
+    }
+    Ok(data)
+}
+
+#[parser(reader)]
+fn read_manifest_data(size: u64) -> BinResult<Vec<u8>> {
+    Ok(read_manifest(reader, size)?)
+}
+
+/// Read the `size` bytes of the serialized manifest from `reader`. The
+/// buffer grows with the bytes read, so a bogus `size` fails as truncated
+/// instead of allocating it up front.
+pub(crate) fn read_manifest<R: Read>(reader: &mut R, size: u64) -> std::io::Result<Vec<u8>> {
+    let mut manifest_data = Vec::new();
+    reader.take(size).read_to_end(&mut manifest_data)?;
+    if manifest_data.len() as u64 != size {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "manifest is truncated",
+        ));
+    }
+    Ok(manifest_data)
+}
+
+/// SHA-256 hash of the metadata of a payload, from its header fields and
+/// its serialized manifest, without reading it again.
+pub(crate) fn metadata_hash(
+    file_format_version: u64,
+    metadata_signature_size: u32,
+    manifest_data: &[u8],
+) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"CrAU");
+    hasher.update(file_format_version.to_be_bytes());
+    hasher.update((manifest_data.len() as u64).to_be_bytes());
+    if file_format_version >= 2 {
+        hasher.update(metadata_signature_size.to_be_bytes());
+    }
+    hasher.update(manifest_data);
+    hasher.finalize().into()
+}
+
+/// Apply a single `operation` to `dst`.
+///
+/// `old` is the image of the old partition, which is only needed by
+/// operations that read from `src_extents` of the source partition, like
+/// SOURCE_COPY and SOURCE_BSDIFF. It can be `None` for full payloads.
+///
+/// `dst` must be readable because deprecated operations like MOVE read
+/// `src_extents` from the partition being written.
+///
+/// Errors don't tell which operation failed, [`dump_operations`] attaches the
+/// index and type of the operation with [`PayloadError::in_operation`].
+///
+/// The buffers and decoders it needs are allocated for this operation only,
+/// apply many with [`DumpContext::dump_operation`] instead.
+///
+/// Returns what applying the operation took, see [`OperationResult`].
+pub fn dump_operation<R: Read + Seek, O: Read + Seek, W: Read + Write + Seek>(
+    src: &mut R,
+    src_blobs_offset: u64,
+    old: Option<&mut O>,
+    dst: &mut W,
+    operation: &InstallOperation,
+    block_size: u64,
+) -> Result<OperationResult, PayloadError> {
+    DumpContext::new().dump_operation(src, src_blobs_offset, old, dst, operation, block_size)
+}
+
+/// Apply a single `operation` to `dst` like [`dump_operation`], but in
+/// place: `dst` holds the old partition, and operations reading the old
+/// partition read it from `dst`.
+///
+/// The data read by an operation is buffered before it's written, so its
+/// src and dst extents may overlap. It must not read blocks written by
+/// earlier operations, which [`validate_in_place`] checks.
+pub fn dump_operation_in_place<R: Read + Seek, W: Read + Write + Seek>(
+    src: &mut R,
+    src_blobs_offset: u64,
+    dst: &mut W,
+    operation: &InstallOperation,
+    block_size: u64,
+) -> Result<OperationResult, PayloadError> {
+    DumpContext::new().dump_operation_in_place(src, src_blobs_offset, dst, operation, block_size)
+}
+
+/// Scratch buffers and decoder state of applying operations, reused from
+/// an operation to the next instead of allocated for each, which adds up
+/// with tens of thousands of operations.
+///
+/// Keep one for the operations applied in a row, e.g. by a thread, with
+/// [`DumpContext::dump_operation`] and
+/// [`DumpContext::dump_operation_in_place`], which are like
+/// [`dump_operation`] and [`dump_operation_in_place`].
+#[derive(Debug, Default)]
+pub struct DumpContext {
+    /// Data of the operations read ahead for the decompressors.
+    read_buffer: Vec<u8>,
+    /// Writes to the dst extents, see [`FragmentWriter`].
+    write_buffer: Vec<u8>,
+    /// The patch of bsdiff operations.
+    patch: Vec<u8>,
+    /// The src extents read by bsdiff operations and MOVE.
+    src: Vec<u8>,
+    decoders: decompress::Decoders,
+}
+
+impl DumpContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-    let data = operation.data_offset
+    /// Apply a single `operation` to `dst`, see [`dump_operation`].
+    pub fn dump_operation<R: Read + Seek, O: Read + Seek, W: Read + Write + Seek>(
+        &mut self,
+        src: &mut R,
+        src_blobs_offset: u64,
+        old: Option<&mut O>,
+        dst: &mut W,
+        operation: &InstallOperation,
+        block_size: u64,
+    ) -> Result<OperationResult, PayloadError> {
+        let old = old.map(Old::Image).unwrap_or(Old::None);
+        apply_operation(self, src, src_blobs_offset, old, dst, operation, block_size)
+    }
+
+    /// Apply a single `operation` to `dst` in place, see
+    /// [`dump_operation_in_place`].
+    pub fn dump_operation_in_place<R: Read + Seek, W: Read + Write + Seek>(
+        &mut self,
+        src: &mut R,
+        src_blobs_offset: u64,
+        dst: &mut W,
+        operation: &InstallOperation,
+        block_size: u64,
+    ) -> Result<OperationResult, PayloadError> {
+        apply_operation(
+            self,
+            src,
+            src_blobs_offset,
+            Old::<W>::InPlace,
+            dst,
+            operation,
+            block_size,
+        )
+    }
+}
+
+/// What applying an operation took, returned by [`dump_operation`] and
+/// added up for a partition in [`DumpStats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OperationResult {
+    /// Type of the operation.
+    pub op_type: chromeos_update_engine::install_operation::Type,
+    /// Bytes of attached data read from the payload, `data_length` for the
+    /// operations using it, or 0.
+    pub data_bytes_read: u64,
+    /// Bytes written to the dst extents, all of them as the last block is
+    /// zero padded, or 0 for DISCARD.
+    pub bytes_written: u64,
+    /// Time taken to apply the operation.
+    pub duration: Duration,
+    /// Whether the attached data was checked against its
+    /// `data_sha256_hash`. Only the patches of the bsdiff operations are, the
+    /// data of REPLACE operations is covered by the hash of the partition.
+    pub hash_verified: bool,
+}
+
+impl OperationResult {
+    /// Result of a ZERO `operation` skipped as the image is zeroed already,
+    /// see [`DumpOptions::dense`]. Its dst extents count as written.
+    pub(crate) fn skipped(operation: &InstallOperation, block_size: u64) -> Self {
+        Self {
+            op_type: operation.r#type(),
+            data_bytes_read: 0,
+            bytes_written: operation.dst_byte_len(block_size),
+            duration: Duration::ZERO,
+            hash_verified: false,
+        }
+    }
+}
+
+/// Where the old partition is read from.
+enum Old<'a, O> {
+    /// Not given, fine for full payloads.
+    None,
+    /// The image of the old partition.
+    Image(&'a mut O),
+    /// The partition being written, see [`dump_operation_in_place`].
+    InPlace,
+}
+
+fn apply_operation<R: Read + Seek, O: Read + Seek, W: Read + Write + Seek>(
+    context: &mut DumpContext,
+    src: &mut R,
+    src_blobs_offset: u64,
+    old: Old<O>,
+    dst: &mut W,
+    operation: &InstallOperation,
+    block_size: u64,
+) -> Result<OperationResult, PayloadError> {
+    let start = Instant::now();
+    check_block_size(block_size)?;
+    let data = operation
+        .data_offset
         .zip(operation.data_length)
-        .ok_or_else(|| "no data".to_string())
+        .ok_or(PayloadError::MissingData)
         .and_then(|(offset, length)| {
-            SectionFile::new(src, src_blobs_offset + offset, length)
-                .map_err(|e| e.to_string())
+            Ok(SectionFile::new(
+                src,
+                blob_offset(src_blobs_offset, offset)?,
+                length,
+            )?)
         });
 
     // println!("\n{} - {}\n", operation.data_offset(), operation.data_length());
@@ -113,76 +680,1998 @@ pub fn dump_operation<R: Read + Seek, W: Write + Seek>(
     // std::io::copy(&mut data?, &mut file);
 
     let dst = if operation.dst_extents.is_empty() {
-        Err("no dst extents")
+        Err(PayloadError::MissingDstExtents)
     } else {
-        Ok(FragmentFile::new_from_extents(dst, &operation.dst_extents, block_size)?)
+        Ok(FragmentFile::new_from_extents(
+            dst,
+            &operation.dst_extents,
+            block_size,
+        )?)
+    };
+
+    // `operation.r#type()` falls back to REPLACE for unknown types, which
+    // must not be applied.
+    let op_type = chromeos_update_engine::install_operation::Type::from_i32(operation.r#type)
+        .ok_or(PayloadError::UnsupportedOperation(operation.r#type))?;
+    let mut result = OperationResult {
+        op_type,
+        data_bytes_read: 0,
+        bytes_written: dst.as_ref().map_or(0, |dst| dst.size()),
+        duration: Duration::ZERO,
+        hash_verified: false,
     };
 
-    match operation.r#type() {
+    match op_type {
         // REPLACE: Replace the dst_extents on the drive with the attached data,
         // zero padding out to block size.
         chromeos_update_engine::install_operation::Type::Replace => {
-            let mut dst = dst?;
+            let dst = dst?;
 
+            let length = operation.data_length();
+            result.data_bytes_read = length;
+            if length > dst.size() {
+                return Err(PayloadError::ExtentOverflow {
+                    size: dst.size(),
+                    produced: length,
+                });
+            }
+            let mut dst = FragmentWriter::with_buffer(
+                dst,
+                WRITE_BUFFER_SIZE,
+                std::mem::take(&mut context.write_buffer),
+            );
             let copied = std::io::copy(&mut data?, &mut dst)?;
-            assert_eq!(copied, operation.data_length());
-            assert_eq!(copied, dst.size());
-        },
+            check_size(length, copied)?;
+            std::io::copy(&mut std::io::repeat(0).take(dst.size() - copied), &mut dst)?;
+            dst.flush()?;
+            context.write_buffer = dst.into_buffer();
+        }
         // REPLACE_BZ: bzip2-uncompress the attached data and write it into
         // dst_extents on the drive, zero padding to block size.
-        chromeos_update_engine::install_operation::Type::ReplaceBz => {
-            let mut dst = dst?;
-
-            let mut data = BufReader::new(data?);
-            libribzip2::stream::decode_stream(&mut data, &mut dst).map_err(|()| "bzip2 error")?;
-            let copied = dst.seek(SeekFrom::Current(0))?;
-            // let mut decoder = bzip2_rs::DecoderReader::new(data?);
-            // let copied = std::io::copy(&mut decoder, &mut dst)?;
-            assert_eq!(copied, dst.size());
-        },
         // REPLACE_XZ: Replace the dst_extents with the contents of the attached
         // xz file after decompression. The xz file should only use crc32 or no crc at
-        // all to be compatible with xz-embedded.
-        chromeos_update_engine::install_operation::Type::ReplaceXz => {
-            let mut data = BufReader::new(data?);
-            let mut dst = dst?;
+        // all to be compatible with xz-embedded. Other checks and concatenated
+        // streams are accepted too.
+        // REPLACE_ZSTD: Replace the dst_extents with the contents of the attached
+        // zstd file after decompression.
+        chromeos_update_engine::install_operation::Type::ReplaceBz
+        | chromeos_update_engine::install_operation::Type::ReplaceXz
+        | chromeos_update_engine::install_operation::Type::ReplaceZstd => {
+            let kind = match op_type {
+                chromeos_update_engine::install_operation::Type::ReplaceBz => Compression::Bzip2,
+                chromeos_update_engine::install_operation::Type::ReplaceXz => Compression::Xz,
+                _ => Compression::Zstd,
+            };
+            let mut data = data?.with_read_buffer(std::mem::take(&mut context.read_buffer));
+            let dst = FragmentWriter::with_buffer(
+                dst?,
+                WRITE_BUFFER_SIZE,
+                std::mem::take(&mut context.write_buffer),
+            );
+            // Padding of the last block is the only slack allowed, more data is
+            // a corrupt payload or a decompression bomb.
+            let limit = dst.size().saturating_add(block_size);
+            let mut dst = CountingWriter::new(dst, limit);
 
-            lzma_rs::xz_decompress(&mut data, &mut dst)?;
-            let size_write = dst.seek(SeekFrom::Current(0))?;
-            assert_eq!(size_write, dst.size());
-        },
+            decompress::decompress(kind, &mut data, &mut dst, &mut context.decoders).map_err(|e| {
+                // Within the span of the operation, which has its index and
+                // data in the payload.
+                tracing::error!(%kind, written = dst.written, size = dst.inner.size(), "decompression failed: {}", e);
+                if dst.exceeded {
+                    return PayloadError::DecompressionLimit { kind, limit: dst.limit };
+                }
+                PayloadError::Decompression {
+                    kind,
+                    source: format!("{} after {} bytes", e, dst.written).into(),
+                }
+            })?;
+            dst.check()?;
+            dst.flush()?;
+            context.read_buffer = data.into_read_buffer();
+            context.write_buffer = dst.inner.into_buffer();
+            result.data_bytes_read = operation.data_length();
+        }
         // ZERO: Write zeros to the destination dst_extents.
         chromeos_update_engine::install_operation::Type::Zero => {
-            let mut dst = dst?;
-            let mut zeros = std::io::repeat(0u8).take(dst.size());
-            std::io::copy(&mut zeros, &mut dst)?;
-        },
+            let mut dst = FragmentWriter::with_buffer(
+                dst?,
+                WRITE_BUFFER_SIZE,
+                std::mem::take(&mut context.write_buffer),
+            );
+            std::io::copy(&mut std::io::repeat(0).take(dst.size()), &mut dst)?;
+            dst.flush()?;
+            context.write_buffer = dst.into_buffer();
+        }
         // DISCARD: Discard the destination dst_extents blocks on the physical medium.
         // the data read from those blocks is undefined.
-        chromeos_update_engine::install_operation::Type::Discard => {},
+        chromeos_update_engine::install_operation::Type::Discard => result.bytes_written = 0,
         // MOVE: Copy the data in src_extents to dst_extents. Extents may overlap,
         // so it may be desirable to read all src_extents data into memory before
         // writing it out. (deprecated)
-        chromeos_update_engine::install_operation::Type::Move => todo!("src_extents"),
+        chromeos_update_engine::install_operation::Type::Move => {
+            copy_within(dst?, operation, block_size, &mut context.src)?
+        }
         // SOURCE_COPY: Copy the data in src_extents in the old partition to
         // dst_extents in the new partition. There's no overlapping of data because
         // the extents are in different partitions.
-        chromeos_update_engine::install_operation::Type::SourceCopy => todo!("src_extents"),
+        chromeos_update_engine::install_operation::Type::SourceCopy
+            if matches!(old, Old::InPlace) =>
+        {
+            copy_within(dst?, operation, block_size, &mut context.src)?
+        }
+        chromeos_update_engine::install_operation::Type::SourceCopy => {
+            let Old::Image(old) = old else {
+                return Err(PayloadError::MissingOldImage(op_type));
+            };
+            let mut dst = dst?;
+
+            check_size(
+                dst.size(),
+                extent::extents_size(&operation.src_extents, block_size)?,
+            )?;
+            // Files are copied with copy_file_range on Linux, see
+            // `copy_extents`.
+            let copied = extent::copy_extents(
+                old,
+                &operation.src_extents,
+                dst.get_mut(),
+                &operation.dst_extents,
+                block_size,
+            )?;
+            // The old image may be shorter than src_extents.
+            check_size(dst.size(), copied)?;
+            dst.flush()?;
+        }
         // BSDIFF: Read src_length bytes from src_extents into memory, perform
         // bspatch with attached data, write new data to dst_extents, zero padding
         // to block size. (deprecated)
-        chromeos_update_engine::install_operation::Type::Bsdiff => todo!("diff"),
+        chromeos_update_engine::install_operation::Type::Bsdiff => {
+            let mut dst = dst?;
+
+            let src =
+                FragmentFile::new_from_extents(dst.get_mut(), &operation.src_extents, block_size)?;
+            read_src(src, operation, &mut context.src)?;
+            dst.rewind()?;
+            result.hash_verified = write_patch(
+                &context.src,
+                data?,
+                operation,
+                &mut dst,
+                &mut context.patch,
+                bspatch::bspatch,
+            )?;
+            result.data_bytes_read = operation.data_length();
+        }
         // SOURCE_BSDIFF: Read the data in src_extents in the old partition, perform
         // bspatch with the attached data and write the new data to dst_extents in the
         // new partition.
-        chromeos_update_engine::install_operation::Type::SourceBsdiff => todo!("diff"),
-        // Like SOURCE_BSDIFF, but compressed with brotli.
-        chromeos_update_engine::install_operation::Type::BrotliBsdiff => todo!("diff"),
+        // BROTLI_BSDIFF: Like SOURCE_BSDIFF, but compressed with brotli.
+        chromeos_update_engine::install_operation::Type::SourceBsdiff
+        | chromeos_update_engine::install_operation::Type::BrotliBsdiff => {
+            let mut dst = dst?;
+            read_old_src(old, &mut dst, operation, block_size, &mut context.src)?;
+            result.hash_verified = write_patch(
+                &context.src,
+                data?,
+                operation,
+                &mut dst,
+                &mut context.patch,
+                bspatch::bspatch,
+            )?;
+            result.data_bytes_read = operation.data_length();
+        }
         // PUFFDIFF: Read the data in src_extents in the old partition, perform
         // puffpatch with the attached data and write the new data to dst_extents in
         // the new partition.
-        chromeos_update_engine::install_operation::Type::Puffdiff => todo!("diff"),
+        #[cfg(feature = "puffdiff")]
+        chromeos_update_engine::install_operation::Type::Puffdiff => {
+            let mut dst = dst?;
+            read_old_src(old, &mut dst, operation, block_size, &mut context.src)?;
+            result.hash_verified = write_patch(
+                &context.src,
+                data?,
+                operation,
+                &mut dst,
+                &mut context.patch,
+                puffin::puffpatch,
+            )?;
+            result.data_bytes_read = operation.data_length();
+        }
+        #[cfg(not(feature = "puffdiff"))]
+        chromeos_update_engine::install_operation::Type::Puffdiff => {
+            return Err(PayloadError::UnsupportedOperation(operation.r#type));
+        }
+        // ZUCCHINI: Like SOURCE_BSDIFF, but the attached data is a zucchini patch.
+        // LZ4DIFF_BSDIFF, LZ4DIFF_PUFFDIFF: The old and new data are EROFS lz4
+        // compressed blocks, the patch is applied on the decompressed data and
+        // the result is compressed again.
+        //
+        // PUFFDIFF needs the `puffdiff` feature, zucchini and lz4diff are not
+        // implemented yet. Lz4diff needs recompression that is bit-exact with
+        // liblz4 to reproduce the image.
+        chromeos_update_engine::install_operation::Type::Zucchini
+        | chromeos_update_engine::install_operation::Type::Lz4diffBsdiff
+        | chromeos_update_engine::install_operation::Type::Lz4diffPuffdiff => {
+            return Err(PayloadError::UnsupportedOperation(operation.r#type));
+        }
     }
 
+    result.duration = start.elapsed();
+    Ok(result)
+}
+
+/// Copy `src_extents` of the partition being written to `dst`, its
+/// `dst_extents`, which may overlap, so all the data is read first, into
+/// `buffer`.
+fn copy_within<W: Read + Write + Seek>(
+    mut dst: FragmentFile<&mut W>,
+    operation: &InstallOperation,
+    block_size: u64,
+    buffer: &mut Vec<u8>,
+) -> Result<(), PayloadError> {
+    buffer.clear();
+    FragmentFile::new_from_extents(dst.get_mut(), &operation.src_extents, block_size)?
+        .read_to_end(buffer)?;
+    check_size(dst.size(), buffer.len() as u64)?;
+    dst.rewind()?;
+    dst.write_all(buffer)?;
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Apply `operation` with its `data` and the bytes of its src extents in
+/// `src`, if it has any, returning the bytes of its dst extents and its
+/// result. MOVE and BSDIFF read `src` like SOURCE_COPY and SOURCE_BSDIFF,
+/// instead of the partition being written.
+pub(crate) fn apply_in_memory(
+    context: &mut DumpContext,
+    operation: &InstallOperation,
+    data: &[u8],
+    src: Option<&[u8]>,
+    block_size: u64,
+) -> Result<(Vec<u8>, OperationResult), PayloadError> {
+    let size = extent::extents_size(&operation.dst_extents, block_size)?;
+    let blocks = |size: u64| {
+        vec![chromeos_update_engine::Extent {
+            start_block: Some(0),
+            num_blocks: Some(size / block_size),
+        }]
+    };
+    let mut local = InstallOperation {
+        data_offset: operation.data_offset.map(|_| 0),
+        dst_extents: blocks(size),
+        ..operation.clone()
+    };
+    if let Some(src) = src {
+        local.src_extents = blocks(src.len() as u64);
+        match local.r#type() {
+            chromeos_update_engine::install_operation::Type::Move => {
+                local.set_type(chromeos_update_engine::install_operation::Type::SourceCopy)
+            }
+            chromeos_update_engine::install_operation::Type::Bsdiff => {
+                local.set_type(chromeos_update_engine::install_operation::Type::SourceBsdiff)
+            }
+            _ => {}
+        }
+    }
+    let mut dst = std::io::Cursor::new(vec![0; buffer_len(size)?]);
+    let mut old = src.map(std::io::Cursor::new);
+    let result = context.dump_operation(
+        &mut std::io::Cursor::new(data),
+        0,
+        old.as_mut(),
+        &mut dst,
+        &local,
+        block_size,
+    )?;
+    // The type it was given, not the one it's applied as.
+    let result = OperationResult {
+        op_type: operation.r#type(),
+        ..result
+    };
+    Ok((dst.into_inner(), result))
+}
+
+/// Read all the bytes of `extents` of `src`.
+pub(crate) fn read_extents<T: Read + Seek>(
+    src: &mut T,
+    extents: &[chromeos_update_engine::Extent],
+    block_size: u64,
+) -> std::io::Result<Vec<u8>> {
+    let mut src = FragmentFile::new_from_extents(src, extents, block_size)?;
+    let mut buffer = vec![0; buffer_len(src.size())?];
+    src.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// Return `block_size` if it's a non-zero power of two, which extents can be
+/// multiplied by, or a [`PayloadError::InvalidBlockSize`].
+pub fn check_block_size(block_size: u64) -> Result<u64, PayloadError> {
+    if !block_size.is_power_of_two() {
+        return Err(PayloadError::InvalidBlockSize(block_size));
+    }
+    Ok(block_size)
+}
+
+/// Offset in the payload of `offset` in the data blobs, which start at
+/// `blobs_offset`, failing if it overflows.
+pub(crate) fn blob_offset(blobs_offset: u64, offset: u64) -> std::io::Result<u64> {
+    blobs_offset.checked_add(offset).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("offset {} in the data blobs overflows", offset),
+        )
+    })
+}
+
+/// Length of a buffer in memory for `length` bytes of the payload or of an
+/// image, which are `u64`, failing where it doesn't fit in a `usize`, like
+/// 4 GiB on 32-bit targets, instead of truncating it.
+pub(crate) fn buffer_len(length: u64) -> std::io::Result<usize> {
+    usize::try_from(length).map_err(|_| {
+        Error::new(
+            ErrorKind::OutOfMemory,
+            format!("{} bytes don't fit in memory", length),
+        )
+    })
+}
+
+/// Return a [`PayloadError::SizeMismatch`] if `actual` bytes are written or
+/// read where `expected` bytes are.
+fn check_size(expected: u64, actual: u64) -> Result<(), PayloadError> {
+    if expected != actual {
+        return Err(PayloadError::SizeMismatch { expected, actual });
+    }
+    Ok(())
+}
+
+/// Write zeros to `dst` from the current position to its end.
+fn zero_fill<W: Write + Seek>(dst: &mut FragmentFile<W>) -> std::io::Result<()> {
+    let remaining = dst.size() - dst.stream_position()?;
+    std::io::copy(&mut std::io::repeat(0u8).take(remaining), dst)?;
+    Ok(())
+}
+
+/// Maximum number of bytes buffered when writing the dst extents, see
+/// [`FragmentWriter`]. Larger buffers save few syscalls, and the writes of
+/// 64 KiB of the xz decoder would be copied instead of written directly.
+const WRITE_BUFFER_SIZE: usize = 64 << 10;
+
+/// A writer counting the bytes written to a [`FragmentFile`], including
+/// those past its end, which are dropped. So decompressed data larger than
+/// the dst extents is reported by its size instead of a write error.
+///
+/// Writes fail once more than `limit` bytes would be written, stopping the
+/// decoder early, and `exceeded` is set.
+struct CountingWriter<W> {
+    inner: FragmentWriter<W>,
+    written: u64,
+    limit: u64,
+    exceeded: bool,
+}
+
+impl<W: Write + Seek> CountingWriter<W> {
+    fn new(inner: FragmentWriter<W>, limit: u64) -> Self {
+        Self {
+            inner,
+            written: 0,
+            limit,
+            exceeded: false,
+        }
+    }
+
+    /// Check that exactly the size of the dst extents is written.
+    fn check(&self) -> Result<(), PayloadError> {
+        let size = self.inner.size();
+        if self.written > size {
+            return Err(PayloadError::ExtentOverflow {
+                size,
+                produced: self.written,
+            });
+        }
+        check_size(size, self.written)
+    }
+}
+
+impl<W: Write + Seek> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.written.saturating_add(buf.len() as u64) > self.limit {
+            self.exceeded = true;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("more than {} bytes decompressed", self.limit),
+            ));
+        }
+        // All of `buf` is taken, as decoders may not retry short writes.
+        match self.inner.write_all(buf) {
+            Err(e) if !e.get_ref().is_some_and(|e| e.is::<Overflow>()) => return Err(e),
+            _ => {}
+        }
+        self.written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Read the source data of a bsdiff `operation` from `src`, which covers its
+/// `src_extents`, into `old_data`. Only the first `src_length` bytes are
+/// read if it is present.
+fn read_src<T: Read + Seek>(
+    src: FragmentFile<T>,
+    operation: &InstallOperation,
+    old_data: &mut Vec<u8>,
+) -> Result<(), PayloadError> {
+    let src_length = operation.src_length.unwrap_or_else(|| src.size());
+    if src_length > src.size() {
+        return Err(PayloadError::InvalidOperation(format!(
+            "src_length {} is larger than src_extents ({} bytes)",
+            src_length,
+            src.size()
+        )));
+    }
+
+    old_data.clear();
+    src.take(src_length).read_to_end(old_data)?;
+    Ok(())
+}
+
+/// Read the data in `src_extents` of the old partition into `old_data`,
+/// from `old`, or from `dst` when applying in place.
+fn read_old_src<O: Read + Seek, W: Read + Write + Seek>(
+    old: Old<O>,
+    dst: &mut FragmentFile<&mut W>,
+    operation: &InstallOperation,
+    block_size: u64,
+    old_data: &mut Vec<u8>,
+) -> Result<(), PayloadError> {
+    match old {
+        Old::Image(old) => {
+            let src = FragmentFile::new_from_extents(old, &operation.src_extents, block_size)?;
+            read_src(src, operation, old_data)
+        }
+        Old::InPlace => {
+            let src =
+                FragmentFile::new_from_extents(dst.get_mut(), &operation.src_extents, block_size)?;
+            read_src(src, operation, old_data)?;
+            dst.rewind()?;
+            Ok(())
+        }
+        Old::None => Err(PayloadError::MissingOldImage(operation.r#type())),
+    }
+}
+
+/// A patch format, applying a patch to the old data, returning the new data
+/// of at most the given size.
+type ApplyPatch = fn(&[u8], &[u8], usize) -> std::io::Result<Vec<u8>>;
+
+/// Apply the patch attached to `operation`, read into `patch`, to
+/// `old_data` with `apply`, like [`bspatch::bspatch`], and write the new
+/// data to `dst`, zero padding to the size of `dst`. Returns whether the
+/// patch was checked against its hash.
+fn write_patch<R: Read, W: Write + Seek>(
+    old_data: &[u8],
+    mut data: R,
+    operation: &InstallOperation,
+    dst: &mut FragmentFile<W>,
+    patch: &mut Vec<u8>,
+    apply: ApplyPatch,
+) -> Result<bool, PayloadError> {
+    patch.clear();
+    patch.reserve(buffer_len(operation.data_length())?);
+    data.read_to_end(patch)?;
+    let hash_verified = verify_data_hash(operation, patch)?;
+
+    // The patch declares the size of the new data, which must not be trusted
+    // for allocation.
+    let max_size = match operation.dst_length {
+        Some(dst_length) if dst_length > dst.size() => {
+            return Err(PayloadError::InvalidOperation(format!(
+                "dst_length {} is larger than dst_extents ({} bytes)",
+                dst_length,
+                dst.size()
+            )))
+        }
+        Some(dst_length) => dst_length,
+        None => dst.size(),
+    };
+    let new_data = apply(old_data, patch, buffer_len(max_size)?).map_err(PayloadError::Patch)?;
+    if let Some(dst_length) = operation.dst_length {
+        if new_data.len() as u64 != dst_length {
+            return Err(PayloadError::SizeMismatch {
+                expected: dst_length,
+                actual: new_data.len() as u64,
+            });
+        }
+    }
+
+    if new_data.len() as u64 > dst.size() {
+        return Err(PayloadError::ExtentOverflow {
+            size: dst.size(),
+            produced: new_data.len() as u64,
+        });
+    }
+    dst.write_all(&new_data)?;
+    zero_fill(dst)?;
+    Ok(hash_verified)
+}
+
+/// Check the attached data of `operation` against its `data_sha256_hash`,
+/// if the hash is present, returning whether it is.
+fn verify_data_hash(operation: &InstallOperation, data: &[u8]) -> Result<bool, PayloadError> {
+    let expected = match operation.data_sha256_hash.as_deref() {
+        Some(hash) if !hash.is_empty() => hash,
+        _ => return Ok(false),
+    };
+
+    let actual = Sha256::digest(data);
+    if actual[..] != *expected {
+        return Err(PayloadError::HashMismatch {
+            expected: expected.to_vec(),
+            actual: actual.to_vec(),
+        });
+    }
+
+    Ok(true)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use chromeos_update_engine::{install_operation::Type, Extent, PartitionInfo};
+    use std::fs::File;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    /// A directory in the temporary directory, removed when dropped.
+    pub(crate) struct TempDir(pub(crate) PathBuf);
+
+    impl TempDir {
+        pub(crate) fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "payload-dumper-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Build a major version 2 payload without signatures.
+    fn payload(manifest: &DeltaArchiveManifest, blobs: &[u8]) -> Vec<u8> {
+        signed_payload(manifest, &[], blobs)
+    }
+
+    /// Build a major version 2 payload with a serialized Signatures message
+    /// as the metadata signature.
+    fn signed_payload(
+        manifest: &DeltaArchiveManifest,
+        metadata_signature: &[u8],
+        blobs: &[u8],
+    ) -> Vec<u8> {
+        let manifest = manifest.encode_to_vec();
+        [
+            &b"CrAU"[..],
+            &2u64.to_be_bytes(),
+            &(manifest.len() as u64).to_be_bytes(),
+            &(metadata_signature.len() as u32).to_be_bytes(),
+            &manifest,
+            metadata_signature,
+            blobs,
+        ]
+        .concat()
+    }
+
+    pub(crate) fn extent(start_block: u64, num_blocks: u64) -> Extent {
+        Extent {
+            start_block: Some(start_block),
+            num_blocks: Some(num_blocks),
+        }
+    }
+
+    /// An operation of `op_type` writing `dst_extents`, with the offset and
+    /// length of its data, if any.
+    pub(crate) fn operation(
+        op_type: Type,
+        data: Option<(u64, u64)>,
+        dst_extents: Vec<Extent>,
+    ) -> InstallOperation {
+        let mut operation = InstallOperation {
+            data_offset: data.map(|(offset, _)| offset),
+            data_length: data.map(|(_, length)| length),
+            dst_extents,
+            ..Default::default()
+        };
+        operation.set_type(op_type);
+        operation
+    }
+
+    /// The partition `name` of `size` bytes, updated by `operations`.
+    pub(crate) fn partition(
+        name: &str,
+        size: u64,
+        operations: Vec<InstallOperation>,
+    ) -> PartitionUpdate {
+        PartitionUpdate {
+            partition_name: name.to_string(),
+            new_partition_info: Some(PartitionInfo {
+                size: Some(size),
+                hash: None,
+            }),
+            operations,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn source_copy() -> Result<(), Box<dyn std::error::Error>> {
+        let mut old = Cursor::new((0..8u8).flat_map(|i| [i; 4]).collect::<Vec<_>>());
+        let mut dst = Cursor::new(vec![0u8; 16]);
+        let mut operation = InstallOperation {
+            src_extents: vec![extent(6, 2), extent(1, 1)],
+            dst_extents: vec![extent(0, 3)],
+            ..Default::default()
+        };
+        operation.set_type(Type::SourceCopy);
+
+        dump_operation(
+            &mut Cursor::new(vec![]),
+            0,
+            Some(&mut old),
+            &mut dst,
+            &operation,
+            4,
+        )?;
+        assert_eq!(&dst.get_ref()[..12], &[6, 6, 6, 6, 7, 7, 7, 7, 1, 1, 1, 1]);
+        assert_eq!(&dst.get_ref()[12..], &[0; 4]);
+
+        let result = dump_operation(
+            &mut Cursor::new(vec![]),
+            0,
+            None::<&mut Cursor<Vec<u8>>>,
+            &mut dst,
+            &operation,
+            4,
+        );
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn source_bsdiff_hash_mismatch() -> Result<(), Box<dyn std::error::Error>> {
+        let mut old = Cursor::new(vec![1u8; 8]);
+        let mut dst = Cursor::new(vec![0u8; 8]);
+        let patch = b"BSDIFF40 not really a patch".to_vec();
+        let mut operation = InstallOperation {
+            data_offset: Some(0),
+            data_length: Some(patch.len() as u64),
+            src_extents: vec![extent(0, 2)],
+            dst_extents: vec![extent(0, 2)],
+            data_sha256_hash: Some(vec![0; 32]),
+            ..Default::default()
+        };
+        operation.set_type(Type::SourceBsdiff);
+
+        let error = dump_operation(
+            &mut Cursor::new(&patch),
+            0,
+            Some(&mut old),
+            &mut dst,
+            &operation,
+            4,
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("hash mismatch"), "{}", error);
+        assert_eq!(dst.get_ref(), &[0; 8]);
+
+        Ok(())
+    }
+
+    /// Apply the PUFFDIFF patch from old.img to new.img in tests/data/puffdiff,
+    /// made from gzip files by generate.py there.
+    #[cfg(feature = "puffdiff")]
+    #[test]
+    fn puffdiff_fixture() -> Result<(), Box<dyn std::error::Error>> {
+        let old = include_bytes!("../tests/data/puffdiff/old.img");
+        let new = include_bytes!("../tests/data/puffdiff/new.img");
+        let patch = include_bytes!("../tests/data/puffdiff/patch.puf");
+        let mut operation = operation(
+            Type::Puffdiff,
+            Some((0, patch.len() as u64)),
+            vec![extent(1, 1)],
+        );
+        operation.src_extents = vec![extent(0, 1)];
+        operation.data_sha256_hash = Some(Sha256::digest(patch).to_vec());
+        let mut dst = Cursor::new(vec![0u8; 8192]);
+
+        let result = dump_operation(
+            &mut Cursor::new(&patch[..]),
+            0,
+            Some(&mut Cursor::new(&old[..])),
+            &mut dst,
+            &operation,
+            4096,
+        )?;
+        assert!(result.hash_verified);
+        assert_eq!(&dst.get_ref()[..4096], &[0; 4096]);
+        assert_eq!(&dst.get_ref()[4096..], new);
+
+        Ok(())
+    }
+
+    #[test]
+    fn move_overlapping() -> Result<(), Box<dyn std::error::Error>> {
+        let mut dst = Cursor::new((0..5u8).flat_map(|i| [i; 4]).collect::<Vec<_>>());
+        let mut operation = InstallOperation {
+            src_extents: vec![extent(0, 3)],
+            dst_extents: vec![extent(1, 3)],
+            ..Default::default()
+        };
+        operation.set_type(Type::Move);
+
+        dump_operation(
+            &mut Cursor::new(vec![]),
+            0,
+            None::<&mut Cursor<Vec<u8>>>,
+            &mut dst,
+            &operation,
+            4,
+        )?;
+        let expected = [0, 0, 1, 2, 4]
+            .iter()
+            .flat_map(|&i| [i; 4])
+            .collect::<Vec<_>>();
+        assert_eq!(dst.get_ref(), &expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn move_from_old() -> Result<(), Box<dyn std::error::Error>> {
+        let mut operation = operation(Type::Move, None, vec![extent(2, 2)]);
+        operation.src_extents = vec![extent(0, 2)];
+        let manifest = DeltaArchiveManifest {
+            block_size: Some(4),
+            partitions: vec![partition("system", 16, vec![operation])],
+            ..Default::default()
+        };
+        let mut file = Cursor::new(payload(&manifest, &[]));
+        let payload = DeltaUpdateFile::parse(&mut file)?;
+        let partition = &payload.partitions()[0];
+
+        // The moved blocks are read from the old image, not the zeroed dst.
+        let mut old = Cursor::new((0..5u8).flat_map(|i| [i; 4]).collect::<Vec<_>>());
+        let mut dst = Cursor::new(vec![0u8; 16]);
+        let options = DumpOptions::default();
+        payload.dump_partition_with(
+            &mut file,
+            partition,
+            Some(&mut old),
+            &mut dst,
+            &options,
+            |_| true,
+        )?;
+        let expected = [0, 1, 0, 1]
+            .iter()
+            .flat_map(|&i| [i; 4])
+            .collect::<Vec<_>>();
+        assert_eq!(dst.get_ref(), &expected);
+
+        let error = payload
+            .dump_partition(&mut file, partition, &mut dst)
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "partition system: operation 0 (type MOVE): MOVE requires the old partition image"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn source_copy_in_place() -> Result<(), Box<dyn std::error::Error>> {
+        let mut dst = Cursor::new((0..5u8).flat_map(|i| [i; 4]).collect::<Vec<_>>());
+        let mut operation = InstallOperation {
+            src_extents: vec![extent(0, 3)],
+            dst_extents: vec![extent(2, 3)],
+            ..Default::default()
+        };
+        operation.set_type(Type::SourceCopy);
+
+        dump_operation_in_place(&mut Cursor::new(vec![]), 0, &mut dst, &operation, 4)?;
+        let expected = [0, 1, 0, 1, 2]
+            .iter()
+            .flat_map(|&i| [i; 4])
+            .collect::<Vec<_>>();
+        assert_eq!(dst.get_ref(), &expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn bsdiff_in_place() -> Result<(), Box<dyn std::error::Error>> {
+        let le = |x: u64| x.to_le_bytes();
+        // BSDF2 patch with uncompressed blocks: add 3 bytes of diff to old.
+        let ctrl = [le(3), le(0), le(0)].concat();
+        let diff = [1, 1, 1];
+        let patch = [
+            &b"BSDF2"[..],
+            &[0, 0, 0],
+            &le(24),
+            &le(3),
+            &le(3),
+            &ctrl,
+            &diff,
+        ]
+        .concat();
+
+        let mut dst = Cursor::new([[1; 4], [2; 4], [9; 4]].concat());
+        let mut operation = operation(
+            Type::Bsdiff,
+            Some((0, patch.len() as u64)),
+            vec![extent(2, 1)],
+        );
+        operation.src_extents = vec![extent(1, 1), extent(0, 1)];
+        operation.src_length = Some(6);
+        operation.dst_length = Some(3);
+
+        let result = dump_operation(
+            &mut Cursor::new(&patch),
+            0,
+            None::<&mut Cursor<Vec<u8>>>,
+            &mut dst,
+            &operation,
+            4,
+        )?;
+        assert_eq!(dst.get_ref(), &[[1; 4], [2; 4], [3, 3, 3, 0]].concat());
+        assert_eq!(
+            (
+                result.op_type,
+                result.data_bytes_read,
+                result.bytes_written,
+                result.hash_verified
+            ),
+            (Type::Bsdiff, patch.len() as u64, 4, false)
+        );
+
+        // Dumping the partition patches the old image, not the zeroed dst.
+        let manifest = DeltaArchiveManifest {
+            block_size: Some(4),
+            partitions: vec![partition("system", 12, vec![operation.clone()])],
+            ..Default::default()
+        };
+        let mut file = Cursor::new(payload(&manifest, &patch));
+        let payload = DeltaUpdateFile::parse(&mut file)?;
+        let mut old = Cursor::new([[1; 4], [2; 4], [9; 4]].concat());
+        let mut dst = Cursor::new(vec![0u8; 12]);
+        payload.dump_partition_with(
+            &mut file,
+            &payload.partitions()[0],
+            Some(&mut old),
+            &mut dst,
+            &DumpOptions::default(),
+            |_| true,
+        )?;
+        assert_eq!(dst.get_ref(), &[[1; 4], [2; 4], [3, 3, 3, 0]].concat());
+
+        // The patch is checked against its hash, if it has one.
+        operation.data_sha256_hash = Some(Sha256::digest(&patch).to_vec());
+        let result = dump_operation(
+            &mut Cursor::new(&patch),
+            0,
+            None::<&mut Cursor<Vec<u8>>>,
+            &mut dst,
+            &operation,
+            4,
+        )?;
+        assert!(result.hash_verified);
+
+        operation.dst_length = Some(4);
+        assert!(dump_operation(
+            &mut Cursor::new(&patch),
+            0,
+            None::<&mut Cursor<Vec<u8>>>,
+            &mut dst,
+            &operation,
+            4
+        )
+        .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn replace_zstd_payload() -> Result<(), Box<dyn std::error::Error>> {
+        // A zstd frame of 4096 bytes, with a raw block "zstd" followed by an
+        // RLE block of 4092 zeros.
+        let frame = [
+            &[0x28, 0xb5, 0x2f, 0xfd, 0x60, 0x00, 0x0f][..],
+            &[0x20, 0x00, 0x00],
+            b"zstd",
+            &[0xe3, 0x7f, 0x00, 0x00],
+        ]
+        .concat();
+        let mut operation = InstallOperation {
+            data_offset: Some(0),
+            data_length: Some(frame.len() as u64),
+            dst_extents: vec![extent(1, 1)],
+            ..Default::default()
+        };
+        operation.set_type(Type::ReplaceZstd);
+        let manifest = DeltaArchiveManifest {
+            block_size: Some(4096),
+            partitions: vec![PartitionUpdate {
+                partition_name: "boot".to_string(),
+                operations: vec![operation],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let mut file = Cursor::new(payload(&manifest, &frame));
+        let payload = DeltaUpdateFile::read(&mut file)?;
+        let operation = &payload.partitions()[0].operations[0];
+        assert_eq!(operation.r#type(), Type::ReplaceZstd);
+
+        let mut dst = Cursor::new(vec![0xff; 8192]);
+        dump_operation(
+            &mut file,
+            payload.blobs_offset,
+            None::<&mut Cursor<Vec<u8>>>,
+            &mut dst,
+            operation,
+            4096,
+        )?;
+        assert_eq!(&dst.get_ref()[..4096], &[0xff; 4096]);
+        assert_eq!(&dst.get_ref()[4096..4100], b"zstd");
+        assert!(dst.get_ref()[4100..].iter().all(|&b| b == 0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn dump_partitions() -> Result<(), Box<dyn std::error::Error>> {
+        let replace = operation(Type::Replace, Some((0, 4)), vec![extent(0, 1)]);
+        let zero = operation(Type::Zero, None, vec![extent(1, 2)]);
+        let mut source_copy = operation(Type::SourceCopy, None, vec![extent(0, 1)]);
+        source_copy.src_extents = vec![extent(0, 1)];
+        let manifest = DeltaArchiveManifest {
+            block_size: Some(4),
+            partitions: vec![
+                partition("boot", 16, vec![replace, zero]),
+                partition("vendor", 4, vec![source_copy]),
+            ],
+            ..Default::default()
+        };
+        let mut file = Cursor::new(payload(&manifest, b"boot"));
+        let payload = DeltaUpdateFile::parse(&mut file)?;
+        let partitions = payload.partitions();
+        let blobs_offset = payload.blobs_offset;
+        assert_eq!(
+            payload.operation_blob_range(&partitions[0].operations[0]),
+            Some(blobs_offset..blobs_offset + 4)
+        );
+        assert_eq!(
+            payload.operation_blob_range(&partitions[0].operations[1]),
+            None
+        );
+
+        let mut dst = Cursor::new(vec![0xffu8; 16]);
+        let stats = payload.dump_partition(&mut file, &partitions[0], &mut dst)?;
+        // ZERO operations are skipped, as dst is expected to be zeroed.
+        assert_eq!(dst.get_ref(), &[&b"boot"[..], &[0xff; 12]].concat());
+        assert_eq!((stats.bytes_written, stats.data_bytes_read), (12, 4));
+        assert_eq!(
+            stats.operations.into_iter().collect::<Vec<_>>(),
+            [(Type::Replace, 1), (Type::Zero, 1)]
+        );
+
+        let error = payload
+            .dump_partition(&mut file, &partitions[1], &mut dst)
+            .unwrap_err();
+        assert_eq!(error.to_string(), "partition vendor: operation 0 (type SOURCE_COPY): SOURCE_COPY requires the old partition image");
+
+        // The same through Payload, which resolves the offsets itself.
+        let mut payload = Payload::parse(file)?;
+        assert_eq!(
+            payload.blobs_offset(),
+            payload.delta_update_file().blobs_offset
+        );
+        assert_eq!(payload.block_size(), 4);
+        assert_eq!(payload.partitions().len(), 2);
+        let operation = payload.partition("boot").unwrap().operations[0].clone();
+        let mut data = Vec::new();
+        payload.operation_data(&operation)?.read_to_end(&mut data)?;
+        assert_eq!(data, b"boot");
+        let mut dst = Cursor::new(vec![0xffu8; 16]);
+        let options = DumpOptions {
+            dense: true,
+            ..Default::default()
+        };
+        payload.dump_partition_with("boot", None::<&mut File>, &mut dst, &options, |_| true)?;
+        assert_eq!(dst.get_ref(), &[&b"boot"[..], &[0; 8], &[0xff; 4]].concat());
+        let error = payload.dump_partition("system", &mut dst).unwrap_err();
+        assert!(matches!(error, PayloadError::PartitionNotFound(name) if name == "system"));
+        let mut file = payload.into_inner();
+        file.rewind()?;
+        let payload = DeltaUpdateFile::parse(&mut file)?;
+
+        let dir = TempDir::new("test");
+        let out_dir = dir.0.join("out");
+        let result = payload.dump_all(&mut file, &out_dir);
+        let boot = std::fs::read(out_dir.join("boot.img"));
+        assert!(matches!(result, Err(PayloadError::Partition { .. })));
+        assert_eq!(boot?, [&b"boot"[..], &[0; 12]].concat());
+        Ok(())
+    }
+
+    #[test]
+    fn read_partition_to_vec() -> Result<(), Box<dyn std::error::Error>> {
+        let image: Vec<u8> = (0..3 * 4096u32).map(|i| (i % 251) as u8).collect();
+        let mut builder = PayloadBuilder::new(Cursor::new(Vec::new()), CreateOptions::default())?;
+        builder.add_partition("boot", &image[..])?;
+        let mut bytes = Vec::new();
+        builder.finish(&mut Cursor::new(&mut bytes))?;
+
+        let mut payload = Payload::parse(Cursor::new(bytes))?;
+        assert_eq!(payload.read_partition_to_vec("boot")?, image);
+        let (data, hash) = payload.read_partition_to_vec_with(
+            "boot",
+            None::<&mut File>,
+            &ReadOptions::default(),
+        )?;
+        assert_eq!(data, image);
+        assert_eq!(hash, Some(hash_image(&image[..])?.1));
+        assert_eq!(Some(hash_image(payload.partition_reader("boot")?)?.1), hash);
+
+        let options = ReadOptions {
+            max_size: 4096,
+            ..Default::default()
+        };
+        let error = payload
+            .read_partition_to_vec_with("boot", None::<&mut File>, &options)
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "partition boot: 12288 bytes is larger than the limit of 4096 bytes in memory"
+        );
+        assert!(matches!(
+            payload.read_partition_to_vec("system"),
+            Err(PayloadError::PartitionNotFound(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn malicious_names() -> Result<(), Box<dyn std::error::Error>> {
+        assert_eq!(sanitize_file_name("boot"), "boot");
+        assert_eq!(
+            sanitize_file_name("../../etc/cron.d/x"),
+            ".._.._etc_cron.d_x"
+        );
+        assert_eq!(sanitize_file_name(".."), "__");
+        assert_eq!(sanitize_file_name(""), "_");
+        assert_eq!(sanitize_file_name("a\\b\0c"), "a_b_c");
+        assert_eq!(
+            sanitize_file_name("con.img"),
+            if cfg!(windows) { "_con.img" } else { "con.img" }
+        );
+        assert_eq!(sanitize_file_name("com10"), "com10");
+
+        let options = CreateOptions {
+            block_size: 4096,
+            ..Default::default()
+        };
+        let mut builder = PayloadBuilder::new(Cursor::new(Vec::new()), options.clone())?;
+        for name in ["../escape", "/tmp/absolute", ".."] {
+            builder.add_partition(name, &[1u8; 4096][..])?;
+        }
+        let mut data = Vec::new();
+        builder.finish(&mut data)?;
+        let mut file = Cursor::new(data);
+        let payload = DeltaUpdateFile::parse(&mut file)?;
+
+        let dir = TempDir::new("names");
+        let out_dir = dir.0.join("out");
+        let result = payload.dump_all(&mut file, &out_dir);
+        let mut names: Vec<_> = std::fs::read_dir(&out_dir)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<Result<_, _>>()?;
+        let outside = std::fs::read_dir(&dir.0)?.count();
+        result?;
+        names.sort();
+        assert_eq!(
+            names,
+            [".._escape.img", "__.img", "_tmp_absolute.img"].map(std::ffi::OsString::from)
+        );
+        assert_eq!(outside, 1);
+
+        // Names made the same file name are refused before writing anything.
+        let mut builder = PayloadBuilder::new(Cursor::new(Vec::new()), options)?;
+        for name in ["a/b", "a_b"] {
+            builder.add_partition(name, &[1u8; 4096][..])?;
+        }
+        let mut data = Vec::new();
+        builder.finish(&mut data)?;
+        let mut file = Cursor::new(data);
+        let payload = DeltaUpdateFile::parse(&mut file)?;
+        let out_dir = dir.0.join("same");
+        let error = payload.dump_all(&mut file, &out_dir).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("partitions a/b and a_b are both written to"),
+            "{}",
+            error
+        );
+        assert!(!out_dir.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn sparse_zero() -> Result<(), Box<dyn std::error::Error>> {
+        let replace = operation(Type::Replace, Some((0, 4)), vec![extent(0, 1)]);
+        let zero = operation(Type::Zero, None, vec![extent(1, 255)]);
+        let partition = partition("userdata", 256 * 4096, vec![replace, zero]);
+        let manifest = DeltaArchiveManifest {
+            partitions: vec![partition.clone()],
+            ..Default::default()
+        };
+        let mut file = Cursor::new(payload(&manifest, b"data"));
+        let payload = DeltaUpdateFile::parse(&mut file)?;
+
+        let dir = TempDir::new("sparse");
+        let mut images = Vec::new();
+        for dense in [false, true] {
+            let path = dir.0.join(format!("{}.img", dense));
+            let mut img = create_image(&path, &partition)?;
+            let options = DumpOptions {
+                dense,
+                ..Default::default()
+            };
+            payload.dump_partition_with(
+                &mut file,
+                &partition,
+                None::<&mut File>,
+                &mut img,
+                &options,
+                |_| true,
+            )?;
+            images.push((std::fs::read(&path)?, img.metadata()?));
+        }
+
+        let (sparse, sparse_metadata) = &images[0];
+        let (dense, dense_metadata) = &images[1];
+        assert_eq!(Sha256::digest(sparse), Sha256::digest(dense));
+        assert_eq!(&sparse[..4], b"data");
+        assert_eq!(sparse_metadata.len(), dense_metadata.len());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            assert!(
+                sparse_metadata.blocks() < dense_metadata.blocks(),
+                "{} {}",
+                sparse_metadata.blocks(),
+                dense_metadata.blocks()
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn open_existing_image_size() -> Result<(), Box<dyn std::error::Error>> {
+        let partition = partition("boot", 8192, Vec::new());
+        let dir = TempDir::new("device");
+        let path = dir.0.join("device.img");
+        std::fs::write(&path, [0xffu8; 4096])?;
+        let small = open_existing_image(&path, &partition);
+        std::fs::write(&path, [0xffu8; 3 * 4096])?;
+        let large = open_existing_image(&path, &partition).map(|img| img.metadata());
+        let contents = std::fs::read(&path);
+
+        let error = small.unwrap_err().to_string();
+        assert!(
+            error.ends_with("4096 bytes, smaller than the partition (8192 bytes)"),
+            "{}",
+            error
+        );
+        // Larger files are not truncated.
+        assert_eq!(large??.len(), 3 * 4096);
+        assert_eq!(contents?, [0xffu8; 3 * 4096]);
+        Ok(())
+    }
+
+    #[test]
+    fn replace_zero_padding() -> Result<(), Box<dyn std::error::Error>> {
+        let data = [7u8; 100];
+        let mut operation = InstallOperation {
+            data_offset: Some(0),
+            data_length: Some(data.len() as u64),
+            dst_extents: vec![extent(1, 1)],
+            ..Default::default()
+        };
+        operation.set_type(Type::Replace);
+
+        let mut dst = Cursor::new(vec![0xffu8; 3 * 4096]);
+        dump_operation(
+            &mut Cursor::new(&data),
+            0,
+            None::<&mut Cursor<Vec<u8>>>,
+            &mut dst,
+            &operation,
+            4096,
+        )?;
+        let dst = dst.into_inner();
+        assert_eq!(&dst[..4096], &[0xff; 4096]);
+        assert_eq!(&dst[4096..4196], &data);
+        assert_eq!(&dst[4196..8192], &[0; 3996]);
+        assert_eq!(&dst[8192..], &[0xff; 4096]);
+        Ok(())
+    }
+
+    #[test]
+    fn size_mismatch() -> Result<(), Box<dyn std::error::Error>> {
+        // xz stream of "xz", 2 bytes for a block of 4.
+        let xz = [
+            0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00, 0x00, 0x01, 0x69, 0x22, 0xde, 0x36, 0x02, 0x00,
+            0x21, 0x01, 0x16, 0x00, 0x00, 0x00, 0x74, 0x2f, 0xe5, 0xa3, 0x01, 0x00, 0x01, 0x78,
+            0x7a, 0x00, 0x00, 0x00, 0x23, 0x79, 0xef, 0x16, 0x00, 0x01, 0x16, 0x02, 0xd0, 0x61,
+            0x10, 0xd2, 0x90, 0x42, 0x99, 0x0d, 0x01, 0x00, 0x00, 0x00, 0x00, 0x01, 0x59, 0x5a,
+        ];
+        let mut operations = vec![
+            InstallOperation {
+                data_offset: Some(0),
+                data_length: Some(4),
+                dst_extents: vec![extent(0, 1)],
+                ..Default::default()
+            },
+            InstallOperation {
+                data_offset: Some(4),
+                data_length: Some(xz.len() as u64),
+                dst_extents: vec![extent(1, 1)],
+                ..Default::default()
+            },
+        ];
+        operations[0].set_type(Type::Replace);
+        operations[1].set_type(Type::ReplaceXz);
+        let blobs = [&[1; 4][..], &xz].concat();
+
+        let dump = |operations: &[InstallOperation]| {
+            dump_operations(
+                &mut Cursor::new(&blobs),
+                0,
+                None::<&mut Cursor<Vec<u8>>>,
+                &mut Cursor::new(vec![0u8; 8]),
+                operations,
+                4,
+                &DumpOptions::default(),
+                |_| true,
+            )
+        };
+
+        let error = dump(&operations).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "operation 1 (type REPLACE_XZ): size mismatch: expected 4, got 2"
+        );
+        // The message of the wrapped error isn't repeated by its source.
+        assert!(std::error::Error::source(&error).is_none());
+        match error {
+            PayloadError::Operation {
+                index,
+                op_type,
+                source,
+            } => {
+                assert_eq!((index, op_type), (1, Type::ReplaceXz as i32));
+                assert!(matches!(
+                    *source,
+                    PayloadError::SizeMismatch {
+                        expected: 4,
+                        actual: 2
+                    }
+                ));
+            }
+            error => panic!("unexpected error {:?}", error),
+        }
+
+        // REPLACE data larger than dst_extents.
+        operations[0].data_length = Some(8);
+        let error = dump(&operations[..1]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "operation 0 (type REPLACE): produced more data than its destination extents: 8 bytes for 4 bytes of extents"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn decompressed_size() {
+        let mut operation = InstallOperation {
+            data_offset: Some(0),
+            dst_extents: vec![extent(0, 1), extent(2, 1)],
+            ..Default::default()
+        };
+        operation.set_type(Type::ReplaceBz);
+        let mut dump = |size: usize| {
+            let mut compressed = Vec::new();
+            libribzip2::stream::encode_stream(
+                &mut &vec![7u8; size][..],
+                &mut compressed,
+                1,
+                libribzip2::EncodingStrategy::Single,
+            );
+            operation.data_length = Some(compressed.len() as u64);
+            let mut dst = Cursor::new(vec![0u8; 12]);
+            dump_operation(
+                &mut Cursor::new(&compressed),
+                0,
+                None::<&mut Cursor<Vec<u8>>>,
+                &mut dst,
+                &operation,
+                4,
+            )
+            .map(|_| dst.into_inner())
+        };
+
+        assert_eq!(dump(8).unwrap(), [7, 7, 7, 7, 0, 0, 0, 0, 7, 7, 7, 7]);
+        assert!(matches!(
+            dump(7),
+            Err(PayloadError::SizeMismatch {
+                expected: 8,
+                actual: 7
+            })
+        ));
+        let error = dump(9).unwrap_err();
+        assert!(
+            matches!(
+                error,
+                PayloadError::ExtentOverflow {
+                    size: 8,
+                    produced: 9
+                }
+            ),
+            "{:?}",
+            error
+        );
+    }
+
+    #[test]
+    fn decompression_bomb() {
+        // 1 MiB of zeros, compressed with `xz -9e --check=crc32`.
+        let bomb = unhex(concat!(
+            "fd377a585a0000016922de3604c0e10180804021011c00000000000037e90383",
+            "efffff00d95d00006ffdffffa3b7ff473e481572396151b89228e6a38607f9ee",
+            "e41e82d32fc53a3c014bb17ec98a8a4d2fa30dd97fa6e38c231153e05918c575",
+            "8ae277f8b6947f0c6ac0de744964e2e95c53b204d8f7440cab5f0d6d46e9e5c3",
+            "7688b79657acb64de1691d6ffb4b88106c42cb883f5c008fd04eaf262894711f",
+            "3d8f24e1709ea7235fec28cb85d195988a7e2a91f22775f719c006984d98fdd8",
+            "afd5900fc42553f8f591363105a5b0ee6fc1704d470cd19111aaad601dbaceb1",
+            "27185c5986e9665258bee976ac59e4e55b0508f9c7daadfcfb522af7e4952500",
+            "000000001cea38a70001f901808040000257bd303e300d8b020000000001595a",
+        ));
+        // Concatenated streams decompress to 64 MiB.
+        let data = bomb.repeat(64);
+        let mut operation = InstallOperation {
+            data_offset: Some(0),
+            data_length: Some(data.len() as u64),
+            dst_extents: vec![extent(0, 1)],
+            ..Default::default()
+        };
+        operation.set_type(Type::ReplaceXz);
+
+        let mut dst = Cursor::new(Vec::new());
+        let error = dump_operation(
+            &mut Cursor::new(&data),
+            0,
+            None::<&mut Cursor<Vec<u8>>>,
+            &mut dst,
+            &operation,
+            4096,
+        )
+        .unwrap_err();
+        assert!(
+            matches!(
+                error,
+                PayloadError::DecompressionLimit {
+                    kind: Compression::Xz,
+                    limit: 8192
+                }
+            ),
+            "{:?}",
+            error
+        );
+        assert!(dst.get_ref().len() <= 4096);
+    }
+
+    #[test]
+    fn unsupported_operation() {
+        let mut dst = Cursor::new(vec![0u8; 8]);
+        let mut operation = InstallOperation {
+            data_offset: Some(0),
+            data_length: Some(4),
+            dst_extents: vec![extent(0, 2)],
+            ..Default::default()
+        };
+
+        operation.set_type(Type::Lz4diffBsdiff);
+        let error = dump_operation(
+            &mut Cursor::new(vec![1; 4]),
+            0,
+            None::<&mut Cursor<Vec<u8>>>,
+            &mut dst,
+            &operation,
+            4,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "operation type LZ4DIFF_BSDIFF is not supported"
+        );
+
+        // Unknown types must not be treated as REPLACE.
+        operation.r#type = 100;
+        let error = dump_operation(
+            &mut Cursor::new(vec![1; 4]),
+            0,
+            None::<&mut Cursor<Vec<u8>>>,
+            &mut dst,
+            &operation,
+            4,
+        )
+        .unwrap_err();
+        assert_eq!(error.to_string(), "operation type 100 is not supported");
+        assert!(matches!(error, PayloadError::UnsupportedOperation(100)));
+        assert_eq!(dst.get_ref(), &[0; 8]);
+    }
+
+    #[test]
+    fn error_sources() {
+        use std::error::Error as _;
+
+        let error = PayloadError::Io(Error::new(ErrorKind::UnexpectedEof, "short read"))
+            .in_operation(3, Type::Replace as i32)
+            .in_partition("boot");
+        assert_eq!(error.source().unwrap().to_string(), "short read");
+        let error = PayloadError::Decompression {
+            kind: error::Compression::Xz,
+            source: "corrupt block".into(),
+        };
+        assert_eq!(error.source().unwrap().to_string(), "corrupt block");
+        assert!(PayloadError::MissingData
+            .in_partition("boot")
+            .source()
+            .is_none());
+    }
+
+    #[test]
+    fn replace_bz_errors() {
+        let mut compressed = Vec::new();
+        libribzip2::stream::encode_stream(
+            &mut &[7u8; 8][..],
+            &mut compressed,
+            1,
+            libribzip2::EncodingStrategy::Single,
+        );
+        let mut operation = InstallOperation {
+            data_offset: Some(0),
+            data_length: Some(compressed.len() as u64),
+            dst_extents: vec![extent(0, 2)],
+            ..Default::default()
+        };
+        operation.set_type(Type::ReplaceBz);
+        let mut dst = Cursor::new(vec![0u8; 8]);
+        dump_operation(
+            &mut Cursor::new(&compressed),
+            0,
+            None::<&mut Cursor<Vec<u8>>>,
+            &mut dst,
+            &operation,
+            4,
+        )
+        .unwrap();
+        assert_eq!(dst.get_ref(), &[7; 8]);
+
+        let mut error = |data: &[u8]| {
+            let mut operation = operation.clone();
+            operation.data_length = Some(data.len() as u64);
+            dump_operation(
+                &mut Cursor::new(data),
+                0,
+                None::<&mut Cursor<Vec<u8>>>,
+                &mut dst,
+                &operation,
+                4,
+            )
+            .unwrap_err()
+            .to_string()
+        };
+        assert_eq!(
+            error(b"\xfd7zXZ\0"),
+            "bzip2 error: invalid magic [fd, 37, 7a] after 0 bytes"
+        );
+        assert_eq!(
+            error(b"BZh0"),
+            "bzip2 error: invalid block size 0x30 after 0 bytes"
+        );
+        // The block is decompressed before the missing footer is noticed,
+        // libbzip2 has its own messages.
+        let truncated = &compressed[..compressed.len() - 8];
+        if cfg!(feature = "bzip2-native") {
+            assert!(error(truncated).starts_with("bzip2 error: "));
+        } else {
+            assert_eq!(
+                error(truncated),
+                "bzip2 error: corrupt stream after 8 bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_block_size() {
+        let mut operation = InstallOperation {
+            dst_extents: vec![extent(0, 1)],
+            ..Default::default()
+        };
+        operation.set_type(Type::Zero);
+        for block_size in [0, 3] {
+            let error = dump_operation(
+                &mut Cursor::new(Vec::new()),
+                0,
+                None::<&mut Cursor<Vec<u8>>>,
+                &mut Cursor::new(vec![1u8; 4]),
+                &operation,
+                block_size,
+            )
+            .unwrap_err();
+            assert!(
+                matches!(error, PayloadError::InvalidBlockSize(size) if size == block_size),
+                "{}",
+                error
+            );
+        }
+        assert_eq!(check_block_size(4096).unwrap(), 4096);
+    }
+
+    #[test]
+    fn offset_overflow() {
+        // The offsets of the extents would wrap around to the start of dst.
+        for (start_block, num_blocks) in [(1 << 52, 1), (0, 1 << 52), (u64::MAX >> 12, 1)] {
+            let mut operation = InstallOperation {
+                dst_extents: vec![extent(start_block, num_blocks)],
+                ..Default::default()
+            };
+            operation.set_type(Type::Zero);
+            let mut dst = Cursor::new(vec![1u8; 8192]);
+            let error = dump_operation(
+                &mut Cursor::new(Vec::new()),
+                0,
+                None::<&mut Cursor<Vec<u8>>>,
+                &mut dst,
+                &operation,
+                4096,
+            )
+            .unwrap_err();
+            assert!(
+                error
+                    .to_string()
+                    .ends_with("with block_size 4096 overflows"),
+                "{}",
+                error
+            );
+            assert_eq!(dst.get_ref(), &[1u8; 8192]);
+        }
+        let error = dump_operations(
+            &mut Cursor::new(Vec::new()),
+            16,
+            None::<&mut Cursor<Vec<u8>>>,
+            &mut Cursor::new(vec![1u8; 8192]),
+            &[InstallOperation {
+                data_offset: Some(u64::MAX - 8),
+                data_length: Some(1),
+                dst_extents: vec![extent(0, 1)],
+                ..Default::default()
+            }],
+            4096,
+            &DumpOptions::default(),
+            |_| true,
+        )
+        .unwrap_err();
+        assert!(
+            error.to_string().ends_with("in the data blobs overflows"),
+            "{}",
+            error
+        );
+
+        // A signatures_offset which wraps around to the metadata.
+        let manifest = DeltaArchiveManifest {
+            block_size: Some(4096),
+            signatures_offset: Some(u64::MAX - 10),
+            signatures_size: Some(4),
+            ..Default::default()
+        };
+        let data = payload(&manifest, b"blobs");
+        let error = DeltaUpdateFile::parse(&mut Cursor::new(&data)).unwrap_err();
+        assert!(
+            error.to_string().contains("in the data blobs overflows"),
+            "{}",
+            error
+        );
+    }
+
+    /// RSA-1024 public key of the test signer.
+    const PUBLIC_KEY: &str = "-----BEGIN PUBLIC KEY-----
+MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQDQ1EtioN4OO5JLVWvnofuAp/C4
+XYUQ/l9bUU6VSi/iNJpocaazM03dDiWJeK5mzf3Vqnq8Ec2TiHayEYVPh+bOqFEB
+Zs4HTd9dI8mlIMzRmpRlOJEzhKo6cIiuKP53cNNnSwU8dRdrZqgiiIOufLuwbZoo
+v16zE4I7xRYobyHkwQIDAQAB
+-----END PUBLIC KEY-----
+";
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn signatures(data: Vec<u8>) -> Vec<u8> {
+        chromeos_update_engine::Signatures {
+            signatures: vec![Signature {
+                unpadded_signature_size: Some(data.len() as u32),
+                data: Some(data),
+                ..Default::default()
+            }],
+        }
+        .encode_to_vec()
+    }
+
+    #[test]
+    fn metadata_signature() -> Result<(), Box<dyn std::error::Error>> {
+        let manifest = DeltaArchiveManifest {
+            block_size: Some(4096),
+            minor_version: Some(0),
+            ..Default::default()
+        };
+        // Signed with `openssl pkeyutl -sign -pkeyopt digest:sha256` over the
+        // metadata hash.
+        let signature = unhex(concat!(
+            "92461f4c5b66bc4f1833e97a3c3ea1d9beb93df959c4e7e0f466bf8092868d2f",
+            "5fbbf833693793805d011fb26e7b7cd0814edf809d793d88f8e721e3ddd8aa9b",
+            "5ea8707eca927c736c31bbb7267e85632feb5f11561080db907308aa185cc2e0",
+            "955a999d6441729f61e2971513e0ef7902869e90e9eb93442dd9ecc96f934b51",
+        ));
+        let unsigned = payload(&manifest, &[]);
+        let data = signed_payload(&manifest, &signatures(signature.clone()), &[]);
+        let payload = DeltaUpdateFile::read(&mut Cursor::new(&data))?;
+
+        let metadata_size = payload.metadata_size as usize;
+        assert_eq!(metadata_size, 24 + manifest.encoded_len());
+        assert_eq!(
+            payload.metadata_hash,
+            <[u8; 32]>::from(Sha256::digest(&data[..metadata_size]))
+        );
+
+        payload.verify_metadata_signature(PUBLIC_KEY.as_bytes())?;
+        let (index, _) = payload.find_metadata_signature(PUBLIC_KEY.as_bytes())?;
+        assert_eq!(index, 0);
+
+        let mut corrupted = signature;
+        corrupted[0] ^= 1;
+        let data = signed_payload(&manifest, &signatures(corrupted), &[]);
+        let payload = DeltaUpdateFile::read(&mut Cursor::new(&data))?;
+        let error = payload
+            .verify_metadata_signature(PUBLIC_KEY.as_bytes())
+            .unwrap_err();
+        assert!(matches!(error, SignatureError::Mismatch));
+
+        let payload = DeltaUpdateFile::read(&mut Cursor::new(unsigned))?;
+        let error = payload
+            .verify_metadata_signature(PUBLIC_KEY.as_bytes())
+            .unwrap_err();
+        assert!(matches!(error, SignatureError::Unsigned));
+        Ok(())
+    }
+
+    #[test]
+    fn payload_signature() -> Result<(), Box<dyn std::error::Error>> {
+        let blobs = b"blob data".to_vec();
+        let placeholder = signatures(vec![0; 128]);
+        let manifest = DeltaArchiveManifest {
+            block_size: Some(4096),
+            signatures_offset: Some(blobs.len() as u64),
+            signatures_size: Some(placeholder.len() as u64),
+            ..Default::default()
+        };
+        // Signed with `openssl pkeyutl -sign -pkeyopt digest:sha256` over the
+        // payload hash.
+        let signature = signatures(unhex(concat!(
+            "695a38c2edd1bc6c502592d1792e7ebf0d5b5dfa0174cdf084fb172a5e446772",
+            "d8e16ed17ce5f44686332b12401e94e99674afbfbd71349394df7eed249c2523",
+            "03f910b8e29014c6cfefd1fd4418e2bf0450c28b47d2e4049e5b83872b358ac0",
+            "04e77489f485a1d422723320652a9987abfb428183b1502871912f267b4a0f1a",
+        )));
+        let data = signed_payload(&manifest, &placeholder, &[&blobs[..], &signature].concat());
+        let payload = DeltaUpdateFile::read(&mut Cursor::new(&data))?;
+
+        // The metadata signature is not covered.
+        let metadata_size = payload.metadata_size as usize;
+        let signed = [
+            &data[..metadata_size],
+            &data[payload.blobs_offset as usize..][..blobs.len()],
+        ]
+        .concat();
+        let hash = payload.payload_hash(&mut Cursor::new(&data))?;
+        assert_eq!(hash, <[u8; 32]>::from(Sha256::digest(&signed)));
+
+        payload.verify_payload_signature(&mut Cursor::new(&data), PUBLIC_KEY.as_bytes())?;
+
+        let truncated = &data[..data.len() - signature.len() - 1];
+        let error = payload
+            .verify_payload_signature(&mut Cursor::new(truncated), PUBLIC_KEY.as_bytes())
+            .unwrap_err();
+        assert!(matches!(error, SignatureError::Io(_)));
+
+        let mut corrupted = data.clone();
+        corrupted[payload.blobs_offset as usize] ^= 1;
+        let error = payload
+            .verify_payload_signature(&mut Cursor::new(corrupted), PUBLIC_KEY.as_bytes())
+            .unwrap_err();
+        assert!(matches!(error, SignatureError::Mismatch));
+        Ok(())
+    }
+
+    #[test]
+    fn decode_signatures() -> Result<(), Box<dyn std::error::Error>> {
+        let unsigned = payload(&DeltaArchiveManifest::default(), &[]);
+        let blobs = b"blob data".to_vec();
+        // An EC signature padded to the maximum size of the key.
+        let padded = chromeos_update_engine::Signatures {
+            signatures: vec![Signature {
+                data: Some([&[1, 2, 3][..], &[0; 5]].concat()),
+                unpadded_signature_size: Some(3),
+                ..Default::default()
+            }],
+        }
+        .encode_to_vec();
+        let manifest = DeltaArchiveManifest {
+            block_size: Some(4096),
+            signatures_offset: Some(blobs.len() as u64),
+            signatures_size: Some(padded.len() as u64),
+            ..Default::default()
+        };
+        let data = signed_payload(
+            &manifest,
+            &signatures(vec![4; 16]),
+            &[&blobs[..], &padded].concat(),
+        );
+        let payload = DeltaUpdateFile::read(&mut Cursor::new(&data))?;
+
+        let metadata = payload.metadata_signatures()?.signatures;
+        assert_eq!(metadata.len(), 1);
+        assert_eq!(metadata[0].unpadded_data(), &[4; 16]);
+        let signatures = payload.payload_signatures()?.signatures;
+        assert_eq!(signatures.len(), 1);
+        assert_eq!(signatures[0].data(), &[1, 2, 3, 0, 0, 0, 0, 0]);
+        assert_eq!(signatures[0].unpadded_data(), &[1, 2, 3]);
+
+        let header = PayloadHeader::parse_prefix(&mut Cursor::new(&data))?;
+        assert_eq!(
+            header.metadata_signatures()?,
+            payload.metadata_signatures()?
+        );
+
+        let unsigned = DeltaUpdateFile::read(&mut Cursor::new(unsigned))?;
+        assert!(unsigned.metadata_signatures()?.signatures.is_empty());
+        let error = DeltaUpdateFile {
+            payload_signatures_message_data: vec![0xff],
+            ..unsigned
+        }
+        .payload_signatures()
+        .unwrap_err();
+        assert!(matches!(error, SignatureError::Decode(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn read_metadata_truncated() -> Result<(), Box<dyn std::error::Error>> {
+        let manifest = DeltaArchiveManifest {
+            block_size: Some(4096),
+            signatures_offset: Some(1 << 20),
+            signatures_size: Some(256),
+            ..Default::default()
+        };
+        let data = payload(&manifest, b"truncated blobs");
+
+        assert!(DeltaUpdateFile::read(&mut Cursor::new(&data)).is_err());
+        let payload = DeltaUpdateFile::read_metadata(&mut Cursor::new(&data))?;
+        assert_eq!(payload.manifest, manifest);
+        assert!(payload.payload_signatures_message_data.is_empty());
+
+        let needed = payload.blobs_offset + (1 << 20) + 256;
+        let error = payload.validate_against_len(data.len() as u64).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "payload appears truncated: need at least {} bytes, file is {} bytes",
+                needed,
+                data.len()
+            )
+        );
+        payload.validate_against_len(needed)?;
+        Ok(())
+    }
+
+    #[test]
+    fn parse_prefix() -> Result<(), Box<dyn std::error::Error>> {
+        let manifest = DeltaArchiveManifest {
+            block_size: Some(4096),
+            signatures_offset: Some(4),
+            signatures_size: Some(3),
+            ..Default::default()
+        };
+        let data = signed_payload(&manifest, b"metadata signature", b"blobsig");
+        let payload = DeltaUpdateFile::read(&mut Cursor::new(&data))?;
+
+        // Parsed from a stream, which is left at the data blobs.
+        let mut stream = &data[..];
+        let header = PayloadHeader::parse_prefix(&mut stream)?;
+        assert_eq!(stream, b"blobsig");
+        assert_eq!(header.manifest, manifest);
+        assert_eq!(header.manifest_data, manifest.encode_to_vec());
+        assert_eq!(header.manifest_data, payload.manifest_data);
+        assert_eq!(header.payload_type(), PayloadType::Full);
+        assert!(!payload.is_delta());
+        assert_eq!(header.metadata_size, payload.metadata_size);
+        assert_eq!(header.metadata_hash, payload.metadata_hash);
+        assert_eq!(header.metadata_signature_message, b"metadata signature");
+        assert_eq!(header.blobs_offset, payload.blobs_offset);
+
+        let signatures = header.read_payload_signatures(&mut Cursor::new(&data))?;
+        assert_eq!(signatures, b"sig");
+        // A bogus size is read up to the end of the payload, not allocated.
+        let mut bogus = header.clone();
+        bogus.manifest.signatures_size = Some(u64::MAX);
+        let error = bogus
+            .read_payload_signatures(&mut Cursor::new(&data))
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("payload signature is truncated"),
+            "{}",
+            error
+        );
+        let file = header.into_delta_update_file(signatures);
+        assert_eq!(
+            file.payload_signatures_message_data,
+            payload.payload_signatures_message_data
+        );
+
+        let error =
+            PayloadHeader::parse_prefix(&mut &b"PK\x03\x04 not a payload header"[..]).unwrap_err();
+        assert!(matches!(
+            error,
+            PayloadError::Parse(binrw::Error::BadMagic { .. })
+        ));
+
+        // A bogus manifest size is read up to the end of the payload, not
+        // allocated.
+        let mut bogus = data.clone();
+        bogus[12..20].copy_from_slice(&(u64::MAX / 2).to_be_bytes());
+        let error = PayloadHeader::parse_prefix(&mut &bogus[..]).unwrap_err();
+        assert!(
+            error.to_string().contains("manifest is truncated"),
+            "{}",
+            error
+        );
+        let error = DeltaUpdateFile::parse(&mut Cursor::new(&bogus)).unwrap_err();
+        assert!(
+            error.to_string().contains("manifest is truncated"),
+            "{}",
+            error
+        );
+
+        // So is a bogus metadata signature size.
+        let mut bogus = data.clone();
+        bogus[20..24].copy_from_slice(&u32::MAX.to_be_bytes());
+        let error = PayloadHeader::parse_prefix(&mut &bogus[..]).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("metadata signature is truncated"),
+            "{}",
+            error
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn write_header() -> Result<(), Box<dyn std::error::Error>> {
+        use binrw::BinWrite;
+
+        let partition = |name: &str| PartitionUpdate {
+            partition_name: name.to_string(),
+            ..Default::default()
+        };
+        let manifest = DeltaArchiveManifest {
+            block_size: Some(4096),
+            signatures_offset: Some(4),
+            signatures_size: Some(3),
+            partitions: vec![partition("boot"), partition("system")],
+            ..Default::default()
+        };
+        let data = signed_payload(&manifest, b"metadata signature", b"blobsig");
+        let payload = DeltaUpdateFile::parse(&mut Cursor::new(&data))?;
+        let header = PayloadHeader::parse_prefix(&mut &data[..])?;
+        let write = |value: &dyn Fn(&mut Cursor<Vec<u8>>) -> binrw::BinResult<()>| {
+            let mut written = Cursor::new(Vec::new());
+            value(&mut written).map(|_| written.into_inner())
+        };
+
+        // Unmodified, the header is written back as is.
+        let metadata = &data[..payload.blobs_offset as usize];
+        assert_eq!(write(&|out| payload.write(out))?, metadata);
+        assert_eq!(write(&|out| header.write(out))?, metadata);
+
+        // Even with a field unknown to the manifest, number 1023, which
+        // encoding it again would drop.
+        let manifest_data = [&manifest.encode_to_vec()[..], &[0xf8, 0x3f, 0x01]].concat();
+        let size = (manifest_data.len() as u64).to_be_bytes();
+        let unknown = [
+            &b"CrAU"[..],
+            &2u64.to_be_bytes(),
+            &size,
+            &0u32.to_be_bytes(),
+            &manifest_data,
+        ]
+        .concat();
+        let parsed = PayloadHeader::parse_prefix(&mut &unknown[..])?;
+        assert_eq!(parsed.manifest, manifest);
+        assert_eq!(write(&|out| parsed.write(out))?, unknown);
+
+        // A modified manifest is encoded again, with its new size.
+        let mut modified = header.clone();
+        modified.manifest.partitions.truncate(1);
+        modified.manifest.max_timestamp = Some(1234);
+        modified.metadata_signature_message.clear();
+        let written = write(&|out| modified.write(out))?;
+        let reparsed = PayloadHeader::parse_prefix(&mut &written[..])?;
+        assert_eq!(reparsed.manifest, modified.manifest);
+        assert_eq!(
+            reparsed.manifest_size,
+            modified.manifest.encoded_len() as u64
+        );
+        assert_eq!(reparsed.metadata_signature_size, 0);
+        assert_eq!(reparsed.blobs_offset, written.len() as u64);
+        let trimmed =
+            DeltaUpdateFile::parse(&mut Cursor::new([&written[..], b"blobsig"].concat()))?;
+        assert_eq!(trimmed.payload_signatures_message_data, b"sig");
+
+        // Major version 1 payloads have no metadata signature.
+        let version_1 = PayloadHeader {
+            file_format_version: 1,
+            ..header
+        };
+        let error = write(&|out| version_1.write(out)).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("payload major version 1 has no metadata signature"),
+            "{}",
+            error
+        );
+        Ok(())
+    }
+}
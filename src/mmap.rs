@@ -0,0 +1,21 @@
+use std::fs::File;
+use std::io::{Cursor, Result};
+use std::path::Path;
+
+use memmap2::Mmap;
+
+/// Map the file at `path` read-only into memory.
+///
+/// Reads from the returned cursor are copies from the mapping instead of
+/// syscalls, and sections of it can be borrowed with
+/// [`SectionFile::as_slice`](crate::SectionFile::as_slice).
+///
+/// The file must not be truncated or modified while mapped, which would
+/// crash the process or change the data being read.
+pub fn map_file(path: &Path) -> Result<Cursor<Mmap>> {
+    let file = File::open(path)?;
+    // SAFETY: the mapping is read-only, and the payload isn't expected to
+    // change while it's being extracted, see above.
+    let map = unsafe { Mmap::map(&file)? };
+    Ok(Cursor::new(map))
+}
@@ -0,0 +1,91 @@
+use std::ops::Range;
+
+use crate::chromeos_update_engine::{Extent, InstallOperation};
+use crate::extent::SPARSE_HOLE;
+
+/// Positions and sizes of an [`InstallOperation`] in the payload and in the
+/// partition.
+pub trait InstallOperationExt {
+    /// Range of the data of the operation in the payload, whose data blobs
+    /// start at `blobs_offset`, or `None` if it has no data, or if the range
+    /// overflows, which [`validate_data_ranges`](crate::validate_data_ranges)
+    /// reports.
+    fn blob_range(&self, blobs_offset: u64) -> Option<Range<u64>>;
+
+    /// Bytes of the dst extents of the operation with blocks of
+    /// `block_size` bytes, holes included, saturating instead of
+    /// overflowing.
+    fn dst_byte_len(&self, block_size: u64) -> u64;
+}
+
+impl InstallOperationExt for InstallOperation {
+    fn blob_range(&self, blobs_offset: u64) -> Option<Range<u64>> {
+        let (offset, length) = self.data_offset.zip(self.data_length)?;
+        let start = blobs_offset.checked_add(offset)?;
+        Some(start..start.checked_add(length)?)
+    }
+
+    fn dst_byte_len(&self, block_size: u64) -> u64 {
+        self.dst_extents
+            .iter()
+            .map(|extent| extent.num_blocks())
+            .fold(0, u64::saturating_add)
+            .saturating_mul(block_size)
+    }
+}
+
+/// Positions of an [`Extent`] in the partition.
+pub trait ExtentExt {
+    /// Range of the bytes of the extent with blocks of `block_size` bytes,
+    /// or `None` if it's a hole, or if the range overflows, which
+    /// [`validate_dst_extents`](crate::validate_dst_extents) reports.
+    fn byte_range(&self, block_size: u64) -> Option<Range<u64>>;
+
+    /// Whether the extent is a hole of an old payload, see [`SPARSE_HOLE`].
+    fn is_sparse_hole(&self) -> bool;
+}
+
+impl ExtentExt for Extent {
+    fn byte_range(&self, block_size: u64) -> Option<Range<u64>> {
+        if self.is_sparse_hole() {
+            return None;
+        }
+        let start = self.start_block().checked_mul(block_size)?;
+        let size = self.num_blocks().checked_mul(block_size)?;
+        Some(start..start.checked_add(size)?)
+    }
+
+    fn is_sparse_hole(&self) -> bool {
+        self.start_block() == SPARSE_HOLE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::extent;
+
+    #[test]
+    fn ranges() {
+        let mut operation = InstallOperation {
+            data_offset: Some(10),
+            data_length: Some(5),
+            dst_extents: vec![extent(2, 3), extent(SPARSE_HOLE, 1)],
+            ..Default::default()
+        };
+        assert_eq!(operation.blob_range(100), Some(110..115));
+        assert_eq!(operation.dst_byte_len(4096), 4 * 4096);
+        assert_eq!(operation.dst_extents[0].byte_range(4096), Some(8192..20480));
+        assert!(!operation.dst_extents[0].is_sparse_hole());
+        assert!(operation.dst_extents[1].is_sparse_hole());
+        assert_eq!(operation.dst_extents[1].byte_range(4096), None);
+
+        // Without data, or overflowing.
+        assert_eq!(operation.blob_range(u64::MAX - 12), None);
+        operation.data_length = None;
+        assert_eq!(operation.blob_range(0), None);
+        assert_eq!(extent(u64::MAX / 4096, 2).byte_range(4096), None);
+        operation.dst_extents.push(extent(0, u64::MAX));
+        assert_eq!(operation.dst_byte_len(4096), u64::MAX);
+    }
+}
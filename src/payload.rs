@@ -0,0 +1,276 @@
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, Write};
+use std::ops::Range;
+
+use crate::chromeos_update_engine::{InstallOperation, PartitionUpdate};
+use crate::{
+    buffer_len, hash_image, verify_hash, DeltaUpdateFile, DumpOptions, DumpStats, PartitionReader,
+    PayloadError, SectionFile,
+};
+
+/// Options of [`Payload::read_partition_to_vec_with`].
+#[derive(Debug, Clone)]
+pub struct ReadOptions {
+    /// Largest partition read in memory, in bytes, so a bogus manifest
+    /// can't make it allocate more. 1 GiB by default.
+    pub max_size: u64,
+    /// Check the size and SHA-256 of the partition against
+    /// `new_partition_info`, on by default.
+    pub verify: bool,
+    /// Options of applying the operations, see [`DumpOptions`].
+    pub dump: DumpOptions,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        Self {
+            max_size: 1 << 30,
+            verify: true,
+            dump: DumpOptions::default(),
+        }
+    }
+}
+
+/// A parsed payload with its reader, resolving the offsets of the data
+/// blobs and the block size for the operations.
+pub struct Payload<R> {
+    reader: R,
+    file: DeltaUpdateFile,
+    /// Partitions of major version 1 payloads, which are not in
+    /// `manifest.partitions`.
+    legacy_partitions: Option<Vec<PartitionUpdate>>,
+}
+
+impl<R: Read + Seek> Payload<R> {
+    /// Parse the payload in `reader`, from its start.
+    pub fn parse(mut reader: R) -> Result<Self, PayloadError> {
+        reader.rewind()?;
+        let file = DeltaUpdateFile::parse(&mut reader)?;
+        Ok(Self::new(reader, file))
+    }
+
+    /// Create a payload from its `reader` and `file` already parsed from it.
+    pub fn new(reader: R, file: DeltaUpdateFile) -> Self {
+        let legacy_partitions = match file.file_format_version {
+            version if version < 2 => Some(file.partitions().into_owned()),
+            _ => None,
+        };
+        Self {
+            reader,
+            file,
+            legacy_partitions,
+        }
+    }
+
+    /// The parsed payload.
+    pub fn delta_update_file(&self) -> &DeltaUpdateFile {
+        &self.file
+    }
+
+    /// Get a mutable reference to the reader of the payload.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// Consume the payload, returning its reader.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Offset of the data blobs in the payload.
+    pub fn blobs_offset(&self) -> u64 {
+        self.file.blobs_offset
+    }
+
+    /// Size of the blocks of the extents of the operations.
+    pub fn block_size(&self) -> u64 {
+        self.file.manifest.block_size() as u64
+    }
+
+    /// Partitions updated by this payload, see [`DeltaUpdateFile::partitions`].
+    pub fn partitions(&self) -> &[PartitionUpdate] {
+        self.legacy_partitions
+            .as_deref()
+            .unwrap_or(&self.file.manifest.partitions)
+    }
+
+    /// The partition named `name`.
+    pub fn partition(&self, name: &str) -> Option<&PartitionUpdate> {
+        self.partitions().iter().find(|p| p.partition_name == name)
+    }
+
+    /// The data attached to `operation`, see [`Payload::operation_blob_reader`].
+    pub fn operation_data(
+        &mut self,
+        operation: &InstallOperation,
+    ) -> Result<impl Read + '_, PayloadError> {
+        self.operation_blob_reader(operation)
+    }
+
+    /// Range of the data of `operation` in the payload, see
+    /// [`InstallOperationExt::blob_range`](crate::InstallOperationExt::blob_range).
+    pub fn operation_blob_range(&self, operation: &InstallOperation) -> Option<Range<u64>> {
+        self.file.operation_blob_range(operation)
+    }
+
+    /// Reader of the data attached to `operation`, failing with
+    /// [`PayloadError::MissingData`] if it has none.
+    pub fn operation_blob_reader(
+        &mut self,
+        operation: &InstallOperation,
+    ) -> Result<SectionFile<&mut R>, PayloadError> {
+        let (offset, length) = operation
+            .data_offset
+            .zip(operation.data_length)
+            .ok_or(PayloadError::MissingData)?;
+        let offset = crate::blob_offset(self.file.blobs_offset, offset)?;
+        Ok(SectionFile::new(&mut self.reader, offset, length)?)
+    }
+
+    /// Dump the partition `name` to `dst`, see
+    /// [`DeltaUpdateFile::dump_partition`].
+    pub fn dump_partition<W>(&mut self, name: &str, dst: &mut W) -> Result<DumpStats, PayloadError>
+    where
+        W: Read + Write + Seek + Send,
+    {
+        let partition = find_partition(&self.legacy_partitions, &self.file, name)?;
+        self.file.dump_partition(&mut self.reader, partition, dst)
+    }
+
+    /// Dump the partition `name` to `dst`, see
+    /// [`DeltaUpdateFile::dump_partition_with`].
+    pub fn dump_partition_with<O, W, F>(
+        &mut self,
+        name: &str,
+        old: Option<&mut O>,
+        dst: &mut W,
+        options: &DumpOptions,
+        progress: F,
+    ) -> Result<Option<DumpStats>, PayloadError>
+    where
+        O: Read + Seek + Send,
+        W: Read + Write + Seek + Send,
+        F: Fn(usize) -> bool + Sync,
+    {
+        let partition = find_partition(&self.legacy_partitions, &self.file, name)?;
+        self.file
+            .dump_partition_with(&mut self.reader, partition, old, dst, options, progress)
+    }
+
+    /// Reader of the contents of the partition `name`, applying the
+    /// operations as it's read, so images of any size are read in bounded
+    /// memory, see [`PartitionReader`]. Partitions reading from the old
+    /// partition need [`Payload::partition_reader_with`].
+    pub fn partition_reader(
+        &mut self,
+        name: &str,
+    ) -> Result<PartitionReader<'_, R, File>, PayloadError> {
+        self.partition_reader_with(name, None)
+    }
+
+    /// Reader of the contents of the partition `name`, reading from `old`,
+    /// the image of the old partition, if it's a delta.
+    pub fn partition_reader_with<'a, O: Read + Seek>(
+        &'a mut self,
+        name: &str,
+        old: Option<&'a mut O>,
+    ) -> Result<PartitionReader<'a, R, O>, PayloadError> {
+        let block_size = self.block_size();
+        let partition = find_partition(&self.legacy_partitions, &self.file, name)?;
+        PartitionReader::new(
+            &mut self.reader,
+            self.file.blobs_offset,
+            block_size,
+            partition,
+            old,
+        )
+        .map_err(|e| e.in_partition(name))
+    }
+
+    /// Read the partition `name` in memory, with the default
+    /// [`ReadOptions`]: it's checked against the manifest, and fails if
+    /// it's larger than 1 GiB. Partitions reading from the old partition
+    /// need [`Payload::read_partition_to_vec_with`].
+    pub fn read_partition_to_vec(&mut self, name: &str) -> Result<Vec<u8>, PayloadError> {
+        let (data, _) =
+            self.read_partition_to_vec_with(name, None::<&mut File>, &ReadOptions::default())?;
+        Ok(data)
+    }
+
+    /// Read the partition `name` in memory, reading from `old`, the image
+    /// of the old partition, if it's a delta. The partition has the size in
+    /// `new_partition_info`, or the end of its dst extents if it has none,
+    /// and fails with [`PayloadError::TooLarge`] before anything is
+    /// allocated if that's more than `options.max_size`.
+    ///
+    /// Returns the bytes of the partition, and their SHA-256 if
+    /// `options.verify` is set, after checking it against the manifest.
+    pub fn read_partition_to_vec_with<O>(
+        &mut self,
+        name: &str,
+        old: Option<&mut O>,
+        options: &ReadOptions,
+    ) -> Result<(Vec<u8>, Option<[u8; 32]>), PayloadError>
+    where
+        O: Read + Seek + Send,
+    {
+        let block_size = self.block_size();
+        let partition = find_partition(&self.legacy_partitions, &self.file, name)?;
+        let info = partition.new_partition_info.clone().unwrap_or_default();
+        let size = match info.size {
+            Some(size) => size,
+            None => partition
+                .operations
+                .iter()
+                .flat_map(|operation| &operation.dst_extents)
+                .map(|extent| {
+                    extent
+                        .start_block()
+                        .saturating_add(extent.num_blocks())
+                        .saturating_mul(block_size)
+                })
+                .max()
+                .unwrap_or(0),
+        };
+        if size > options.max_size {
+            return Err(PayloadError::TooLarge {
+                size,
+                max: options.max_size,
+            }
+            .in_partition(name));
+        }
+
+        let len = buffer_len(size).map_err(|e| PayloadError::from(e).in_partition(name))?;
+        let mut dst = Cursor::new(vec![0; len]);
+        self.file.dump_partition_with(
+            &mut self.reader,
+            partition,
+            old,
+            &mut dst,
+            &options.dump,
+            |_| true,
+        )?;
+        let data = dst.into_inner();
+        if !options.verify {
+            return Ok((data, None));
+        }
+        let (size, hash) = hash_image(&data[..])?;
+        verify_hash(size, &hash, &info).map_err(|e| e.in_partition(name))?;
+        Ok((data, Some(hash)))
+    }
+}
+
+/// Find the partition `name`, borrowing only the partitions so the reader
+/// can be borrowed mutably at the same time.
+pub(crate) fn find_partition<'a>(
+    legacy_partitions: &'a Option<Vec<PartitionUpdate>>,
+    file: &'a DeltaUpdateFile,
+    name: &str,
+) -> Result<&'a PartitionUpdate, PayloadError> {
+    legacy_partitions
+        .as_deref()
+        .unwrap_or(&file.manifest.partitions)
+        .iter()
+        .find(|p| p.partition_name == name)
+        .ok_or_else(|| PayloadError::PartitionNotFound(name.to_string()))
+}
@@ -0,0 +1,776 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::sync::mpsc::{channel, sync_channel, Receiver, RecvError, Sender, TryRecvError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::chromeos_update_engine::{install_operation::Type, InstallOperation};
+use crate::extent::FragmentFile;
+use crate::trace::operation_span;
+use crate::{apply_in_memory, blob_offset, buffer_len, check_block_size, read_extents};
+use crate::{DumpContext, DumpOptions, DumpStats, OperationResult, PayloadError};
+
+/// An operation with its data read from the payload, or borrowed from it
+/// if it's in memory.
+struct Job<'a> {
+    index: usize,
+    operation: &'a InstallOperation,
+    data: Cow<'a, [u8]>,
+    /// Span of the operation, entered by each thread working on it.
+    span: tracing::Span,
+}
+
+/// An operation ready to be written.
+enum Prepared<'a> {
+    /// New data of `dst_extents`, decompressed by a worker, and the result
+    /// of decompressing it.
+    Decompressed(&'a InstallOperation, Vec<u8>, OperationResult),
+    /// An operation to apply by the writer, with its data.
+    Apply(&'a InstallOperation, Cow<'a, [u8]>),
+}
+
+/// How often, and how long, the workers decompressing the data of the
+/// operations waited for it to be read, see [`DumpStats::read_waits`](crate::DumpStats::read_waits).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReadWaits {
+    /// Number of times a worker found no operation read yet.
+    pub count: usize,
+    /// Time the workers waited, added up.
+    pub elapsed: Duration,
+}
+
+impl ReadWaits {
+    /// Add the waits of `other`.
+    pub fn add(&mut self, other: &ReadWaits) {
+        self.count += other.count;
+        self.elapsed += other.elapsed;
+    }
+}
+
+/// Where the data of the operations comes from.
+pub(crate) enum Source<'a, R> {
+    /// Read with seeks, in payload order.
+    Reader(&'a mut R),
+    /// Borrowed from the payload in memory, like a memory mapped file.
+    Slice(&'a [u8]),
+}
+
+/// Apply `operations` to `dst` like [`dump_operation`](crate::dump_operation), but pipelined: the
+/// data of the operations is prefetched in payload order on the calling
+/// thread, `options.workers` threads decompress REPLACE, REPLACE_BZ,
+/// REPLACE_XZ and REPLACE_ZSTD data into memory, and a writer thread
+/// applies the operations to `dst` in order.
+///
+/// At most `options.in_flight` operations, with `options.prefetch_bytes`
+/// bytes of data, are read but not written yet, which bounds the memory
+/// used. ZERO operations are skipped unless
+/// `options.dense` is set, see [`DumpOptions::dense`], and the first
+/// `options.skip_operations` operations are not applied. Operations are
+/// applied in place if `options.in_place` is set, see
+/// [`DumpOptions::in_place`], `old` is not used then. `progress` is called
+/// with the index of each operation before it's written, applying stops if
+/// it returns false.
+///
+/// Returns false if stopped by `progress`.
+#[allow(clippy::too_many_arguments)]
+pub fn dump_operations<R, O, W, F>(
+    src: &mut R,
+    src_blobs_offset: u64,
+    old: Option<&mut O>,
+    dst: &mut W,
+    operations: &[InstallOperation],
+    block_size: u64,
+    options: &DumpOptions,
+    progress: F,
+) -> Result<bool, PayloadError>
+where
+    R: Read + Seek,
+    O: Read + Seek + Send,
+    W: Read + Write + Seek + Send,
+    F: Fn(usize) -> bool + Sync,
+{
+    let source = Source::Reader(src);
+    run(
+        source,
+        src_blobs_offset,
+        old,
+        dst,
+        operations,
+        block_size,
+        options,
+        progress,
+        &mut DumpStats::default(),
+    )
+}
+
+/// Apply `operations` to `dst` like [`dump_operations`], with the payload
+/// in memory in `src`, e.g. memory mapped. The data of the operations is
+/// borrowed from `src` instead of copied, and the decompressors read it
+/// directly, and don't count in `options.prefetch_bytes`.
+#[allow(clippy::too_many_arguments)]
+pub fn dump_operations_from_slice<O, W, F>(
+    src: &[u8],
+    src_blobs_offset: u64,
+    old: Option<&mut O>,
+    dst: &mut W,
+    operations: &[InstallOperation],
+    block_size: u64,
+    options: &DumpOptions,
+    progress: F,
+) -> Result<bool, PayloadError>
+where
+    O: Read + Seek + Send,
+    W: Read + Write + Seek + Send,
+    F: Fn(usize) -> bool + Sync,
+{
+    let source = Source::<std::io::Empty>::Slice(src);
+    run(
+        source,
+        src_blobs_offset,
+        old,
+        dst,
+        operations,
+        block_size,
+        options,
+        progress,
+        &mut DumpStats::default(),
+    )
+}
+
+/// Apply `operations` from `src` like [`dump_operations`], adding the
+/// result of each operation applied and the waits of the workers for their
+/// data to `stats`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn run<'a, R, O, W, F>(
+    src: Source<'a, R>,
+    src_blobs_offset: u64,
+    old: Option<&mut O>,
+    dst: &mut W,
+    operations: &'a [InstallOperation],
+    block_size: u64,
+    options: &DumpOptions,
+    progress: F,
+    stats: &mut DumpStats,
+) -> Result<bool, PayloadError>
+where
+    R: Read + Seek,
+    O: Read + Seek + Send,
+    W: Read + Write + Seek + Send,
+    F: Fn(usize) -> bool + Sync,
+{
+    check_block_size(block_size)?;
+    let in_flight = options.in_flight.max(1);
+    let (job_tx, job_rx) = sync_channel::<Job>(in_flight);
+    let job_rx = Mutex::new(job_rx);
+    let (prepared_tx, prepared_rx) = channel();
+    let (written_tx, written_rx) = channel();
+    let mut read_ahead = ReadAhead {
+        in_flight,
+        prefetch_bytes: options.prefetch_bytes,
+        pending: VecDeque::new(),
+        bytes: 0,
+        written: written_rx,
+    };
+
+    let written_stats = &mut *stats;
+    let mut read_waits = ReadWaits::default();
+    let done = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..options.workers.max(1))
+            .map(|_| {
+                let prepared_tx = prepared_tx.clone();
+                let job_rx = &job_rx;
+                scope.spawn(move || {
+                    let mut context = DumpContext::new();
+                    let mut waits = ReadWaits::default();
+                    loop {
+                        let Ok(job) = next_job(job_rx, &mut waits) else {
+                            return waits;
+                        };
+                        let index = job.index;
+                        let span = job.span.clone();
+                        let prepared = span.in_scope(|| prepare(&mut context, job, block_size));
+                        if prepared_tx.send((index, span, prepared)).is_err() {
+                            return waits;
+                        }
+                    }
+                })
+            })
+            .collect();
+        drop(prepared_tx);
+
+        let progress = &progress;
+        let start = options.skip_operations;
+        let writer = scope.spawn(move || {
+            write(
+                old,
+                dst,
+                block_size,
+                options,
+                prepared_rx,
+                written_tx,
+                progress,
+                written_stats,
+            )
+        });
+
+        let read = read_jobs(
+            src,
+            src_blobs_offset,
+            operations,
+            start,
+            |length| read_ahead.reserve(length),
+            |job| job_tx.send(job).is_ok(),
+        );
+        drop(job_tx);
+
+        let written = writer.join().unwrap();
+        for worker in workers {
+            read_waits.add(&worker.join().unwrap());
+        }
+        read?;
+        written
+    });
+    stats.read_waits.add(&read_waits);
+    done
+}
+
+/// Take the next operation read for a worker, counting in `waits` if it
+/// had to wait for it.
+fn next_job<'a>(
+    job_rx: &Mutex<Receiver<Job<'a>>>,
+    waits: &mut ReadWaits,
+) -> Result<Job<'a>, RecvError> {
+    let job_rx = job_rx.lock().unwrap();
+    match job_rx.try_recv() {
+        Ok(job) => Ok(job),
+        Err(TryRecvError::Disconnected) => Err(RecvError),
+        Err(TryRecvError::Empty) => {
+            let start = Instant::now();
+            let job = job_rx.recv()?;
+            waits.count += 1;
+            waits.elapsed += start.elapsed();
+            Ok(job)
+        }
+    }
+}
+
+/// Bounds the operations read ahead of the writer to `in_flight`, with
+/// `prefetch_bytes` bytes of data read into memory.
+struct ReadAhead {
+    in_flight: usize,
+    prefetch_bytes: u64,
+    /// Bytes of data read of each operation not written yet, in order.
+    pending: VecDeque<u64>,
+    /// Sum of `pending`.
+    bytes: u64,
+    /// Receives a message for each operation written, in order.
+    written: Receiver<()>,
+}
+
+impl ReadAhead {
+    /// Wait for the writer until an operation with `length` bytes of data to
+    /// read fits, and count it. An operation with more data than
+    /// `prefetch_bytes` fits once nothing else is read ahead. Returns false
+    /// if the writer stopped.
+    fn reserve(&mut self, length: u64) -> bool {
+        while self.pending.len() >= self.in_flight
+            || (self.bytes > 0 && self.bytes + length > self.prefetch_bytes)
+        {
+            if self.written.recv().is_err() {
+                return false;
+            }
+            self.bytes -= self
+                .pending
+                .pop_front()
+                .expect("an operation is read ahead");
+        }
+        self.pending.push_back(length);
+        self.bytes += length;
+        true
+    }
+}
+
+/// Read the data of `operations` from `start`, from `src`, passing them to
+/// `send` until it or `reserve` returns false. `reserve` is called with the
+/// number of bytes to read into memory before reading the data of each
+/// operation.
+fn read_jobs<'a, R: Read + Seek>(
+    mut src: Source<'a, R>,
+    src_blobs_offset: u64,
+    operations: &'a [InstallOperation],
+    start: usize,
+    mut reserve: impl FnMut(u64) -> bool,
+    mut send: impl FnMut(Job<'a>) -> bool,
+) -> Result<(), PayloadError> {
+    for (index, operation) in operations.iter().enumerate().skip(start) {
+        let span = operation_span(index, operation);
+        let read = match (&src, operation.data_offset, operation.data_length) {
+            (Source::Reader(_), Some(_), Some(length)) => length,
+            _ => 0,
+        };
+        if !reserve(read) {
+            break;
+        }
+        let data = match (operation.data_offset, operation.data_length) {
+            (Some(offset), Some(length)) => span
+                .in_scope(|| {
+                    blob_offset(src_blobs_offset, offset)
+                        .and_then(|offset| read_data(&mut src, offset, length))
+                })
+                .map_err(|e| PayloadError::from(e).in_operation(index, operation.r#type))?,
+            _ => Cow::Borrowed(&[][..]),
+        };
+        if !send(Job {
+            index,
+            operation,
+            data,
+            span,
+        }) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Read the `length` bytes at `offset` of `src`.
+fn read_data<'a, R: Read + Seek>(
+    src: &mut Source<'a, R>,
+    offset: u64,
+    length: u64,
+) -> std::io::Result<Cow<'a, [u8]>> {
+    match src {
+        Source::Reader(reader) => {
+            let mut data = vec![0; buffer_len(length)?];
+            reader.seek(SeekFrom::Start(offset))?;
+            reader.read_exact(&mut data)?;
+            Ok(Cow::Owned(data))
+        }
+        // Past the end of the slice if it doesn't fit in a usize.
+        Source::Slice(slice) => offset
+            .checked_add(length)
+            .and_then(|end| slice.get(usize::try_from(offset).ok()?..usize::try_from(end).ok()?))
+            .map(Cow::Borrowed)
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof)),
+    }
+}
+
+/// Decompress the data of REPLACE operations, other operations are passed
+/// to the writer as is.
+fn prepare<'a>(
+    context: &mut DumpContext,
+    job: Job<'a>,
+    block_size: u64,
+) -> Result<Prepared<'a>, PayloadError> {
+    let operation = job.operation;
+    let replace = matches!(
+        Type::from_i32(operation.r#type),
+        Some(Type::Replace | Type::ReplaceBz | Type::ReplaceXz | Type::ReplaceZstd)
+    );
+    if !replace {
+        return Ok(Prepared::Apply(operation, job.data));
+    }
+
+    // Decompress into a buffer of the size of dst_extents.
+    let (buffer, result) = apply_in_memory(context, operation, &job.data, None, block_size)
+        .map_err(|e| e.in_operation(job.index, operation.r#type))?;
+    Ok(Prepared::Decompressed(operation, buffer, result))
+}
+
+/// Write prepared operations to `dst` in order, from
+/// `options.skip_operations`, adding their results to `stats`.
+#[allow(clippy::too_many_arguments)]
+fn write<O, W, F>(
+    mut old: Option<&mut O>,
+    dst: &mut W,
+    block_size: u64,
+    options: &DumpOptions,
+    prepared_rx: Receiver<(usize, tracing::Span, Result<Prepared, PayloadError>)>,
+    written_tx: Sender<()>,
+    progress: &F,
+    stats: &mut DumpStats,
+) -> Result<bool, PayloadError>
+where
+    O: Read + Seek,
+    W: Read + Write + Seek,
+    F: Fn(usize) -> bool,
+{
+    let mut context = DumpContext::new();
+    let mut pending = BTreeMap::new();
+    let mut next = options.skip_operations;
+    for (index, span, prepared) in prepared_rx {
+        pending.insert(index, (span, prepared));
+        while let Some((span, prepared)) = pending.remove(&next) {
+            if !progress(next) {
+                return Ok(false);
+            }
+            let _span = span.entered();
+            let result = match prepared? {
+                Prepared::Decompressed(operation, buffer, result) => {
+                    let start = Instant::now();
+                    write_extents(dst, operation, block_size, options.aligned_writes, &buffer)
+                        .map_err(|e| PayloadError::from(e).in_operation(next, operation.r#type))?;
+                    OperationResult {
+                        duration: result.duration + start.elapsed(),
+                        ..result
+                    }
+                }
+                Prepared::Apply(operation, _)
+                    if !options.dense && operation.r#type == Type::Zero as i32 =>
+                {
+                    OperationResult::skipped(operation, block_size)
+                }
+                Prepared::Apply(operation, data) if options.aligned_writes => apply_aligned(
+                    &mut context,
+                    old.as_deref_mut(),
+                    dst,
+                    operation,
+                    &data,
+                    block_size,
+                    options.in_place,
+                )
+                .map_err(|e| e.in_operation(next, operation.r#type))?,
+                Prepared::Apply(operation, data) => {
+                    let local = InstallOperation {
+                        data_offset: operation.data_offset.map(|_| 0),
+                        ..operation.clone()
+                    };
+                    let result = match options.in_place {
+                        true => context.dump_operation_in_place(
+                            &mut Cursor::new(data),
+                            0,
+                            dst,
+                            &local,
+                            block_size,
+                        ),
+                        false => context.dump_operation(
+                            &mut Cursor::new(data),
+                            0,
+                            old.as_deref_mut(),
+                            dst,
+                            &local,
+                            block_size,
+                        ),
+                    };
+                    result.map_err(|e| e.in_operation(next, operation.r#type))?
+                }
+            };
+            stats.add(&result);
+            // The reader may have stopped already.
+            let _ = written_tx.send(());
+            next += 1;
+        }
+    }
+    Ok(true)
+}
+
+/// Apply `operation` in memory, reading its src extents from `old`, or
+/// `dst` if it's applied `in_place`, and write it to `dst` with aligned
+/// writes, see [`DumpOptions::aligned_writes`], returning its result.
+fn apply_aligned<O, W>(
+    context: &mut DumpContext,
+    old: Option<&mut O>,
+    dst: &mut W,
+    operation: &InstallOperation,
+    data: &[u8],
+    block_size: u64,
+    in_place: bool,
+) -> Result<OperationResult, PayloadError>
+where
+    O: Read + Seek,
+    W: Read + Write + Seek,
+{
+    let start = Instant::now();
+    let src = match (Type::from_i32(operation.r#type), old) {
+        _ if operation.src_extents.is_empty() => None,
+        // MOVE and BSDIFF read the partition being written.
+        (Some(Type::Move | Type::Bsdiff), _) => {
+            Some(read_extents(dst, &operation.src_extents, block_size)?)
+        }
+        _ if in_place => Some(read_extents(dst, &operation.src_extents, block_size)?),
+        (_, Some(old)) => Some(read_extents(old, &operation.src_extents, block_size)?),
+        (_, None) => None,
+    };
+    let (buffer, result) = apply_in_memory(context, operation, data, src.as_deref(), block_size)?;
+    write_extents(dst, operation, block_size, true, &buffer)?;
+    Ok(OperationResult {
+        duration: start.elapsed(),
+        ..result
+    })
+}
+
+/// Write `buffer` to the dst extents of `operation` in `dst`.
+fn write_extents<W: Write + Seek>(
+    dst: &mut W,
+    operation: &InstallOperation,
+    block_size: u64,
+    aligned_writes: bool,
+    buffer: &[u8],
+) -> std::io::Result<()> {
+    let mut dst = FragmentFile::new_from_extents_with(
+        dst,
+        &operation.dst_extents,
+        block_size,
+        aligned_writes,
+    )?;
+    dst.write_all(buffer)?;
+    dst.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extent::tests::Pattern;
+    use crate::tests::extent;
+
+    #[test]
+    fn pipelined() -> Result<(), Box<dyn std::error::Error>> {
+        let mut operations = Vec::new();
+        let mut blobs = Vec::new();
+        // Replace blocks in reverse order, then copy the first block over the
+        // last one with MOVE, which depends on the earlier operations.
+        for block in (0..8u8).rev() {
+            let mut operation = InstallOperation {
+                data_offset: Some(blobs.len() as u64),
+                data_length: Some(4),
+                dst_extents: vec![extent(block as u64, 1)],
+                ..Default::default()
+            };
+            operation.set_type(Type::Replace);
+            operations.push(operation);
+            blobs.extend_from_slice(&[block; 4]);
+        }
+        let mut operation = InstallOperation {
+            src_extents: vec![extent(0, 1)],
+            dst_extents: vec![extent(7, 1)],
+            ..Default::default()
+        };
+        operation.set_type(Type::Move);
+        operations.push(operation);
+
+        let options = DumpOptions {
+            workers: 3,
+            in_flight: 2,
+            dense: true,
+            ..Default::default()
+        };
+        let mut dst = Cursor::new(vec![0u8; 32]);
+        let done = dump_operations(
+            &mut Cursor::new(&blobs),
+            0,
+            None::<&mut Cursor<Vec<u8>>>,
+            &mut dst,
+            &operations,
+            4,
+            &options,
+            |_| true,
+        )?;
+        assert!(done);
+        let expected: Vec<u8> = [0, 1, 2, 3, 4, 5, 6, 0]
+            .iter()
+            .flat_map(|&b| [b; 4])
+            .collect();
+        assert_eq!(dst.get_ref(), &expected);
+
+        // The same from the payload in memory.
+        let mut dst = Cursor::new(vec![0u8; 32]);
+        let done = dump_operations_from_slice(
+            &blobs,
+            0,
+            None::<&mut Cursor<Vec<u8>>>,
+            &mut dst,
+            &operations,
+            4,
+            &options,
+            |_| true,
+        )?;
+        assert!(done);
+        assert_eq!(dst.get_ref(), &expected);
+
+        // The same reading the data of an operation at a time.
+        let prefetch = DumpOptions {
+            prefetch_bytes: 1,
+            ..options.clone()
+        };
+        let mut dst = Cursor::new(vec![0u8; 32]);
+        let mut stats = DumpStats::default();
+        let source = Source::Reader(&mut Cursor::new(&blobs));
+        let old = None::<&mut Cursor<Vec<u8>>>;
+        let done = run(
+            source,
+            0,
+            old,
+            &mut dst,
+            &operations,
+            4,
+            &prefetch,
+            |_| true,
+            &mut stats,
+        )?;
+        assert!(done);
+        assert_eq!(dst.get_ref(), &expected);
+        assert!(stats.read_waits.count > 0);
+        assert_eq!(stats.operations[&Type::Replace], 8);
+        assert_eq!(stats.operations[&Type::Move], 1);
+        assert_eq!((stats.bytes_written, stats.data_bytes_read), (36, 32));
+
+        // The same with aligned writes, MOVE is applied in memory.
+        let aligned = DumpOptions {
+            aligned_writes: true,
+            ..options.clone()
+        };
+        let mut dst = Cursor::new(vec![0u8; 32]);
+        let done = dump_operations(
+            &mut Cursor::new(&blobs),
+            0,
+            None::<&mut Cursor<Vec<u8>>>,
+            &mut dst,
+            &operations,
+            4,
+            &aligned,
+            |_| true,
+        )?;
+        assert!(done);
+        assert_eq!(dst.get_ref(), &expected);
+
+        // Stop after the first two operations.
+        let mut dst = Cursor::new(vec![0xffu8; 32]);
+        let done = dump_operations(
+            &mut Cursor::new(&blobs),
+            0,
+            None::<&mut Cursor<Vec<u8>>>,
+            &mut dst,
+            &operations,
+            4,
+            &options,
+            |index| index < 2,
+        )?;
+        assert!(!done);
+        assert_eq!(&dst.get_ref()[..24], &[0xff; 24]);
+        assert_eq!(&dst.get_ref()[24..], &[6, 6, 6, 6, 7, 7, 7, 7]);
+
+        // Resume from where it stopped.
+        let resume = DumpOptions {
+            skip_operations: 2,
+            ..options.clone()
+        };
+        let applied = Mutex::new(Vec::new());
+        let done = dump_operations(
+            &mut Cursor::new(&blobs),
+            0,
+            None::<&mut Cursor<Vec<u8>>>,
+            &mut dst,
+            &operations,
+            4,
+            &resume,
+            |index| {
+                applied.lock().unwrap().push(index);
+                true
+            },
+        )?;
+        assert!(done);
+        assert_eq!(applied.into_inner().unwrap(), (2..9).collect::<Vec<_>>());
+        assert_eq!(dst.get_ref(), &expected);
+
+        // Errors mention the failed operation.
+        operations[3].data_length = Some(100);
+        let error = dump_operations(
+            &mut Cursor::new(&blobs),
+            0,
+            None::<&mut Cursor<Vec<u8>>>,
+            &mut Cursor::new(vec![0u8; 32]),
+            &operations,
+            4,
+            &options,
+            |_| true,
+        )
+        .unwrap_err();
+        assert!(
+            error.to_string().starts_with("operation 3 (type REPLACE)"),
+            "{}",
+            error
+        );
+        assert!(matches!(error, PayloadError::Operation { index: 3, .. }));
+        let error = dump_operations_from_slice(
+            &blobs,
+            0,
+            None::<&mut Cursor<Vec<u8>>>,
+            &mut Cursor::new(vec![0u8; 32]),
+            &operations,
+            4,
+            &options,
+            |_| true,
+        )
+        .unwrap_err();
+        assert!(
+            matches!(error, PayloadError::Operation { index: 3, .. }),
+            "{}",
+            error
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn blobs_past_4_gib() -> Result<(), Box<dyn std::error::Error>> {
+        const GIB_4: u64 = 1 << 32;
+        let mut operation = InstallOperation {
+            data_offset: Some(GIB_4 + 3),
+            data_length: Some(4),
+            dst_extents: vec![extent(1, 1)],
+            ..Default::default()
+        };
+        operation.set_type(Type::Replace);
+        let mut src = Pattern {
+            pos: 0,
+            size: 3 * GIB_4,
+        };
+        let mut dst = Cursor::new(vec![0u8; 8]);
+        let old = None::<&mut Cursor<Vec<u8>>>;
+        dump_operations(
+            &mut src,
+            GIB_4,
+            old,
+            &mut dst,
+            &[operation],
+            4,
+            &DumpOptions::default(),
+            |_| true,
+        )?;
+        let expected: Vec<_> = (2 * GIB_4 + 3..2 * GIB_4 + 7).map(Pattern::byte).collect();
+        assert_eq!(&dst.get_ref()[4..], &expected);
+
+        // Offsets past the end of a slice are past 4 GiB on 32-bit targets.
+        let error =
+            read_data(&mut Source::<std::io::Empty>::Slice(&[0; 16]), GIB_4 + 4, 4).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+        Ok(())
+    }
+
+    #[test]
+    fn read_ahead() {
+        let (written_tx, written_rx) = channel();
+        let mut read_ahead = ReadAhead {
+            in_flight: 3,
+            prefetch_bytes: 10,
+            pending: VecDeque::new(),
+            bytes: 0,
+            written: written_rx,
+        };
+        // More than the budget, but nothing else is read ahead.
+        assert!(read_ahead.reserve(12));
+        written_tx.send(()).unwrap();
+        assert!(read_ahead.reserve(6));
+        assert!(read_ahead.reserve(0));
+        assert!(read_ahead.reserve(4));
+        assert_eq!(read_ahead.bytes, 10);
+
+        // Waits for an operation to be written, three are in flight.
+        written_tx.send(()).unwrap();
+        assert!(read_ahead.reserve(5));
+        assert_eq!(read_ahead.pending, [0, 4, 5]);
+
+        // The writer stopped.
+        drop(written_tx);
+        assert!(!read_ahead.reserve(8));
+    }
+}
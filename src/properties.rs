@@ -0,0 +1,321 @@
+use std::fmt;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+use sha2::{Digest, Sha256};
+
+use crate::{PayloadError, PayloadHeader};
+
+/// The properties of a payload that update_engine needs to apply it, as in
+/// the `payload_properties.txt` written by `brillo_update_payload
+/// properties` and passed to `update_engine_client --headers`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PayloadProperties {
+    /// SHA-256 hash of the whole payload.
+    pub file_hash: [u8; 32],
+    /// Size of the payload, see [`PayloadHeader::payload_size`].
+    pub file_size: u64,
+    /// SHA-256 hash of the metadata and the metadata signature, unlike
+    /// [`PayloadHeader::metadata_hash`], which is signed.
+    pub metadata_hash: [u8; 32],
+    /// Size of the metadata and the metadata signature, which is
+    /// [`PayloadHeader::blobs_offset`].
+    pub metadata_size: u64,
+}
+
+impl PayloadProperties {
+    /// Read the payload from `reader`, which is at the beginning of the
+    /// payload, once, hashing the metadata while its header is parsed and
+    /// then the rest of the payload. Only [`PayloadHeader::payload_size`]
+    /// bytes are read.
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self, PayloadError> {
+        let mut reader = HashReader {
+            inner: reader,
+            hasher: Sha256::new(),
+            len: 0,
+        };
+        let header = PayloadHeader::parse_prefix(&mut reader)?;
+        // The header is parsed without reading past the metadata signature.
+        debug_assert_eq!(reader.len, header.blobs_offset);
+        let metadata_hash = reader.hasher.clone().finalize().into();
+
+        let file_size = header.payload_size();
+        let rest = file_size - header.blobs_offset;
+        let copied = std::io::copy(
+            &mut Read::by_ref(&mut reader).take(rest),
+            &mut std::io::sink(),
+        )?;
+        if copied != rest {
+            return Err(PayloadError::Truncated {
+                needed: file_size,
+                len: header.blobs_offset + copied,
+            });
+        }
+        Ok(Self {
+            file_hash: reader.hasher.finalize().into(),
+            file_size,
+            metadata_hash,
+            metadata_size: header.blobs_offset,
+        })
+    }
+
+    /// Write the properties to `out` in the format of
+    /// `payload_properties.txt`, which is also how they are displayed.
+    pub fn write<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        write!(out, "{}", self)
+    }
+
+    /// The keys of `payload_properties.txt`, sorted, with their values as
+    /// they are written, the hashes in base64.
+    pub fn fields(&self) -> [(&'static str, String); 4] {
+        [
+            (FILE_HASH, base64(&self.file_hash)),
+            (FILE_SIZE, self.file_size.to_string()),
+            (METADATA_HASH, base64(&self.metadata_hash)),
+            (METADATA_SIZE, self.metadata_size.to_string()),
+        ]
+    }
+}
+
+impl fmt::Display for PayloadProperties {
+    /// The `KEY=value` lines of `payload_properties.txt`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (key, value) in self.fields() {
+            writeln!(f, "{}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for PayloadProperties {
+    type Err = PropertiesError;
+
+    /// Parse the `KEY=value` lines of `payload_properties.txt`, like
+    /// update_engine: empty lines and `#` comments are skipped, other keys,
+    /// like the `POWERWASH=1` sometimes appended, are ignored, and the last
+    /// value of a key repeated wins.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mut file_hash, mut file_size, mut metadata_hash, mut metadata_size) =
+            (None, None, None, None);
+        for (index, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or(PropertiesError::Syntax { line: index + 1 })?;
+            let (key, value) = (key.trim(), value.trim());
+            let invalid = || PropertiesError::Invalid {
+                key: key.to_string(),
+                value: value.to_string(),
+            };
+            let hash = || {
+                unbase64(value)
+                    .and_then(|hash| hash.try_into().ok())
+                    .ok_or_else(invalid)
+            };
+            let size = || value.parse::<u64>().map_err(|_| invalid());
+            match key {
+                FILE_HASH => file_hash = Some(hash()?),
+                FILE_SIZE => file_size = Some(size()?),
+                METADATA_HASH => metadata_hash = Some(hash()?),
+                METADATA_SIZE => metadata_size = Some(size()?),
+                _ => {}
+            }
+        }
+        Ok(Self {
+            file_hash: file_hash.ok_or(PropertiesError::Missing(FILE_HASH))?,
+            file_size: file_size.ok_or(PropertiesError::Missing(FILE_SIZE))?,
+            metadata_hash: metadata_hash.ok_or(PropertiesError::Missing(METADATA_HASH))?,
+            metadata_size: metadata_size.ok_or(PropertiesError::Missing(METADATA_SIZE))?,
+        })
+    }
+}
+
+const FILE_HASH: &str = "FILE_HASH";
+const FILE_SIZE: &str = "FILE_SIZE";
+const METADATA_HASH: &str = "METADATA_HASH";
+const METADATA_SIZE: &str = "METADATA_SIZE";
+
+/// Error parsing `payload_properties.txt`.
+#[derive(Debug)]
+pub enum PropertiesError {
+    /// The line, counted from 1, is not `KEY=value`.
+    Syntax { line: usize },
+    /// The value of `key` is not a base64 SHA-256 hash or a size.
+    Invalid { key: String, value: String },
+    /// The key is missing.
+    Missing(&'static str),
+}
+
+impl fmt::Display for PropertiesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PropertiesError::Syntax { line } => write!(f, "line {} is not KEY=value", line),
+            PropertiesError::Invalid { key, value } => write!(f, "invalid {}: {:?}", key, value),
+            PropertiesError::Missing(key) => write!(f, "no {}", key),
+        }
+    }
+}
+
+impl std::error::Error for PropertiesError {}
+
+/// A reader hashing everything read from `inner`.
+struct HashReader<R> {
+    inner: R,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+}
+
+/// Standard base64 of `data`, with padding.
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => encoded.push(ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize] as char),
+                false => encoded.push('='),
+            }
+        }
+    }
+    encoded
+}
+
+/// Decode the standard base64 `s`, with padding, or `None` if it's not.
+fn unbase64(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(s.len() / 4 * 3);
+    let chunks = s.as_bytes().chunks(4);
+    let last = chunks.len().saturating_sub(1);
+    for (index, chunk) in chunks.enumerate() {
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && index != last) {
+            return None;
+        }
+        let mut n = 0u32;
+        for &c in &chunk[..4 - padding] {
+            let value = match c {
+                b'A'..=b'Z' => c - b'A',
+                b'a'..=b'z' => c - b'a' + 26,
+                b'0'..=b'9' => c - b'0' + 52,
+                b'+' => 62,
+                b'/' => 63,
+                _ => return None,
+            };
+            n = n << 6 | value as u32;
+        }
+        n <<= 6 * padding;
+        decoded.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CreateOptions, PayloadBuilder};
+    use std::io::Cursor;
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foob"), "Zm9vYg==");
+        assert_eq!(base64(&[0xfb, 0xff]), "+/8=");
+        for data in [&b""[..], b"f", b"fo", b"foo", b"foob", &[0xfb, 0xff]] {
+            assert_eq!(unbase64(&base64(data)).as_deref(), Some(data));
+        }
+        for invalid in ["Zg=", "Zg=a", "Z===", "Zg==Zg==", "Zm9*"] {
+            assert_eq!(unbase64(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn properties() -> Result<(), Box<dyn std::error::Error>> {
+        let mut builder = PayloadBuilder::new(Cursor::new(Vec::new()), CreateOptions::default())?;
+        builder.add_partition("boot", &[7; 8192][..])?;
+        let mut data = Vec::new();
+        builder.finish(&mut data)?;
+        let header = PayloadHeader::parse_prefix(&mut &data[..])?;
+        let metadata_size = header.blobs_offset as usize;
+
+        // Trailing data after the payload is not covered.
+        let properties = PayloadProperties::read(&mut &[&data[..], b"trailing"].concat()[..])?;
+        assert_eq!(properties.file_size, data.len() as u64);
+        assert_eq!(
+            properties.file_hash,
+            <[u8; 32]>::from(Sha256::digest(&data))
+        );
+        assert_eq!(properties.metadata_size, metadata_size as u64);
+        assert_eq!(
+            properties.metadata_hash,
+            <[u8; 32]>::from(Sha256::digest(&data[..metadata_size]))
+        );
+
+        let text = properties.to_string();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            lines[0],
+            format!("FILE_HASH={}", base64(&properties.file_hash))
+        );
+        assert_eq!(lines[1], format!("FILE_SIZE={}", data.len()));
+        assert_eq!(lines[3], format!("METADATA_SIZE={}", metadata_size));
+
+        assert_eq!(text.parse::<PayloadProperties>()?, properties);
+
+        let error = PayloadProperties::read(&mut &data[..data.len() - 1]).unwrap_err();
+        assert!(
+            matches!(error, PayloadError::Truncated { needed, len } if needed == data.len() as u64 && len == needed - 1)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse() {
+        let hash = base64(&[1; 32]);
+        let text = format!(
+            "# comment\n\nMETADATA_SIZE=10\nFILE_HASH={0}\nFILE_SIZE=1\nFILE_SIZE = 20 \nMETADATA_HASH={0}\nPOWERWASH=1\n",
+            hash
+        );
+        let properties: PayloadProperties = text.parse().unwrap();
+        assert_eq!(properties.file_size, 20);
+        assert_eq!(properties.metadata_size, 10);
+        assert_eq!(properties.file_hash, [1; 32]);
+
+        let error = "FILE_HASH".parse::<PayloadProperties>().unwrap_err();
+        assert!(matches!(error, PropertiesError::Syntax { line: 1 }));
+        let error = format!("FILE_HASH={}", base64(&[1; 31]))
+            .parse::<PayloadProperties>()
+            .unwrap_err();
+        assert!(matches!(error, PropertiesError::Invalid { key, .. } if key == "FILE_HASH"));
+        let error = "FILE_SIZE=-1".parse::<PayloadProperties>().unwrap_err();
+        assert!(matches!(error, PropertiesError::Invalid { key, .. } if key == "FILE_SIZE"));
+        let error = text
+            .replace("METADATA_SIZE=10", "")
+            .parse::<PayloadProperties>()
+            .unwrap_err();
+        assert!(matches!(error, PropertiesError::Missing("METADATA_SIZE")));
+    }
+}
@@ -0,0 +1,1128 @@
+use std::io::{Error, ErrorKind, Result};
+use std::iter;
+use std::ops::Range;
+
+use prost::Message;
+
+use crate::bspatch::bspatch;
+
+mod metadata {
+    include!(concat!(env!("OUT_DIR"), "/puffin.metadata.rs"));
+}
+
+use metadata::{patch_header::PatchType, BitExtent, PatchHeader};
+
+/// A puff is at most 16 times as large as its deflate stream: a length and
+/// distance pair takes 2 bits at least in the stream and 4 bytes in the puff.
+const MAX_PUFF_RATIO: usize = 16;
+
+/// Literals are written in runs of at most this many bytes.
+const MAX_LITERALS: usize = 127 + 0xFFFF + 1;
+
+/// Order of the code lengths of the code length alphabet in dynamic blocks.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA_BITS: [u32; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASES: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA_BITS: [u32; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Apply a puffin patch to `old`, returning the new data of `size` bytes.
+///
+/// The patch is represented by this struct pseudocode:
+/// ```text
+/// struct puffin_patch {
+///   char magic[4] = "PUF1";
+///   uint32 header_size;  // Big endian
+///   char header[header_size];  // PatchHeader protobuf, see puffin.proto
+///   char patch[];  // bsdiff patch from the puffed old to the puffed new
+/// };
+/// ```
+/// The header lists the bit extents of the deflate streams in the old and
+/// new data. Puffing replaces each stream by a puff, which keeps the
+/// Huffman tables and the literals and length/distance pairs of its blocks
+/// in bytes, leaving out the codes bsdiff finds no similarity in. The
+/// patched puffs are huffed back into deflate streams.
+pub fn puffpatch(old: &[u8], patch: &[u8], size: usize) -> Result<Vec<u8>> {
+    let header_size = match patch {
+        [b'P', b'U', b'F', b'1', a, b, c, d, ..] => u32::from_be_bytes([*a, *b, *c, *d]) as usize,
+        _ => return Err(corrupt("unknown magic")),
+    };
+    let header_end = 8usize
+        .checked_add(header_size)
+        .filter(|&end| end <= patch.len())
+        .ok_or_else(|| corrupt("header out of range"))?;
+    let header = PatchHeader::decode(&patch[8..header_end])
+        .map_err(|e| corrupt(&format!("invalid header: {}", e)))?;
+    match PatchType::from_i32(header.r#type) {
+        Some(PatchType::Bsdiff) => {}
+        Some(PatchType::Zucchini) => {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "zucchini puffin patches are not supported",
+            ))
+        }
+        None => return Err(corrupt(&format!("unknown patch type {}", header.r#type))),
+    }
+    let src = header.src.unwrap_or_default();
+    let dst = header.dst.unwrap_or_default();
+
+    let (puffed, puffs) = puff(old, &bit_extents(&src.deflates)?)?;
+    if puffs != byte_extents(&src.puffs)? || puffed.len() as u64 != src.puff_length {
+        return Err(corrupt("old puffs don't match the header"));
+    }
+
+    // The header declares the size of the new puffs, which must not be
+    // trusted for allocation.
+    let puff_length = usize::try_from(dst.puff_length)
+        .ok()
+        .filter(|&length| length <= size.saturating_mul(MAX_PUFF_RATIO))
+        .ok_or_else(|| {
+            corrupt(&format!(
+                "new puff length {} is too large for {} bytes",
+                dst.puff_length, size
+            ))
+        })?;
+    let puffed = bspatch(&puffed, &patch[header_end..], puff_length)?;
+    if puffed.len() != puff_length {
+        return Err(corrupt(&format!(
+            "new puffs are {} bytes, not {}",
+            puffed.len(),
+            puff_length
+        )));
+    }
+    huff(
+        &puffed,
+        &bit_extents(&dst.deflates)?,
+        &byte_extents(&dst.puffs)?,
+        size,
+    )
+}
+
+/// Convert the extents of a `StreamInfo` to ranges.
+fn bit_extents(extents: &[BitExtent]) -> Result<Vec<Range<u64>>> {
+    extents
+        .iter()
+        .map(|extent| {
+            let end = extent
+                .offset
+                .checked_add(extent.length)
+                .ok_or_else(|| corrupt("extent out of range"))?;
+            Ok(extent.offset..end)
+        })
+        .collect()
+}
+
+/// Convert the puff extents of a `StreamInfo`, in bits, to byte ranges.
+fn byte_extents(extents: &[BitExtent]) -> Result<Vec<Range<u64>>> {
+    Ok(bit_extents(extents)?
+        .into_iter()
+        .map(|extent| extent.start / 8..extent.end / 8)
+        .collect())
+}
+
+/// Check that `extents` are in order, don't overlap and end by `end`.
+fn check_extents(extents: &[Range<u64>], end: u64, what: &str) -> Result<()> {
+    let mut last = 0;
+    for extent in extents {
+        if extent.start < last {
+            return Err(corrupt(&format!("{} extents overlap", what)));
+        }
+        last = extent.end;
+    }
+    if last > end {
+        return Err(corrupt(&format!("{} extents out of range", what)));
+    }
+    Ok(())
+}
+
+/// The bytes between a deflate stream ending at bit `end` and the next
+/// starting at bit `start`, including the bytes they take part of, unless
+/// they both take the same byte.
+fn raw_bytes(end: u64, start: u64) -> Range<usize> {
+    let first = (end / 8) as usize;
+    if end == start {
+        first..first
+    } else {
+        first..start.div_ceil(8) as usize
+    }
+}
+
+/// Puff the deflate streams at bit extents `deflates` of `data`, returning
+/// the puffed data and the byte extents of the puffs in it.
+///
+/// The bytes between the streams are copied, with the bits of the streams
+/// in bytes they share cut off.
+fn puff(data: &[u8], deflates: &[Range<u64>]) -> Result<(Vec<u8>, Vec<Range<u64>>)> {
+    let data_bits = data.len() as u64 * 8;
+    check_extents(deflates, data_bits, "deflate")?;
+
+    let mut out = Vec::with_capacity(data.len());
+    let mut puffs = Vec::with_capacity(deflates.len());
+    let mut literals = Vec::new();
+    let mut end = 0;
+    let sentinel = data_bits..data_bits;
+    for (i, deflate) in deflates.iter().chain(iter::once(&sentinel)).enumerate() {
+        let raw = raw_bytes(end, deflate.start);
+        if !raw.is_empty() {
+            let first = out.len();
+            out.extend_from_slice(&data[raw]);
+            if deflate.start % 8 != 0 {
+                *out.last_mut().unwrap() &= (1 << (deflate.start % 8)) - 1;
+            }
+            out[first] >>= end % 8;
+        }
+        if i == deflates.len() {
+            break;
+        }
+
+        let start = out.len();
+        let bytes = &data[(deflate.start / 8) as usize..deflate.end.div_ceil(8) as usize];
+        let mut reader = BitReader {
+            data: bytes,
+            position: deflate.start % 8,
+        };
+        let mut writer = PuffWriter {
+            out: &mut out,
+            literals: &mut literals,
+        };
+        // Like puffin, blocks are read up to the last byte of the extent,
+        // whether or not one is marked final.
+        while reader.remaining() >= 8 {
+            puff_block(&mut reader, &mut writer)?;
+        }
+        puffs.push(start as u64..out.len() as u64);
+        end = deflate.end;
+    }
+    Ok((out, puffs))
+}
+
+/// Puff the next block of a deflate stream.
+fn puff_block(reader: &mut BitReader, writer: &mut PuffWriter) -> Result<()> {
+    let last = reader.read(1)? as u8;
+    let kind = reader.read(2)? as u8;
+    let mut metadata = vec![last << 7 | kind << 5];
+    match kind {
+        // Stored: the bits up to the byte boundary, which are kept in the
+        // metadata, then LEN, NLEN and LEN bytes.
+        0 => {
+            metadata[0] |= reader.read_boundary()? as u8;
+            let length = reader.read(16)?;
+            if reader.read(16)? != !length & 0xFFFF {
+                return Err(corrupt("stored block length doesn't match its complement"));
+            }
+            writer.metadata(&metadata);
+            let bytes = reader.read_bytes(length as usize)?;
+            if !bytes.is_empty() {
+                writer.literals(bytes);
+            }
+            writer.end_of_block();
+            Ok(())
+        }
+        1 => {
+            writer.metadata(&metadata);
+            let (literal, distance) = fixed_lengths();
+            puff_codes(
+                reader,
+                writer,
+                &Decoder::new(&literal)?,
+                &Decoder::new(&distance)?,
+            )
+        }
+        2 => {
+            let (literal, distance) = puff_dynamic_header(reader, &mut metadata)?;
+            writer.metadata(&metadata);
+            puff_codes(reader, writer, &literal, &distance)
+        }
+        _ => Err(corrupt("reserved block type")),
+    }
+}
+
+/// Read the code lengths of a dynamic block, appending them to `metadata`
+/// in bytes: HLIT, HDIST and HCLEN, the code lengths of the code length
+/// alphabet two to a byte, then one byte for each code length symbol with
+/// its extra bits, 16 to 19 for 16, 20 to 27 for 17 and 28 to 155 for 18.
+fn puff_dynamic_header(
+    reader: &mut BitReader,
+    metadata: &mut Vec<u8>,
+) -> Result<(Decoder, Decoder)> {
+    let literal_count = reader.read(5)? as usize + 257;
+    let distance_count = reader.read(5)? as usize + 1;
+    let code_length_count = reader.read(4)? as usize + 4;
+    if literal_count > 286 || distance_count > 30 {
+        return Err(corrupt("too many Huffman codes"));
+    }
+    metadata.extend([
+        (literal_count - 257) as u8,
+        (distance_count - 1) as u8,
+        (code_length_count - 4) as u8,
+    ]);
+
+    let mut code_lengths = [0; 19];
+    for (i, &symbol) in CODE_LENGTH_ORDER[..code_length_count].iter().enumerate() {
+        let length = reader.read(3)? as u8;
+        code_lengths[symbol] = length;
+        if i % 2 == 0 {
+            metadata.push(length << 4);
+        } else {
+            *metadata.last_mut().unwrap() |= length;
+        }
+    }
+    let code_length_decoder = Decoder::new(&code_lengths)?;
+
+    let count = literal_count + distance_count;
+    let mut lengths = Vec::with_capacity(count);
+    while lengths.len() < count {
+        let (length, repeat, byte) = match code_length_decoder.decode(reader)? {
+            symbol @ 0..=15 => (symbol as u8, 1, symbol as u8),
+            16 => {
+                let extra = reader.read(2)?;
+                let previous = *lengths
+                    .last()
+                    .ok_or_else(|| corrupt("code length repeated before the first"))?;
+                (previous, extra as usize + 3, 16 + extra as u8)
+            }
+            17 => {
+                let extra = reader.read(3)?;
+                (0, extra as usize + 3, 20 + extra as u8)
+            }
+            _ => {
+                let extra = reader.read(7)?;
+                (0, extra as usize + 11, 28 + extra as u8)
+            }
+        };
+        if lengths.len() + repeat > count {
+            return Err(corrupt("code lengths overflow the alphabets"));
+        }
+        lengths.extend(iter::repeat_n(length, repeat));
+        metadata.push(byte);
+    }
+    Ok((
+        Decoder::new(&lengths[..literal_count])?,
+        Decoder::new(&lengths[literal_count..])?,
+    ))
+}
+
+/// Puff the literals and length/distance pairs of a compressed block, up
+/// to its end of block code.
+fn puff_codes(
+    reader: &mut BitReader,
+    writer: &mut PuffWriter,
+    literal: &Decoder,
+    distance: &Decoder,
+) -> Result<()> {
+    loop {
+        match literal.decode(reader)? {
+            symbol @ 0..=255 => writer.literal(symbol as u8),
+            256 => {
+                writer.end_of_block();
+                return Ok(());
+            }
+            symbol @ 257..=285 => {
+                let i = symbol as usize - 257;
+                let length = LENGTH_BASES[i] + reader.read(LENGTH_EXTRA_BITS[i])? as u16;
+                let d = distance.decode(reader)? as usize;
+                if d >= DISTANCE_BASES.len() {
+                    return Err(corrupt("invalid distance code"));
+                }
+                let dist = DISTANCE_BASES[d] + reader.read(DISTANCE_EXTRA_BITS[d])? as u16;
+                writer.length_distance(length, dist);
+            }
+            _ => return Err(corrupt("invalid length code")),
+        }
+    }
+}
+
+/// Huff the puffs at byte extents `puffs` of `data` back to deflate
+/// streams at bit extents `deflates` of the returned `size` bytes, the
+/// reverse of [`puff`].
+fn huff(
+    data: &[u8],
+    deflates: &[Range<u64>],
+    puffs: &[Range<u64>],
+    size: usize,
+) -> Result<Vec<u8>> {
+    let size_bits = size as u64 * 8;
+    if deflates.len() != puffs.len() {
+        return Err(corrupt("numbers of deflates and puffs differ"));
+    }
+    check_extents(deflates, size_bits, "deflate")?;
+    check_extents(puffs, data.len() as u64, "puff")?;
+
+    let mut out = vec![0; size];
+    let mut end = 0;
+    let mut puff_end = 0;
+    let sentinel = (size_bits..size_bits, data.len() as u64..data.len() as u64);
+    let extents = deflates.iter().zip(puffs);
+    for (i, (deflate, puff)) in extents
+        .chain(iter::once((&sentinel.0, &sentinel.1)))
+        .enumerate()
+    {
+        let raw = raw_bytes(end, deflate.start);
+        let bytes = &data[puff_end as usize..puff.start as usize];
+        if bytes.len() != raw.len() {
+            return Err(corrupt("puffs don't match the deflates"));
+        }
+        let last = bytes.len().wrapping_sub(1);
+        for (i, (out, &byte)) in out[raw].iter_mut().zip(bytes).enumerate() {
+            let mut byte = if i == 0 { byte << (end % 8) } else { byte };
+            if i == last && deflate.start % 8 != 0 {
+                byte &= (1 << (deflate.start % 8)) - 1;
+            }
+            *out |= byte;
+        }
+        if i == deflates.len() {
+            break;
+        }
+
+        let bytes = deflate.end.div_ceil(8) as usize;
+        let mut writer = BitWriter {
+            data: &mut out[..bytes],
+            position: deflate.start,
+        };
+        let mut reader = PuffReader {
+            data: &data[puff.start as usize..puff.end as usize],
+        };
+        while !reader.data.is_empty() {
+            huff_block(&mut reader, &mut writer)?;
+        }
+        if writer.position.div_ceil(8) != bytes as u64 {
+            return Err(corrupt(&format!(
+                "deflate stream at bit {} ends before its extent",
+                deflate.start
+            )));
+        }
+        end = deflate.end;
+        puff_end = puff.end;
+    }
+    Ok(out)
+}
+
+/// Huff the next block of a puff, the reverse of [`puff_block`].
+fn huff_block(reader: &mut PuffReader, writer: &mut BitWriter) -> Result<()> {
+    let length = reader.u16()? as usize + 1;
+    let metadata = reader.bytes(length)?;
+    let kind = (metadata[0] >> 5) & 3;
+    writer.write(1, (metadata[0] >> 7).into())?;
+    writer.write(2, kind.into())?;
+    match kind {
+        0 => {
+            if metadata.len() != 1 {
+                return Err(corrupt("stored block with Huffman tables"));
+            }
+            writer.write_boundary((metadata[0] & 0x1F).into())?;
+            let bytes = match reader.next()? {
+                Puff::Literals(bytes) => {
+                    if !matches!(reader.next()?, Puff::EndOfBlock) {
+                        return Err(corrupt("stored block not ended after its bytes"));
+                    }
+                    bytes
+                }
+                Puff::EndOfBlock => &[],
+                Puff::LengthDistance(..) => {
+                    return Err(corrupt("length/distance pair in a stored block"))
+                }
+            };
+            let length = u32::try_from(bytes.len())
+                .ok()
+                .filter(|&length| length <= 0xFFFF)
+                .ok_or_else(|| corrupt("stored block too long"))?;
+            writer.write(16, length)?;
+            writer.write(16, !length & 0xFFFF)?;
+            writer.write_bytes(bytes)
+        }
+        1 => {
+            if metadata.len() != 1 {
+                return Err(corrupt("fixed block with Huffman tables"));
+            }
+            let (literal, distance) = fixed_lengths();
+            huff_codes(
+                reader,
+                writer,
+                &Encoder::new(&literal)?,
+                &Encoder::new(&distance)?,
+            )
+        }
+        2 => {
+            let (literal, distance) = huff_dynamic_header(&metadata[1..], writer)?;
+            huff_codes(reader, writer, &literal, &distance)
+        }
+        _ => Err(corrupt("reserved block type")),
+    }
+}
+
+/// Write the code lengths of a dynamic block from the `metadata` written
+/// by [`puff_dynamic_header`].
+fn huff_dynamic_header(metadata: &[u8], writer: &mut BitWriter) -> Result<(Encoder, Encoder)> {
+    let [literal_count, distance_count, code_length_count, rest @ ..] = metadata else {
+        return Err(corrupt("dynamic block metadata too short"));
+    };
+    let literal_count = *literal_count as usize + 257;
+    let distance_count = *distance_count as usize + 1;
+    let code_length_count = *code_length_count as usize + 4;
+    if literal_count > 286 || distance_count > 30 || code_length_count > 19 {
+        return Err(corrupt("too many Huffman codes"));
+    }
+    writer.write(5, (literal_count - 257) as u32)?;
+    writer.write(5, (distance_count - 1) as u32)?;
+    writer.write(4, (code_length_count - 4) as u32)?;
+
+    let packed = code_length_count.div_ceil(2);
+    if rest.len() < packed {
+        return Err(corrupt("dynamic block metadata too short"));
+    }
+    let mut code_lengths = [0; 19];
+    for (i, &symbol) in CODE_LENGTH_ORDER[..code_length_count].iter().enumerate() {
+        let length = if i % 2 == 0 {
+            rest[i / 2] >> 4
+        } else {
+            rest[i / 2] & 0xF
+        };
+        if length > 7 {
+            return Err(corrupt("code length code too long"));
+        }
+        code_lengths[symbol] = length;
+        writer.write(3, length.into())?;
+    }
+    let code_length_encoder = Encoder::new(&code_lengths)?;
+
+    let count = literal_count + distance_count;
+    let mut lengths = Vec::with_capacity(count);
+    for &byte in &rest[packed..] {
+        let (length, repeat) = match byte {
+            0..=15 => {
+                code_length_encoder.write(writer, byte.into())?;
+                (byte, 1)
+            }
+            16..=19 => {
+                code_length_encoder.write(writer, 16)?;
+                writer.write(2, (byte - 16).into())?;
+                let previous = *lengths
+                    .last()
+                    .ok_or_else(|| corrupt("code length repeated before the first"))?;
+                (previous, byte as usize - 16 + 3)
+            }
+            20..=27 => {
+                code_length_encoder.write(writer, 17)?;
+                writer.write(3, (byte - 20).into())?;
+                (0, byte as usize - 20 + 3)
+            }
+            28..=155 => {
+                code_length_encoder.write(writer, 18)?;
+                writer.write(7, (byte - 28).into())?;
+                (0, byte as usize - 28 + 11)
+            }
+            _ => return Err(corrupt("invalid code length symbol")),
+        };
+        if lengths.len() + repeat > count {
+            return Err(corrupt("code lengths overflow the alphabets"));
+        }
+        lengths.extend(iter::repeat_n(length, repeat));
+    }
+    if lengths.len() != count {
+        return Err(corrupt("code lengths don't cover the alphabets"));
+    }
+    Ok((
+        Encoder::new(&lengths[..literal_count])?,
+        Encoder::new(&lengths[literal_count..])?,
+    ))
+}
+
+/// Huff the literals and length/distance pairs of a compressed block, up
+/// to its end of block.
+fn huff_codes(
+    reader: &mut PuffReader,
+    writer: &mut BitWriter,
+    literal: &Encoder,
+    distance: &Encoder,
+) -> Result<()> {
+    loop {
+        match reader.next()? {
+            Puff::Literals(bytes) => {
+                for &byte in bytes {
+                    literal.write(writer, byte.into())?;
+                }
+            }
+            Puff::LengthDistance(length, dist) => {
+                let i = LENGTH_BASES
+                    .iter()
+                    .rposition(|&base| base <= length)
+                    .unwrap();
+                literal.write(writer, 257 + i)?;
+                writer.write(LENGTH_EXTRA_BITS[i], (length - LENGTH_BASES[i]).into())?;
+                let d = DISTANCE_BASES
+                    .iter()
+                    .rposition(|&base| base <= dist)
+                    .unwrap();
+                distance.write(writer, d)?;
+                writer.write(DISTANCE_EXTRA_BITS[d], (dist - DISTANCE_BASES[d]).into())?;
+            }
+            Puff::EndOfBlock => return literal.write(writer, 256),
+        }
+    }
+}
+
+/// Code lengths of the literal/length and distance alphabets of fixed
+/// blocks.
+fn fixed_lengths() -> ([u8; 288], [u8; 32]) {
+    let mut literal = [8; 288];
+    literal[144..256].fill(9);
+    literal[256..280].fill(7);
+    (literal, [5; 32])
+}
+
+/// Canonical Huffman codes of the code `lengths` of an alphabet, bit
+/// reversed to be written least significant bit first.
+fn huffman_codes(lengths: &[u8]) -> Result<Vec<u16>> {
+    let mut counts = [0u16; 16];
+    for &length in lengths {
+        counts[length as usize] += 1;
+    }
+    counts[0] = 0;
+
+    // Incomplete codes are allowed, like a single distance code.
+    let mut left = 1i32;
+    for &count in &counts[1..] {
+        left = (left << 1) - i32::from(count);
+        if left < 0 {
+            return Err(corrupt("over-subscribed Huffman code"));
+        }
+    }
+
+    let mut next = [0u16; 16];
+    let mut code = 0;
+    for bits in 1..16 {
+        code = (code + counts[bits - 1]) << 1;
+        next[bits] = code;
+    }
+    Ok(lengths
+        .iter()
+        .map(|&length| {
+            if length == 0 {
+                return 0;
+            }
+            let code = next[length as usize];
+            next[length as usize] += 1;
+            code.reverse_bits() >> (16 - length)
+        })
+        .collect())
+}
+
+/// Decoder of canonical Huffman codes, with a table indexed by the next
+/// bits of the stream holding the symbol and code length of each code.
+struct Decoder {
+    table: Vec<u16>,
+    bits: u32,
+}
+
+impl Decoder {
+    fn new(lengths: &[u8]) -> Result<Self> {
+        let codes = huffman_codes(lengths)?;
+        let bits = lengths.iter().copied().max().unwrap_or(0) as u32;
+        let mut table = vec![0; 1 << bits];
+        for (symbol, (&length, &code)) in lengths.iter().zip(&codes).enumerate() {
+            if length == 0 {
+                continue;
+            }
+            for entry in table.iter_mut().skip(code as usize).step_by(1 << length) {
+                *entry = (symbol as u16) << 4 | length as u16;
+            }
+        }
+        Ok(Decoder { table, bits })
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16> {
+        let entry = self.table[reader.peek(self.bits) as usize];
+        if entry == 0 {
+            return Err(corrupt("invalid Huffman code"));
+        }
+        reader.consume((entry & 0xF).into())?;
+        Ok(entry >> 4)
+    }
+}
+
+/// Encoder of canonical Huffman codes.
+struct Encoder {
+    codes: Vec<u16>,
+    lengths: Vec<u8>,
+}
+
+impl Encoder {
+    fn new(lengths: &[u8]) -> Result<Self> {
+        Ok(Encoder {
+            codes: huffman_codes(lengths)?,
+            lengths: lengths.to_vec(),
+        })
+    }
+
+    fn write(&self, writer: &mut BitWriter, symbol: usize) -> Result<()> {
+        match self.lengths.get(symbol) {
+            Some(&length) if length > 0 => writer.write(length.into(), self.codes[symbol].into()),
+            _ => Err(corrupt("symbol without a Huffman code")),
+        }
+    }
+}
+
+/// Reader of a deflate stream, least significant bit first.
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: u64,
+}
+
+impl<'a> BitReader<'a> {
+    fn remaining(&self) -> u64 {
+        self.data.len() as u64 * 8 - self.position
+    }
+
+    /// The next `bits`, at most 25, zero past the end of the stream.
+    fn peek(&self, bits: u32) -> u32 {
+        let mut word = 0;
+        let bytes = self.data.iter().skip((self.position / 8) as usize).take(4);
+        for (i, &byte) in bytes.enumerate() {
+            word |= u32::from(byte) << (8 * i);
+        }
+        (word >> (self.position % 8)) & ((1 << bits) - 1)
+    }
+
+    fn consume(&mut self, bits: u32) -> Result<()> {
+        if u64::from(bits) > self.remaining() {
+            return Err(corrupt("deflate stream truncated"));
+        }
+        self.position += u64::from(bits);
+        Ok(())
+    }
+
+    fn read(&mut self, bits: u32) -> Result<u32> {
+        let value = self.peek(bits);
+        self.consume(bits)?;
+        Ok(value)
+    }
+
+    /// Read the bits up to the next byte boundary.
+    fn read_boundary(&mut self) -> Result<u32> {
+        self.read(((8 - self.position % 8) % 8) as u32)
+    }
+
+    /// Read `length` bytes at a byte boundary.
+    fn read_bytes(&mut self, length: usize) -> Result<&'a [u8]> {
+        let start = (self.position / 8) as usize;
+        let bytes = self
+            .data
+            .get(start..start.saturating_add(length))
+            .ok_or_else(|| corrupt("deflate stream truncated"))?;
+        self.position += length as u64 * 8;
+        Ok(bytes)
+    }
+}
+
+/// Writer of a deflate stream into zeroed `data`, least significant bit
+/// first.
+struct BitWriter<'a> {
+    data: &'a mut [u8],
+    position: u64,
+}
+
+impl BitWriter<'_> {
+    fn write(&mut self, mut bits: u32, value: u32) -> Result<()> {
+        if self.position + u64::from(bits) > self.data.len() as u64 * 8 {
+            return Err(corrupt("deflate stream overflows its extent"));
+        }
+        let mut value = u64::from(value) & ((1 << bits) - 1);
+        while bits > 0 {
+            let offset = (self.position % 8) as u32;
+            let take = bits.min(8 - offset);
+            self.data[(self.position / 8) as usize] |=
+                ((value & ((1 << take) - 1)) << offset) as u8;
+            value >>= take;
+            self.position += u64::from(take);
+            bits -= take;
+        }
+        Ok(())
+    }
+
+    /// Write `value` into the bits up to the next byte boundary.
+    fn write_boundary(&mut self, value: u32) -> Result<()> {
+        self.write(((8 - self.position % 8) % 8) as u32, value)
+    }
+
+    /// Write `bytes` at a byte boundary.
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        let start = (self.position / 8) as usize;
+        let out = self
+            .data
+            .get_mut(start..start + bytes.len())
+            .ok_or_else(|| corrupt("deflate stream overflows its extent"))?;
+        out.copy_from_slice(bytes);
+        self.position += bytes.len() as u64 * 8;
+        Ok(())
+    }
+}
+
+/// Writer of a puff, buffering literals to write them in runs.
+///
+/// Block metadata is its length minus one in two big endian bytes, then
+/// the bytes. Literals are a run length minus one below 127 in a byte, or
+/// 127 and the run length minus 128 in two bytes, then the bytes. A
+/// length/distance pair is 0x80 with the length minus 3 below 130, or 0xFF
+/// and the length minus 130, then the distance minus one in two bytes. The
+/// end of block is 0xFF and 129.
+struct PuffWriter<'a> {
+    out: &'a mut Vec<u8>,
+    literals: &'a mut Vec<u8>,
+}
+
+impl PuffWriter<'_> {
+    fn flush(&mut self) {
+        let length = self.literals.len();
+        if length == 0 {
+            return;
+        }
+        if length <= 127 {
+            self.out.push((length - 1) as u8);
+        } else {
+            self.out.push(127);
+            self.out
+                .extend_from_slice(&((length - 128) as u16).to_be_bytes());
+        }
+        self.out.append(self.literals);
+    }
+
+    fn literal(&mut self, byte: u8) {
+        if self.literals.len() == MAX_LITERALS {
+            self.flush();
+        }
+        self.literals.push(byte);
+    }
+
+    fn literals(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            if self.literals.len() == MAX_LITERALS {
+                self.flush();
+            }
+            let take = bytes.len().min(MAX_LITERALS - self.literals.len());
+            self.literals.extend_from_slice(&bytes[..take]);
+            bytes = &bytes[take..];
+        }
+    }
+
+    fn length_distance(&mut self, length: u16, distance: u16) {
+        self.flush();
+        if length < 130 {
+            self.out.push(0x80 | (length - 3) as u8);
+        } else {
+            self.out.extend([0xFF, (length - 130) as u8]);
+        }
+        self.out.extend_from_slice(&(distance - 1).to_be_bytes());
+    }
+
+    fn metadata(&mut self, metadata: &[u8]) {
+        self.flush();
+        self.out
+            .extend_from_slice(&((metadata.len() - 1) as u16).to_be_bytes());
+        self.out.extend_from_slice(metadata);
+    }
+
+    fn end_of_block(&mut self) {
+        self.flush();
+        self.out.extend([0xFF, 129]);
+    }
+}
+
+/// An item of a puff after the block metadata, see [`PuffWriter`].
+enum Puff<'a> {
+    Literals(&'a [u8]),
+    LengthDistance(u16, u16),
+    EndOfBlock,
+}
+
+/// Reader of a puff, see [`PuffWriter`].
+struct PuffReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> PuffReader<'a> {
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8]> {
+        if length > self.data.len() {
+            return Err(corrupt("puff truncated"));
+        }
+        let (bytes, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn next(&mut self) -> Result<Puff<'a>> {
+        let byte = self.u8()?;
+        if byte & 0x80 == 0 {
+            let length = match byte {
+                127 => self.u16()? as usize + 128,
+                _ => byte as usize + 1,
+            };
+            return Ok(Puff::Literals(self.bytes(length)?));
+        }
+        let length = match byte & 0x7F {
+            127 => match self.u8()? {
+                129 => return Ok(Puff::EndOfBlock),
+                length @ 0..=128 => u16::from(length) + 130,
+                _ => return Err(corrupt("invalid length in puff")),
+            },
+            length => u16::from(length) + 3,
+        };
+        let distance = self.u16()?;
+        if distance >= 32768 {
+            return Err(corrupt("invalid distance in puff"));
+        }
+        Ok(Puff::LengthDistance(length, distance + 1))
+    }
+}
+
+fn corrupt(message: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("corrupt puffin patch: {}", message),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metadata::StreamInfo;
+    use std::slice;
+
+    /// Huff `puff` into a stream starting at bit `offset` of `data`,
+    /// returning the bit extent of the stream.
+    fn huff_at(data: &mut Vec<u8>, offset: u64, puff: &[u8]) -> Result<Range<u64>> {
+        data.resize(data.len() + puff.len() * 2 + 8, 0);
+        let mut writer = BitWriter {
+            data,
+            position: offset,
+        };
+        let mut reader = PuffReader { data: puff };
+        while !reader.data.is_empty() {
+            huff_block(&mut reader, &mut writer)?;
+        }
+        let end = writer.position;
+        data.truncate(end.div_ceil(8) as usize);
+        Ok(offset..end)
+    }
+
+    fn round_trip(puff: &[u8]) -> Result<()> {
+        let mut data = Vec::new();
+        let deflate = huff_at(&mut data, 0, puff)?;
+        let (puffed, puffs) = super::puff(&data, slice::from_ref(&deflate))?;
+        // The bits after the stream in its last byte are kept as a byte.
+        let tail = if deflate.end % 8 == 0 { &[][..] } else { &[0] };
+        assert_eq!(puffed, [puff, tail].concat());
+        assert_eq!(puffs.len(), 1);
+        assert_eq!(puffs[0], 0..puff.len() as u64);
+        assert_eq!(
+            huff(&puffed, slice::from_ref(&deflate), &puffs, data.len())?,
+            data
+        );
+        Ok(())
+    }
+
+    fn puff(build: impl FnOnce(&mut PuffWriter)) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut literals = Vec::new();
+        build(&mut PuffWriter {
+            out: &mut out,
+            literals: &mut literals,
+        });
+        out
+    }
+
+    /// Code lengths of a dynamic block coding 'a', 'b', the end of block
+    /// and length 3 in 2 bits, and distance 1 in 1 bit.
+    const DYNAMIC_METADATA: [u8; 21] = [
+        0xC0, 1, 0, 14, // Final dynamic block, HLIT, HDIST, HCLEN
+        0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x02, // 18: 1, 2: 2, 1: 2
+        114, 2, 2, 155, 36, 2, 2, 1, // 97 zeros, 2, 2, 157 zeros, 2, 2, 1
+    ];
+
+    #[test]
+    fn fixed_block() -> Result<()> {
+        round_trip(&puff(|writer| {
+            writer.metadata(&[0xA0]);
+            writer.literals(b"hello, ");
+            writer.length_distance(5, 7);
+            writer.literal(b'!');
+            writer.length_distance(258, 1);
+            writer.end_of_block();
+        }))
+    }
+
+    #[test]
+    fn stored_blocks() -> Result<()> {
+        round_trip(&puff(|writer| {
+            writer.metadata(&[0x20]);
+            writer.literal(b'x');
+            writer.end_of_block();
+            writer.metadata(&[0x00]);
+            writer.end_of_block();
+            writer.metadata(&[0x80]);
+            writer.literals(&[7; 300]);
+            writer.end_of_block();
+        }))
+    }
+
+    #[test]
+    fn dynamic_block() -> Result<()> {
+        round_trip(&puff(|writer| {
+            writer.metadata(&DYNAMIC_METADATA);
+            writer.literals(b"abba");
+            writer.length_distance(3, 1);
+            writer.end_of_block();
+        }))
+    }
+
+    #[test]
+    fn long_literal_runs() -> Result<()> {
+        let literals: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        let puffed = puff(|writer| {
+            writer.metadata(&[0xA0]);
+            writer.literals(&literals);
+            writer.end_of_block();
+        });
+        assert_eq!(puffed[3], 127);
+        round_trip(&puffed)
+    }
+
+    #[test]
+    fn invalid_code() {
+        // A fixed block with the unused literal/length code 286.
+        let mut data = vec![0; 2];
+        let mut writer = BitWriter {
+            data: &mut data,
+            position: 0,
+        };
+        writer.write(3, 0b011).unwrap();
+        let codes = huffman_codes(&fixed_lengths().0).unwrap();
+        writer.write(8, codes[286].into()).unwrap();
+        let error = super::puff(&data, slice::from_ref(&(0..11))).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    /// Build a stream of raw bytes and a deflate stream starting and ending
+    /// in the middle of a byte.
+    fn stream(text: &[u8]) -> Result<(Vec<u8>, Range<u64>)> {
+        let mut data = b"raw\x05".to_vec();
+        let deflate = huff_at(
+            &mut data,
+            3 * 8 + 3,
+            &puff(|writer| {
+                writer.metadata(&[0xA0]);
+                writer.literals(text);
+                writer.end_of_block();
+            }),
+        )?;
+        if deflate.end % 8 != 0 {
+            *data.last_mut().unwrap() |= 0x80;
+        }
+        data.extend_from_slice(b"tail");
+        Ok((data, deflate))
+    }
+
+    fn stream_info(deflate: Range<u64>, puffs: &[Range<u64>], puff_length: usize) -> StreamInfo {
+        StreamInfo {
+            deflates: vec![BitExtent {
+                offset: deflate.start,
+                length: deflate.end - deflate.start,
+            }],
+            puffs: puffs
+                .iter()
+                .map(|puff| BitExtent {
+                    offset: puff.start * 8,
+                    length: (puff.end - puff.start) * 8,
+                })
+                .collect(),
+            puff_length: puff_length as u64,
+        }
+    }
+
+    #[test]
+    fn apply_patch() -> Result<()> {
+        let (old, old_deflate) = stream(b"the quick brown fox")?;
+        let (new, new_deflate) = stream(b"jumps over the lazy dog")?;
+        let (old_puffed, old_puffs) = super::puff(&old, slice::from_ref(&old_deflate))?;
+        let (new_puffed, new_puffs) = super::puff(&new, slice::from_ref(&new_deflate))?;
+        assert_eq!(&old_puffed[..4], b"raw\x05");
+
+        let header = PatchHeader {
+            version: 1,
+            src: Some(stream_info(old_deflate, &old_puffs, old_puffed.len())),
+            dst: Some(stream_info(new_deflate, &new_puffs, new_puffed.len())),
+            r#type: PatchType::Bsdiff as i32,
+        }
+        .encode_to_vec();
+
+        // An uncompressed BSDF2 patch copying the new puffs from its extra
+        // block.
+        let ctrl = [0, new_puffed.len() as i64, 0]
+            .map(i64::to_le_bytes)
+            .concat();
+        let bsdiff = [
+            &b"BSDF2\0\0\0"[..],
+            &(ctrl.len() as i64).to_le_bytes(),
+            &0i64.to_le_bytes(),
+            &(new_puffed.len() as i64).to_le_bytes(),
+            &ctrl,
+            &new_puffed,
+        ]
+        .concat();
+        let patch = [
+            &b"PUF1"[..],
+            &(header.len() as u32).to_be_bytes(),
+            &header,
+            &bsdiff,
+        ]
+        .concat();
+
+        assert_eq!(puffpatch(&old, &patch, new.len())?, new);
+        let error = puffpatch(&new, &patch, new.len()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[cfg(feature = "compress")]
+    #[test]
+    fn deflate_streams() -> Result<()> {
+        use flate2::{write::DeflateEncoder, Compression};
+        use std::io::Write;
+
+        let text: Vec<u8> = (0..20_000u32)
+            .flat_map(|i| format!("line {} of {}\n", i % 777, i % 13).into_bytes())
+            .collect();
+        for level in [0, 1, 6, 9] {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::new(level));
+            encoder.write_all(&text)?;
+            let data = encoder.finish()?;
+            let deflate = 0..data.len() as u64 * 8;
+            let deflates = slice::from_ref(&deflate);
+            let (puffed, puffs) = super::puff(&data, deflates)?;
+            assert_eq!(huff(&puffed, deflates, &puffs, data.len())?, data);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+//! Python module `payload_dumper_rust`, with the `python` feature.
+//!
+//! ```python
+//! from payload_dumper_rust import Payload
+//!
+//! payload = Payload("ota.zip")
+//! for partition in payload.partitions:
+//!     print(partition["name"], partition["size"], partition["ops"])
+//! payload.extract("boot", "boot.img", progress=lambda done, total: print(done, total))
+//! assert payload.verify("boot", "boot.img")
+//! ```
+
+// The wrappers pyo3 generates for methods taking `Python` convert their
+// `PyErr` into itself.
+#![allow(clippy::useless_conversion)]
+
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use pyo3::exceptions::{PyKeyError, PyOSError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList};
+
+use crate::{
+    create_image, open_payload, verify_partition, DeltaUpdateFile, DumpOptions,
+    InstallOperationExt, PayloadError, SectionFile,
+};
+
+/// A payload file, read from the local disk or, with the `http` feature,
+/// over HTTP.
+trait ReadSeek: Read + Seek + Send {}
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+/// Convert `error` to the closest Python exception.
+fn to_py_err(error: PayloadError) -> PyErr {
+    match error {
+        PayloadError::Io(e) => PyOSError::new_err(e.to_string()),
+        PayloadError::PartitionNotFound(name) => PyKeyError::new_err(name),
+        e => PyValueError::new_err(e.to_string()),
+    }
+}
+
+/// A payload, or the payload in an OTA zip file.
+#[pyclass(module = "payload_dumper_rust")]
+struct Payload {
+    payload: crate::Payload<SectionFile<Box<dyn ReadSeek>>>,
+}
+
+#[pymethods]
+impl Payload {
+    /// Open the payload or OTA zip file at `path_or_url`, which can be an
+    /// HTTP(S) URL with the `http` feature.
+    #[new]
+    fn new(py: Python<'_>, path_or_url: &str) -> PyResult<Self> {
+        py.allow_threads(|| {
+            let reader: Box<dyn ReadSeek> = match path_or_url {
+                #[cfg(feature = "http")]
+                url if url.starts_with("http://") || url.starts_with("https://") => Box::new(
+                    crate::HttpReader::new(url)
+                        .map_err(|e| PyOSError::new_err(format!("{}: {}", url, e)))?,
+                ),
+                path => Box::new(
+                    File::open(path).map_err(|e| PyOSError::new_err(format!("{}: {}", path, e)))?,
+                ),
+            };
+            let mut reader = open_payload(reader).map_err(|e| match e.kind() {
+                std::io::ErrorKind::InvalidData => {
+                    PyValueError::new_err(format!("{}: {}", path_or_url, e))
+                }
+                _ => PyOSError::new_err(format!("{}: {}", path_or_url, e)),
+            })?;
+            // The payload signature isn't needed to extract.
+            let file = DeltaUpdateFile::read_metadata(&mut reader).map_err(to_py_err)?;
+            Ok(Self {
+                payload: crate::Payload::new(reader, file),
+            })
+        })
+    }
+
+    /// The partitions, as dicts with their `name`, `size` in bytes, or
+    /// `None` if it's unknown, and number of operations `ops`.
+    #[getter]
+    fn partitions<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyList>> {
+        let list = PyList::empty_bound(py);
+        for partition in self.payload.partitions() {
+            let dict = PyDict::new_bound(py);
+            dict.set_item("name", &partition.partition_name)?;
+            dict.set_item(
+                "size",
+                partition.new_partition_info.as_ref().and_then(|i| i.size),
+            )?;
+            dict.set_item("ops", partition.operations.len())?;
+            list.append(dict)?;
+        }
+        Ok(list)
+    }
+
+    /// Extract the partition `name` to a new image at `out_path`, without
+    /// holding the GIL. `progress` is called with the bytes written so far
+    /// and their total as operations are applied; an exception it raises
+    /// stops the extraction and is raised again. Only full payloads can be
+    /// extracted, as the old images of delta payloads can't be given.
+    #[pyo3(signature = (name, out_path, progress = None))]
+    fn extract(
+        &mut self,
+        py: Python<'_>,
+        name: &str,
+        out_path: PathBuf,
+        progress: Option<PyObject>,
+    ) -> PyResult<()> {
+        let block_size = self.payload.block_size();
+        let partition = self
+            .payload
+            .partition(name)
+            .ok_or_else(|| PyKeyError::new_err(name.to_string()))?;
+        // Operations count as the bytes they write.
+        let mut offsets = vec![0];
+        for operation in &partition.operations {
+            offsets.push(offsets[offsets.len() - 1] + operation.dst_byte_len(block_size));
+        }
+        let total = offsets[offsets.len() - 1];
+
+        let failed = Mutex::new(None);
+        let report = |done: u64| match &progress {
+            Some(progress) => Python::with_gil(|py| match progress.call1(py, (done, total)) {
+                Ok(_) => true,
+                Err(e) => {
+                    *failed.lock().unwrap() = Some(e);
+                    false
+                }
+            }),
+            None => true,
+        };
+        let payload = &mut self.payload;
+        let stats = py.allow_threads(|| {
+            let partition = payload
+                .partition(name)
+                .expect("the partition is found above");
+            let mut img = create_image(&out_path, partition)?;
+            payload.dump_partition_with(
+                name,
+                None::<&mut File>,
+                &mut img,
+                &DumpOptions::default(),
+                |index| report(offsets[index]),
+            )
+        });
+        if let Ok(Some(_)) = stats {
+            report(total);
+        }
+        match failed.into_inner().unwrap() {
+            Some(e) => Err(e),
+            None => stats.map(|_| ()).map_err(to_py_err),
+        }
+    }
+
+    /// Whether the image at `path` has the size and hash of the partition
+    /// `name` in the manifest, without holding the GIL.
+    fn verify(&self, py: Python<'_>, name: &str, path: PathBuf) -> PyResult<bool> {
+        let partition = self
+            .payload
+            .partition(name)
+            .ok_or_else(|| PyKeyError::new_err(name.to_string()))?;
+        let info = partition.new_partition_info.clone().unwrap_or_default();
+        match py.allow_threads(|| verify_partition(&path, &info)) {
+            Ok(()) => Ok(true),
+            Err(PayloadError::SizeMismatch { .. } | PayloadError::HashMismatch { .. }) => Ok(false),
+            Err(e) => Err(to_py_err(e)),
+        }
+    }
+}
+
+/// The `payload_dumper_rust` Python module.
+#[pymodule]
+fn payload_dumper_rust(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Payload>()
+}
@@ -0,0 +1,324 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::chromeos_update_engine::{install_operation::Type, PartitionUpdate};
+use crate::extent::{Fragment, SectionFile, SPARSE_HOLE};
+use crate::trace::operation_span;
+use crate::{
+    apply_in_memory, blob_offset, read_extents, validate_dst_extents, DumpContext, PayloadError,
+};
+
+/// Bytes of the partition written by an operation.
+struct Piece {
+    /// Offset in the partition.
+    start: u64,
+    size: u64,
+    /// Index of the operation.
+    operation: usize,
+    /// Offset in the bytes of the dst extents of the operation.
+    offset: u64,
+}
+
+/// The contents of a partition, reconstructed lazily while it's read, see
+/// [`Payload::partition_reader`](crate::Payload::partition_reader).
+///
+/// Operations are applied when their first byte is read, in memory, and
+/// kept until the position leaves the range from the first to the last of
+/// their dst extents, so memory is bounded by the operations whose extents
+/// are interleaved at the position, usually one. Bytes not written by any
+/// operation, and those of ZERO and DISCARD, read as zeros. Seeking back
+/// applies the operations again.
+///
+/// Errors applying the operations are returned as [`std::io::Error`]s
+/// wrapping the [`PayloadError`], with the operation and the partition.
+pub struct PartitionReader<'a, R, O> {
+    reader: &'a mut R,
+    blobs_offset: u64,
+    block_size: u64,
+    partition: &'a PartitionUpdate,
+    old: Option<&'a mut O>,
+    /// Sorted by `start`, they don't overlap.
+    pieces: Vec<Piece>,
+    /// Range of the partition from the first to the last dst extent of
+    /// each operation.
+    ranges: Vec<(u64, u64)>,
+    /// Bytes of the dst extents of the operations applied, by index.
+    applied: Vec<(usize, Vec<u8>)>,
+    context: DumpContext,
+    size: u64,
+    pos: u64,
+}
+
+impl<'a, R: Read + Seek, O: Read + Seek> PartitionReader<'a, R, O> {
+    /// Reader of `partition`, whose data blobs are at `blobs_offset` of
+    /// `reader`, reading from `old`, the image of the old partition, if
+    /// it's a delta.
+    ///
+    /// The dst extents are checked with [`validate_dst_extents`]. Fails if
+    /// an operation reads the partition itself, like MOVE and BSDIFF, as
+    /// the bytes read may not be there anymore.
+    pub(crate) fn new(
+        reader: &'a mut R,
+        blobs_offset: u64,
+        block_size: u64,
+        partition: &'a PartitionUpdate,
+        old: Option<&'a mut O>,
+    ) -> Result<Self, PayloadError> {
+        validate_dst_extents(partition, block_size)?;
+
+        let mut pieces = Vec::new();
+        let mut ranges = Vec::with_capacity(partition.operations.len());
+        for (index, operation) in partition.operations.iter().enumerate() {
+            if matches!(operation.r#type(), Type::Move | Type::Bsdiff) {
+                return Err(PayloadError::InvalidOperation(
+                    "it reads the partition being written, which can't be read sequentially"
+                        .to_string(),
+                )
+                .in_operation(index, operation.r#type));
+            }
+            let mut range = (u64::MAX, 0);
+            if !matches!(operation.r#type(), Type::Zero | Type::Discard) {
+                let mut offset = 0;
+                for extent in &operation.dst_extents {
+                    let fragment = Fragment::from_extent(extent, block_size)?;
+                    if fragment.offset != SPARSE_HOLE && fragment.size > 0 {
+                        pieces.push(Piece {
+                            start: fragment.offset,
+                            size: fragment.size,
+                            operation: index,
+                            offset,
+                        });
+                        range = (
+                            range.0.min(fragment.offset),
+                            range.1.max(fragment.offset + fragment.size),
+                        );
+                    }
+                    offset += fragment.size;
+                }
+            }
+            ranges.push(range);
+        }
+        pieces.sort_by_key(|piece| piece.start);
+
+        let size = match partition
+            .new_partition_info
+            .as_ref()
+            .and_then(|info| info.size)
+        {
+            Some(size) => size,
+            None => pieces.last().map_or(0, |piece| piece.start + piece.size),
+        };
+        Ok(Self {
+            reader,
+            blobs_offset,
+            block_size,
+            partition,
+            old,
+            pieces,
+            ranges,
+            applied: Vec::new(),
+            context: DumpContext::new(),
+            size,
+            pos: 0,
+        })
+    }
+
+    /// Size of the partition, in `new_partition_info`, or the end of its
+    /// last dst extent if it has none.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Bytes of the dst extents of the operation at `index`, applying it if
+    /// it's not yet.
+    fn dst_bytes(&mut self, index: usize) -> Result<&[u8], PayloadError> {
+        let pos = self.pos;
+        let ranges = &self.ranges;
+        self.applied
+            .retain(|(i, _)| ranges[*i].0 <= pos && pos < ranges[*i].1);
+        if let Some(i) = self.applied.iter().position(|(i, _)| *i == index) {
+            return Ok(&self.applied[i].1);
+        }
+
+        let operation = &self.partition.operations[index];
+        let _span = operation_span(index, operation).entered();
+        let bytes = (|| {
+            let mut data = Vec::new();
+            if let Some((offset, length)) = operation.data_offset.zip(operation.data_length) {
+                let offset = blob_offset(self.blobs_offset, offset)?;
+                SectionFile::new(&mut *self.reader, offset, length)?.read_to_end(&mut data)?;
+            }
+            let src = match (&mut self.old, operation.src_extents.is_empty()) {
+                (Some(old), false) => {
+                    Some(read_extents(old, &operation.src_extents, self.block_size)?)
+                }
+                (None, false) => return Err(PayloadError::MissingOldImage(operation.r#type())),
+                (_, true) => None,
+            };
+            apply_in_memory(
+                &mut self.context,
+                operation,
+                &data,
+                src.as_deref(),
+                self.block_size,
+            )
+            .map(|(bytes, _)| bytes)
+        })()
+        .map_err(|e| e.in_operation(index, operation.r#type))?;
+        self.applied.push((index, bytes));
+        Ok(&self.applied.last().unwrap().1)
+    }
+}
+
+impl<R: Read + Seek, O: Read + Seek> Read for PartitionReader<'_, R, O> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.size.saturating_sub(self.pos);
+        let len = (buf.len() as u64).min(remaining) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+        let pos = self.pos;
+        let i = self
+            .pieces
+            .partition_point(|piece| piece.start + piece.size <= pos);
+        let read = match self.pieces.get(i) {
+            Some(piece) if piece.start <= pos => {
+                let (index, start) = (piece.operation, piece.offset + (pos - piece.start));
+                let len = (len as u64).min(piece.start + piece.size - pos) as usize;
+                let partition = self.partition;
+                let bytes = self.dst_bytes(index).map_err(|e| {
+                    std::io::Error::other(e.in_partition(&partition.partition_name))
+                })?;
+                buf[..len].copy_from_slice(&bytes[start as usize..start as usize + len]);
+                len
+            }
+            // A gap up to the next piece.
+            next => {
+                let len = next.map_or(len, |piece| (len as u64).min(piece.start - pos) as usize);
+                buf[..len].fill(0);
+                len
+            }
+        };
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R, O> Seek for PartitionReader<'_, R, O> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::Current(pos) => self.pos.checked_add_signed(pos),
+            SeekFrom::End(pos) => self.size.checked_add_signed(pos),
+        }
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::tests::{extent, operation};
+
+    #[test]
+    fn read_and_seek() -> Result<(), Box<dyn std::error::Error>> {
+        // Blocks of 4 bytes: "abcd" at 6, "efgh" and "ijkl" at 1 and 3, a
+        // ZERO at 0 and gaps at 2, 4, 5 and 7.
+        let mut blobs = Cursor::new(b"abcdefghijkl".to_vec());
+        let partition = PartitionUpdate {
+            partition_name: "boot".to_string(),
+            operations: vec![
+                operation(Type::Replace, Some((0, 4)), vec![extent(6, 1)]),
+                operation(
+                    Type::Replace,
+                    Some((4, 8)),
+                    vec![extent(1, 1), extent(3, 1)],
+                ),
+                operation(Type::Zero, None, vec![extent(0, 1)]),
+            ],
+            ..Default::default()
+        };
+        let mut reader =
+            PartitionReader::new(&mut blobs, 0, 4, &partition, None::<&mut Cursor<Vec<u8>>>)?;
+        assert_eq!(reader.size(), 28);
+        let mut image = Vec::new();
+        reader.read_to_end(&mut image)?;
+        let expected = [&[0; 4][..], b"efgh", &[0; 4], b"ijkl", &[0; 8], b"abcd"].concat();
+        assert_eq!(image, expected);
+
+        reader.seek(SeekFrom::Start(14))?;
+        let mut buf = [0; 10];
+        reader.read_exact(&mut buf)?;
+        assert_eq!(buf, expected[14..24]);
+        assert_eq!(reader.seek(SeekFrom::End(-2))?, 26);
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest)?;
+        assert_eq!(rest, b"cd");
+        Ok(())
+    }
+
+    #[test]
+    fn gap_past_4_gib() -> Result<(), Box<dyn std::error::Error>> {
+        const GIB_4: u64 = 1 << 32;
+        // A block past 4 GiB, after a gap of more than 4 GiB.
+        let mut blobs = Cursor::new(b"abcd".to_vec());
+        let partition = PartitionUpdate {
+            partition_name: "boot".to_string(),
+            operations: vec![operation(
+                Type::Replace,
+                Some((0, 4)),
+                vec![extent(GIB_4 / 4 + 1, 1)],
+            )],
+            ..Default::default()
+        };
+        let mut reader =
+            PartitionReader::new(&mut blobs, 0, 4, &partition, None::<&mut Cursor<Vec<u8>>>)?;
+        assert_eq!(reader.size(), GIB_4 + 8);
+        let mut buf = [1; 16];
+        reader.read_exact(&mut buf)?;
+        assert_eq!(buf, [0; 16]);
+        reader.seek(SeekFrom::Start(GIB_4 - 2))?;
+        let mut end = Vec::new();
+        reader.read_to_end(&mut end)?;
+        assert_eq!(end, b"\0\0\0\0\0\0abcd");
+        Ok(())
+    }
+
+    #[test]
+    fn errors() {
+        let mut blobs = Cursor::new(b"abcd".to_vec());
+        let mut source_copy = operation(Type::SourceCopy, None, vec![extent(0, 1)]);
+        source_copy.src_extents = vec![extent(0, 1)];
+        let partition = PartitionUpdate {
+            partition_name: "boot".to_string(),
+            operations: vec![source_copy],
+            ..Default::default()
+        };
+        let mut reader =
+            PartitionReader::new(&mut blobs, 0, 4, &partition, None::<&mut Cursor<Vec<u8>>>)
+                .unwrap();
+        let error = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "partition boot: operation 0 (type SOURCE_COPY): SOURCE_COPY requires the old partition image"
+        );
+
+        let partition = PartitionUpdate {
+            partition_name: "boot".to_string(),
+            operations: vec![operation(Type::Move, None, vec![extent(0, 1)])],
+            ..Default::default()
+        };
+        let error =
+            PartitionReader::new(&mut blobs, 0, 4, &partition, None::<&mut Cursor<Vec<u8>>>)
+                .err()
+                .unwrap();
+        assert!(matches!(error, PayloadError::Operation { index: 0, .. }));
+    }
+}
@@ -0,0 +1,146 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::{PayloadError, PayloadHeader, SectionFile};
+
+/// A payload found by [`find_payloads`].
+#[derive(Debug, Clone)]
+pub struct FoundPayload {
+    /// Offset of the payload in the file.
+    pub offset: u64,
+    /// Size of the file from `offset` up to the next payload or the end of
+    /// the file, which holds the payload and any data after it.
+    pub len: u64,
+    /// The header of the payload.
+    pub header: PayloadHeader,
+}
+
+/// Find the payloads concatenated in `reader`, with a payload at position
+/// 0. After the end of each payload, see [`PayloadHeader::payload_size`],
+/// the rest of the file is scanned for the `CrAU` magic of another payload,
+/// skipping magics which aren't followed by a valid header.
+///
+/// Returns at least one payload, or the error parsing the first one.
+pub fn find_payloads<R: Read + Seek>(reader: &mut R) -> Result<Vec<FoundPayload>, PayloadError> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    let mut payloads = vec![parse_at(reader, 0, len)?];
+    let mut from = payloads[0].header.payload_size();
+    while let Some(offset) = find_magic(reader, from, len)? {
+        match parse_at(reader, offset, len) {
+            Ok(payload) => {
+                from = offset.saturating_add(payload.header.payload_size());
+                payloads.push(payload);
+            }
+            Err(_) => from = offset + 1,
+        }
+    }
+
+    for i in 1..payloads.len() {
+        payloads[i - 1].len = payloads[i].offset - payloads[i - 1].offset;
+    }
+    Ok(payloads)
+}
+
+/// Parse the header of the payload at `offset` of `reader`, which is `len`
+/// bytes long.
+fn parse_at<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+    len: u64,
+) -> Result<FoundPayload, PayloadError> {
+    let mut section = SectionFile::new(&mut *reader, offset, len - offset)?;
+    let header = PayloadHeader::parse_prefix(&mut section)?;
+    if !(1..=2).contains(&header.file_format_version) {
+        let message = format!(
+            "unsupported payload major version {}",
+            header.file_format_version
+        );
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message).into());
+    }
+    Ok(FoundPayload {
+        offset,
+        len: len - offset,
+        header,
+    })
+}
+
+/// Offset of the first `CrAU` magic in `reader` between `from` and `len`.
+fn find_magic<R: Read + Seek>(reader: &mut R, from: u64, len: u64) -> std::io::Result<Option<u64>> {
+    const MAGIC: &[u8] = b"CrAU";
+    if from >= len {
+        return Ok(None);
+    }
+    reader.seek(SeekFrom::Start(from))?;
+    let mut reader = Read::by_ref(reader).take(len - from);
+    let mut buf = vec![0; 1 << 20];
+    // Offset of `buf[0]` in the file, and the number of bytes in `buf`.
+    let (mut start, mut filled) = (from, 0);
+    loop {
+        let n = reader.read(&mut buf[filled..])?;
+        if n == 0 {
+            return Ok(None);
+        }
+        filled += n;
+        if let Some(pos) = buf[..filled]
+            .windows(MAGIC.len())
+            .position(|window| window == MAGIC)
+        {
+            return Ok(Some(start + pos as u64));
+        }
+        // Keep the end, which may be the beginning of a magic.
+        let keep = filled.min(MAGIC.len() - 1);
+        buf.copy_within(filled - keep..filled, 0);
+        start += (filled - keep) as u64;
+        filled = keep;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chromeos_update_engine::DeltaArchiveManifest;
+    use prost::Message;
+    use std::io::Cursor;
+
+    fn payload(minor_version: u32, blobs: &[u8]) -> Vec<u8> {
+        let manifest = DeltaArchiveManifest {
+            minor_version: Some(minor_version),
+            signatures_offset: Some(0),
+            signatures_size: Some(blobs.len() as u64),
+            ..Default::default()
+        }
+        .encode_to_vec();
+        [
+            &b"CrAU"[..],
+            &2u64.to_be_bytes(),
+            &(manifest.len() as u64).to_be_bytes(),
+            &0u32.to_be_bytes(),
+            &manifest,
+            blobs,
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn concatenated() -> Result<(), Box<dyn std::error::Error>> {
+        // The magic in the blobs of the first payload and the one in the
+        // padding without a valid header are skipped.
+        let first = payload(0, b"CrAU");
+        let second = payload(7, b"blobs");
+        let data = [&first[..], b"padCrAUpad", &second, b"trailing"].concat();
+
+        let payloads = find_payloads(&mut Cursor::new(&data))?;
+        assert_eq!(payloads.len(), 2);
+        assert_eq!(
+            (payloads[0].offset, payloads[0].len),
+            (0, first.len() as u64 + 10)
+        );
+        assert_eq!(payloads[0].header.manifest.minor_version(), 0);
+        assert_eq!(payloads[1].offset, first.len() as u64 + 10);
+        assert_eq!(payloads[1].len, second.len() as u64 + 8);
+        assert_eq!(payloads[1].header.manifest.minor_version(), 7);
+
+        assert_eq!(find_payloads(&mut Cursor::new(&first))?.len(), 1);
+        assert!(find_payloads(&mut Cursor::new(&data[1..])).is_err());
+        Ok(())
+    }
+}
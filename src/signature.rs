@@ -0,0 +1,143 @@
+use std::fmt;
+
+use p256::ecdsa::{signature::hazmat::PrehashVerifier, VerifyingKey};
+use prost::Message;
+use rsa::{pkcs1::DecodeRsaPublicKey, Pkcs1v15Sign, RsaPublicKey};
+use sha2::Sha256;
+
+// The pkcs8 trait, which rsa re-exports too, decodes both kinds of keys.
+use p256::pkcs8::DecodePublicKey as _;
+
+use crate::chromeos_update_engine::{signatures::Signature, Signatures};
+
+/// Error verifying a signature of the payload.
+#[derive(Debug)]
+pub enum SignatureError {
+    /// The key is not a PEM or DER encoded RSA or EC public key.
+    InvalidKey,
+    /// The signed data can not be read.
+    Io(std::io::Error),
+    /// The Signatures message can not be decoded.
+    Decode(prost::DecodeError),
+    /// The Signatures message contains no signature.
+    Unsigned,
+    /// No signature is made by the key.
+    Mismatch,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::InvalidKey => {
+                write!(f, "not a PEM or DER encoded RSA or EC public key")
+            }
+            SignatureError::Io(e) => write!(f, "{}", e),
+            SignatureError::Decode(e) => write!(f, "invalid Signatures message: {}", e),
+            SignatureError::Unsigned => write!(f, "no signature"),
+            SignatureError::Mismatch => write!(f, "no signature matches the public key"),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SignatureError::Io(e) => Some(e),
+            SignatureError::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Public key of the payload signer.
+enum PublicKey {
+    Rsa(RsaPublicKey),
+    Ec(VerifyingKey),
+}
+
+impl PublicKey {
+    /// Parse a PEM or DER encoded public key, in SubjectPublicKeyInfo format
+    /// or PKCS#1 format for RSA keys.
+    fn parse(key: &[u8]) -> Result<Self, SignatureError> {
+        let key = match std::str::from_utf8(key) {
+            Ok(pem) if pem.contains("-----BEGIN") => RsaPublicKey::from_public_key_pem(pem)
+                .or_else(|_| RsaPublicKey::from_pkcs1_pem(pem))
+                .map(PublicKey::Rsa)
+                .or_else(|_| VerifyingKey::from_public_key_pem(pem).map(PublicKey::Ec))
+                .ok(),
+            _ => RsaPublicKey::from_public_key_der(key)
+                .or_else(|_| RsaPublicKey::from_pkcs1_der(key))
+                .map(PublicKey::Rsa)
+                .or_else(|_| VerifyingKey::from_public_key_der(key).map(PublicKey::Ec))
+                .ok(),
+        };
+        key.ok_or(SignatureError::InvalidKey)
+    }
+
+    /// Verify `signature` of the SHA-256 `hash`, like update_engine, RSA
+    /// signatures use PKCS#1 v1.5 padding and EC signatures are DER encoded
+    /// ECDSA signatures.
+    fn verify(&self, hash: &[u8], signature: &[u8]) -> bool {
+        match self {
+            PublicKey::Rsa(key) => key
+                .verify(Pkcs1v15Sign::new::<Sha256>(), hash, signature)
+                .is_ok(),
+            PublicKey::Ec(key) => p256::ecdsa::Signature::from_der(signature)
+                .and_then(|signature| key.verify_prehash(hash, &signature))
+                .is_ok(),
+        }
+    }
+}
+
+impl Signature {
+    /// The signature without the padding of EC signatures, which are padded
+    /// to the maximum size of the key so the size of the Signatures message
+    /// is known before signing, see `unpadded_signature_size`.
+    pub fn unpadded_data(&self) -> &[u8] {
+        let data = self.data();
+        match self.unpadded_signature_size {
+            Some(size) if (size as usize) <= data.len() => &data[..size as usize],
+            _ => data,
+        }
+    }
+}
+
+/// Decode the serialized Signatures `message`, empty if it's empty.
+pub(crate) fn decode_signatures(message: &[u8]) -> Result<Signatures, SignatureError> {
+    Signatures::decode(message).map_err(SignatureError::Decode)
+}
+
+/// Find the signature made by `key` over the SHA-256 `hash` in the
+/// serialized Signatures `message`, returning its index and itself.
+pub(crate) fn find_signature(
+    message: &[u8],
+    hash: &[u8],
+    key: &[u8],
+) -> Result<(usize, Signature), SignatureError> {
+    let key = PublicKey::parse(key)?;
+    let signatures = decode_signatures(message)?;
+    if signatures.signatures.is_empty() {
+        return Err(SignatureError::Unsigned);
+    }
+
+    signatures
+        .signatures
+        .into_iter()
+        .enumerate()
+        .find(|(_, signature)| key.verify(hash, signature.unpadded_data()))
+        .ok_or(SignatureError::Mismatch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_key() {
+        let error = find_signature(&[], &[0; 32], b"not a key").unwrap_err();
+        assert!(matches!(error, SignatureError::InvalidKey));
+        let error =
+            PublicKey::parse(b"-----BEGIN PUBLIC KEY-----\nAAAA\n-----END PUBLIC KEY-----\n");
+        assert!(matches!(error, Err(SignatureError::InvalidKey)));
+    }
+}
@@ -0,0 +1,323 @@
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+
+use crate::chromeos_update_engine::{install_operation::Type, PartitionUpdate};
+
+const SPARSE_HEADER_MAGIC: u32 = 0xed26ff3a;
+const SPARSE_HEADER_SIZE: u16 = 28;
+const CHUNK_HEADER_SIZE: u16 = 12;
+const CHUNK_TYPE_RAW: u16 = 0xcac1;
+const CHUNK_TYPE_FILL: u16 = 0xcac2;
+const CHUNK_TYPE_DONT_CARE: u16 = 0xcac3;
+
+/// Maximum size of the data of a RAW chunk, larger runs are split.
+const MAX_RAW_CHUNK_SIZE: u64 = 64 << 20;
+
+/// A chunk of an Android sparse image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SparseChunk {
+    /// Blocks copied from the raw image.
+    Raw(u32),
+    /// Blocks filled with a 4-byte value.
+    Fill(u32, u32),
+    /// Blocks left as is when flashed, zeros when unsparsed.
+    DontCare(u32),
+}
+
+impl SparseChunk {
+    /// Number of blocks of the chunk.
+    pub fn blocks(&self) -> u32 {
+        match *self {
+            SparseChunk::Raw(n) | SparseChunk::Fill(_, n) | SparseChunk::DontCare(n) => n,
+        }
+    }
+}
+
+/// Plan the chunks of the sparse image of `partition`, which is `size`
+/// bytes.
+///
+/// Blocks written by ZERO operations are FILL chunks of zeros, blocks
+/// written by other operations are RAW chunks, and blocks not written, or
+/// only discarded, are DONT_CARE chunks. Fails if the image has more blocks
+/// than a sparse image can hold.
+pub fn plan_chunks(
+    partition: &PartitionUpdate,
+    size: u64,
+    block_size: u64,
+) -> std::io::Result<Vec<SparseChunk>> {
+    let total_blocks = size.div_ceil(block_size);
+    if total_blocks > u32::MAX as u64 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{} blocks are more than a sparse image holds", total_blocks),
+        ));
+    }
+
+    // Disjoint runs of blocks by their first block, with their end and how
+    // they are written. `size` comes from the manifest, so there is a run
+    // for each extent rather than an entry for each block.
+    let mut runs: BTreeMap<u64, (u64, Block)> = BTreeMap::new();
+    for operation in &partition.operations {
+        let block = match Type::from_i32(operation.r#type) {
+            Some(Type::Zero) => Block::Zero,
+            Some(Type::Discard) => Block::Untouched,
+            _ => Block::Data,
+        };
+        for extent in &operation.dst_extents {
+            let start = extent.start_block().min(total_blocks);
+            let end = start.saturating_add(extent.num_blocks()).min(total_blocks);
+            if start == end {
+                continue;
+            }
+            // Later operations overwrite the blocks of earlier ones: cut the
+            // run overlapping the start, and those starting in the extent.
+            if let Some((&first, &(last, written))) = runs.range(..start).next_back() {
+                if last > start {
+                    runs.insert(first, (start, written));
+                    if last > end {
+                        runs.insert(end, (last, written));
+                    }
+                }
+            }
+            let inside: Vec<u64> = runs.range(start..end).map(|(&first, _)| first).collect();
+            for first in inside {
+                let (last, written) = runs.remove(&first).unwrap();
+                if last > end {
+                    runs.insert(end, (last, written));
+                }
+            }
+            runs.insert(start, (end, block));
+        }
+    }
+
+    let max_raw_blocks = (MAX_RAW_CHUNK_SIZE / block_size).max(1) as u32;
+    let mut chunks: Vec<SparseChunk> = Vec::new();
+    let mut next = 0;
+    for (first, (last, block)) in runs
+        .into_iter()
+        .chain([(total_blocks, (total_blocks, Block::Untouched))])
+    {
+        push_blocks(
+            &mut chunks,
+            Block::Untouched,
+            (first - next) as u32,
+            max_raw_blocks,
+        );
+        push_blocks(&mut chunks, block, (last - first) as u32, max_raw_blocks);
+        next = last;
+    }
+    Ok(chunks)
+}
+
+/// How the blocks of a run are written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Block {
+    Untouched,
+    Data,
+    Zero,
+}
+
+/// Add `count` blocks written as `block` to `chunks`, extending the last
+/// chunk if it's of the same type. RAW chunks are split at
+/// `max_raw_blocks`.
+fn push_blocks(chunks: &mut Vec<SparseChunk>, block: Block, mut count: u32, max_raw_blocks: u32) {
+    while count > 0 {
+        let added = match (chunks.last_mut(), block) {
+            (Some(SparseChunk::Raw(n)), Block::Data) if *n < max_raw_blocks => {
+                let added = count.min(max_raw_blocks - *n);
+                *n += added;
+                added
+            }
+            (Some(SparseChunk::Fill(0, n)), Block::Zero)
+            | (Some(SparseChunk::DontCare(n)), Block::Untouched) => {
+                *n += count;
+                count
+            }
+            (_, Block::Data) => {
+                let added = count.min(max_raw_blocks);
+                chunks.push(SparseChunk::Raw(added));
+                added
+            }
+            (_, Block::Zero) => {
+                chunks.push(SparseChunk::Fill(0, count));
+                count
+            }
+            (_, Block::Untouched) => {
+                chunks.push(SparseChunk::DontCare(count));
+                count
+            }
+        };
+        count -= added;
+    }
+}
+
+/// Write the Android sparse image of `chunks` to `out`, with the data of
+/// RAW chunks read from the raw image `raw`. The last block is padded with
+/// zeros if `raw` ends in the middle of it.
+pub fn write_sparse_image<R: Read + Seek, W: Write>(
+    raw: &mut R,
+    chunks: &[SparseChunk],
+    block_size: u64,
+    out: &mut W,
+) -> std::io::Result<()> {
+    let total_blocks: u32 = chunks.iter().map(|chunk| chunk.blocks()).sum();
+    out.write_all(&SPARSE_HEADER_MAGIC.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?; // major version
+    out.write_all(&0u16.to_le_bytes())?; // minor version
+    out.write_all(&SPARSE_HEADER_SIZE.to_le_bytes())?;
+    out.write_all(&CHUNK_HEADER_SIZE.to_le_bytes())?;
+    out.write_all(&(block_size as u32).to_le_bytes())?;
+    out.write_all(&total_blocks.to_le_bytes())?;
+    out.write_all(&(chunks.len() as u32).to_le_bytes())?;
+    out.write_all(&0u32.to_le_bytes())?; // checksum, unused
+
+    let mut block = 0u64;
+    for chunk in chunks {
+        let (chunk_type, data_size) = match chunk {
+            SparseChunk::Raw(n) => (CHUNK_TYPE_RAW, *n as u64 * block_size),
+            SparseChunk::Fill(..) => (CHUNK_TYPE_FILL, 4),
+            SparseChunk::DontCare(_) => (CHUNK_TYPE_DONT_CARE, 0),
+        };
+        out.write_all(&chunk_type.to_le_bytes())?;
+        out.write_all(&0u16.to_le_bytes())?;
+        out.write_all(&chunk.blocks().to_le_bytes())?;
+        out.write_all(&((CHUNK_HEADER_SIZE as u64 + data_size) as u32).to_le_bytes())?;
+
+        match chunk {
+            SparseChunk::Raw(_) => {
+                raw.seek(SeekFrom::Start(block * block_size))?;
+                let copied = std::io::copy(&mut Read::by_ref(raw).take(data_size), out)?;
+                std::io::copy(&mut std::io::repeat(0).take(data_size - copied), out)?;
+            }
+            SparseChunk::Fill(value, _) => out.write_all(&value.to_le_bytes())?,
+            SparseChunk::DontCare(_) => {}
+        }
+        block += chunk.blocks() as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{extent, operation};
+    use std::io::Cursor;
+
+    /// Unsparse an Android sparse image like simg2img, DONT_CARE chunks are
+    /// zeros.
+    fn unsparse(image: &[u8]) -> Vec<u8> {
+        let u16_at =
+            |offset: usize| u16::from_le_bytes(image[offset..offset + 2].try_into().unwrap());
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(image[offset..offset + 4].try_into().unwrap());
+        assert_eq!(u32_at(0), SPARSE_HEADER_MAGIC);
+        let block_size = u32_at(12) as usize;
+        let total_blocks = u32_at(16) as usize;
+        let total_chunks = u32_at(20);
+
+        let mut raw = Vec::new();
+        let mut offset = u16_at(8) as usize;
+        for _ in 0..total_chunks {
+            let blocks = u32_at(offset + 4) as usize;
+            let total_size = u32_at(offset + 8) as usize;
+            let data = &image[offset + 12..offset + total_size];
+            match u16_at(offset) {
+                CHUNK_TYPE_RAW => raw.extend_from_slice(data),
+                CHUNK_TYPE_FILL => raw.extend(data.iter().cycle().take(blocks * block_size)),
+                CHUNK_TYPE_DONT_CARE => raw.resize(raw.len() + blocks * block_size, 0),
+                chunk_type => panic!("unexpected chunk type {:x}", chunk_type),
+            }
+            offset += total_size;
+        }
+        assert_eq!(offset, image.len());
+        assert_eq!(raw.len(), total_blocks * block_size);
+        raw
+    }
+
+    #[test]
+    fn round_trip() -> std::io::Result<()> {
+        let partition = PartitionUpdate {
+            operations: vec![
+                operation(Type::Replace, None, vec![extent(0, 2)]),
+                operation(Type::Zero, None, vec![extent(2, 1)]),
+                operation(Type::ReplaceXz, None, vec![extent(3, 1)]),
+                operation(Type::Discard, None, vec![extent(5, 1)]),
+                operation(Type::SourceCopy, None, vec![extent(7, 1)]),
+            ],
+            ..Default::default()
+        };
+        let raw: Vec<u8> = (0..8u8).flat_map(|block| [block + 1; 4]).collect();
+        let chunks = plan_chunks(&partition, raw.len() as u64, 4)?;
+        assert_eq!(
+            chunks,
+            [
+                SparseChunk::Raw(2),
+                SparseChunk::Fill(0, 1),
+                SparseChunk::Raw(1),
+                SparseChunk::DontCare(3),
+                SparseChunk::Raw(1),
+            ]
+        );
+
+        let mut image = Vec::new();
+        write_sparse_image(&mut Cursor::new(&raw), &chunks, 4, &mut image)?;
+        let expected: Vec<u8> = [1, 2, 0, 4, 0, 0, 0, 8]
+            .iter()
+            .flat_map(|&b| [b; 4])
+            .collect();
+        assert_eq!(unsparse(&image), expected);
+
+        // Extents past the end, also past 4 GiB blocks which would wrap
+        // around on 32-bit targets, are left out.
+        let partition = PartitionUpdate {
+            operations: vec![
+                operation(Type::Replace, None, vec![extent((1 << 32) + 1, 1)]),
+                operation(Type::Zero, None, vec![extent(6, u64::MAX)]),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            plan_chunks(&partition, 32, 4)?,
+            [SparseChunk::DontCare(6), SparseChunk::Fill(0, 2)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn overlapping_and_huge() -> std::io::Result<()> {
+        // Later operations overwrite earlier ones, RAW chunks are split.
+        let partition = PartitionUpdate {
+            operations: vec![
+                operation(Type::Replace, None, vec![extent(0, 40000)]),
+                operation(Type::Zero, None, vec![extent(1, 2)]),
+                operation(Type::Discard, None, vec![extent(39999, 3)]),
+                operation(Type::Replace, None, vec![extent(2, 1)]),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            plan_chunks(&partition, 40002 * 4096, 4096)?,
+            [
+                SparseChunk::Raw(1),
+                SparseChunk::Fill(0, 1),
+                SparseChunk::Raw(16384),
+                SparseChunk::Raw(16384),
+                SparseChunk::Raw(7229),
+                SparseChunk::DontCare(3),
+            ]
+        );
+
+        // The size in the manifest is not allocated for.
+        let partition = PartitionUpdate {
+            operations: vec![operation(Type::Replace, None, vec![extent(0, 1)])],
+            ..Default::default()
+        };
+        assert_eq!(
+            plan_chunks(&partition, u32::MAX as u64 * 4096, 4096)?,
+            [SparseChunk::Raw(1), SparseChunk::DontCare(u32::MAX - 1)]
+        );
+        let error = plan_chunks(&partition, u64::MAX, 4096).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        Ok(())
+    }
+}
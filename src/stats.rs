@@ -0,0 +1,286 @@
+use std::collections::BTreeMap;
+
+use crate::chromeos_update_engine::{install_operation::Type, PartitionUpdate};
+use crate::operation::ExtentExt;
+
+/// Totals of operations of a type, from the manifest alone.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OperationStats {
+    /// Number of operations.
+    pub count: usize,
+    /// Bytes of data in the payload, the sum of `data_length`.
+    pub data_length: u64,
+    /// Blocks written, the sum of the blocks of `dst_extents`.
+    pub dst_blocks: u64,
+}
+
+impl OperationStats {
+    /// Add the totals of `other`.
+    pub fn add(&mut self, other: &OperationStats) {
+        self.count += other.count;
+        self.data_length += other.data_length;
+        self.dst_blocks += other.dst_blocks;
+    }
+
+    /// Ratio of the data in the payload to the bytes written, with blocks
+    /// of `block_size` bytes, or `None` if nothing is written or the size
+    /// written overflows.
+    pub fn ratio(&self, block_size: u64) -> Option<f64> {
+        match self.dst_blocks.checked_mul(block_size)? {
+            0 => None,
+            written => Some(self.data_length as f64 / written as f64),
+        }
+    }
+}
+
+/// Totals of the operations of `partition` by operation type, which is an
+/// `i32` so unknown types are counted too.
+pub fn operation_stats(partition: &PartitionUpdate) -> BTreeMap<i32, OperationStats> {
+    let mut stats: BTreeMap<i32, OperationStats> = BTreeMap::new();
+    for operation in &partition.operations {
+        let entry = stats.entry(operation.r#type).or_default();
+        entry.count += 1;
+        entry.data_length += operation.data_length();
+        entry.dst_blocks += operation
+            .dst_extents
+            .iter()
+            .map(|e| e.num_blocks())
+            .sum::<u64>();
+    }
+    stats
+}
+
+/// Estimated size of the COW of a partition, the snapshot written during
+/// the update on Virtual A/B devices, see [`cow_estimate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CowEstimate {
+    /// `estimate_cow_size` of the manifest, `None` if it's not set or 0,
+    /// which means libsnapshot has to estimate it.
+    pub manifest: Option<u64>,
+    /// Approximation from the operations alone: the bytes written by the
+    /// operations other than SOURCE_COPY, which are stored in the COW
+    /// uncompressed. SOURCE_COPY only records where to copy from.
+    pub approximation: u64,
+}
+
+impl CowEstimate {
+    /// The estimate of the manifest if any, otherwise the approximation.
+    pub fn size(&self) -> u64 {
+        self.manifest.unwrap_or(self.approximation)
+    }
+
+    /// Whether [`CowEstimate::size`] is the approximation.
+    pub fn is_approximation(&self) -> bool {
+        self.manifest.is_none()
+    }
+}
+
+/// Estimate the size of the COW of `partition`, with blocks of
+/// `block_size` bytes, see [`CowEstimate`].
+pub fn cow_estimate(partition: &PartitionUpdate, block_size: u64) -> CowEstimate {
+    let blocks: u64 = partition
+        .operations
+        .iter()
+        .filter(|operation| operation.r#type() != Type::SourceCopy)
+        .flat_map(|operation| &operation.dst_extents)
+        .filter(|extent| !extent.is_sparse_hole())
+        .map(|extent| extent.num_blocks())
+        .fold(0, u64::saturating_add);
+    CowEstimate {
+        manifest: partition.estimate_cow_size.filter(|&size| size > 0),
+        approximation: blocks.saturating_mul(block_size),
+    }
+}
+
+/// Blocks written by two operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Overlap {
+    /// Indices of the operations, the first applied first.
+    pub operations: (usize, usize),
+    /// First block written by both.
+    pub start_block: u64,
+    /// Block after the last written by both.
+    pub end_block: u64,
+}
+
+/// How the dst extents of the operations of a partition cover it, from the
+/// manifest alone, see [`extent_map`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtentMap {
+    /// Blocks written by more than one operation, by start block.
+    pub overlaps: Vec<Overlap>,
+    /// Number of blocks written by any operation.
+    pub covered_blocks: u64,
+    /// Number of blocks of the partition, if `new_partition_info` has its
+    /// size.
+    pub total_blocks: Option<u64>,
+}
+
+/// Map the dst extents of the operations of `partition`, with blocks of
+/// `block_size` bytes: the blocks written by more than one operation, and
+/// how many blocks are written at all. Overlaps between the extents of
+/// an operation, which [`validate_dst_extents`](crate::validate_dst_extents)
+/// rejects, and holes, see [`SPARSE_HOLE`](crate::SPARSE_HOLE), are left out.
+pub fn extent_map(partition: &PartitionUpdate, block_size: u64) -> ExtentMap {
+    // Written ranges of blocks, as `(start, end, operation)`.
+    let mut ranges: Vec<_> = partition
+        .operations
+        .iter()
+        .enumerate()
+        .flat_map(|(index, operation)| {
+            operation
+                .dst_extents
+                .iter()
+                .filter(|extent| !extent.is_sparse_hole() && extent.num_blocks() > 0)
+                .map(move |extent| {
+                    (
+                        extent.start_block(),
+                        extent.start_block().saturating_add(extent.num_blocks()),
+                        index,
+                    )
+                })
+        })
+        .collect();
+    ranges.sort_unstable();
+
+    let mut map = ExtentMap {
+        total_blocks: partition
+            .new_partition_info
+            .as_ref()
+            .and_then(|info| info.size)
+            .map(|size| size.div_ceil(block_size.max(1))),
+        ..Default::default()
+    };
+    // Ranges started before the current one, which may still overlap it.
+    let mut active: Vec<(u64, usize)> = Vec::new();
+    let mut covered_to = 0;
+    for (start, end, index) in ranges {
+        active.retain(|&(active_end, _)| active_end > start);
+        for &(active_end, other) in &active {
+            if other != index {
+                map.overlaps.push(Overlap {
+                    operations: (other.min(index), other.max(index)),
+                    start_block: start,
+                    end_block: active_end.min(end),
+                });
+            }
+        }
+        active.push((end, index));
+
+        map.covered_blocks += end.saturating_sub(start.max(covered_to));
+        covered_to = covered_to.max(end);
+    }
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chromeos_update_engine::{install_operation::Type, PartitionInfo};
+    use crate::extent::SPARSE_HOLE;
+    use crate::tests::{extent, operation};
+
+    #[test]
+    fn stats() {
+        let partition = PartitionUpdate {
+            operations: vec![
+                operation(Type::ReplaceXz, Some((0, 100)), vec![extent(0, 2)]),
+                operation(Type::Zero, None, vec![extent(0, 8)]),
+                operation(Type::ReplaceXz, Some((0, 300)), vec![extent(0, 2)]),
+            ],
+            ..Default::default()
+        };
+        let stats = operation_stats(&partition);
+        let xz = stats[&(Type::ReplaceXz as i32)];
+        assert_eq!(
+            xz,
+            OperationStats {
+                count: 2,
+                data_length: 400,
+                dst_blocks: 4,
+            }
+        );
+        assert_eq!(xz.ratio(100), Some(1.0));
+
+        let mut total = OperationStats::default();
+        stats.values().for_each(|s| total.add(s));
+        assert_eq!(total.count, 3);
+        assert_eq!(total.dst_blocks, 12);
+        assert_eq!(OperationStats::default().ratio(4096), None);
+        assert_eq!(xz.ratio(u64::MAX), None);
+    }
+
+    #[test]
+    fn cow() {
+        let mut partition = PartitionUpdate {
+            operations: vec![
+                operation(Type::SourceCopy, None, vec![extent(0, 100)]),
+                operation(Type::ReplaceXz, Some((0, 100)), vec![extent(0, 2)]),
+                operation(Type::SourceBsdiff, Some((0, 10)), vec![extent(0, 3)]),
+                operation(Type::Zero, None, vec![extent(0, 1)]),
+            ],
+            ..Default::default()
+        };
+        partition.operations[3].dst_extents[0].start_block = Some(SPARSE_HOLE);
+        let estimate = cow_estimate(&partition, 4096);
+        assert_eq!(estimate.manifest, None);
+        assert_eq!(estimate.approximation, 5 * 4096);
+        assert_eq!(estimate.size(), 5 * 4096);
+        assert!(estimate.is_approximation());
+
+        partition.estimate_cow_size = Some(0);
+        assert!(cow_estimate(&partition, 4096).is_approximation());
+        partition.estimate_cow_size = Some(1234);
+        let estimate = cow_estimate(&partition, 4096);
+        assert_eq!(estimate.size(), 1234);
+        assert!(!estimate.is_approximation());
+    }
+
+    #[test]
+    fn extents() {
+        let mut operations = vec![
+            operation(Type::Replace, Some((0, 1)), vec![extent(0, 0)]),
+            operation(Type::Replace, Some((0, 1)), vec![extent(0, 0)]),
+            operation(Type::Zero, None, vec![extent(0, 0)]),
+            operation(Type::Replace, Some((0, 1)), vec![extent(0, 0)]),
+        ];
+        operations[0].dst_extents = vec![extent(0, 2), extent(5, 1)];
+        operations[1].dst_extents = vec![extent(2, 2)];
+        // Overlaps operations 1 and 0.
+        operations[2].dst_extents = vec![extent(3, 3), extent(SPARSE_HOLE, 4)];
+        operations[3].dst_extents = vec![extent(8, 2), extent(10, 0)];
+        let partition = PartitionUpdate {
+            operations,
+            new_partition_info: Some(PartitionInfo {
+                size: Some(12 * 4096),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let map = extent_map(&partition, 4096);
+        assert_eq!(
+            map.overlaps,
+            [
+                Overlap {
+                    operations: (1, 2),
+                    start_block: 3,
+                    end_block: 4,
+                },
+                Overlap {
+                    operations: (0, 2),
+                    start_block: 5,
+                    end_block: 6,
+                },
+            ]
+        );
+        // Blocks 0 to 5, 8 and 9.
+        assert_eq!(map.covered_blocks, 8);
+        assert_eq!(map.total_blocks, Some(12));
+
+        assert_eq!(
+            extent_map(&PartitionUpdate::default(), 4096),
+            ExtentMap::default()
+        );
+    }
+}
@@ -0,0 +1,488 @@
+use std::io::{Cursor, Read, Seek, Write};
+use std::time::Instant;
+
+use crate::chromeos_update_engine::{install_operation::Type, InstallOperation, PartitionUpdate};
+use crate::dump::copy_old;
+use crate::trace::{operation_span, partition_span};
+use crate::{
+    check_block_size, extent_map, validate_dst_extents, DumpContext, DumpOptions, DumpStats,
+    OperationResult, PayloadError,
+};
+
+/// Apply the operations of `partitions` to their images in `dst`, reading
+/// the data blobs forward only from `src`, which is at the beginning of the
+/// data blobs, e.g. right after [`PayloadHeader::parse_prefix`].
+///
+/// Operations of each partition are applied in order, and operations of
+/// different partitions are interleaved so their data is read in payload
+/// order. Data of partitions not in `partitions` is skipped. `old` are the
+/// images of the old partitions, if any, in the same order as `partitions`.
+/// ZERO operations are skipped unless `dense` is set, see
+/// [`DumpOptions::dense`](crate::DumpOptions::dense). The dst extents of
+/// all partitions are checked with [`validate_dst_extents`] first, then the
+/// old images of partitions with MOVE or BSDIFF operations are copied to
+/// their `dst`, as those read the partition being written.
+///
+/// `progress` is called with the index of the partition and the index of
+/// the operation before it's applied, applying stops if it returns false.
+/// Returns the statistics of each partition, in the order of `partitions`,
+/// or `None` if stopped by `progress`. The partitions are dumped together,
+/// so the `elapsed` time of each is the time taken by all of them.
+///
+/// [`PayloadHeader::parse_prefix`]: crate::PayloadHeader::parse_prefix
+pub fn dump_streaming<R, O, W, F>(
+    src: &mut R,
+    block_size: u64,
+    partitions: &[&PartitionUpdate],
+    old: &mut [Option<O>],
+    dst: &mut [W],
+    dense: bool,
+    mut progress: F,
+) -> Result<Option<Vec<DumpStats>>, PayloadError>
+where
+    R: Read,
+    O: Read + Seek,
+    W: Read + Write + Seek,
+    F: FnMut(usize, usize) -> bool,
+{
+    check_block_size(block_size)?;
+    for partition in partitions {
+        validate_dst_extents(partition, block_size)
+            .map_err(|e| e.in_partition(&partition.partition_name))?;
+    }
+    for ((partition, old), dst) in partitions.iter().zip(old.iter_mut()).zip(dst.iter_mut()) {
+        copy_old(partition, old.as_mut(), dst, &DumpOptions::default())
+            .map_err(|e| e.in_partition(&partition.partition_name))?;
+    }
+    let start = Instant::now();
+    let spans: Vec<_> = partitions
+        .iter()
+        .map(|partition| partition_span(partition))
+        .collect();
+    let mut stats = vec![DumpStats::default(); partitions.len()];
+    // Index of the next operation of each partition.
+    let mut next = vec![0; partitions.len()];
+    // Offset of `src` from the beginning of the data blobs.
+    let mut pos = 0;
+    let mut context = DumpContext::new();
+
+    loop {
+        // Operations without data first, then the one with the smallest
+        // data offset.
+        let candidate = partitions
+            .iter()
+            .zip(&next)
+            .enumerate()
+            .filter_map(|(i, (partition, &index))| {
+                let operation = partition.operations.get(index)?;
+                Some((operation.data_length.and(operation.data_offset), i))
+            })
+            .min();
+        let Some((data_offset, i)) = candidate else {
+            return Ok(Some(finish(stats, start)));
+        };
+        let index = next[i];
+        let partition = partitions[i];
+        let operation = &partition.operations[index];
+        next[i] += 1;
+
+        if !progress(i, index) {
+            return Ok(None);
+        }
+        if !dense && operation.r#type == Type::Zero as i32 {
+            stats[i].add(&OperationResult::skipped(operation, block_size));
+            continue;
+        }
+        let _span = spans[i]
+            .in_scope(|| operation_span(index, operation))
+            .entered();
+        let result = apply(
+            &mut context,
+            src,
+            &mut pos,
+            data_offset,
+            operation,
+            old[i].as_mut(),
+            &mut dst[i],
+            block_size,
+        )
+        .map_err(|e| {
+            e.in_operation(index, operation.r#type)
+                .in_partition(&partition.partition_name)
+        })?;
+        stats[i].add(&result);
+    }
+}
+
+/// Apply the operations of `partitions` to their images in `dst` like
+/// [`dump_streaming`], but sorted by the offset of their data, whatever
+/// their order in the partitions, so the data blobs of the payload read
+/// from `src`, which start at `blobs_offset`, are read front to back. This
+/// saves the seeks back of spinning disks, network filesystems and HTTP.
+/// Operations without data are applied first.
+///
+/// The operations of a full payload don't depend on each other, so they
+/// can be applied in any order. Fails before applying anything if an
+/// operation reads the partition being written, like MOVE and BSDIFF of
+/// old payloads, or if operations write the same blocks, see
+/// [`extent_map`]. `progress` is called, and the statistics are returned,
+/// like with [`dump_streaming`].
+#[allow(clippy::too_many_arguments)]
+pub fn dump_in_data_order<R, O, W, F>(
+    src: &mut R,
+    blobs_offset: u64,
+    block_size: u64,
+    partitions: &[&PartitionUpdate],
+    old: &mut [Option<O>],
+    dst: &mut [W],
+    dense: bool,
+    mut progress: F,
+) -> Result<Option<Vec<DumpStats>>, PayloadError>
+where
+    R: Read + Seek,
+    O: Read + Seek,
+    W: Read + Write + Seek,
+    F: FnMut(usize, usize) -> bool,
+{
+    check_block_size(block_size)?;
+    for partition in partitions {
+        check_independent(partition, block_size)
+            .map_err(|e| e.in_partition(&partition.partition_name))?;
+    }
+    let start = Instant::now();
+    let spans: Vec<_> = partitions
+        .iter()
+        .map(|partition| partition_span(partition))
+        .collect();
+    let mut stats = vec![DumpStats::default(); partitions.len()];
+    // The partition and the index of every operation, by data offset.
+    let mut order: Vec<_> = partitions
+        .iter()
+        .enumerate()
+        .flat_map(|(i, partition)| {
+            partition
+                .operations
+                .iter()
+                .enumerate()
+                .map(move |(index, operation)| {
+                    (operation.data_length.and(operation.data_offset), i, index)
+                })
+        })
+        .collect();
+    order.sort_unstable();
+
+    let mut context = DumpContext::new();
+    for (_, i, index) in order {
+        let partition = partitions[i];
+        let operation = &partition.operations[index];
+        if !progress(i, index) {
+            return Ok(None);
+        }
+        if !dense && operation.r#type == Type::Zero as i32 {
+            stats[i].add(&OperationResult::skipped(operation, block_size));
+            continue;
+        }
+        let _span = spans[i]
+            .in_scope(|| operation_span(index, operation))
+            .entered();
+        let result = context
+            .dump_operation(
+                src,
+                blobs_offset,
+                old[i].as_mut(),
+                &mut dst[i],
+                operation,
+                block_size,
+            )
+            .map_err(|e| {
+                e.in_operation(index, operation.r#type)
+                    .in_partition(&partition.partition_name)
+            })?;
+        stats[i].add(&result);
+    }
+    Ok(Some(finish(stats, start)))
+}
+
+/// Set the `elapsed` time of the partitions dumped together since `start`.
+fn finish(mut stats: Vec<DumpStats>, start: Instant) -> Vec<DumpStats> {
+    let elapsed = start.elapsed();
+    for stats in &mut stats {
+        stats.elapsed = elapsed;
+    }
+    stats
+}
+
+/// Check that the operations of `partition` can be applied in any order:
+/// their dst extents are valid, see [`validate_dst_extents`], none reads
+/// the partition being written, and no two write the same blocks.
+fn check_independent(partition: &PartitionUpdate, block_size: u64) -> Result<(), PayloadError> {
+    validate_dst_extents(partition, block_size)?;
+    let reading = partition
+        .operations
+        .iter()
+        .enumerate()
+        .find(|(_, operation)| matches!(operation.r#type(), Type::Move | Type::Bsdiff));
+    if let Some((index, operation)) = reading {
+        return Err(PayloadError::InvalidOperation(
+            "it reads the partition being written, so the operations can't be reordered"
+                .to_string(),
+        )
+        .in_operation(index, operation.r#type));
+    }
+    if let Some(overlap) = extent_map(partition, block_size).overlaps.first() {
+        let (a, b) = overlap.operations;
+        return Err(PayloadError::InvalidOperation(format!(
+            "operations {} and {} both write block {}, so they can't be reordered",
+            a, b, overlap.start_block
+        )));
+    }
+    Ok(())
+}
+
+/// Read the data of `operation` at `data_offset` of the data blobs from
+/// `src`, which is at `pos`, and apply it to `dst`, returning its result.
+#[allow(clippy::too_many_arguments)]
+fn apply<R: Read, O: Read + Seek, W: Read + Write + Seek>(
+    context: &mut DumpContext,
+    src: &mut R,
+    pos: &mut u64,
+    data_offset: Option<u64>,
+    operation: &InstallOperation,
+    old: Option<&mut O>,
+    dst: &mut W,
+    block_size: u64,
+) -> Result<OperationResult, PayloadError> {
+    let mut data = Vec::new();
+    if let Some(offset) = data_offset {
+        if offset < *pos {
+            return Err(PayloadError::InvalidOperation(format!(
+                "data at offset {} is before offset {} of the stream, the payload can not be streamed",
+                offset, pos
+            )));
+        }
+        let skipped = std::io::copy(
+            &mut Read::by_ref(src).take(offset - *pos),
+            &mut std::io::sink(),
+        )?;
+        let length = operation.data_length();
+        Read::by_ref(src).take(length).read_to_end(&mut data)?;
+        *pos += skipped + data.len() as u64;
+        if Some(*pos) != offset.checked_add(length) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "payload is truncated",
+            )
+            .into());
+        }
+    }
+
+    let local = InstallOperation {
+        data_offset: data_offset.map(|_| 0),
+        ..operation.clone()
+    };
+    context.dump_operation(&mut Cursor::new(data), 0, old, dst, &local, block_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{extent, operation};
+
+    #[test]
+    fn streaming() -> Result<(), Box<dyn std::error::Error>> {
+        // Data of boot and system interleaved, with data of an unselected
+        // partition in between.
+        let blobs = b"boo0sys0skipboo1sys1";
+        let boot = PartitionUpdate {
+            partition_name: "boot".to_string(),
+            operations: vec![
+                operation(Type::Replace, Some((0, 4)), vec![extent(0, 1)]),
+                operation(Type::Zero, None, vec![extent(1, 1)]),
+                operation(Type::Replace, Some((12, 4)), vec![extent(2, 1)]),
+            ],
+            ..Default::default()
+        };
+        let mut system = PartitionUpdate {
+            partition_name: "system".to_string(),
+            operations: vec![
+                operation(Type::Replace, Some((4, 4)), vec![extent(0, 1)]),
+                operation(Type::Replace, Some((16, 4)), vec![extent(1, 1)]),
+            ],
+            ..Default::default()
+        };
+
+        let mut dst = vec![Cursor::new(vec![0xffu8; 12]), Cursor::new(vec![0xffu8; 8])];
+        let mut applied = Vec::new();
+        let done = dump_streaming(
+            &mut &blobs[..],
+            4,
+            &[&boot, &system],
+            &mut [None::<Cursor<Vec<u8>>>, None],
+            &mut dst,
+            true,
+            |partition, index| {
+                applied.push((partition, index));
+                true
+            },
+        )?;
+        assert_eq!(
+            done.map(|stats| stats.iter().map(|s| s.bytes_written).collect()),
+            Some(vec![12, 8])
+        );
+        assert_eq!(applied, [(0, 0), (0, 1), (1, 0), (0, 2), (1, 1)]);
+        assert_eq!(dst[0].get_ref(), b"boo0\0\0\0\0boo1");
+        assert_eq!(dst[1].get_ref(), b"sys0sys1");
+
+        // Data before data already read can't be streamed.
+        system.operations.swap(0, 1);
+        let error = dump_streaming(
+            &mut &blobs[..],
+            4,
+            &[&system],
+            &mut [None::<Cursor<Vec<u8>>>],
+            &mut dst[1..],
+            true,
+            |_, _| true,
+        )
+        .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("partition system: operation 1 (type REPLACE): data at offset 4"),
+            "{}",
+            error
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn streaming_move() -> Result<(), Box<dyn std::error::Error>> {
+        // MOVE of a major version 1 payload reads the partition being
+        // written, which starts as a copy of the old one.
+        let mut operation = operation(Type::Move, None, vec![extent(1, 1)]);
+        operation.src_extents = vec![extent(0, 1)];
+        let system = PartitionUpdate {
+            partition_name: "system".to_string(),
+            operations: vec![operation],
+            ..Default::default()
+        };
+
+        let mut dst = vec![Cursor::new(vec![0u8; 8])];
+        let done = dump_streaming(
+            &mut &b""[..],
+            4,
+            &[&system],
+            &mut [Some(Cursor::new(b"old0old1".to_vec()))],
+            &mut dst,
+            false,
+            |_, _| true,
+        )?;
+        assert!(done.is_some());
+        assert_eq!(dst[0].get_ref(), b"old0old0");
+
+        // It can't be applied without the old image.
+        let error = dump_streaming(
+            &mut &b""[..],
+            4,
+            &[&system],
+            &mut [None::<Cursor<Vec<u8>>>],
+            &mut dst,
+            false,
+            |_, _| true,
+        )
+        .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("partition system: operation 0 (type MOVE)"),
+            "{}",
+            error
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn data_order() -> Result<(), Box<dyn std::error::Error>> {
+        // The data of system is in reverse order of its operations.
+        let blobs = b"sys1boo0sys0";
+        let boot = PartitionUpdate {
+            partition_name: "boot".to_string(),
+            operations: vec![
+                operation(Type::Replace, Some((4, 4)), vec![extent(0, 1)]),
+                operation(Type::Zero, None, vec![extent(1, 1)]),
+            ],
+            ..Default::default()
+        };
+        let mut system = PartitionUpdate {
+            partition_name: "system".to_string(),
+            operations: vec![
+                operation(Type::Replace, Some((8, 4)), vec![extent(0, 1)]),
+                operation(Type::Replace, Some((0, 4)), vec![extent(1, 1)]),
+            ],
+            ..Default::default()
+        };
+
+        let mut dst = vec![Cursor::new(vec![0xffu8; 8]), Cursor::new(vec![0xffu8; 8])];
+        let mut applied = Vec::new();
+        let done = dump_in_data_order(
+            &mut Cursor::new(&blobs[..]),
+            0,
+            4,
+            &[&boot, &system],
+            &mut [None::<Cursor<Vec<u8>>>, None],
+            &mut dst,
+            false,
+            |partition, index| {
+                applied.push((partition, index));
+                true
+            },
+        )?;
+        let stats = done.expect("not stopped");
+        assert_eq!(
+            (stats[0].bytes_written, stats[0].operations[&Type::Zero]),
+            (8, 1)
+        );
+        assert_eq!((stats[1].bytes_written, stats[1].data_bytes_read), (8, 8));
+        assert_eq!(applied, [(0, 1), (1, 1), (0, 0), (1, 0)]);
+        assert_eq!(dst[0].get_ref(), b"boo0\xff\xff\xff\xff");
+        assert_eq!(dst[1].get_ref(), b"sys0sys1");
+
+        // Operations writing the same blocks depend on their order.
+        system.operations[1].dst_extents[0].start_block = Some(0);
+        let error = dump_in_data_order(
+            &mut Cursor::new(&blobs[..]),
+            0,
+            4,
+            &[&system],
+            &mut [None::<Cursor<Vec<u8>>>],
+            &mut dst[1..],
+            true,
+            |_, _| panic!("nothing is applied"),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "partition system: operations 0 and 1 both write block 0, so they can't be reordered"
+        );
+        system.operations[1] = operation(Type::Move, None, vec![extent(1, 1)]);
+        let error = dump_in_data_order(
+            &mut Cursor::new(&blobs[..]),
+            0,
+            4,
+            &[&system],
+            &mut [None::<Cursor<Vec<u8>>>],
+            &mut dst[1..],
+            true,
+            |_, _| true,
+        )
+        .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("partition system: operation 1 (type MOVE)"),
+            "{}",
+            error
+        );
+        Ok(())
+    }
+}
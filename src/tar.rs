@@ -0,0 +1,261 @@
+use std::io::Write;
+
+/// Size of the blocks of a tar archive.
+const BLOCK: usize = 512;
+
+/// A writer of a tar archive of regular files, in the POSIX format.
+/// Names longer than the 100 bytes of the header and files of 8 GiB or
+/// more, like `super`, have a pax extended header.
+pub struct TarWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> TarWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+
+    /// Start a file `name` of `size` bytes, modified at `mtime` in seconds
+    /// since the epoch. Exactly `size` bytes must be written to the entry
+    /// before [`TarEntry::finish`].
+    pub fn append(
+        &mut self,
+        name: &str,
+        size: u64,
+        mtime: u64,
+    ) -> std::io::Result<TarEntry<'_, W>> {
+        // The largest size of the 11 octal digits of the header.
+        const MAX_SIZE: u64 = (1 << 33) - 1;
+        if name.len() > 100 || size > MAX_SIZE {
+            let mut records = Vec::new();
+            if name.len() > 100 {
+                records.extend(pax_record("path", name));
+            }
+            if size > MAX_SIZE {
+                records.extend(pax_record("size", &size.to_string()));
+            }
+            let pax_name = format!("PaxHeaders/{}", truncate(name, 100 - "PaxHeaders/".len()));
+            self.inner
+                .write_all(&header(&pax_name, records.len() as u64, mtime, b'x'))?;
+            self.inner.write_all(&records)?;
+            self.inner
+                .write_all(&[0; BLOCK][..padding(records.len() as u64)])?;
+        }
+        self.inner.write_all(&header(
+            truncate(name, 100),
+            size.min(MAX_SIZE),
+            mtime,
+            b'0',
+        ))?;
+        Ok(TarEntry {
+            tar: self,
+            size,
+            remaining: size,
+        })
+    }
+
+    /// Write the end of the archive, returning the inner writer.
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.inner.write_all(&[0; 2 * BLOCK])?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// A file in a [`TarWriter`], written with [`Write`].
+pub struct TarEntry<'a, W: Write> {
+    tar: &'a mut TarWriter<W>,
+    size: u64,
+    /// Bytes left to write.
+    remaining: u64,
+}
+
+impl<W: Write> TarEntry<'_, W> {
+    /// Pad the file to the next block, failing if it's not complete.
+    pub fn finish(self) -> std::io::Result<()> {
+        if self.remaining > 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!(
+                    "{} bytes of {} written to the tar entry",
+                    self.size - self.remaining,
+                    self.size
+                ),
+            ));
+        }
+        self.tar.inner.write_all(&[0; BLOCK][..padding(self.size)])
+    }
+}
+
+impl<W: Write> Write for TarEntry<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.len() as u64 > self.remaining {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("more than the {} bytes of the tar entry written", self.size),
+            ));
+        }
+        let n = self.tar.inner.write(buf)?;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.tar.inner.flush()
+    }
+}
+
+/// Bytes of zeros after `size` bytes to the next block.
+fn padding(size: u64) -> usize {
+    (BLOCK - (size % BLOCK as u64) as usize) % BLOCK
+}
+
+/// The longest prefix of `name` of at most `len` bytes, on a character
+/// boundary.
+fn truncate(name: &str, len: usize) -> &str {
+    let mut end = name.len().min(len);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    &name[..end]
+}
+
+/// A record of a pax extended header, `<length> <key>=<value>\n` where the
+/// length includes itself.
+fn pax_record(key: &str, value: &str) -> Vec<u8> {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+    format!("{} {}={}\n", len, key, value).into_bytes()
+}
+
+/// The ustar header of a file `name` of `size` bytes and type `kind`.
+fn header(name: &str, size: u64, mtime: u64, kind: u8) -> [u8; BLOCK] {
+    let mut header = [0; BLOCK];
+    let mut field =
+        |offset: usize, value: &[u8]| header[offset..offset + value.len()].copy_from_slice(value);
+    field(0, name.as_bytes());
+    field(100, b"0000644\0");
+    field(108, b"0000000\0");
+    field(116, b"0000000\0");
+    field(124, format!("{:011o}\0", size).as_bytes());
+    field(
+        136,
+        format!("{:011o}\0", mtime.min((1 << 33) - 1)).as_bytes(),
+    );
+    field(148, b"        ");
+    field(156, &[kind]);
+    field(257, b"ustar\x0000");
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Read the files of a tar archive, with the names and sizes of pax
+    /// extended headers.
+    fn read_tar(mut tar: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let octal = |field: &[u8]| {
+            let field = std::str::from_utf8(field).unwrap().trim_end_matches('\0');
+            u64::from_str_radix(field, 8).unwrap()
+        };
+        let mut files = Vec::new();
+        let mut pax = Vec::new();
+        loop {
+            let (header, rest) = tar.split_at(BLOCK);
+            if header.iter().all(|&b| b == 0) {
+                assert!(rest.len() == BLOCK && rest.iter().all(|&b| b == 0));
+                return files;
+            }
+            let checksum: u32 = header[..148]
+                .iter()
+                .chain(b"        ")
+                .chain(&header[156..])
+                .map(|&b| b as u32)
+                .sum();
+            assert_eq!(octal(&header[148..154]), checksum as u64);
+            assert_eq!(&header[257..265], b"ustar\x0000");
+            let mut name = std::str::from_utf8(&header[..100])
+                .unwrap()
+                .trim_end_matches('\0')
+                .to_string();
+            let mut size = octal(&header[124..135]);
+            for record in std::mem::take(&mut pax) {
+                match record {
+                    (key, value) if key == "path" => name = value,
+                    (key, value) if key == "size" => size = value.parse().unwrap(),
+                    _ => unreachable!(),
+                }
+            }
+            let data = &rest[..size as usize];
+            if header[156] == b'x' {
+                for line in std::str::from_utf8(data).unwrap().lines() {
+                    let (len, record) = line.split_once(' ').unwrap();
+                    assert_eq!(len.parse::<usize>().unwrap(), line.len() + 1);
+                    let (key, value) = record.split_once('=').unwrap();
+                    pax.push((key.to_string(), value.to_string()));
+                }
+            } else {
+                assert_eq!(header[156], b'0');
+                files.push((name, data.to_vec()));
+            }
+            tar = &rest[size as usize + padding(size)..];
+        }
+    }
+
+    #[test]
+    fn entries() {
+        let long = "x".repeat(120) + ".img";
+        let mut tar = TarWriter::new(Vec::new());
+        let files = [
+            ("boot.img", vec![1; 1000]),
+            ("empty.img", vec![]),
+            (long.as_str(), vec![2; 512]),
+        ];
+        for (name, data) in &files {
+            let mut entry = tar.append(name, data.len() as u64, 1_700_000_000).unwrap();
+            entry.write_all(data).unwrap();
+            entry.finish().unwrap();
+        }
+        let tar = tar.finish().unwrap();
+        assert_eq!(tar.len() % BLOCK, 0);
+        let read: Vec<_> = read_tar(&tar);
+        assert_eq!(
+            read,
+            files
+                .iter()
+                .map(|(name, data)| (name.to_string(), data.clone()))
+                .collect::<Vec<_>>()
+        );
+
+        // Entries must have exactly their size.
+        let mut tar = TarWriter::new(Vec::new());
+        let mut entry = tar.append("boot.img", 4, 0).unwrap();
+        assert!(entry.write_all(&[0; 5]).is_err());
+        entry.write_all(&[0; 3]).unwrap();
+        assert!(entry.finish().is_err());
+
+        // Sizes too large for the header are in a pax header.
+        let mut tar = TarWriter::new(Vec::new());
+        tar.append("super.img", 1 << 34, 0).unwrap();
+        let tar = tar.finish().unwrap();
+        assert_eq!(&tar[BLOCK..BLOCK + 21], b"20 size=17179869184\n\0");
+    }
+
+    #[test]
+    fn pax_records() {
+        for value in ["a", &"b".repeat(94), &"c".repeat(95), &"d".repeat(1000)] {
+            let record = pax_record("path", value);
+            let (len, _) = std::str::from_utf8(&record)
+                .unwrap()
+                .split_once(' ')
+                .unwrap();
+            assert_eq!(len.parse::<usize>().unwrap(), record.len());
+        }
+    }
+}
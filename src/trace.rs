@@ -0,0 +1,70 @@
+use tracing::Span;
+
+use crate::chromeos_update_engine::{Extent, InstallOperation, PartitionUpdate};
+use crate::error::type_name;
+
+/// Span of dumping `partition`, at the info level.
+pub(crate) fn partition_span(partition: &PartitionUpdate) -> Span {
+    tracing::info_span!(
+        "partition",
+        name = %partition.partition_name,
+        operations = partition.operations.len(),
+    )
+}
+
+/// Span of applying the operation at `index`, at the debug level, with its
+/// type, its data in the payload and a summary of its dst extents. It's
+/// entered while the data is read, applied and written, so subscribers can
+/// time it.
+pub(crate) fn operation_span(index: usize, operation: &InstallOperation) -> Span {
+    tracing::debug_span!(
+        "operation",
+        index,
+        op_type = %type_name(operation.r#type),
+        data_offset = operation.data_offset,
+        data_length = operation.data_length,
+        dst = %extents_summary(&operation.dst_extents),
+    )
+}
+
+/// Number of `extents`, their blocks and the range of blocks they're in,
+/// like `2 extents, 20 blocks in 0..112`.
+fn extents_summary(extents: &[Extent]) -> String {
+    let blocks: u64 = extents.iter().map(|e| e.num_blocks()).sum();
+    let start = extents.iter().map(|e| e.start_block()).min().unwrap_or(0);
+    let end = extents
+        .iter()
+        .map(|e| e.start_block().saturating_add(e.num_blocks()))
+        .max()
+        .unwrap_or(0);
+    let plural = |n: u64| if n == 1 { "" } else { "s" };
+    let count = extents.len() as u64;
+    format!(
+        "{} extent{}, {} block{} in {}..{}",
+        count,
+        plural(count),
+        blocks,
+        plural(blocks),
+        start,
+        end
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::extent;
+
+    #[test]
+    fn summary() {
+        assert_eq!(
+            extents_summary(&[extent(3, 1)]),
+            "1 extent, 1 block in 3..4"
+        );
+        assert_eq!(
+            extents_summary(&[extent(100, 12), extent(0, 8)]),
+            "2 extents, 20 blocks in 0..112"
+        );
+        assert_eq!(extents_summary(&[]), "0 extents, 0 blocks in 0..0");
+    }
+}
@@ -0,0 +1,380 @@
+use std::collections::BTreeMap;
+
+use crate::chromeos_update_engine::{
+    install_operation::Type, Extent, InstallOperation, PartitionUpdate,
+};
+use crate::extent::SPARSE_HOLE;
+use crate::operation::ExtentExt;
+use crate::{check_block_size, PayloadError};
+
+/// Check the dst extents of the operations of `partition`, with blocks of
+/// `block_size` bytes, before anything is written.
+///
+/// Every dst extent must end within `new_partition_info.size`, if known,
+/// and the dst extents of an operation must not overlap, otherwise a
+/// malformed manifest could write far past the end of the image. Holes,
+/// see [`SPARSE_HOLE`], are not checked. Errors tell the index of the
+/// operation and of the extent.
+///
+/// Returns the ranges of blocks of the partition not written by any
+/// operation, which are legal, they are left as they are in the image.
+pub fn validate_dst_extents(
+    partition: &PartitionUpdate,
+    block_size: u64,
+) -> Result<Vec<Extent>, PayloadError> {
+    check_block_size(block_size)?;
+    let size = partition
+        .new_partition_info
+        .as_ref()
+        .and_then(|info| info.size);
+    let total_blocks = size.map(|size| size.div_ceil(block_size));
+
+    // Written ranges of blocks, as `(start, end)`.
+    let mut written = Vec::new();
+    for (index, operation) in partition.operations.iter().enumerate() {
+        let error = |message: String| {
+            PayloadError::InvalidOperation(message).in_operation(index, operation.r#type)
+        };
+
+        let mut ranges = Vec::with_capacity(operation.dst_extents.len());
+        for (i, extent) in operation.dst_extents.iter().enumerate() {
+            let (start, num_blocks) = (extent.start_block(), extent.num_blocks());
+            // Writes to holes are discarded.
+            if start == SPARSE_HOLE {
+                continue;
+            }
+            let end = start.checked_add(num_blocks).ok_or_else(|| {
+                error(format!(
+                    "dst extent {} (start_block {}, num_blocks {}) overflows",
+                    i, start, num_blocks
+                ))
+            })?;
+            if let Some(total_blocks) = total_blocks.filter(|&total_blocks| end > total_blocks) {
+                return Err(error(format!(
+                    "dst extent {} (start_block {}, num_blocks {}) ends past the partition of {} blocks",
+                    i, start, num_blocks, total_blocks
+                )));
+            }
+            if num_blocks > 0 {
+                ranges.push((start, end, i));
+            }
+        }
+
+        ranges.sort_unstable();
+        for pair in ranges.windows(2) {
+            let ((_, end, a), (start, _, b)) = (pair[0], pair[1]);
+            if start < end {
+                let (a, b) = (a.min(b), a.max(b));
+                return Err(error(format!(
+                    "dst extents {} and {} overlap at block {}",
+                    a, b, start
+                )));
+            }
+        }
+        written.extend(ranges.into_iter().map(|(start, end, _)| (start, end)));
+    }
+
+    let Some(total_blocks) = total_blocks else {
+        return Ok(Vec::new());
+    };
+    written.sort_unstable();
+    let mut gaps = Vec::new();
+    let mut covered = 0;
+    for (start, end) in written.into_iter().chain([(total_blocks, total_blocks)]) {
+        if start > covered {
+            gaps.push(Extent {
+                start_block: Some(covered),
+                num_blocks: Some(start - covered),
+            });
+        }
+        covered = covered.max(end);
+    }
+    Ok(gaps)
+}
+
+/// Check that the operations of `partition` can be applied in place, see
+/// [`dump_operation_in_place`](crate::dump_operation_in_place): no
+/// operation reads blocks of the old partition which earlier operations
+/// have already overwritten.
+///
+/// MOVE and BSDIFF of old payloads are meant to read the partition being
+/// written, they are not checked. Holes are never written.
+pub fn validate_in_place(partition: &PartitionUpdate, block_size: u64) -> Result<(), PayloadError> {
+    check_block_size(block_size)?;
+    // Blocks written so far, as disjoint ranges `start => end`.
+    let mut written: BTreeMap<u64, u64> = BTreeMap::new();
+    for (index, operation) in partition.operations.iter().enumerate() {
+        if !matches!(operation.r#type(), Type::Move | Type::Bsdiff) {
+            for (i, extent) in operation.src_extents.iter().enumerate() {
+                let (start, end) = match extent_range(extent) {
+                    Some(range) => range,
+                    None => continue,
+                };
+                if written
+                    .range(..end)
+                    .next_back()
+                    .is_some_and(|(_, &written_end)| written_end > start)
+                {
+                    return Err(PayloadError::InvalidOperation(format!(
+                        "src extent {} (start_block {}, num_blocks {}) was overwritten by an earlier operation, \
+                         the payload can't be applied in place",
+                        i,
+                        extent.start_block(),
+                        extent.num_blocks()
+                    ))
+                    .in_operation(index, operation.r#type));
+                }
+            }
+        }
+
+        for (mut start, mut end) in operation.dst_extents.iter().filter_map(extent_range) {
+            // Merge with the overlapping and adjacent ranges.
+            while let Some((&other_start, &other_end)) = written.range(..=end).next_back() {
+                if other_end < start {
+                    break;
+                }
+                written.remove(&other_start);
+                start = start.min(other_start);
+                end = end.max(other_end);
+            }
+            written.insert(start, end);
+        }
+    }
+    Ok(())
+}
+
+/// Check that the data of the operations of `partition` is within a
+/// payload of `len` bytes, whose data blobs start at `blobs_offset`, so a
+/// truncated payload is reported per partition.
+pub fn validate_data_ranges(
+    partition: &PartitionUpdate,
+    blobs_offset: u64,
+    len: u64,
+) -> Result<(), PayloadError> {
+    for (index, operation) in partition.operations.iter().enumerate() {
+        if let (Some(offset), Some(length)) = (operation.data_offset, operation.data_length) {
+            let needed = blobs_offset
+                .checked_add(offset)
+                .and_then(|start| start.checked_add(length))
+                .unwrap_or(u64::MAX);
+            if needed > len {
+                return Err(
+                    PayloadError::Truncated { needed, len }.in_operation(index, operation.r#type)
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The blocks of `extent` as `start..end`, `None` if it's a hole or empty.
+fn extent_range(extent: &Extent) -> Option<(u64, u64)> {
+    let start = extent.start_block();
+    match start {
+        SPARSE_HOLE => None,
+        _ => Some((start, start.checked_add(extent.num_blocks())?))
+            .filter(|(start, end)| start < end),
+    }
+}
+
+/// The operations of `partition` sorted by their first dst extent, if they
+/// then write the blocks of the image in order, so the image can be written
+/// to a writer which can't seek back, like a
+/// [`SequentialWriter`](crate::SequentialWriter).
+///
+/// Returns `None` if the dst extents of the sorted operations go back or
+/// overlap, or if an operation reads the partition being written, like MOVE
+/// and BSDIFF of old payloads. Holes are never written.
+pub fn sequential_order(partition: &PartitionUpdate) -> Option<Vec<InstallOperation>> {
+    let reads_dst =
+        |operation: &InstallOperation| matches!(operation.r#type(), Type::Move | Type::Bsdiff);
+    if partition.operations.iter().any(reads_dst) {
+        return None;
+    }
+    let written = |operation: &InstallOperation| {
+        operation
+            .dst_extents
+            .iter()
+            .filter(|extent| !extent.is_sparse_hole() && extent.num_blocks() > 0)
+            .map(|extent| {
+                (
+                    extent.start_block(),
+                    extent.start_block().saturating_add(extent.num_blocks()),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let mut operations = partition.operations.clone();
+    operations.sort_by_key(|operation| written(operation).first().map_or(0, |&(start, _)| start));
+    let mut end = 0;
+    for operation in &operations {
+        for (start, extent_end) in written(operation) {
+            if start < end {
+                return None;
+            }
+            end = extent_end;
+        }
+    }
+    Some(operations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{extent, operation, partition};
+
+    /// A partition of `size` bytes with a ZERO operation writing each list
+    /// of dst extents.
+    fn zeros(size: u64, dst_extents: &[&[Extent]]) -> PartitionUpdate {
+        let operations = dst_extents
+            .iter()
+            .map(|extents| operation(Type::Zero, None, extents.to_vec()))
+            .collect();
+        partition("system", size, operations)
+    }
+
+    #[test]
+    fn dst_extents() {
+        // The last block is partial, and blocks 2 and 5 are not written.
+        let gaps = validate_dst_extents(
+            &zeros(22, &[&[extent(3, 2), extent(0, 2)], &[extent(4, 1)]]),
+            4,
+        );
+        assert_eq!(gaps.unwrap(), [extent(2, 1), extent(5, 1)]);
+        assert_eq!(
+            validate_dst_extents(&zeros(24, &[&[extent(0, 6)]]), 4).unwrap(),
+            []
+        );
+
+        let error = validate_dst_extents(
+            &zeros(22, &[&[extent(0, 1)], &[extent(0, 1), extent(4, 3)]]),
+            4,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "operation 1 (type ZERO): dst extent 1 (start_block 4, num_blocks 3) ends past the partition of 6 blocks"
+        );
+
+        let error = validate_dst_extents(
+            &zeros(32, &[&[extent(4, 2), extent(0, 2), extent(1, 2)]]),
+            4,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "operation 0 (type ZERO): dst extents 1 and 2 overlap at block 1"
+        );
+
+        assert_eq!(
+            validate_dst_extents(&zeros(8, &[&[extent(SPARSE_HOLE, 2), extent(0, 2)]]), 4).unwrap(),
+            []
+        );
+        let error = validate_dst_extents(&zeros(32, &[&[extent(u64::MAX - 1, 2)]]), 4).unwrap_err();
+        assert!(error.to_string().ends_with("overflows"), "{}", error);
+
+        // Without the size, only overlaps are checked.
+        let mut unsized_partition = zeros(0, &[&[extent(100, 1)]]);
+        unsized_partition.new_partition_info = None;
+        assert_eq!(validate_dst_extents(&unsized_partition, 4).unwrap(), []);
+    }
+
+    #[test]
+    fn in_place() {
+        let source_copy = |src: Extent, dst: Extent| {
+            let mut operation = InstallOperation {
+                src_extents: vec![src],
+                dst_extents: vec![dst],
+                ..Default::default()
+            };
+            operation.set_type(Type::SourceCopy);
+            operation
+        };
+        let mut partition = zeros(32, &[]);
+        // Reading blocks overwritten by the same operation is fine.
+        partition.operations = vec![
+            source_copy(extent(0, 3), extent(1, 3)),
+            source_copy(extent(6, 2), extent(4, 2)),
+        ];
+        validate_in_place(&partition, 4).unwrap();
+
+        partition
+            .operations
+            .push(source_copy(extent(SPARSE_HOLE, 1), extent(7, 1)));
+        partition
+            .operations
+            .push(source_copy(extent(5, 2), extent(6, 1)));
+        let error = validate_in_place(&partition, 4).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "operation 3 (type SOURCE_COPY): src extent 0 (start_block 5, num_blocks 2) was overwritten by an earlier \
+             operation, the payload can't be applied in place"
+        );
+    }
+
+    #[test]
+    fn data_ranges() {
+        let mut partition = zeros(32, &[&[extent(0, 1)], &[extent(1, 1)]]);
+        partition.operations[0].data_offset = Some(0);
+        partition.operations[0].data_length = Some(10);
+        partition.operations[1].data_offset = Some(10);
+        partition.operations[1].data_length = Some(20);
+        validate_data_ranges(&partition, 100, 130).unwrap();
+
+        let error = validate_data_ranges(&partition, 100, 129).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "operation 1 (type ZERO): payload appears truncated: need at least 130 bytes, file is 129 bytes"
+        );
+        partition.operations[1].data_offset = Some(u64::MAX);
+        assert!(validate_data_ranges(&partition, 100, 130).is_err());
+    }
+
+    #[test]
+    fn sequential() {
+        let starts = |operations: Option<Vec<InstallOperation>>| {
+            operations.map(|operations| {
+                operations
+                    .iter()
+                    .map(|op| op.dst_extents[0].start_block())
+                    .collect::<Vec<_>>()
+            })
+        };
+        let sorted = sequential_order(&zeros(
+            40,
+            &[
+                &[extent(4, 2)],
+                &[extent(0, 2), extent(2, 1)],
+                &[extent(8, 1)],
+            ],
+        ));
+        assert_eq!(starts(sorted), Some(vec![0, 4, 8]));
+        // Holes are skipped.
+        let sorted = sequential_order(&zeros(
+            40,
+            &[&[extent(SPARSE_HOLE, 2), extent(4, 1)], &[extent(1, 2)]],
+        ));
+        assert_eq!(starts(sorted), Some(vec![1, SPARSE_HOLE]));
+
+        // An operation writes before the end of the one sorted before it.
+        assert_eq!(
+            starts(sequential_order(&zeros(
+                40,
+                &[&[extent(0, 1), extent(6, 1)], &[extent(4, 1)]]
+            ))),
+            None
+        );
+        assert_eq!(
+            starts(sequential_order(&zeros(
+                40,
+                &[&[extent(0, 4)], &[extent(2, 4)]]
+            ))),
+            None
+        );
+
+        let mut moved = zeros(40, &[&[extent(0, 1)]]);
+        moved.operations[0].set_type(Type::Move);
+        assert_eq!(starts(sequential_order(&moved)), None);
+    }
+}
@@ -0,0 +1,81 @@
+use std::{fs::File, io::Read, path::Path};
+
+use sha2::{Digest, Sha256};
+
+use crate::{chromeos_update_engine::PartitionInfo, PayloadError};
+
+/// Check the partition image at `path` against the size and hash in `info`.
+pub fn verify_partition<P: AsRef<Path>>(path: P, info: &PartitionInfo) -> Result<(), PayloadError> {
+    verify_image(File::open(path)?, info)
+}
+
+/// Check a partition image read from `image` against the size and hash in
+/// `info`. Fields absent in `info` are not checked.
+pub fn verify_image<R: Read>(image: R, info: &PartitionInfo) -> Result<(), PayloadError> {
+    let (size, hash) = hash_image(image)?;
+    verify_hash(size, &hash, info)
+}
+
+/// Read a partition image from `image`, returning its size and SHA-256.
+pub fn hash_image<R: Read>(mut image: R) -> std::io::Result<(u64, [u8; 32])> {
+    let mut hasher = Sha256::new();
+    let size = std::io::copy(&mut image, &mut hasher)?;
+    Ok((size, hasher.finalize().into()))
+}
+
+/// Check the `size` and SHA-256 `hash` of a partition image against those
+/// in `info`. Fields absent in `info` are not checked.
+pub fn verify_hash(size: u64, hash: &[u8], info: &PartitionInfo) -> Result<(), PayloadError> {
+    if let Some(expected) = info.size {
+        if size != expected {
+            return Err(PayloadError::SizeMismatch {
+                expected,
+                actual: size,
+            });
+        }
+    }
+
+    match info.hash.as_deref() {
+        Some(expected) if !expected.is_empty() && hash != expected => {
+            Err(PayloadError::HashMismatch {
+                expected: expected.to_vec(),
+                actual: hash.to_vec(),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify() {
+        let image = vec![7u8; 4096];
+        let info = PartitionInfo {
+            size: Some(4096),
+            hash: Some(Sha256::digest(&image).to_vec()),
+        };
+
+        assert!(verify_image(&image[..], &info).is_ok());
+        let error = verify_image(&image[..4095], &info).unwrap_err();
+        assert!(matches!(
+            error,
+            PayloadError::SizeMismatch {
+                expected: 4096,
+                actual: 4095
+            }
+        ));
+        let mut corrupted = image.clone();
+        corrupted[100] = 0;
+        let error = verify_image(&corrupted[..], &info).unwrap_err();
+        assert!(matches!(error, PayloadError::HashMismatch { .. }));
+        assert!(verify_image(&corrupted[..], &PartitionInfo::default()).is_ok());
+
+        let (size, hash) = hash_image(&image[..]).unwrap();
+        assert_eq!((size, hash.to_vec()), (4096, info.hash.clone().unwrap()));
+        assert!(verify_hash(size, &hash, &info).is_ok());
+        assert!(verify_hash(size, &[0; 32], &info).is_err());
+    }
+}
@@ -0,0 +1,597 @@
+use std::io::{BufRead, Read, Write};
+
+use crc::{Crc, CRC_32_ISO_HDLC, CRC_64_XZ};
+use lzma_rs::decompress::raw::Lzma2Decoder;
+use sha2::{Digest, Sha256};
+
+use crate::hex;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+static CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+static CRC64: Crc<u64> = Crc::<u64>::new(&CRC_64_XZ);
+
+const HEADER_MAGIC: [u8; 6] = [0xfd, b'7', b'z', b'X', b'Z', 0];
+const FOOTER_MAGIC: [u8; 2] = *b"YZ";
+const FILTER_LZMA2: u64 = 0x21;
+
+/// Decompress the xz streams in `data` to `out`.
+///
+/// lzma-rs decodes a single stream only, and can't validate SHA-256 checks,
+/// so the container is parsed here and only the LZMA2 data of the blocks is
+/// decoded by it. The check of every block is validated, whichever it is,
+/// and streams may be concatenated, with stream padding in between, as in
+/// the [xz file format](https://tukaani.org/xz/xz-file-format.txt).
+///
+/// The blocks are decoded by `lzma2`, reset for each, so its state is
+/// allocated once for all the streams it decodes.
+pub(crate) fn decompress<R: BufRead, W: Write>(
+    data: R,
+    mut out: W,
+    lzma2: &mut Lzma2Decoder,
+) -> Result<()> {
+    let mut data = CountingReader {
+        inner: data,
+        count: 0,
+    };
+    let mut streams = 0;
+    loop {
+        if streams > 0 {
+            // Stream padding, null bytes in multiples of 4.
+            let start = data.count;
+            loop {
+                let buf = data.fill_buf()?;
+                let zeros = buf.iter().take_while(|&&b| b == 0).count();
+                let end = zeros < buf.len();
+                data.consume(zeros);
+                if end || zeros == 0 {
+                    break;
+                }
+            }
+            if (data.count - start) % 4 != 0 {
+                return Err(format!(
+                    "stream padding of {} bytes is not a multiple of 4",
+                    data.count - start
+                )
+                .into());
+            }
+            if data.fill_buf()?.is_empty() {
+                return Ok(());
+            }
+        }
+        decode_stream(&mut data, &mut out, lzma2).map_err(|e| match streams {
+            0 => e,
+            n => format!("stream {}: {}", n, e).into(),
+        })?;
+        streams += 1;
+    }
+}
+
+/// Size of the pieces of data compressed to an LZMA2 chunk by [`compress`],
+/// the most an uncompressed chunk can hold.
+const CHUNK_SIZE: usize = 1 << 16;
+
+/// Compress `data` to a stream of a single block with a CRC64 check.
+///
+/// lzma-rs only writes uncompressed LZMA2 chunks, so the data is cut in
+/// pieces compressed by its LZMA encoder, each to a chunk resetting the
+/// dictionary, as the encoder starts from scratch. Pieces which don't get
+/// smaller are stored in uncompressed chunks.
+pub(crate) fn compress(data: &[u8]) -> Result<Vec<u8>> {
+    let options = lzma_rs::compress::Options {
+        unpacked_size: lzma_rs::compress::UnpackedSize::SkipWritingToHeader,
+    };
+    let mut lzma2 = Vec::new();
+    for piece in data.chunks(CHUNK_SIZE) {
+        let mut lzma = Vec::new();
+        lzma_rs::lzma_compress_with_options(&mut &piece[..], &mut lzma, &options)?;
+        // The properties, then the dictionary size which isn't needed.
+        let (properties, compressed) = (lzma[0], &lzma[5..]);
+        let unpacked_size = ((piece.len() - 1) as u16).to_be_bytes();
+        if compressed.len() < piece.len() {
+            lzma2.push(0xe0);
+            lzma2.extend_from_slice(&unpacked_size);
+            lzma2.extend_from_slice(&((compressed.len() - 1) as u16).to_be_bytes());
+            lzma2.push(properties);
+            lzma2.extend_from_slice(compressed);
+        } else {
+            lzma2.push(0x01);
+            lzma2.extend_from_slice(&unpacked_size);
+            lzma2.extend_from_slice(piece);
+        }
+    }
+    lzma2.push(0x00);
+    // A dictionary of 64 KiB, the size of the chunks.
+    Ok(encode_stream(&lzma2, 0x08, data, Check::Crc64))
+}
+
+/// Wrap the LZMA2 data `compressed` of `data` in a stream of a single block
+/// with `check`, whose LZMA2 dictionary size is `dict_size`, encoded as in
+/// the filter properties.
+fn encode_stream(compressed: &[u8], dict_size: u8, data: &[u8], check: Check) -> Vec<u8> {
+    let flags = [0, check.id()];
+    let mut stream = HEADER_MAGIC.to_vec();
+    stream.extend_from_slice(&flags);
+    stream.extend_from_slice(&CRC32.checksum(&flags).to_le_bytes());
+
+    // Block header of 8 bytes: size, flags, LZMA2 filter, padding.
+    let header = [
+        0x02,
+        0x00,
+        FILTER_LZMA2 as u8,
+        0x01,
+        dict_size,
+        0x00,
+        0x00,
+        0x00,
+    ];
+    stream.extend_from_slice(&header);
+    stream.extend_from_slice(&CRC32.checksum(&header).to_le_bytes());
+    stream.extend_from_slice(compressed);
+    stream.resize(stream.len() + (4 - compressed.len() % 4) % 4, 0);
+    let mut checked = CheckedWriter::new(std::io::sink(), check);
+    checked.write_all(data).expect("writing to a sink");
+    stream.extend_from_slice(&checked.finish());
+
+    let unpadded = (12 + compressed.len() + check.size()) as u64;
+    let mut index = vec![0x00, 0x01];
+    for mut value in [unpadded, data.len() as u64] {
+        while value >= 0x80 {
+            index.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        index.push(value as u8);
+    }
+    index.resize(index.len().div_ceil(4) * 4, 0);
+    index.extend_from_slice(&CRC32.checksum(&index).to_le_bytes());
+    stream.extend_from_slice(&index);
+
+    let mut footer = ((index.len() / 4 - 1) as u32).to_le_bytes().to_vec();
+    footer.extend_from_slice(&flags);
+    stream.extend_from_slice(&CRC32.checksum(&footer).to_le_bytes());
+    stream.extend_from_slice(&footer);
+    stream.extend_from_slice(&FOOTER_MAGIC);
+    stream
+}
+
+/// Decode a stream, from its header to its footer.
+fn decode_stream<R: BufRead, W: Write>(
+    data: &mut CountingReader<R>,
+    out: &mut W,
+    lzma2: &mut Lzma2Decoder,
+) -> Result<()> {
+    let mut header = [0; 12];
+    read_exact(data, &mut header, "stream header")?;
+    if header[..6] != HEADER_MAGIC {
+        return Err(format!("invalid magic {:02x?}", &header[..6]).into());
+    }
+    check_crc32(&header[6..8], &header[8..], "stream header")?;
+    let flags = [header[6], header[7]];
+    let check = Check::from_flags(flags)?;
+
+    // Unpadded and uncompressed size of each block.
+    let mut records = Vec::new();
+    loop {
+        let mut size = [0];
+        read_exact(data, &mut size, "block header")?;
+        if size[0] == 0 {
+            break;
+        }
+        let record = decode_block(data, out, lzma2, size[0], check)
+            .map_err(|e| format!("block {}: {}", records.len(), e))?;
+        records.push(record);
+    }
+    let index_size = decode_index(data, &records)?;
+
+    let mut footer = [0; 12];
+    read_exact(data, &mut footer, "stream footer")?;
+    check_crc32(&footer[4..10], &footer[..4], "stream footer")?;
+    let backward_size = (u32::from_le_bytes(footer[4..8].try_into().unwrap()) as u64 + 1) * 4;
+    if backward_size != index_size {
+        return Err(format!(
+            "index is {} bytes, but the footer says {}",
+            index_size, backward_size
+        )
+        .into());
+    }
+    if footer[8..10] != flags {
+        return Err("stream flags in the footer don't match the header".into());
+    }
+    if footer[10..] != FOOTER_MAGIC {
+        return Err(format!("invalid footer magic {:02x?}", &footer[10..]).into());
+    }
+    Ok(())
+}
+
+/// Decode the block whose header starts with `size`, writing its data to
+/// `out`. Returns its unpadded and uncompressed size.
+fn decode_block<R: BufRead, W: Write>(
+    data: &mut CountingReader<R>,
+    out: &mut W,
+    lzma2: &mut Lzma2Decoder,
+    size: u8,
+    check: Check,
+) -> Result<(u64, u64)> {
+    let header_size = (size as usize + 1) * 4;
+    let mut header = [size; 1024];
+    let header = &mut header[..header_size];
+    read_exact(data, &mut header[1..], "block header")?;
+    let (fields, crc) = header.split_at(header_size - 4);
+    check_crc32(fields, crc, "block header")?;
+
+    let flags = fields[1];
+    if flags & 0x3c != 0 {
+        return Err(format!("unsupported block flags {:#04x}", flags).into());
+    }
+    let mut fields = &fields[2..];
+    let mut next = || -> Result<u8> {
+        let (&byte, rest) = fields.split_first().ok_or("block header is truncated")?;
+        fields = rest;
+        Ok(byte)
+    };
+    let compressed_size = if flags & 0x40 != 0 {
+        Some(read_varint(&mut next)?)
+    } else {
+        None
+    };
+    let uncompressed_size = if flags & 0x80 != 0 {
+        Some(read_varint(&mut next)?)
+    } else {
+        None
+    };
+    let filter = read_varint(&mut next)?;
+    if filter != FILTER_LZMA2 {
+        return Err(format!("unsupported filter {:#x}", filter).into());
+    }
+    if flags & 0x03 != 0 {
+        return Err(format!("unsupported chain of {} filters", (flags & 0x03) + 1).into());
+    }
+    if read_varint(&mut next)? != 1 || next()? > 40 {
+        return Err("invalid LZMA2 properties".into());
+    }
+    if fields.iter().any(|&b| b != 0) {
+        return Err("block header padding is not null".into());
+    }
+
+    let start = data.count;
+    let mut checked = CheckedWriter::new(out, check);
+    lzma2.reset();
+    lzma2.decompress(data, &mut checked)?;
+    let compressed = data.count - start;
+    let uncompressed = checked.written;
+    if let Some(size) = compressed_size.filter(|&size| size != compressed) {
+        return Err(format!(
+            "compressed size is {}, but the header says {}",
+            compressed, size
+        )
+        .into());
+    }
+    if let Some(size) = uncompressed_size.filter(|&size| size != uncompressed) {
+        return Err(format!(
+            "uncompressed size is {}, but the header says {}",
+            uncompressed, size
+        )
+        .into());
+    }
+
+    let padding = (4 - (header_size as u64 + compressed) % 4) % 4;
+    read_padding(data, padding, "block padding")?;
+    let mut stored = [0; 32];
+    let stored = &mut stored[..check.size()];
+    read_exact(data, stored, "block check")?;
+    let computed = checked.finish();
+    if *stored != computed[..] {
+        return Err(format!(
+            "{} mismatch, stored {} but computed {}",
+            check.name(),
+            hex(stored),
+            hex(&computed)
+        )
+        .into());
+    }
+    Ok((
+        header_size as u64 + compressed + check.size() as u64,
+        uncompressed,
+    ))
+}
+
+/// Decode the index after its indicator, which is already read, and check it
+/// against the `records` of the decoded blocks. Returns the size of the
+/// index.
+fn decode_index<R: BufRead>(data: &mut CountingReader<R>, records: &[(u64, u64)]) -> Result<u64> {
+    let mut index = vec![0];
+    let mut next = || -> Result<u8> {
+        let mut byte = [0];
+        read_exact(data, &mut byte, "index")?;
+        index.push(byte[0]);
+        Ok(byte[0])
+    };
+    let count = read_varint(&mut next)?;
+    if count != records.len() as u64 {
+        return Err(format!(
+            "index has {} records, but there are {} blocks",
+            count,
+            records.len()
+        )
+        .into());
+    }
+    for (i, &(unpadded, uncompressed)) in records.iter().enumerate() {
+        let record = (read_varint(&mut next)?, read_varint(&mut next)?);
+        if record != (unpadded, uncompressed) {
+            return Err(format!("index record {} doesn't match the block", i).into());
+        }
+    }
+
+    let padding = (4 - index.len() % 4) % 4;
+    read_padding(data, padding as u64, "index padding")?;
+    index.resize(index.len() + padding, 0);
+
+    let mut crc = [0; 4];
+    read_exact(data, &mut crc, "index")?;
+    check_crc32(&index, &crc, "index")?;
+    Ok(index.len() as u64 + 4)
+}
+
+/// Read a variable-length integer from the bytes returned by `next`.
+fn read_varint(mut next: impl FnMut() -> Result<u8>) -> Result<u64> {
+    let mut value = 0;
+    for i in 0..9 {
+        let byte = next()?;
+        if i > 0 && byte == 0 {
+            return Err("invalid variable-length integer".into());
+        }
+        value |= ((byte & 0x7f) as u64) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err("invalid variable-length integer".into())
+}
+
+fn read_exact<R: Read>(data: &mut R, buf: &mut [u8], what: &str) -> Result<()> {
+    data.read_exact(buf).map_err(|e| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => format!("{} is truncated", what).into(),
+        _ => e.into(),
+    })
+}
+
+fn read_padding<R: Read>(data: &mut R, size: u64, what: &str) -> Result<()> {
+    let mut padding = vec![0; size as usize];
+    read_exact(data, &mut padding, what)?;
+    if padding.iter().any(|&b| b != 0) {
+        return Err(format!("{} is not null", what).into());
+    }
+    Ok(())
+}
+
+fn check_crc32(data: &[u8], stored: &[u8], what: &str) -> Result<()> {
+    if CRC32.checksum(data).to_le_bytes() != stored {
+        return Err(format!("{} CRC32 mismatch", what).into());
+    }
+    Ok(())
+}
+
+/// The type of the check of the blocks of a stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Check {
+    None,
+    Crc32,
+    Crc64,
+    Sha256,
+}
+
+impl Check {
+    fn from_flags(flags: [u8; 2]) -> Result<Self> {
+        if flags[0] != 0 || flags[1] & 0xf0 != 0 {
+            return Err(format!("unsupported stream flags {:02x?}", flags).into());
+        }
+        match flags[1] {
+            0x00 => Ok(Check::None),
+            0x01 => Ok(Check::Crc32),
+            0x04 => Ok(Check::Crc64),
+            0x0a => Ok(Check::Sha256),
+            id => Err(format!("unsupported check {:#04x}", id).into()),
+        }
+    }
+
+    fn id(self) -> u8 {
+        match self {
+            Check::None => 0x00,
+            Check::Crc32 => 0x01,
+            Check::Crc64 => 0x04,
+            Check::Sha256 => 0x0a,
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Check::None => 0,
+            Check::Crc32 => 4,
+            Check::Crc64 => 8,
+            Check::Sha256 => 32,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Check::None => "None",
+            Check::Crc32 => "CRC32",
+            Check::Crc64 => "CRC64",
+            Check::Sha256 => "SHA-256",
+        }
+    }
+}
+
+/// A reader counting the bytes consumed from it.
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: BufRead> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: BufRead> BufRead for CountingReader<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.count += amt as u64;
+        self.inner.consume(amt)
+    }
+}
+
+/// A writer computing the check of the data written through it.
+struct CheckedWriter<'a, W> {
+    inner: W,
+    hasher: Hasher<'a>,
+    written: u64,
+}
+
+enum Hasher<'a> {
+    None,
+    Crc32(crc::Digest<'a, u32>),
+    Crc64(crc::Digest<'a, u64>),
+    Sha256(Sha256),
+}
+
+impl<W: Write> CheckedWriter<'static, W> {
+    fn new(inner: W, check: Check) -> Self {
+        let hasher = match check {
+            Check::None => Hasher::None,
+            Check::Crc32 => Hasher::Crc32(CRC32.digest()),
+            Check::Crc64 => Hasher::Crc64(CRC64.digest()),
+            Check::Sha256 => Hasher::Sha256(Sha256::new()),
+        };
+        Self {
+            inner,
+            hasher,
+            written: 0,
+        }
+    }
+}
+
+impl<W> CheckedWriter<'_, W> {
+    /// The check of the data written, as stored after the block.
+    fn finish(self) -> Vec<u8> {
+        match self.hasher {
+            Hasher::None => Vec::new(),
+            Hasher::Crc32(digest) => digest.finalize().to_le_bytes().to_vec(),
+            Hasher::Crc64(digest) => digest.finalize().to_le_bytes().to_vec(),
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+        }
+    }
+}
+
+impl<W: Write> Write for CheckedWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        match &mut self.hasher {
+            Hasher::None => {}
+            Hasher::Crc32(digest) => digest.update(&buf[..written]),
+            Hasher::Crc64(digest) => digest.update(&buf[..written]),
+            Hasher::Sha256(hasher) => hasher.update(&buf[..written]),
+        }
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compress `data` to a stream of a single block with `check`, with
+    /// the uncompressed chunks of lzma-rs.
+    fn stream(data: &[u8], check: Check) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        lzma_rs::lzma2_compress(&mut &data[..], &mut compressed).unwrap();
+        // An 8 MiB dictionary.
+        encode_stream(&compressed, 0x16, data, check)
+    }
+
+    #[test]
+    fn checks() {
+        let data: Vec<u8> = (0..10000u32).map(|i| (i * i % 251) as u8).collect();
+        for check in [Check::None, Check::Crc32, Check::Crc64, Check::Sha256] {
+            let stream = stream(&data, check);
+            let mut out = Vec::new();
+            decompress(&stream[..], &mut out, &mut Lzma2Decoder::new()).unwrap();
+            assert!(out == data, "{:?}", check);
+
+            if check == Check::None {
+                continue;
+            }
+            // The check is right before the index and the footer, 12 bytes
+            // each.
+            let mut corrupt = stream.clone();
+            let len = corrupt.len();
+            corrupt[len - 25] ^= 1;
+            let error = decompress(&corrupt[..], &mut Vec::new(), &mut Lzma2Decoder::new())
+                .unwrap_err()
+                .to_string();
+            assert!(
+                error.starts_with(&format!("block 0: {} mismatch", check.name())),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn concatenated() {
+        let mut data = stream(b"first", Check::Crc32);
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&stream(b"second", Check::Sha256));
+        let mut out = Vec::new();
+        decompress(&data[..], &mut out, &mut Lzma2Decoder::new()).unwrap();
+        assert_eq!(out, b"firstsecond");
+
+        // Stream padding must be a multiple of 4 bytes, and what follows a
+        // stream another stream.
+        let mut padded = data.clone();
+        padded.push(0);
+        let error = decompress(&padded[..], &mut Vec::new(), &mut Lzma2Decoder::new())
+            .unwrap_err()
+            .to_string();
+        assert_eq!(error, "stream padding of 1 bytes is not a multiple of 4");
+        data.extend_from_slice(b"junk");
+        let error = decompress(&data[..], &mut Vec::new(), &mut Lzma2Decoder::new())
+            .unwrap_err()
+            .to_string();
+        assert_eq!(error, "stream 2: stream header is truncated");
+    }
+
+    #[test]
+    fn compressed() {
+        let text: Vec<u8> = (0..100_000u32)
+            .flat_map(|i| format!("line {}\n", i % 1000).into_bytes())
+            .collect();
+        let mut state = 1u32;
+        let noise: Vec<u8> = (0..100_000)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect();
+        for data in [&text[..], &noise, &[0; 5], &[]] {
+            let stream = compress(data).unwrap();
+            let mut out = Vec::new();
+            decompress(&stream[..], &mut out, &mut Lzma2Decoder::new()).unwrap();
+            assert!(out == data);
+        }
+        assert!(compress(&text).unwrap().len() < text.len() / 2);
+        // Stored in uncompressed chunks, with 3 bytes of header each.
+        assert!(compress(&noise).unwrap().len() < noise.len() + 100);
+    }
+}
@@ -0,0 +1,237 @@
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom};
+
+const LOCAL_FILE_HEADER_SIGNATURE: u32 = 0x04034b50;
+const CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06054b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+
+/// Find the entry `name` in the zip file `reader`, returning the offset and
+/// length of its data. The entry must be stored without compression, like
+/// `payload.bin` in OTA packages, so the data can be read in place.
+pub fn find_stored_entry<R: Read + Seek>(reader: &mut R, name: &str) -> Result<(u64, u64)> {
+    let (cd_offset, entries) = read_end_of_central_directory(reader)?;
+
+    reader.seek(SeekFrom::Start(cd_offset))?;
+    for _ in 0..entries {
+        let mut header = [0; 46];
+        reader.read_exact(&mut header)?;
+        if u32_at(&header, 0) != CENTRAL_DIRECTORY_SIGNATURE {
+            return Err(invalid("bad central directory entry"));
+        }
+        let method = u16_at(&header, 10);
+        let mut compressed_size = u32_at(&header, 20) as u64;
+        let mut uncompressed_size = u32_at(&header, 24) as u64;
+        let name_len = u16_at(&header, 28) as usize;
+        let extra_len = u16_at(&header, 30) as usize;
+        let comment_len = u16_at(&header, 32) as i64;
+        let mut local_header_offset = u32_at(&header, 42) as u64;
+
+        let mut entry_name = vec![0; name_len];
+        reader.read_exact(&mut entry_name)?;
+        let mut extra = vec![0; extra_len];
+        reader.read_exact(&mut extra)?;
+        reader.seek(SeekFrom::Current(comment_len))?;
+
+        if entry_name != name.as_bytes() {
+            continue;
+        }
+
+        // Values that do not fit in 32 bits are stored in the ZIP64 extra
+        // field, in this order.
+        if let Some(mut zip64) = find_extra(&extra, ZIP64_EXTRA_ID) {
+            for value in [
+                &mut uncompressed_size,
+                &mut compressed_size,
+                &mut local_header_offset,
+            ] {
+                if *value == 0xffffffff && zip64.len() >= 8 {
+                    *value = u64_at(zip64, 0);
+                    zip64 = &zip64[8..];
+                }
+            }
+        }
+
+        if method != 0 {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!(
+                    "{} is compressed in the zip file (method {}), extract it first",
+                    name, method
+                ),
+            ));
+        }
+        if compressed_size != uncompressed_size {
+            return Err(invalid(
+                "stored entry with different compressed and uncompressed size",
+            ));
+        }
+
+        // The extra field of the local header may differ from the central
+        // directory one.
+        let mut local = [0; 30];
+        reader.seek(SeekFrom::Start(local_header_offset))?;
+        reader.read_exact(&mut local)?;
+        if u32_at(&local, 0) != LOCAL_FILE_HEADER_SIGNATURE {
+            return Err(invalid("bad local file header"));
+        }
+        let data_offset =
+            local_header_offset + 30 + u16_at(&local, 26) as u64 + u16_at(&local, 28) as u64;
+        return Ok((data_offset, compressed_size));
+    }
+
+    Err(Error::new(
+        ErrorKind::NotFound,
+        format!("{} not found in the zip file", name),
+    ))
+}
+
+/// Read the end of central directory record, returning the offset of the
+/// central directory and the number of entries.
+fn read_end_of_central_directory<R: Read + Seek>(reader: &mut R) -> Result<(u64, u64)> {
+    // The record is 22 bytes, followed by a comment of at most 65535 bytes.
+    let file_size = reader.seek(SeekFrom::End(0))?;
+    let tail_size = file_size.min(22 + 0xffff);
+    let mut tail = vec![0; tail_size as usize];
+    reader.seek(SeekFrom::Start(file_size - tail_size))?;
+    reader.read_exact(&mut tail)?;
+
+    let eocd = (0..tail.len().saturating_sub(21))
+        .rev()
+        .find(|&i| u32_at(&tail, i) == END_OF_CENTRAL_DIRECTORY_SIGNATURE)
+        .ok_or_else(|| invalid("not a zip file"))?;
+    let entries = u16_at(&tail, eocd + 10) as u64;
+    let cd_offset = u32_at(&tail, eocd + 16) as u64;
+    if entries != 0xffff && cd_offset != 0xffffffff {
+        return Ok((cd_offset, entries));
+    }
+
+    // ZIP64, the locator is right before the end of central directory record.
+    let eocd_offset = file_size - tail_size + eocd as u64;
+    let mut locator = [0; 20];
+    reader.seek(SeekFrom::Start(
+        eocd_offset
+            .checked_sub(20)
+            .ok_or_else(|| invalid("bad ZIP64 locator"))?,
+    ))?;
+    reader.read_exact(&mut locator)?;
+    if u32_at(&locator, 0) != ZIP64_LOCATOR_SIGNATURE {
+        return Err(invalid("bad ZIP64 locator"));
+    }
+
+    let mut record = [0; 56];
+    reader.seek(SeekFrom::Start(u64_at(&locator, 8)))?;
+    reader.read_exact(&mut record)?;
+    if u32_at(&record, 0) != ZIP64_END_OF_CENTRAL_DIRECTORY_SIGNATURE {
+        return Err(invalid("bad ZIP64 end of central directory record"));
+    }
+    Ok((u64_at(&record, 48), u64_at(&record, 32)))
+}
+
+/// Find the data of the extra field with `id`.
+fn find_extra(mut extra: &[u8], id: u16) -> Option<&[u8]> {
+    while extra.len() >= 4 {
+        let size = u16_at(extra, 2) as usize;
+        let data = extra.get(4..4 + size)?;
+        if u16_at(extra, 0) == id {
+            return Some(data);
+        }
+        extra = &extra[4 + size..];
+    }
+    None
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Build a zip file with entries of `(name, method, data)`.
+    fn zip(entries: &[(&str, u16, &[u8])]) -> Vec<u8> {
+        let mut file = Vec::new();
+        let mut central_directory = Vec::new();
+        for (name, method, data) in entries {
+            let offset = file.len() as u32;
+            let mut common = Vec::new();
+            common.extend_from_slice(&20u16.to_le_bytes()); // version needed
+            common.extend_from_slice(&0u16.to_le_bytes()); // flags
+            common.extend_from_slice(&method.to_le_bytes());
+            common.extend_from_slice(&[0; 8]); // time, date, crc32
+            common.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            common.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            common.extend_from_slice(&(name.len() as u16).to_le_bytes());
+
+            // Local extra field used for alignment, like zipalign does.
+            file.extend_from_slice(&LOCAL_FILE_HEADER_SIGNATURE.to_le_bytes());
+            file.extend_from_slice(&common);
+            file.extend_from_slice(&3u16.to_le_bytes());
+            file.extend_from_slice(name.as_bytes());
+            file.extend_from_slice(&[0; 3]);
+            file.extend_from_slice(data);
+
+            central_directory.extend_from_slice(&CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes());
+            central_directory.extend_from_slice(&20u16.to_le_bytes()); // version made by
+            central_directory.extend_from_slice(&common);
+            central_directory.extend_from_slice(&[0; 12]); // extra, comment, disk, attributes
+            central_directory.extend_from_slice(&offset.to_le_bytes());
+            central_directory.extend_from_slice(name.as_bytes());
+        }
+
+        let cd_offset = file.len() as u32;
+        file.extend_from_slice(&central_directory);
+        file.extend_from_slice(&END_OF_CENTRAL_DIRECTORY_SIGNATURE.to_le_bytes());
+        file.extend_from_slice(&[0; 4]);
+        file.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        file.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        file.extend_from_slice(&(central_directory.len() as u32).to_le_bytes());
+        file.extend_from_slice(&cd_offset.to_le_bytes());
+        file.extend_from_slice(&4u16.to_le_bytes());
+        file.extend_from_slice(b"test");
+        file
+    }
+
+    #[test]
+    fn stored_entry() -> Result<()> {
+        let data = zip(&[
+            ("META-INF/com/android/metadata", 0, b"ota-type=AB"),
+            ("payload.bin", 0, b"CrAU payload"),
+        ]);
+
+        let (offset, length) = find_stored_entry(&mut Cursor::new(&data), "payload.bin")?;
+        assert_eq!(&data[offset as usize..][..length as usize], b"CrAU payload");
+
+        let error = find_stored_entry(&mut Cursor::new(&data), "care_map.pb").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_entry() {
+        let data = zip(&[("payload.bin", 8, b"deflated")]);
+        let error = find_stored_entry(&mut Cursor::new(&data), "payload.bin").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn not_zip() {
+        let error = find_stored_entry(&mut Cursor::new(vec![0; 100]), "payload.bin").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
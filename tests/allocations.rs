@@ -0,0 +1,132 @@
+//! Count the allocations of applying operations with a [`DumpContext`],
+//! reused across them, and with [`dump_operation`], which makes a context
+//! for each.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::io::Cursor;
+
+use payload_dumper_rust::chromeos_update_engine::{
+    install_operation::Type, Extent, InstallOperation,
+};
+use payload_dumper_rust::{dump_operation, DumpContext};
+
+const BLOCK_SIZE: u64 = 4096;
+const OPERATIONS: u64 = 48;
+
+/// The system allocator, counting the allocations of each thread, so those
+/// of other tests don't count.
+struct Counting;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+fn allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    f();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+/// REPLACE_XZ, REPLACE and ZERO operations of a block each, in turn, with
+/// their data blobs.
+fn operations() -> (Vec<InstallOperation>, Vec<u8>) {
+    let mut blobs = Vec::new();
+    let operations = (0..OPERATIONS)
+        .map(|i| {
+            let block: Vec<u8> = (0..BLOCK_SIZE).map(|j| (i + j / 16) as u8).collect();
+            let (op_type, data) = match i % 3 {
+                0 => {
+                    let mut xz = Vec::new();
+                    lzma_rs::xz_compress(&mut &block[..], &mut xz).unwrap();
+                    (Type::ReplaceXz, xz)
+                }
+                1 => (Type::Replace, block),
+                _ => (Type::Zero, Vec::new()),
+            };
+            let mut operation = InstallOperation {
+                dst_extents: vec![Extent {
+                    start_block: Some(i),
+                    num_blocks: Some(1),
+                }],
+                ..Default::default()
+            };
+            operation.set_type(op_type);
+            if !data.is_empty() {
+                operation.data_offset = Some(blobs.len() as u64);
+                operation.data_length = Some(data.len() as u64);
+                blobs.extend_from_slice(&data);
+            }
+            operation
+        })
+        .collect();
+    (operations, blobs)
+}
+
+#[test]
+#[cfg_attr(feature = "xz-native", ignore = "liblzma has no decoder to reuse")]
+fn reused_context() {
+    let (operations, blobs) = operations();
+    let mut src = Cursor::new(&blobs);
+    let mut expected = Cursor::new(vec![0u8; (OPERATIONS * BLOCK_SIZE) as usize]);
+    let mut dst = Cursor::new(vec![0u8; (OPERATIONS * BLOCK_SIZE) as usize]);
+
+    let fresh = allocations(|| {
+        for operation in &operations {
+            dump_operation(
+                &mut src,
+                0,
+                None::<&mut Cursor<Vec<u8>>>,
+                &mut expected,
+                operation,
+                BLOCK_SIZE,
+            )
+            .unwrap();
+        }
+    });
+    let mut context = DumpContext::new();
+    let reused = allocations(|| {
+        for operation in &operations {
+            context
+                .dump_operation(
+                    &mut src,
+                    0,
+                    None::<&mut Cursor<Vec<u8>>>,
+                    &mut dst,
+                    operation,
+                    BLOCK_SIZE,
+                )
+                .unwrap();
+        }
+    });
+    assert_eq!(dst.get_ref(), expected.get_ref());
+    println!(
+        "{} operations: {} allocations with a context each, {} with one reused",
+        OPERATIONS, fresh, reused
+    );
+    assert!(
+        reused * 2 < fresh,
+        "{} allocations with one context, {} with a context each",
+        reused,
+        fresh
+    );
+}
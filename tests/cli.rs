@@ -0,0 +1,1096 @@
+//! Run the binary on a payload created with [`PayloadBuilder`], with and
+//! without subcommands.
+
+use std::{
+    fs::File,
+    io::{Cursor, Write},
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+use binrw::BinWrite;
+use payload_dumper_rust::{CreateOptions, PayloadBuilder, PayloadHeader};
+use sha2::{Digest, Sha256};
+
+/// A directory in the temporary directory, removed when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "payload-dumper-cli-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn run(args: &[&str]) -> Output {
+    let output = run_unchecked(args);
+    assert!(
+        output.status.success(),
+        "{:?}: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+fn run_unchecked(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_payload-dumper-rust"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn image(seed: u8) -> Vec<u8> {
+    (0..8192u32).map(|i| (i as u8).wrapping_mul(seed)).collect()
+}
+
+/// Write a payload with partitions `boot` and `system` to `dir`.
+fn create_payload(dir: &Path) -> PathBuf {
+    let mut builder =
+        PayloadBuilder::new(Cursor::new(Vec::new()), CreateOptions::default()).unwrap();
+    builder.add_partition("boot", &image(3)[..]).unwrap();
+    builder.add_partition("system", &image(7)[..]).unwrap();
+    let path = dir.join("payload.bin");
+    builder.finish(&mut File::create(&path).unwrap()).unwrap();
+    path
+}
+
+#[test]
+fn extract() {
+    let dir = TempDir::new("extract");
+    let payload = create_payload(&dir.0);
+    let payload = payload.to_str().unwrap();
+    let legacy = dir.0.join("legacy");
+    let subcommand = dir.0.join("subcommand");
+
+    run(&[payload, "-o", legacy.to_str().unwrap(), "-p", "boot", "-q"]);
+    run(&[
+        "extract",
+        payload,
+        "-o",
+        subcommand.to_str().unwrap(),
+        "-p",
+        "boot",
+        "-q",
+    ]);
+    for out in [legacy, subcommand] {
+        assert_eq!(std::fs::read(out.join("boot.img")).unwrap(), image(3));
+        assert!(!out.join("system.img").exists());
+    }
+}
+
+#[test]
+fn list() {
+    let dir = TempDir::new("list");
+    let payload = create_payload(&dir.0);
+    let payload = payload.to_str().unwrap();
+
+    let legacy = run(&[payload, "--list"]).stdout;
+    assert_eq!(run(&["list", payload]).stdout, legacy);
+    let legacy = String::from_utf8(legacy).unwrap();
+    assert!(legacy.starts_with("Full payload, version 2."));
+    assert!(legacy.contains("boot") && legacy.contains("system"));
+
+    let json = String::from_utf8(run(&["list", "--json", payload]).stdout).unwrap();
+    assert_eq!(run(&["--json", "--list", payload]).stdout, json.as_bytes());
+    assert!(json.contains(r#"{"name": "system", "version": null, "size": 8192, "old_size": null"#));
+}
+
+#[test]
+fn info() {
+    let dir = TempDir::new("info");
+    let payload = create_payload(&dir.0);
+    let payload = payload.to_str().unwrap();
+
+    let text = String::from_utf8(run(&["info", payload]).stdout).unwrap();
+    assert!(text.contains("partitions:") && text.contains("payload signatures:"));
+    assert!(
+        text.contains("metadata signatures message:")
+            && text.contains("payload signatures message:")
+    );
+    let json = String::from_utf8(run(&["info", payload, "--json"]).stdout).unwrap();
+    assert!(json.contains(r#""metadata_signatures_message": null"#));
+    assert!(json.contains(r#""payload_signatures_message": null"#));
+    assert!(json.contains(r#""type": "full""#));
+    assert!(json.contains(r#""partitions": 2"#));
+    assert!(json.contains(r#""partitions_size": 16384"#));
+}
+
+#[test]
+fn contents() {
+    let dir = TempDir::new("contents");
+    let mut builder =
+        PayloadBuilder::new(Cursor::new(Vec::new()), CreateOptions::default()).unwrap();
+    let mut boot = image(3);
+    boot[..8].copy_from_slice(b"ANDROID!");
+    builder.add_partition("boot", &boot[..]).unwrap();
+    builder.add_partition("misc", &[0; 8192][..]).unwrap();
+    builder.add_partition("system", &image(7)[..]).unwrap();
+    let payload = dir.0.join("payload.bin");
+    builder
+        .finish(&mut File::create(&payload).unwrap())
+        .unwrap();
+    let payload = payload.to_str().unwrap();
+
+    let text = String::from_utf8(run(&["list", payload]).stdout).unwrap();
+    let row = |name: &str| {
+        text.lines()
+            .find(|line| line.starts_with(name))
+            .unwrap()
+            .to_string()
+    };
+    assert!(row("NAME").ends_with("CONTENT"), "{}", text);
+    assert!(row("boot ").ends_with(" boot"), "{}", text);
+    assert!(row("misc ").ends_with(" zero"), "{}", text);
+    assert!(row("system ").ends_with(" unknown"), "{}", text);
+    let json = String::from_utf8(run(&["list", "--json", payload]).stdout).unwrap();
+    assert!(
+        json.contains(r#""operations": 1, "content": "boot"}"#),
+        "{}",
+        json
+    );
+
+    let text = String::from_utf8(run(&["info", payload]).stdout).unwrap();
+    assert!(
+        text.contains("contents:") && text.contains("boot: boot") && text.contains("misc: zero")
+    );
+    let json = String::from_utf8(run(&["info", payload, "--json"]).stdout).unwrap();
+    assert!(
+        json.contains(r#""contents": {"boot": "boot", "misc": "zero", "system": "unknown"}"#),
+        "{}",
+        json
+    );
+}
+
+#[test]
+fn properties() {
+    let dir = TempDir::new("properties");
+    let payload = create_payload(&dir.0);
+    let data = std::fs::read(&payload).unwrap();
+    let payload = payload.to_str().unwrap();
+
+    let text = String::from_utf8(run(&["properties", payload]).stdout).unwrap();
+    let lines: Vec<_> = text.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[0].starts_with("FILE_HASH=") && lines[0].ends_with('='));
+    assert_eq!(lines[1], format!("FILE_SIZE={}", data.len()));
+    assert!(lines[2].starts_with("METADATA_HASH="));
+    assert!(lines[3].starts_with("METADATA_SIZE="));
+
+    let out = dir.0.join("payload_properties.txt");
+    run(&["properties", payload, "--out", out.to_str().unwrap()]);
+    assert_eq!(std::fs::read_to_string(&out).unwrap(), text);
+
+    let json = String::from_utf8(run(&["properties", payload, "--json"]).stdout).unwrap();
+    let sha256: String = Sha256::digest(&data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    assert!(json.contains(&format!(
+        r#""file_hash": "{}", "file_size": {}"#,
+        sha256,
+        data.len()
+    )));
+}
+
+#[test]
+fn verify_properties() {
+    let dir = TempDir::new("verify-properties");
+    let payload = create_payload(&dir.0);
+    let payload = payload.to_str().unwrap();
+    let properties = dir.0.join("payload_properties.txt");
+    let properties = properties.to_str().unwrap();
+    run(&["properties", payload, "--out", properties]);
+
+    let text = String::from_utf8(run(&["verify-properties", payload, properties]).stdout).unwrap();
+    assert_eq!(
+        text,
+        "FILE_HASH: OK\nFILE_SIZE: OK\nMETADATA_HASH: OK\nMETADATA_SIZE: OK\n"
+    );
+
+    // Stale properties of a payload changed after them.
+    let stale = std::fs::read_to_string(properties).unwrap();
+    let stale = stale.replace(
+        &format!("FILE_SIZE={}", std::fs::metadata(payload).unwrap().len()),
+        "FILE_SIZE=1",
+    );
+    std::fs::write(properties, stale).unwrap();
+    let output = run_unchecked(&["verify-properties", payload, properties]);
+    assert_eq!(output.status.code(), Some(4));
+    let text = String::from_utf8(output.stdout).unwrap();
+    assert!(text.contains("FILE_HASH: OK\nFILE_SIZE: MISMATCH, expected 1, got "));
+    let json = String::from_utf8(
+        run_unchecked(&["verify-properties", payload, properties, "--json"]).stdout,
+    )
+    .unwrap();
+    assert!(
+        json.contains(r#""key": "FILE_SIZE", "expected": "1""#) && json.contains(r#""ok": false"#)
+    );
+
+    std::fs::write(properties, "FILE_SIZE=1\n").unwrap();
+    let output = run_unchecked(&["verify-properties", payload, properties]);
+    assert_eq!(output.status.code(), Some(3));
+}
+
+#[test]
+fn stats() {
+    let dir = TempDir::new("stats");
+    let payload = create_payload(&dir.0);
+    let payload = payload.to_str().unwrap();
+
+    // Payloads from `create` have no estimate_cow_size.
+    let text = String::from_utf8(run(&["stats", payload]).stdout).unwrap();
+    assert!(text.contains("COW SIZE") && text.contains("~16.0 KiB"));
+    let json = String::from_utf8(run(&["stats", payload, "--json"]).stdout).unwrap();
+    assert!(json.contains(r#"{"partition": "boot", "estimate_cow_size": null, "approximation": 8192, "size": 8192, "approximated": true}"#));
+    assert!(json.contains(r#""total": 16384,"#));
+}
+
+#[test]
+fn checksum_file() {
+    let dir = TempDir::new("checksum");
+    let payload = create_payload(&dir.0);
+    let out = dir.0.join("out");
+    let hex = |image: &[u8]| {
+        Sha256::digest(image)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>()
+    };
+
+    run(&[
+        payload.to_str().unwrap(),
+        "-o",
+        out.to_str().unwrap(),
+        "--checksum-file",
+        "-q",
+    ]);
+    let sums = std::fs::read_to_string(out.join("SHA256SUMS")).unwrap();
+    assert_eq!(
+        sums,
+        format!(
+            "{}  boot.img\n{}  system.img\n",
+            hex(&image(3)),
+            hex(&image(7))
+        )
+    );
+    assert_eq!(
+        std::fs::read_to_string(out.join("SHA256SUMS.expected")).unwrap(),
+        sums
+    );
+
+    // Skipped partitions have no entries.
+    std::fs::remove_file(out.join("system.img")).unwrap();
+    run(&[
+        payload.to_str().unwrap(),
+        "-o",
+        out.to_str().unwrap(),
+        "--checksum-file",
+        "--skip-existing",
+        "-q",
+    ]);
+    let sums = std::fs::read_to_string(out.join("SHA256SUMS")).unwrap();
+    assert_eq!(sums, format!("{}  system.img\n", hex(&image(7))));
+}
+
+#[cfg(feature = "compress")]
+#[test]
+fn compress() {
+    let dir = TempDir::new("compress");
+    let payload = create_payload(&dir.0);
+    let out = dir.0.join("out");
+
+    run(&[
+        payload.to_str().unwrap(),
+        "-o",
+        out.to_str().unwrap(),
+        "--compress",
+        "xz",
+        "-q",
+    ]);
+    assert!(!out.join("boot.img").exists());
+    for (name, seed) in [("boot.img.xz", 3), ("system.img.xz", 7)] {
+        let mut image = Vec::new();
+        lzma_rs::xz_decompress(&mut &std::fs::read(out.join(name)).unwrap()[..], &mut image)
+            .unwrap();
+        assert_eq!(image, self::image(seed));
+    }
+}
+
+#[test]
+fn tar() {
+    let dir = TempDir::new("tar");
+    let payload = create_payload(&dir.0);
+    let output = run(&["--tar", payload.to_str().unwrap(), "-q"]);
+
+    // Read the headers, the size is in octal at 124.
+    let mut tar = &output.stdout[..];
+    for (name, seed) in [("boot.img", 3), ("system.img", 7)] {
+        let (header, rest) = tar.split_at(512);
+        assert_eq!(&header[..name.len() + 1], format!("{}\0", name).as_bytes());
+        assert_eq!(&header[124..136], b"00000020000\0");
+        assert_eq!(&rest[..8192], image(seed));
+        tar = &rest[8192..];
+    }
+    assert_eq!(tar, [0; 1024]);
+}
+
+#[test]
+fn dry_run() {
+    let dir = TempDir::new("dry_run");
+    let payload = create_payload(&dir.0);
+    let out = dir.0.join("out");
+
+    let output = run(&[
+        payload.to_str().unwrap(),
+        "-o",
+        out.to_str().unwrap(),
+        "--dry-run=hash",
+        "-q",
+    ]);
+    let table = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(table.matches("PASS").count(), 2, "{}", table);
+    assert!(!out.exists());
+
+    // The data of system is past the end of a truncated payload.
+    let data = std::fs::read(&payload).unwrap();
+    let truncated = dir.0.join("truncated.bin");
+    std::fs::write(&truncated, &data[..data.len() - 600]).unwrap();
+    let output = run_unchecked(&[
+        truncated.to_str().unwrap(),
+        "-o",
+        out.to_str().unwrap(),
+        "--dry-run",
+        "-q",
+    ]);
+    assert!(!output.status.success());
+    let table = String::from_utf8(output.stdout).unwrap();
+    assert!(table.contains("boot       PASS"), "{}", table);
+    assert!(
+        table.contains("system     FAIL") && table.contains("truncated"),
+        "{}",
+        table
+    );
+    assert!(!out.exists());
+}
+
+#[test]
+fn partial_update() {
+    let dir = TempDir::new("partial-update");
+    let data = std::fs::read(create_payload(&dir.0)).unwrap();
+    let mut header = PayloadHeader::parse_prefix(&mut &data[..]).unwrap();
+    header.manifest.partial_update = Some(true);
+    let mut partial = Cursor::new(Vec::new());
+    header.write(&mut partial).unwrap();
+    partial
+        .write_all(&data[header.blobs_offset as usize..])
+        .unwrap();
+    let payload = dir.0.join("partial.bin");
+    std::fs::write(&payload, partial.into_inner()).unwrap();
+    let payload = payload.to_str().unwrap();
+
+    let text = String::from_utf8(run(&["info", payload]).stdout).unwrap();
+    assert!(text.contains("full (partial update)"), "{}", text);
+
+    // The other partitions of the device are copied from --old as they are.
+    let old = dir.0.join("old");
+    std::fs::create_dir(&old).unwrap();
+    std::fs::write(old.join("boot.img"), image(5)).unwrap();
+    std::fs::write(old.join("vendor.img"), image(11)).unwrap();
+    let out = dir.0.join("out");
+    run(&[
+        payload,
+        "-o",
+        out.to_str().unwrap(),
+        "--old",
+        old.to_str().unwrap(),
+        "-q",
+    ]);
+    assert_eq!(std::fs::read(out.join("boot.img")).unwrap(), image(3));
+    assert_eq!(std::fs::read(out.join("system.img")).unwrap(), image(7));
+    assert_eq!(std::fs::read(out.join("vendor.img")).unwrap(), image(11));
+
+    // A partition only in --old can be selected, not one in neither.
+    let out = dir.0.join("selected");
+    run(&[
+        payload,
+        "-o",
+        out.to_str().unwrap(),
+        "--old",
+        old.to_str().unwrap(),
+        "-p",
+        "vendor",
+        "-q",
+    ]);
+    assert_eq!(std::fs::read(out.join("vendor.img")).unwrap(), image(11));
+    assert!(!out.join("boot.img").exists());
+
+    // Copies are skipped by --skip-existing only if complete.
+    let args = [
+        payload,
+        "-o",
+        out.to_str().unwrap(),
+        "--old",
+        old.to_str().unwrap(),
+    ];
+    let output = run_unchecked(&[&args[..], &["-p", "vendor"]].concat());
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("--skip-existing to skip complete ones"),
+        "{}",
+        stderr
+    );
+    std::fs::write(out.join("vendor.img"), &image(11)[..100]).unwrap();
+    let output = run(&[&args[..], &["-p", "vendor", "--skip-existing"]].concat());
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("skipped"));
+    assert_eq!(std::fs::read(out.join("vendor.img")).unwrap(), image(11));
+    let output = run(&[&args[..], &["-p", "vendor", "--skip-existing"]].concat());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("vendor.img exists, skipped"));
+    let output = run_unchecked(&[
+        payload,
+        "-o",
+        out.to_str().unwrap(),
+        "--old",
+        old.to_str().unwrap(),
+        "-p",
+        "odm",
+        "-q",
+    ]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn verbose() {
+    let dir = TempDir::new("verbose");
+    let payload = create_payload(&dir.0);
+    let payload = payload.to_str().unwrap();
+    let out = dir.0.join("out");
+    let out = out.to_str().unwrap();
+
+    // Spans are logged on stderr when they close, the operations only with -vv.
+    let output = run(&[payload, "-o", out, "-p", "boot", "-v", "--progress", "none"]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("partition{name=boot"), "{}", stderr);
+    assert!(!stderr.contains("operation{"), "{}", stderr);
+
+    let output = run(&[
+        payload,
+        "-o",
+        out,
+        "-p",
+        "boot",
+        "-vv",
+        "--progress",
+        "none",
+        "--force",
+    ]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("operation{index=0 op_type=REPLACE_XZ"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn summary() {
+    let dir = TempDir::new("summary");
+    let payload = create_payload(&dir.0);
+    let payload = payload.to_str().unwrap();
+    let out = dir.0.join("out");
+    let out = out.to_str().unwrap();
+
+    let output = run(&[payload, "-o", out, "--progress", "none"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<Vec<_>> = stdout
+        .lines()
+        .map(|line| line.split_whitespace().collect())
+        .collect();
+    assert!(
+        lines.contains(&vec![
+            "PARTITION",
+            "STATUS",
+            "SIZE",
+            "OPS",
+            "WRITTEN",
+            "ELAPSED",
+            "THROUGHPUT",
+            "WAITS"
+        ]),
+        "{}",
+        stdout
+    );
+    assert!(
+        lines
+            .iter()
+            .any(|line| line.starts_with(&["total", "16.0", "KiB", "2", "16.0"])),
+        "{}",
+        stdout
+    );
+
+    let output = run(&[
+        payload,
+        "-o",
+        out,
+        "--summary",
+        "json",
+        "--force",
+        "-q",
+        "--prefetch-mb",
+        "1",
+    ]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains(
+            "\"partition\": \"boot\", \"status\": \"ok\", \"size\": 8192, \"operations\": 1"
+        ),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("{\"event\": \"summary_total\", \"partitions\": 2, \"size\": 16384"),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("\"operations\": 1, \"bytes\": 8192, \"data_bytes\": "),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("\"read_waits\": "), "{}", stderr);
+}
+
+#[test]
+fn raw_ops() {
+    let dir = TempDir::new("raw_ops");
+    let payload = create_payload(&dir.0);
+    let out = dir.0.join("raw");
+    run(&[
+        "raw-ops",
+        payload.to_str().unwrap(),
+        "-p",
+        "sys*",
+        "--ops",
+        "0",
+        "--out",
+        out.to_str().unwrap(),
+    ]);
+
+    // The data is xz compressed, as it is in the payload.
+    let data = std::fs::read(out.join("system.op0000.replace_xz.bin")).unwrap();
+    assert!(data.starts_with(b"\xfd7zXZ\0"));
+    let json = std::fs::read_to_string(out.join("system.op0000.replace_xz.json")).unwrap();
+    let sha256: String = Sha256::digest(&data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    assert!(
+        json.contains(&format!("\"sha256\": \"{}\"", sha256)),
+        "{}",
+        json
+    );
+    assert!(
+        json.contains(&format!("\"data_length\": {}", data.len())),
+        "{}",
+        json
+    );
+    assert!(!out.join("boot.op0000.replace_xz.bin").exists());
+
+    let output = run_unchecked(&[
+        "raw-ops",
+        payload.to_str().unwrap(),
+        "-p",
+        "boot",
+        "--ops",
+        "9",
+    ]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no operation 9"));
+}
+
+#[test]
+fn op_range() {
+    let dir = TempDir::new("op_range");
+    let options = CreateOptions {
+        blocks_per_operation: 1,
+        ..Default::default()
+    };
+    let mut builder = PayloadBuilder::new(Cursor::new(Vec::new()), options).unwrap();
+    builder.add_partition("boot", &image(3)[..]).unwrap();
+    let payload = dir.0.join("payload.bin");
+    builder
+        .finish(&mut File::create(&payload).unwrap())
+        .unwrap();
+    let payload = payload.to_str().unwrap();
+    let out = dir.0.join("out");
+
+    // Only the second block is written, to the existing image.
+    std::fs::create_dir_all(&out).unwrap();
+    std::fs::write(out.join("boot.img"), [0xffu8; 8192]).unwrap();
+    let output = run(&[
+        payload,
+        "-o",
+        out.to_str().unwrap(),
+        "--op-range",
+        "1..",
+        "--progress",
+        "none",
+    ]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("boot: operation 1 REPLACE"), "{}", stderr);
+    assert!(!stderr.contains("operation 0"), "{}", stderr);
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .contains("not verified"));
+    assert_eq!(
+        std::fs::read(out.join("boot.img")).unwrap(),
+        [&[0xff; 4096][..], &image(3)[4096..]].concat()
+    );
+
+    let output = run(&[
+        payload,
+        "-o",
+        out.to_str().unwrap(),
+        "--stop-on-op",
+        "0",
+        "--force",
+        "-q",
+    ]);
+    assert!(output.stderr.is_empty());
+    assert_eq!(
+        std::fs::read(out.join("boot.img")).unwrap(),
+        [&image(3)[..4096], &[0; 4096]].concat()
+    );
+
+    let output = run_unchecked(&[payload, "-o", out.to_str().unwrap(), "--op-range", "2.."]);
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("Partition boot has no operation 2, it has 2"));
+}
+
+#[test]
+fn op_range_zero() {
+    let dir = TempDir::new("op_range_zero");
+    let options = CreateOptions {
+        blocks_per_operation: 1,
+        ..Default::default()
+    };
+    let mut builder = PayloadBuilder::new(Cursor::new(Vec::new()), options).unwrap();
+    let boot = [&image(3)[..4096], &[0; 4096]].concat();
+    builder.add_partition("boot", &boot[..]).unwrap();
+    let payload = dir.0.join("payload.bin");
+    builder
+        .finish(&mut File::create(&payload).unwrap())
+        .unwrap();
+    let out = dir.0.join("out");
+
+    // The second block is a ZERO operation, which must clear the existing
+    // image.
+    std::fs::create_dir_all(&out).unwrap();
+    std::fs::write(out.join("boot.img"), [0xffu8; 8192]).unwrap();
+    let output = run(&[
+        payload.to_str().unwrap(),
+        "-o",
+        out.to_str().unwrap(),
+        "--op-range",
+        "1..",
+        "--progress",
+        "none",
+    ]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("boot: operation 1 ZERO"), "{}", stderr);
+    assert_eq!(
+        std::fs::read(out.join("boot.img")).unwrap(),
+        [&[0xff; 4096][..], &[0; 4096]].concat()
+    );
+}
+
+#[test]
+fn keep_going() {
+    let dir = TempDir::new("keep_going");
+    let payload = create_payload(&dir.0);
+    // Break the xz stream of boot, the first partition.
+    let mut data = std::fs::read(&payload).unwrap();
+    let xz = data.windows(6).position(|w| w == b"\xfd7zXZ\0").unwrap();
+    data[xz + 7] ^= 0xff;
+    std::fs::write(&payload, data).unwrap();
+    let payload = payload.to_str().unwrap();
+    let out = dir.0.join("out");
+
+    let output = run_unchecked(&[
+        payload,
+        "-o",
+        out.to_str().unwrap(),
+        "--keep-going",
+        "--progress",
+        "none",
+    ]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.contains("boot: operation 0 (type REPLACE_XZ): xz error"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("system: OK"), "{}", stdout);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Failed to dump boot"));
+    assert!(out.join("boot.img.partial").exists());
+    assert!(!out.join("boot.img").exists());
+    assert_eq!(std::fs::read(out.join("system.img")).unwrap(), image(7));
+
+    let output = run_unchecked(&[
+        payload,
+        "-o",
+        out.to_str().unwrap(),
+        "--keep-going",
+        "--delete-failed",
+        "--force",
+        "-q",
+    ]);
+    assert!(!output.status.success());
+    assert!(!out.join("boot.img").exists());
+}
+
+#[test]
+fn atomic() {
+    let dir = TempDir::new("atomic");
+    let payload = create_payload(&dir.0);
+    let out = dir.0.join("out");
+    run(&[payload.to_str().unwrap(), "-o", out.to_str().unwrap(), "-q"]);
+    assert_eq!(std::fs::read(out.join("system.img")).unwrap(), image(7));
+    assert!(!out.join("boot.img.tmp").exists() && !out.join("system.img.tmp").exists());
+
+    // Break the xz stream of boot, the first partition.
+    let mut data = std::fs::read(&payload).unwrap();
+    let xz = data.windows(6).position(|w| w == b"\xfd7zXZ\0").unwrap();
+    data[xz + 7] ^= 0xff;
+    std::fs::write(&payload, data).unwrap();
+    let payload = payload.to_str().unwrap();
+
+    // The image from before is only replaced by a complete one.
+    let boot = std::fs::read(out.join("boot.img")).unwrap();
+    let output = run_unchecked(&[payload, "-o", out.to_str().unwrap(), "--force", "-q"]);
+    assert!(!output.status.success());
+    assert_eq!(std::fs::read(out.join("boot.img")).unwrap(), boot);
+    assert!(!out.join("boot.img.tmp").exists());
+
+    // Kept to be resumed.
+    let output = run_unchecked(&[
+        payload,
+        "-o",
+        out.to_str().unwrap(),
+        "--force",
+        "--resume",
+        "-q",
+    ]);
+    assert!(!output.status.success());
+    assert!(out.join("boot.img.tmp").exists());
+    std::fs::remove_file(out.join("boot.img.tmp")).unwrap();
+
+    let output = run_unchecked(&[
+        payload,
+        "-o",
+        out.to_str().unwrap(),
+        "--force",
+        "--no-atomic",
+        "-q",
+    ]);
+    assert!(!output.status.success());
+    assert_ne!(std::fs::read(out.join("boot.img")).unwrap(), boot);
+    assert!(!out.join("boot.img.tmp").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn interrupt() {
+    use std::{io::Write, process::Stdio};
+
+    let dir = TempDir::new("interrupt");
+    let payload = std::fs::read(create_payload(&dir.0)).unwrap();
+    let out = dir.0.join("out");
+    // Read from stdin, which is kept open without the data until it's
+    // interrupted.
+    let mut child = Command::new(env!("CARGO_BIN_EXE_payload-dumper-rust"))
+        .args(["-", "-o", out.to_str().unwrap()])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Up to the data of the first operation.
+    let data = payload.windows(6).position(|w| w == b"\xfd7zXZ\0").unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(&payload[..data]).unwrap();
+    while !out.join("boot.img.tmp").exists() {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) };
+    stdin.write_all(&payload[data..]).unwrap();
+    drop(stdin);
+
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(130));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Interrupted"));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("boot: stopped at operation"), "{}", stdout);
+    assert!(!out.join("boot.img.tmp").exists() && !out.join("boot.img").exists());
+}
+
+#[test]
+fn exit_codes() {
+    let dir = TempDir::new("exit_codes");
+    let payload = create_payload(&dir.0);
+    let out = dir.0.join("out");
+    let code = |args: &[&str], class: &str| {
+        let output = run_unchecked(args);
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(
+            stderr.contains(&format!("Error ({}): ", class)),
+            "{}",
+            stderr
+        );
+        output.status.code().unwrap()
+    };
+
+    let args = [payload.to_str().unwrap(), "-o", out.to_str().unwrap(), "-q"];
+    assert_eq!(code(&[&args[..], &["-p", "nope"]].concat(), "usage"), 2);
+    let file = dir.0.join("file");
+    std::fs::write(&file, b"").unwrap();
+    assert_eq!(
+        code(
+            &[
+                payload.to_str().unwrap(),
+                "-o",
+                file.join("out").to_str().unwrap()
+            ],
+            "io"
+        ),
+        5
+    );
+
+    // Data of boot, which isn't compressed, doesn't match its hash.
+    let options = CreateOptions {
+        compression: None,
+        ..Default::default()
+    };
+    let mut builder = PayloadBuilder::new(Cursor::new(Vec::new()), options).unwrap();
+    builder.add_partition("boot", &image(3)[..]).unwrap();
+    let mut data = Vec::new();
+    builder.finish(&mut data).unwrap();
+    let boot = data.windows(64).position(|w| w == &image(3)[..64]).unwrap();
+    data[boot] ^= 0xff;
+    std::fs::write(&payload, &data).unwrap();
+    assert_eq!(code(&args, "verification"), 4);
+
+    // Broken xz stream.
+    let mut data = std::fs::read(create_payload(&dir.0)).unwrap();
+    let start = data.windows(6).position(|w| w == b"\xfd7zXZ\0").unwrap();
+    data[start + 7] ^= 0xff;
+    std::fs::write(&payload, &data).unwrap();
+    assert_eq!(code(&[&args[..], &["--force"]].concat(), "format"), 3);
+}
+
+#[test]
+fn show_extents() {
+    let dir = TempDir::new("show_extents");
+    let payload = create_payload(&dir.0);
+    let payload = payload.to_str().unwrap();
+
+    let output = run(&["info", payload, "--show-extents", "system"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<Vec<_>> = stdout
+        .lines()
+        .map(|line| line.split_whitespace().collect())
+        .collect();
+    assert_eq!(lines[0], ["OPERATION", "TYPE", "DST", "EXTENTS"]);
+    assert_eq!(lines[1], ["0", "REPLACE_XZ", "0..2"]);
+    assert_eq!(
+        stdout.lines().last(),
+        Some("Covered: 2 of 2 blocks (100.0%)")
+    );
+
+    let output = run(&["info", payload, "--show-extents", "boot", "--json"]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.contains("\"dst_extents\": [{\"start_block\": 0, \"num_blocks\": 2}]"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("\"overlaps\": []"), "{}", stdout);
+    assert!(stdout.contains("\"covered_blocks\": 2"), "{}", stdout);
+
+    let output = run_unchecked(&["info", payload, "--show-extents", "bot"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Did you mean 'boot'?"));
+}
+
+#[test]
+fn sequential_read() {
+    let dir = TempDir::new("sequential_read");
+    let payload = create_payload(&dir.0);
+    let payload = payload.to_str().unwrap();
+    let out = dir.0.join("out");
+
+    let output = run(&[
+        payload,
+        "-o",
+        out.to_str().unwrap(),
+        "--sequential-read",
+        "--progress",
+        "json",
+    ]);
+    assert_eq!(std::fs::read(out.join("boot.img")).unwrap(), image(3));
+    assert_eq!(std::fs::read(out.join("system.img")).unwrap(), image(7));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains(
+            "{\"event\": \"op_done\", \"partition\": \"system\", \"index\": 0, \"bytes\": 8192}"
+        ),
+        "{}",
+        stderr
+    );
+
+    let output = run_unchecked(&[
+        payload,
+        "-o",
+        out.to_str().unwrap(),
+        "--sequential-read",
+        "--mmap",
+        "--force",
+    ]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn malicious_names() {
+    let dir = TempDir::new("names");
+    let mut builder =
+        PayloadBuilder::new(Cursor::new(Vec::new()), CreateOptions::default()).unwrap();
+    builder.add_partition("../escape", &image(3)[..]).unwrap();
+    let payload = dir.0.join("payload.bin");
+    builder
+        .finish(&mut File::create(&payload).unwrap())
+        .unwrap();
+    let out = dir.0.join("out");
+
+    let output = run(&[payload.to_str().unwrap(), "-o", out.to_str().unwrap(), "-q"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("not a valid file name"));
+    assert_eq!(std::fs::read(out.join(".._escape.img")).unwrap(), image(3));
+    assert!(!dir.0.join("escape.img").exists());
+}
+
+/// Ranges requested from [`serve`] as `(offset, length)`.
+#[cfg(feature = "http")]
+type Requests = std::sync::Arc<std::sync::Mutex<Vec<(u64, u64)>>>;
+
+/// Serve `data` on a local port, answering range requests. Returns the URL
+/// and the requested ranges.
+#[cfg(feature = "http")]
+fn serve(data: Vec<u8>) -> (String, Requests) {
+    use std::io::{BufRead, BufReader};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/payload.bin", listener.local_addr().unwrap());
+    let requests = Requests::default();
+    let requested = requests.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let range = BufReader::new(&stream)
+                .lines()
+                .map(Result::unwrap)
+                .take_while(|line| !line.is_empty())
+                .find_map(|line| {
+                    let line = line.to_lowercase();
+                    let (first, last) = line.strip_prefix("range: bytes=")?.split_once('-')?;
+                    Some((first.parse::<u64>().ok()?, last.parse::<u64>().ok()?))
+                });
+            let (first, last) = range.unwrap();
+            requests.lock().unwrap().push((first, last - first + 1));
+            let end = (last + 1).min(data.len() as u64);
+            let _ = write!(
+                stream,
+                "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                first,
+                end - 1,
+                data.len(),
+                end - first
+            );
+            let _ = stream.write_all(&data[first as usize..end as usize]);
+        }
+    });
+    (url, requested)
+}
+
+#[cfg(feature = "http")]
+#[test]
+fn http() {
+    // Partitions of incompressible data larger than the read-ahead, so the
+    // data of each is fetched by its own requests.
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut random = |len: usize| -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    };
+    let boot = random(3 << 20);
+    let system = random(3 << 20);
+    let mut builder =
+        PayloadBuilder::new(Cursor::new(Vec::new()), CreateOptions::default()).unwrap();
+    builder.add_partition("boot", &boot[..]).unwrap();
+    builder.add_partition("system", &system[..]).unwrap();
+    let mut data = Vec::new();
+    builder.finish(&mut data).unwrap();
+    let header = PayloadHeader::parse_prefix(&mut &data[..]).unwrap();
+    let blobs = |name: &str| {
+        let partitions = &header.manifest.partitions;
+        let partition = partitions.iter().find(|p| p.partition_name == name);
+        let operations = &partition.unwrap().operations;
+        let first = operations[0].data_offset.unwrap();
+        let last = operations.last().unwrap();
+        let end = last.data_offset.unwrap() + last.data_length.unwrap();
+        header.blobs_offset + first..header.blobs_offset + end
+    };
+    let (boot_blobs, system_blobs) = (blobs("boot"), blobs("system"));
+    let (url, requests) = serve(data);
+
+    // Only the headers and the beginning of each partition, which tells
+    // what it holds, are read.
+    let text = String::from_utf8(run(&["list", &url]).stdout).unwrap();
+    assert!(text.contains("boot") && text.contains("system"), "{}", text);
+    let starts = [boot_blobs.start, system_blobs.start];
+    for (offset, _) in requests.lock().unwrap().drain(..) {
+        assert!(offset < header.blobs_offset || starts.contains(&offset));
+    }
+
+    // The read-ahead may go past the data of `boot`, but nothing is fetched
+    // for `system`.
+    let dir = TempDir::new("http");
+    let out = dir.0.join("out");
+    let out = out.to_str().unwrap();
+    run(&["extract", &url, "-o", out, "--partitions", "boot", "-q"]);
+    assert_eq!(std::fs::read(dir.0.join("out/boot.img")).unwrap(), boot);
+    assert!(!dir.0.join("out/system.img").exists());
+    let requests = requests.lock().unwrap();
+    assert!(requests
+        .iter()
+        .any(|(offset, _)| boot_blobs.contains(offset)));
+    for (offset, _) in requests.iter() {
+        assert!(!system_blobs.contains(offset), "{:?}", requests);
+    }
+}
@@ -0,0 +1,280 @@
+#!/usr/bin/env python3
+"""Generate the PUFFDIFF fixture of the `puffdiff_fixture` test in src/lib.rs.
+
+old.img and new.img are two blocks of two gzip members each, compressed by
+zlib. patch.puf is a puffin patch from old.img to new.img: the deflate
+streams are puffed here, independently of src/puffin.rs, and the bsdiff
+patch between the puffs is a BSDF2 patch with bzip2 blocks.
+
+Run from this directory: python3 generate.py
+"""
+
+import bz2
+import gzip
+import struct
+
+BLOCK_SIZE = 4096
+
+LENGTH_BASES = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35,
+                43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258]
+LENGTH_EXTRA = [0] * 8 + [1] * 4 + [2] * 4 + [3] * 4 + [4] * 4 + [5] * 4 + [0]
+DISTANCE_BASES = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+                  257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+                  8193, 12289, 16385, 24577]
+DISTANCE_EXTRA = [0, 0, 0, 0] + [i // 2 for i in range(2, 28)]
+CODE_LENGTH_ORDER = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2,
+                     14, 1, 15]
+
+
+class Bits:
+    def __init__(self, data, position):
+        self.data = data
+        self.position = position
+
+    def read(self, n):
+        value = 0
+        for i in range(n):
+            byte = self.data[self.position // 8]
+            value |= ((byte >> (self.position % 8)) & 1) << i
+            self.position += 1
+        return value
+
+
+def huffman(lengths):
+    """Map (length, code) to symbol, codes read most significant bit first."""
+    codes = {}
+    code = 0
+    for length in range(1, 16):
+        for symbol, l in enumerate(lengths):
+            if l == length:
+                codes[(length, code)] = symbol
+                code += 1
+        code <<= 1
+    return codes
+
+
+def decode(bits, codes):
+    code = 0
+    for length in range(1, 16):
+        code = code << 1 | bits.read(1)
+        if (length, code) in codes:
+            return codes[(length, code)]
+    raise ValueError("invalid code")
+
+
+class Puff:
+    def __init__(self):
+        self.out = bytearray()
+        self.literals = bytearray()
+
+    def flush(self):
+        n = len(self.literals)
+        if n == 0:
+            return
+        if n <= 127:
+            self.out.append(n - 1)
+        else:
+            self.out.append(127)
+            self.out += struct.pack(">H", n - 128)
+        self.out += self.literals
+        self.literals = bytearray()
+
+    def literal(self, byte):
+        if len(self.literals) == 127 + 65536:
+            self.flush()
+        self.literals.append(byte)
+
+    def metadata(self, data):
+        self.flush()
+        self.out += struct.pack(">H", len(data) - 1) + data
+
+    def length_distance(self, length, distance):
+        self.flush()
+        if length < 130:
+            self.out.append(0x80 | (length - 3))
+        else:
+            self.out += bytes([0xFF, length - 130])
+        self.out += struct.pack(">H", distance - 1)
+
+    def end_of_block(self):
+        self.flush()
+        self.out += bytes([0xFF, 129])
+
+
+def puff_deflate(data, start, puff):
+    """Puff the deflate stream at bit `start` of `data`, returning its end."""
+    bits = Bits(data, start)
+    while True:
+        last = bits.read(1)
+        kind = bits.read(2)
+        header = bytearray([last << 7 | kind << 5])
+        if kind == 0:
+            skipped = (8 - bits.position % 8) % 8
+            header[0] |= bits.read(skipped)
+            length = bits.read(16)
+            assert bits.read(16) == length ^ 0xFFFF
+            puff.metadata(header)
+            offset = bits.position // 8
+            for byte in data[offset:offset + length]:
+                puff.literal(byte)
+            bits.position += length * 8
+            puff.end_of_block()
+        else:
+            if kind == 1:
+                lengths = [8] * 144 + [9] * 112 + [7] * 24 + [8] * 8
+                literal_codes = huffman(lengths)
+                distance_codes = huffman([5] * 30)
+            else:
+                hlit, hdist, hclen = bits.read(5), bits.read(5), bits.read(4)
+                header += bytes([hlit, hdist, hclen])
+                code_lengths = [0] * 19
+                nibbles = []
+                for i in range(hclen + 4):
+                    code_lengths[CODE_LENGTH_ORDER[i]] = bits.read(3)
+                    nibbles.append(code_lengths[CODE_LENGTH_ORDER[i]])
+                nibbles += [0] * (len(nibbles) % 2)
+                for i in range(0, len(nibbles), 2):
+                    header.append(nibbles[i] << 4 | nibbles[i + 1])
+                code_length_codes = huffman(code_lengths)
+                lengths = []
+                while len(lengths) < hlit + 257 + hdist + 1:
+                    symbol = decode(bits, code_length_codes)
+                    if symbol < 16:
+                        lengths.append(symbol)
+                        header.append(symbol)
+                    elif symbol == 16:
+                        extra = bits.read(2)
+                        lengths += [lengths[-1]] * (3 + extra)
+                        header.append(16 + extra)
+                    elif symbol == 17:
+                        extra = bits.read(3)
+                        lengths += [0] * (3 + extra)
+                        header.append(20 + extra)
+                    else:
+                        extra = bits.read(7)
+                        lengths += [0] * (11 + extra)
+                        header.append(28 + extra)
+                literal_codes = huffman(lengths[:hlit + 257])
+                distance_codes = huffman(lengths[hlit + 257:])
+            puff.metadata(header)
+            while True:
+                symbol = decode(bits, literal_codes)
+                if symbol < 256:
+                    puff.literal(symbol)
+                elif symbol == 256:
+                    puff.end_of_block()
+                    break
+                else:
+                    i = symbol - 257
+                    length = LENGTH_BASES[i] + bits.read(LENGTH_EXTRA[i])
+                    d = decode(bits, distance_codes)
+                    distance = DISTANCE_BASES[d] + bits.read(DISTANCE_EXTRA[d])
+                    puff.length_distance(length, distance)
+        if last:
+            return bits.position
+
+
+def gzip_deflates(data):
+    """Bit offsets of the deflate streams of gzip members without names."""
+    starts = []
+    offset = 0
+    while offset + 10 <= len(data) and data[offset:offset + 2] == b"\x1f\x8b":
+        assert data[offset + 3] == 0, "no optional gzip fields"
+        starts.append((offset + 10) * 8)
+        end = puff_deflate(data, (offset + 10) * 8, Puff())
+        offset = (end + 7) // 8 + 8
+    return starts
+
+
+def puff(data):
+    """Puff all deflate streams of `data`, returning the puffed data and
+    the bit extents of the deflates and the byte extents of the puffs."""
+    puffed = Puff()
+    deflates = []
+    puffs = []
+    end = 0
+    for start in gzip_deflates(data) + [len(data) * 8]:
+        if start != end:
+            raw = bytearray(data[end // 8:(start + 7) // 8])
+            if start % 8:
+                raw[-1] &= (1 << (start % 8)) - 1
+            raw[0] >>= end % 8
+            puffed.out += raw
+        if start == len(data) * 8:
+            break
+        offset = len(puffed.out)
+        end = puff_deflate(data, start, puffed)
+        deflates.append((start, end - start))
+        puffs.append((offset, len(puffed.out) - offset))
+    return bytes(puffed.out), deflates, puffs
+
+
+def varint(n):
+    out = bytearray()
+    while True:
+        byte = n & 0x7F
+        n >>= 7
+        if n:
+            out.append(byte | 0x80)
+        else:
+            out.append(byte)
+            return bytes(out)
+
+
+def field(number, wire_type, payload):
+    key = varint(number << 3 | wire_type)
+    if wire_type == 0:
+        return key + varint(payload)
+    return key + varint(len(payload)) + payload
+
+
+def stream_info(deflates, puffs, puff_length):
+    message = b""
+    for offset, length in deflates:
+        message += field(1, 2, field(1, 0, offset) + field(2, 0, length))
+    for offset, length in puffs:
+        message += field(2, 2, field(1, 0, offset * 8) + field(2, 0, length * 8))
+    return message + field(3, 0, puff_length)
+
+
+def offtout(n):
+    return struct.pack("<q", n)
+
+
+def bsdiff(old, new):
+    """A BSDF2 patch adding the common prefix length and copying the rest."""
+    common = min(len(old), len(new))
+    ctrl = offtout(common) + offtout(len(new) - common) + offtout(0)
+    diff = bytes((n - o) % 256 for o, n in zip(old, new))
+    extra = new[common:]
+    ctrl, diff, extra = bz2.compress(ctrl), bz2.compress(diff), bz2.compress(extra)
+    return (b"BSDF2\x01\x01\x01" + offtout(len(ctrl)) + offtout(len(diff))
+            + offtout(len(new)) + ctrl + diff + extra)
+
+
+def image(texts):
+    data = b"".join(gzip.compress(text, level, mtime=0) for text, level in texts)
+    return data + bytes(-len(data) % BLOCK_SIZE)
+
+
+def main():
+    lines = [b"partition %d offset %d\n" % (i % 7, i * 4096) for i in range(200)]
+    old = image([(b"".join(lines), 9), (b"version 1\n" * 20, 0)])
+    lines[50:60] = [b"patched line %d\n" % i for i in range(10)]
+    new = image([(b"".join(lines), 6), (b"version 2\n" * 20, 0)])
+
+    old_puffed, old_deflates, old_puffs = puff(old)
+    new_puffed, new_deflates, new_puffs = puff(new)
+    header = (field(1, 0, 1)
+              + field(2, 2, stream_info(old_deflates, old_puffs, len(old_puffed)))
+              + field(3, 2, stream_info(new_deflates, new_puffs, len(new_puffed))))
+    patch = (b"PUF1" + struct.pack(">I", len(header)) + header
+             + bsdiff(old_puffed, new_puffed))
+
+    for name, data in [("old.img", old), ("new.img", new), ("patch.puf", patch)]:
+        with open(name, "wb") as f:
+            f.write(data)
+
+
+if __name__ == "__main__":
+    main()